@@ -0,0 +1,222 @@
+// Benchmarks for the emulator's inner loop: whole frames through `Nes::step_frame`, and the CPU
+// and PPU ticking on their own.
+//
+// `cargo bench -p covnes` runs them all, and criterion compares each run against the last one,
+// so the usual way to check a change is to run it once before and once after. The ROM is built
+// below rather than loaded from roms/, so the numbers don't depend on a file that might change.
+
+use std::cell::Cell;
+
+use covnes::{
+    nes::{
+        cpu::{CpuHostAccess, CPU},
+        io::DummyIO,
+        ppu::{PPUHostAccess, PPU},
+        Nes,
+    },
+    romfiles::RomFile,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+// Where the parts of `PROGRAM` are, in an NROM-128 cartridge's PRG ROM at $C000
+const RESET: u16 = 0xC000;
+const MAIN_LOOP: u16 = 0xC055;
+const NMI: u16 = 0xC060;
+const IRQ: u16 = 0xC06F;
+
+// Turns on the background and sprites with a full nametable, 64 sprites and a palette, then spins
+// doing arithmetic in RAM while the NMI handler copies OAM each frame. That's enough for every
+// part of the PPU's rendering along with a CPU that's always busy, like most games.
+#[rustfmt::skip]
+const PROGRAM: &[u8] = &[
+    // Reset: wait two vblanks for the PPU to warm up, with rendering off
+    0x78,             // SEI
+    0xD8,             // CLD
+    0xA2, 0xFF,       // LDX #$FF
+    0x9A,             // TXS
+    0xA9, 0x00,       // LDA #$00
+    0x8D, 0x00, 0x20, // STA $2000
+    0x8D, 0x01, 0x20, // STA $2001
+    0x2C, 0x02, 0x20, // BIT $2002
+    0x10, 0xFB,       // BPL -5
+    0x2C, 0x02, 0x20, // BIT $2002
+    0x10, 0xFB,       // BPL -5
+    // Palette: colours 0-31
+    0xA9, 0x3F,       // LDA #$3F
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00,       // LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA2, 0x00,       // LDX #$00
+    0x8A,             // TXA
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8,             // INX
+    0xE0, 0x20,       // CPX #$20
+    0xD0, 0xF7,       // BNE -9
+    // The first nametable and its attributes: 0-255 four times
+    0xA9, 0x20,       // LDA #$20
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA9, 0x00,       // LDA #$00
+    0x8D, 0x06, 0x20, // STA $2006
+    0xA0, 0x04,       // LDY #$04
+    0xA2, 0x00,       // LDX #$00
+    0x8A,             // TXA
+    0x8D, 0x07, 0x20, // STA $2007
+    0xE8,             // INX
+    0xD0, 0xF9,       // BNE -7
+    0x88,             // DEY
+    0xD0, 0xF6,       // BNE -10
+    // Sprites for the NMI handler to copy, with every byte of OAM set to its index
+    0x8A,             // TXA
+    0x9D, 0x00, 0x02, // STA $0200,X
+    0xE8,             // INX
+    0xD0, 0xF9,       // BNE -7
+    // NMI on, then background and sprites on without the left column clipped
+    0xA9, 0x80,       // LDA #$80
+    0x8D, 0x00, 0x20, // STA $2000
+    0xA9, 0x1E,       // LDA #$1E
+    0x8D, 0x01, 0x20, // STA $2001
+    // Main loop
+    0xE6, 0x00,       // INC $00
+    0xA5, 0x00,       // LDA $00
+    0x65, 0x01,       // ADC $01
+    0x85, 0x01,       // STA $01
+    0x4C, 0x55, 0xC0, // JMP $C055
+    // NMI: OAM DMA and reset the scroll
+    0x48,             // PHA
+    0xA9, 0x02,       // LDA #$02
+    0x8D, 0x14, 0x40, // STA $4014
+    0xA9, 0x00,       // LDA #$00
+    0x8D, 0x05, 0x20, // STA $2005
+    0x8D, 0x05, 0x20, // STA $2005
+    0x68,             // PLA
+    // IRQ (which never happens) shares the NMI's RTI
+    0x40,             // RTI
+];
+
+const PRG_SIZE: usize = 0x4000;
+const CHR_SIZE: usize = 0x2000;
+
+fn prg() -> Vec<u8> {
+    let mut prg = PROGRAM.to_vec();
+    prg.resize(PRG_SIZE, 0);
+    for (i, vector) in [NMI, RESET, IRQ].iter().enumerate() {
+        prg[0x3FFA + i * 2..0x3FFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+    }
+    prg
+}
+
+// Tiles with a bit of everything in them, so that no pixel is left transparent for long
+fn chr() -> Vec<u8> {
+    (0..CHR_SIZE)
+        .map(|i| (i * 7 + (i >> 4) * 13) as u8)
+        .collect()
+}
+
+fn rom() -> RomFile {
+    let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0];
+    data.resize(16, 0);
+    data.extend(prg());
+    data.extend(chr());
+    RomFile::from_bytes(&data).unwrap()
+}
+
+fn step_frame(c: &mut Criterion) {
+    let mut nes = Nes::new(DummyIO);
+    nes.load_rom(rom()).unwrap();
+    // Past the reset code and the first partial frame, so every frame measured is the same
+    for _ in 0..3 {
+        nes.step_frame();
+    }
+    assert!(nes.ppu.is_rendering());
+
+    let mut group = c.benchmark_group("nes");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("step_frame", |b| b.iter(|| nes.step_frame()));
+    group.finish();
+}
+
+// The CPU on its own, with nothing but 64kb of RAM to talk to
+struct FlatMemory {
+    memory: Vec<Cell<u8>>,
+}
+
+impl CpuHostAccess for FlatMemory {
+    fn read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize].get()
+    }
+
+    fn write(&self, addr: u16, value: u8) {
+        self.memory[addr as usize].set(value)
+    }
+}
+
+fn cpu_tick(c: &mut Criterion) {
+    let memory = FlatMemory {
+        memory: vec![Cell::new(0); 0x10000],
+    };
+    for (i, &byte) in prg().iter().enumerate() {
+        memory.memory[0xC000 + i].set(byte);
+    }
+    // Straight into the main loop, as the reset code would wait forever for a PPU
+    memory.memory[0xFFFC].set(MAIN_LOOP as u8);
+    memory.memory[0xFFFD].set((MAIN_LOOP >> 8) as u8);
+
+    let cpu = CPU::new();
+    c.bench_function("cpu_tick", |b| b.iter(|| cpu.tick(&memory)));
+}
+
+// The PPU on its own, with the cartridge's CHR and the nametable in a flat 16kb
+struct PpuMemory {
+    memory: Vec<Cell<u8>>,
+    last_pixel: Cell<u8>,
+}
+
+impl PPUHostAccess for PpuMemory {
+    fn ppu_read(&self, addr: u16) -> u8 {
+        self.memory[addr as usize & 0x3FFF].get()
+    }
+
+    fn ppu_write(&self, addr: u16, value: u8) {
+        self.memory[addr as usize & 0x3FFF].set(value)
+    }
+
+    fn ppu_trigger_nmi(&self) {}
+
+    fn ppu_suppress_nmi(&self) {}
+
+    fn ppu_set_pixel(&self, _row: u16, _col: u16, palette_index: u8, _emphasis: u8) {
+        self.last_pixel.set(palette_index)
+    }
+}
+
+fn ppu_tick(c: &mut Criterion) {
+    let memory = PpuMemory {
+        memory: vec![Cell::new(0); 0x4000],
+        last_pixel: Cell::new(0),
+    };
+    for (i, byte) in chr().into_iter().enumerate() {
+        memory.memory[i].set(byte);
+    }
+    for i in 0..0x400 {
+        memory.memory[0x2000 + i].set(i as u8);
+    }
+
+    // The same palette, sprites and registers the ROM ends up with
+    let ppu = PPU::new();
+    ppu.reg_write(&memory, 6, 0x3F);
+    ppu.reg_write(&memory, 6, 0x00);
+    for i in 0..0x20 {
+        ppu.reg_write(&memory, 7, i);
+    }
+    ppu.reg_write(&memory, 3, 0);
+    for i in 0..=0xFF {
+        ppu.reg_write(&memory, 4, i);
+    }
+    ppu.reg_write(&memory, 1, 0x1E);
+    assert!(ppu.is_rendering());
+
+    c.bench_function("ppu_tick", |b| b.iter(|| ppu.tick(&memory)));
+}
+
+criterion_group!(benches, step_frame, cpu_tick, ppu_tick);
+criterion_main!(benches);
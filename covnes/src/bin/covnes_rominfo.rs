@@ -0,0 +1,156 @@
+// Prints what covnes makes of an iNES file, and whether it can run it:
+//
+//     cargo run -p covnes --bin covnes_rominfo -- game.nes [--json]
+
+use std::{env, process};
+
+use covnes::{nes::mappers, romfiles::RomFile, Error};
+
+struct RomInfo {
+    mapper: usize,
+    submapper: u8,
+    prg_rom_size: usize,
+    chr_rom_size: Option<usize>,
+    mirroring: String,
+    region: String,
+    battery: bool,
+    // Only if there is any
+    prg_ram_size: Option<usize>,
+    crc32: u32,
+    // Why the ROM can't be loaded, if it can't
+    unsupported: Option<Error>,
+    // What the mapper doesn't do yet, if it can
+    missing_features: &'static [&'static str],
+}
+
+impl RomInfo {
+    fn from_rom(rom: RomFile) -> RomInfo {
+        let mut info = RomInfo {
+            mapper: rom.mapper,
+            submapper: rom.submapper,
+            prg_rom_size: rom.prg_rom.len(),
+            chr_rom_size: rom.chr_rom.as_ref().map(|c| c.len()),
+            mirroring: format!("{:?}", rom.mirroring),
+            region: format!("{:?}", rom.timing),
+            battery: rom.provide_prg_ram,
+            prg_ram_size: Some(rom.prg_ram_size).filter(|_| rom.provide_prg_ram),
+            crc32: rom.prg_chr_crc32(),
+            unsupported: None,
+            missing_features: &[],
+        };
+        match mappers::from_rom(rom) {
+            Ok(cartridge) => info.missing_features = cartridge.missing_features(),
+            Err(e) => info.unsupported = Some(e),
+        }
+        info
+    }
+
+    fn print(&self) {
+        println!("Mapper:     {}", self.mapper);
+        println!("Submapper:  {}", self.submapper);
+        println!("PRG ROM:    {} KB", self.prg_rom_size / 1024);
+        match self.chr_rom_size {
+            Some(size) => println!("CHR ROM:    {} KB", size / 1024),
+            None => println!("CHR ROM:    none (uses CHR RAM)"),
+        }
+        println!("Mirroring:  {}", self.mirroring);
+        println!("Region:     {}", self.region);
+        println!("Battery:    {}", if self.battery { "yes" } else { "no" });
+        if let Some(size) = self.prg_ram_size {
+            println!("PRG RAM:    {} KB", size / 1024);
+        }
+        println!("CRC32:      {:08X}", self.crc32);
+        match &self.unsupported {
+            None if self.missing_features.is_empty() => println!("Supported:  yes"),
+            None => println!(
+                "Supported:  partly (no {})",
+                self.missing_features.join(", ")
+            ),
+            Some(Error::UnsupportedMapper { .. }) => {
+                println!("Supported:  no (mapper not implemented)")
+            }
+            Some(reason) => println!("Supported:  no ({})", reason),
+        }
+    }
+
+    fn print_json(&self) {
+        let chr_rom_size = match self.chr_rom_size {
+            Some(size) => size.to_string(),
+            None => "null".to_string(),
+        };
+        let prg_ram_size = match self.prg_ram_size {
+            Some(size) => size.to_string(),
+            None => "null".to_string(),
+        };
+        let unsupported = match &self.unsupported {
+            Some(reason) => json_string(&reason.to_string()),
+            None => "null".to_string(),
+        };
+        let unsupported_mapper = matches!(self.unsupported, Some(Error::UnsupportedMapper { .. }));
+        let missing_features: Vec<String> = self
+            .missing_features
+            .iter()
+            .map(|f| json_string(f))
+            .collect();
+        println!(
+            "{{\"mapper\": {}, \"submapper\": {}, \"prg_rom_size\": {}, \"chr_rom_size\": {}, \
+             \"mirroring\": {}, \"region\": {}, \"battery\": {}, \"prg_ram_size\": {}, \
+             \"crc32\": \"{:08X}\", \"supported\": {}, \"unsupported_reason\": {}, \
+             \"unsupported_mapper\": {}, \"missing_features\": [{}]}}",
+            self.mapper,
+            self.submapper,
+            self.prg_rom_size,
+            chr_rom_size,
+            json_string(&self.mirroring),
+            json_string(&self.region),
+            self.battery,
+            prg_ram_size,
+            self.crc32,
+            self.unsupported.is_none(),
+            unsupported,
+            unsupported_mapper,
+            missing_features.join(", ")
+        );
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let json = args.iter().any(|a| a == "--json");
+    let paths: Vec<&String> = args.iter().filter(|a| *a != "--json").collect();
+    if paths.len() != 1 {
+        eprintln!("usage: covnes_rominfo <rom.nes> [--json]");
+        process::exit(2);
+    }
+    let path = paths[0];
+
+    // Trainers and truncated files are rejected while parsing, before there's anything to show
+    let rom = match RomFile::from_filename(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+
+    let info = RomInfo::from_rom(rom);
+    if json {
+        info.print_json();
+    } else {
+        info.print();
+    }
+}
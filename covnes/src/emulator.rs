@@ -0,0 +1,170 @@
+// A `Nes` with a controller and a frame buffer already plugged in, for embedders that just want
+// to show a game and don't need any more control than that. It's all on the calling thread: run a
+// frame, then draw `frame_buffer`. `Nes` is still there underneath for anything else.
+
+use alloc::boxed::Box;
+use core::{cell::Cell, mem::swap};
+
+use crate::{
+    error::Result,
+    nes::{
+        io::{
+            SingleStandardController, SingleStandardControllerIO, StandardControllerButtons,
+            TwoStandardControllersIO,
+        },
+        test_pattern, Nes, FRAME_BUFFER_SIZE,
+    },
+    romfiles::RomFile,
+};
+
+/// An IO that draws into a frame buffer and reports whichever buttons were last set.
+///
+/// The frame being drawn can be swapped out at the end of each frame with `swap_frame`, so the
+/// finished one can be shown while the next is drawn. It works with one controller or two.
+pub struct BufferedIO {
+    frame: Box<Cell<[u8; FRAME_BUFFER_SIZE]>>,
+    buttons: Cell<[StandardControllerButtons; 2]>,
+}
+
+impl BufferedIO {
+    pub fn new() -> BufferedIO {
+        BufferedIO {
+            frame: Box::new(Cell::new([0; FRAME_BUFFER_SIZE])),
+            buttons: Cell::new([StandardControllerButtons::empty(); 2]),
+        }
+    }
+
+    /// Sets the buttons held down on controller `port` (0 or 1), until they're next set.
+    pub fn set_buttons(&self, port: usize, buttons: StandardControllerButtons) {
+        let mut all = self.buttons.get();
+        all[port] = buttons;
+        self.buttons.set(all);
+    }
+
+    /// Swaps the frame being drawn with `frame`, as RGB24 row by row.
+    ///
+    /// The PPU draws every pixel of every frame, so whatever's in `frame` is all overwritten by
+    /// the end of the next one.
+    pub fn swap_frame(&mut self, frame: &mut [u8; FRAME_BUFFER_SIZE]) {
+        swap(self.frame.get_mut(), frame);
+    }
+
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        let frame: &Cell<[u8]> = &*self.frame;
+        let frame = frame.as_slice_of_cells();
+        let i = (row as usize * 256 + col as usize) * 3;
+        frame[i].set(r);
+        frame[i + 1].set(g);
+        frame[i + 2].set(b);
+    }
+}
+
+impl Default for BufferedIO {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SingleStandardControllerIO for BufferedIO {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        BufferedIO::set_pixel(self, row, col, r, g, b);
+    }
+
+    fn poll_buttons(&self) -> StandardControllerButtons {
+        self.buttons.get()[0]
+    }
+}
+
+impl TwoStandardControllersIO for BufferedIO {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        BufferedIO::set_pixel(self, row, col, r, g, b);
+    }
+
+    fn poll_buttons(&self, port: usize) -> StandardControllerButtons {
+        self.buttons.get()[port]
+    }
+}
+
+/// A console with one standard controller, showing whole frames at a time.
+///
+/// Until a ROM is loaded the frame buffer has the test pattern in it, and stepping does nothing.
+pub struct Emulator {
+    nes: Nes<SingleStandardController<BufferedIO>>,
+    // The last finished frame. The IO has the one being drawn, and they're swapped at the end of
+    // every frame.
+    frame: Box<[u8; FRAME_BUFFER_SIZE]>,
+}
+
+impl Emulator {
+    pub fn new() -> Emulator {
+        let mut emulator = Emulator {
+            nes: Nes::new(SingleStandardController::new(BufferedIO::new())),
+            frame: Box::new([0; FRAME_BUFFER_SIZE]),
+        };
+        emulator.clear();
+        emulator
+    }
+
+    /// Swaps the cartridge for a new one and powers on.
+    ///
+    /// If the ROM can't be loaded the old cartridge is left in and nothing changes.
+    pub fn load_rom(&mut self, rom: RomFile) -> Result<()> {
+        self.nes.load_rom(rom)?;
+        self.clear();
+        Ok(())
+    }
+
+    /// Sets the buttons held down, which stay held until they're next set.
+    pub fn set_buttons(&self, buttons: StandardControllerButtons) {
+        self.nes.io.io.set_buttons(0, buttons);
+    }
+
+    /// Runs a frame, after which `frame_buffer` has it in. Returns the number of PPU ticks taken,
+    /// which is 0 without a ROM.
+    pub fn step_frame(&mut self) -> usize {
+        if !self.nes.has_cartridge() {
+            return 0;
+        }
+
+        let ticks = self.nes.step_frame();
+        self.nes.io.io.swap_frame(&mut self.frame);
+        ticks
+    }
+
+    /// Presses the reset button, and lets go of all the buttons on the controller.
+    pub fn reset(&mut self) {
+        self.nes.reset();
+        self.clear();
+    }
+
+    /// The last frame that was run as RGB24 row by row, `FRAME_BUFFER_SIZE` bytes long.
+    pub fn frame_buffer(&self) -> &[u8] {
+        &self.frame[..]
+    }
+
+    pub fn nes(&self) -> &Nes<SingleStandardController<BufferedIO>> {
+        &self.nes
+    }
+
+    pub fn nes_mut(&mut self) -> &mut Nes<SingleStandardController<BufferedIO>> {
+        &mut self.nes
+    }
+
+    // So the old game's last frame isn't left up until the next one is finished, and nothing held
+    // down before carries over. Without a ROM there's the test pattern instead.
+    fn clear(&mut self) {
+        self.frame.fill(0);
+        if !self.nes.has_cartridge() {
+            test_pattern::draw_into(&mut self.frame[..]);
+        }
+        self.set_buttons(StandardControllerButtons::empty());
+        // Lowering the strobe latches the (now empty) buttons in to the controller
+        self.nes.write_u8(0x4016, 0);
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,64 @@
+use thiserror::Error;
+
+#[cfg(feature = "std")]
+use crate::fm2_movie_file;
+use crate::romfiles::Mirroring;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[cfg(feature = "std")]
+    #[error("IO error")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not read header")]
+    TruncatedHeader,
+
+    #[error("File is not in the iNES format")]
+    NotINes,
+
+    #[error("ROMs with trainers are not supported")]
+    TrainerUnsupported,
+
+    #[error("Could not read all of the prg_rom")]
+    TruncatedPrgRom,
+
+    #[error("Could not read all of the chr_rom")]
+    TruncatedChrRom,
+
+    // The submapper is 0 unless the ROM has an NES 2.0 header
+    #[error("Unsupported mapper: {mapper} (submapper {submapper})")]
+    UnsupportedMapper { mapper: usize, submapper: u8 },
+
+    #[error("Badly sized prg_rom for mapper {mapper} ({size} bytes)")]
+    BadPrgRomSize { mapper: usize, size: usize },
+
+    #[error("Badly sized chr_rom for mapper {mapper} ({size} bytes)")]
+    BadChrRomSize { mapper: usize, size: usize },
+
+    #[error("Mirroring mode {mirroring:?} is not supported by mapper {mapper}")]
+    UnsupportedMirroring { mapper: usize, mirroring: Mirroring },
+
+    #[error("Save state is corrupt or from a different cartridge")]
+    BadSaveState,
+
+    #[error("Palette files should be 192 or 1536 bytes, not {0}")]
+    BadPaletteSize(usize),
+
+    #[cfg(feature = "fds")]
+    #[error("File is not an FDS disk image")]
+    NotFds,
+
+    #[cfg(feature = "fds")]
+    #[error("FDS disk images should be a whole number of 65500 byte sides, not {0} bytes")]
+    BadFdsImageSize(usize),
+
+    #[cfg(feature = "fds")]
+    #[error("The FDS BIOS should be 8192 bytes, not {0}")]
+    BadFdsBiosSize(usize),
+
+    #[cfg(feature = "std")]
+    #[error("Could not parse movie file")]
+    Movie(#[from] fm2_movie_file::Error),
+}
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
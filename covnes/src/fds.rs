@@ -0,0 +1,112 @@
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::{fs::File, io::Read, path::Path};
+
+use crate::{
+    checksum,
+    error::{Error, Result},
+};
+
+// Famicom Disk System disk images (.fds). Each side of a disk is 65500 bytes of the blocks on it,
+// one after the other, optionally after a 16 byte fwNES header. Every side starts with the disk
+// info block, which starts with "*NINTENDO-HVC*".
+//
+// The image leaves out everything that isn't a block: the gaps between them, and the CRC after
+// each one. The drive needs those to find the blocks, so they're put back in by `FdsImage::disk`.
+
+pub const SIDE_SIZE: usize = 65500;
+
+const HEADER_MAGIC: &[u8; 4] = b"FDS\x1A";
+const HEADER_LEN: usize = 16;
+const DISK_INFO_MAGIC: &[u8; 15] = b"\x01*NINTENDO-HVC*";
+
+// There's at least this much gap before the first block, and after each one. Both are in bits on
+// the disk, so this is rounded to bytes.
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+
+#[derive(Debug)]
+pub struct FdsImage {
+    // Each side as it is in the file, without the gaps
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    #[cfg(feature = "std")]
+    pub fn from_filename<P: AsRef<Path>>(path: P) -> Result<FdsImage> {
+        let mut f = File::open(path)?;
+        Self::from_read(&mut f)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_read<R: Read>(f: &mut R) -> Result<FdsImage> {
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<FdsImage> {
+        // The header's side count isn't needed, as the sides are all the same size
+        let data = if data.starts_with(HEADER_MAGIC) && data.len() >= HEADER_LEN {
+            &data[HEADER_LEN..]
+        } else {
+            data
+        };
+
+        if data.is_empty() || data.len() % SIDE_SIZE != 0 {
+            return Err(Error::BadFdsImageSize(data.len()));
+        }
+        let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(|s| s.to_vec()).collect();
+        if !sides.iter().all(|s| s.starts_with(DISK_INFO_MAGIC)) {
+            return Err(Error::NotFds);
+        }
+
+        Ok(FdsImage { sides })
+    }
+
+    // CRC-32 of every side, for telling disks apart (e.g. in save state files)
+    pub fn crc32(&self) -> u32 {
+        checksum::crc32(&self.sides.concat())
+    }
+
+    // Side `side` as the drive sees it, with the gaps and CRCs put back. Each block is preceded
+    // by a gap of zeros ending in a 1 bit (so the byte $80), which is how the drive finds the
+    // start of it. The CRCs are never checked, so they're left as zeros.
+    pub fn disk(&self, side: usize) -> Vec<u8> {
+        let data = &self.sides[side];
+        let mut disk = vec![0; LEAD_IN];
+        let mut pos = 0;
+        let mut file_size = 0;
+
+        while pos < data.len() {
+            let len = match data[pos] {
+                // Disk info, file count and file header
+                1 => 56,
+                2 => 2,
+                3 => 16,
+                // File data, the size of which was in the header just before it
+                4 => 1 + file_size,
+                // Anything else is the unused space at the end
+                _ => break,
+            };
+            if pos + len > data.len() {
+                break;
+            }
+            if data[pos] == 3 {
+                file_size = data[pos + 13] as usize | (data[pos + 14] as usize) << 8;
+            }
+
+            disk.push(0x80);
+            disk.extend_from_slice(&data[pos..pos + len]);
+            disk.extend_from_slice(&[0, 0]);
+            disk.resize(disk.len() + BLOCK_GAP, 0);
+            pos += len;
+        }
+
+        // The rest of the disk is blank
+        if disk.len() < SIDE_SIZE {
+            disk.resize(SIDE_SIZE, 0);
+        }
+        disk
+    }
+}
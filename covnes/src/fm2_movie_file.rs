@@ -5,6 +5,8 @@ use std::{
 
 use thiserror::Error;
 
+use crate::romfiles::RomFile;
+
 #[derive(Debug, Clone)]
 pub struct FM2File {
     pub version: i32,
@@ -255,8 +257,8 @@ impl FM2File {
                 return Err(Error::BadInputLine { line_no });
             }
 
-            // Ensure it goes like "|A|B|C|D|"
-            if parts[0] != "" || parts[parts.len() - 1] != "" || parts[parts.len() - 2] == "" {
+            // Ensure it goes like "|A|B|C|D|". Any of the ports can be blank, like port2 always is.
+            if !parts[0].is_empty() || !parts[parts.len() - 1].is_empty() {
                 return Err(Error::BadInputLine { line_no });
             }
             let command = match parts[1].parse::<i32>() {
@@ -329,6 +331,31 @@ impl FM2File {
             commands,
         });
     }
+
+    // Whether the movie was recorded with this ROM, going by `rom_checksum` (which FCEUX writes as
+    // "base64:" then the base64 of the MD5 from `RomFile::rom_md5`)
+    pub fn matches_rom(&self, rom: &RomFile) -> bool {
+        self.rom_checksum == format!("base64:{}", base64(&rom.rom_md5()))
+    }
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
 }
 
 fn optional(map: &mut HashMap<String, String>, key: &'static str) -> Option<String> {
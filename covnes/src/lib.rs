@@ -1,8 +1,21 @@
-#![feature(generators, generator_trait)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
 #[macro_use]
 extern crate bitflags;
 
+mod checksum;
+pub mod emulator;
+pub mod error;
+#[cfg(feature = "fds")]
+pub mod fds;
+#[cfg(feature = "std")]
 pub mod fm2_movie_file;
 pub mod nes;
+/// The types most embedders need, so `use covnes::prelude::*` is enough to run a game.
+pub mod prelude;
 pub mod romfiles;
+pub mod test_support;
+
+pub use emulator::Emulator;
+pub use error::{Error, Result};
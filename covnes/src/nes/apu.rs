@@ -0,0 +1,786 @@
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::state::{SaveState, StateReader, StateWriter},
+};
+
+// The APU: the two pulse channels with their envelopes and sweeps, the noise channel, the DMC, the
+// length counters, the frame counter and its IRQ, and the $4015 status register. The triangle
+// only has its length counter so far, so is silent.
+//
+// The DMC fetches its samples with DMA, which steals cycles from the CPU, so the fetching itself
+// is done by `DMA` when `Dmc::dma_address` says a byte's wanted.
+
+// Length counter values, indexed by the top 5 bits of the channel's 4th register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// The pulse channels' waveforms, indexed by the top 2 bits of $4000/$4004 and then the sequencer's
+// step
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// The noise channel's timer periods in CPU cycles, indexed by the bottom 4 bits of $400E. These
+// are NTSC's, as PAL consoles have a table of their own.
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+// The DMC's timer periods in CPU cycles, indexed by the bottom 4 bits of $4010 (NTSC again)
+const DMC_PERIODS: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+// CPU cycles into the frame counter's sequence at which the envelopes are clocked (every step),
+// and the length counters and sweeps (every other step), for the 4 and 5 step modes
+const FOUR_STEP_QUARTER_FRAMES: [u16; 4] = [7457, 14913, 22371, 29829];
+const FIVE_STEP_QUARTER_FRAMES: [u16; 4] = [7457, 14913, 22371, 37281];
+const FOUR_STEP_HALF_FRAMES: [u16; 2] = [14913, 29829];
+const FIVE_STEP_HALF_FRAMES: [u16; 2] = [14913, 37281];
+// The 4 step sequence sets the IRQ flag for its last 3 cycles
+const FOUR_STEP_IRQ: u16 = 29828;
+const FOUR_STEP_LENGTH: u16 = 29830;
+const FIVE_STEP_LENGTH: u16 = 37282;
+
+// The mixer's two DACs, which aren't linear, as lookup tables from the nesdev wiki's APU mixer
+// page. The pulses share one, indexed by the sum of their outputs, and the triangle, noise and DMC
+// the other, indexed by 3 * triangle + 2 * noise + DMC.
+const PULSE_TABLE: [f32; 31] = dac_table(95.52, 8128.0);
+const TND_TABLE: [f32; 203] = dac_table(163.67, 24329.0);
+
+const fn dac_table<const N: usize>(scale: f32, divisor: f32) -> [f32; N] {
+    let mut table = [0.0; N];
+    let mut i = 1;
+    while i < N {
+        table[i] = scale / (divisor / i as f32 + 100.0);
+        i += 1;
+    }
+    table
+}
+
+// What the DACs put out with every channel at full volume, which is just under 1.0
+const MAX_LEVEL: f32 = PULSE_TABLE[30] + TND_TABLE[202];
+
+// Converts levels on the DACs' scale, such as expansion audio from `CartridgeImpl::audio_sample`,
+// to `mix`'s
+pub const LEVEL_SCALE: f32 = 2.0 / MAX_LEVEL;
+
+// Mixes the channels' outputs (0-15 for all but the DMC's 0-127) the way the NES's DACs do, into
+// a sample from -1.0 for silence to 1.0 with every channel at full volume
+pub fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let pulse = PULSE_TABLE[(pulse1 + pulse2) as usize];
+    let tnd = TND_TABLE[3 * triangle as usize + 2 * noise as usize + dmc as usize];
+    (pulse + tnd) * LEVEL_SCALE - 1.0
+}
+
+// Pulse 1, pulse 2, triangle and noise, in the order of their bits in $4015
+pub const CHANNELS: usize = 4;
+
+// The channels that go in to the mixer, for `set_channel_enabled`: the ones above then the DMC
+pub const CHANNEL_NAMES: [&str; 5] = ["pulse 1", "pulse 2", "triangle", "noise", "DMC"];
+
+// A pulse channel's sweep unit, which moves its period up or down every few half frames
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Sweep {
+    pub enabled: Cell<bool>,
+    // The divider's period, in half frames minus 1
+    pub period: Cell<u8>,
+    pub negate: Cell<bool>,
+    pub shift: Cell<u8>,
+    pub divider: Cell<u8>,
+    pub reload: Cell<bool>,
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.enabled.save_state(w);
+        self.period.save_state(w);
+        self.negate.save_state(w);
+        self.shift.save_state(w);
+        self.divider.save_state(w);
+        self.reload.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.enabled.load_state(r)?;
+        self.period.load_state(r)?;
+        self.negate.load_state(r)?;
+        self.shift.load_state(r)?;
+        self.divider.load_state(r)?;
+        self.reload.load_state(r)
+    }
+}
+
+// A channel's volume, which is either constant or decays from 15 to 0 every few quarter frames.
+// The envelope's loop flag is the same bit as the length counter's halt flag, so it's in
+// `APU::length_halt` rather than here.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Envelope {
+    pub start: Cell<bool>,
+    pub constant_volume: Cell<bool>,
+    // The volume when it's constant, and the divider's period otherwise
+    pub volume: Cell<u8>,
+    pub divider: Cell<u8>,
+    pub decay: Cell<u8>,
+}
+
+impl Envelope {
+    fn clock(&self, looping: bool) {
+        if self.start.get() {
+            self.start.set(false);
+            self.decay.set(15);
+            self.divider.set(self.volume.get());
+        } else if self.divider.get() > 0 {
+            self.divider.set(self.divider.get() - 1);
+        } else {
+            self.divider.set(self.volume.get());
+            if self.decay.get() > 0 {
+                self.decay.set(self.decay.get() - 1);
+            } else if looping {
+                self.decay.set(15);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume.get() {
+            self.volume.get()
+        } else {
+            self.decay.get()
+        }
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.start.save_state(w);
+        self.constant_volume.save_state(w);
+        self.volume.save_state(w);
+        self.divider.save_state(w);
+        self.decay.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.start.load_state(r)?;
+        self.constant_volume.load_state(r)?;
+        self.volume.load_state(r)?;
+        self.divider.load_state(r)?;
+        self.decay.load_state(r)
+    }
+}
+
+// The delta modulation channel, which plays 1 bit samples from memory: each bit moves the output
+// level up or down by 2. Samples are read a byte at a time into a buffer, and the output unit
+// takes bytes from the buffer 8 bits at a time.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Dmc {
+    pub irq_enabled: Cell<bool>,
+    pub looping: Cell<bool>,
+    pub period: Cell<u16>,
+    pub timer: Cell<u16>,
+    // 0-127
+    pub level: Cell<u8>,
+
+    // Where the sample starts and how long it is, from $4012 and $4013
+    pub sample_address: Cell<u16>,
+    pub sample_length: Cell<u16>,
+    // How far through the sample the memory reader is
+    pub current_address: Cell<u16>,
+    pub bytes_remaining: Cell<u16>,
+    pub buffer: Cell<Option<u8>>,
+
+    // The output unit
+    pub shift: Cell<u8>,
+    pub bits_remaining: Cell<u8>,
+    // Set when the buffer was empty at the start of the last 8 bits, and the level is left alone
+    pub silence: Cell<bool>,
+
+    pub irq: Cell<bool>,
+}
+
+impl Dmc {
+    fn new() -> Dmc {
+        Dmc {
+            period: Cell::new(DMC_PERIODS[0]),
+            sample_address: Cell::new(0xC000),
+            sample_length: Cell::new(1),
+            bits_remaining: Cell::new(8),
+            silence: Cell::new(true),
+            ..Dmc::default()
+        }
+    }
+
+    fn clock_timer(&self) {
+        if self.timer.get() > 0 {
+            self.timer.set(self.timer.get() - 1);
+            return;
+        }
+        self.timer.set(self.period.get().saturating_sub(1));
+
+        if !self.silence.get() {
+            let level = self.level.get();
+            if self.shift.get() & 1 == 1 {
+                if level <= 125 {
+                    self.level.set(level + 2);
+                }
+            } else if level >= 2 {
+                self.level.set(level - 2);
+            }
+        }
+        self.shift.set(self.shift.get() >> 1);
+
+        self.bits_remaining.set(self.bits_remaining.get() - 1);
+        if self.bits_remaining.get() == 0 {
+            self.bits_remaining.set(8);
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence.set(false);
+                    self.shift.set(byte);
+                }
+                None => self.silence.set(true),
+            }
+        }
+    }
+
+    fn restart(&self) {
+        self.current_address.set(self.sample_address.get());
+        self.bytes_remaining.set(self.sample_length.get());
+    }
+
+    // The address of the next byte of the sample, when it's needed to refill the buffer
+    pub fn dma_address(&self) -> Option<u16> {
+        if self.buffer.get().is_none() && self.bytes_remaining.get() > 0 {
+            Some(self.current_address.get())
+        } else {
+            None
+        }
+    }
+
+    // Called by `DMA` with the byte it read from `dma_address`
+    pub fn fill_buffer(&self, value: u8) {
+        self.buffer.set(Some(value));
+        // The address wraps around to $8000 rather than $0000
+        let address = self.current_address.get();
+        self.current_address.set(if address == 0xFFFF {
+            0x8000
+        } else {
+            address + 1
+        });
+        self.bytes_remaining.set(self.bytes_remaining.get() - 1);
+        if self.bytes_remaining.get() == 0 {
+            if self.looping.get() {
+                self.restart();
+            } else if self.irq_enabled.get() {
+                self.irq.set(true);
+            }
+        }
+    }
+}
+
+impl SaveState for Dmc {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.irq_enabled.save_state(w);
+        self.looping.save_state(w);
+        self.period.save_state(w);
+        self.timer.save_state(w);
+        self.level.save_state(w);
+        self.sample_address.save_state(w);
+        self.sample_length.save_state(w);
+        self.current_address.save_state(w);
+        self.bytes_remaining.save_state(w);
+        w.bool(self.buffer.get().is_some());
+        w.u8(self.buffer.get().unwrap_or(0));
+        self.shift.save_state(w);
+        self.bits_remaining.save_state(w);
+        self.silence.save_state(w);
+        self.irq.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.irq_enabled.load_state(r)?;
+        self.looping.load_state(r)?;
+        self.period.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.level.load_state(r)?;
+        self.sample_address.load_state(r)?;
+        self.sample_length.load_state(r)?;
+        self.current_address.load_state(r)?;
+        self.bytes_remaining.load_state(r)?;
+        let buffered = r.bool()?;
+        let buffer = r.u8()?;
+        self.buffer.set(if buffered { Some(buffer) } else { None });
+        self.shift.load_state(r)?;
+        self.bits_remaining.load_state(r)?;
+        self.silence.load_state(r)?;
+        self.irq.load_state(r)?;
+        if self.bits_remaining.get() == 0 {
+            return Err(Error::BadSaveState);
+        }
+        Ok(())
+    }
+}
+
+pub struct APU {
+    pub length_counters: [Cell<u8>; CHANNELS],
+    pub length_halt: [Cell<bool>; CHANNELS],
+    // Bits 0-4 of the last write to $4015
+    pub enabled: Cell<u8>,
+
+    // The 11 bit timer periods of the two pulse channels, and their sweeps
+    pub pulse_periods: [Cell<u16>; 2],
+    pub sweeps: [Sweep; 2],
+    // The pulse channels' timers count down every other CPU cycle, and step the duty sequencer
+    // each time they get past 0
+    pub pulse_timers: [Cell<u16>; 2],
+    pub pulse_duties: [Cell<u8>; 2],
+    pub pulse_steps: [Cell<u8>; 2],
+    pub envelopes: [Envelope; 2],
+    // Whether this CPU cycle is the second half of an APU cycle, when the pulse timers are clocked
+    pub odd_cycle: Cell<bool>,
+
+    // The noise channel's timer counts down every CPU cycle, and shifts the 15 bit LFSR each time
+    // it gets past 0. Bit 0 of the LFSR silences the channel when it's set.
+    pub noise_period: Cell<u16>,
+    pub noise_timer: Cell<u16>,
+    // Feedback from bit 6 rather than bit 1, for a much shorter (and more tonal) sequence
+    pub noise_short_mode: Cell<bool>,
+    pub noise_lfsr: Cell<u16>,
+    pub noise_envelope: Envelope,
+
+    // Frame counter
+    pub frame_cycle: Cell<u16>,
+    pub five_step: Cell<bool>,
+    pub irq_inhibit: Cell<bool>,
+    pub frame_irq: Cell<bool>,
+    // A write to $4017 restarts the sequence a few cycles later, see `write`. Until then the old
+    // one carries on. This counts down the cycles left, and holds the mode it's switching to.
+    pub frame_reset_delay: Cell<u8>,
+    pub pending_five_step: Cell<bool>,
+
+    pub dmc: Dmc,
+
+    // Which channels are mixed in to `output`. This is for frontends, so isn't saved or reset.
+    pub mixer_enabled: [Cell<bool>; CHANNEL_NAMES.len()],
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> APU {
+        APU {
+            length_counters: Default::default(),
+            length_halt: Default::default(),
+            enabled: Cell::new(0),
+            pulse_periods: Default::default(),
+            sweeps: Default::default(),
+            pulse_timers: Default::default(),
+            pulse_duties: Default::default(),
+            pulse_steps: Default::default(),
+            envelopes: Default::default(),
+            odd_cycle: Cell::new(false),
+            noise_period: Cell::new(NOISE_PERIODS[0]),
+            noise_timer: Cell::new(0),
+            noise_short_mode: Cell::new(false),
+            noise_lfsr: Cell::new(1),
+            noise_envelope: Envelope::default(),
+            frame_cycle: Cell::new(0),
+            five_step: Cell::new(false),
+            irq_inhibit: Cell::new(false),
+            frame_irq: Cell::new(false),
+            frame_reset_delay: Cell::new(0),
+            pending_five_step: Cell::new(false),
+            dmc: Dmc::new(),
+            mixer_enabled: core::array::from_fn(|_| Cell::new(true)),
+        }
+    }
+
+    // Reset silences every channel, but leaves the frame counter mode alone. The noise channel's
+    // LFSR starts again from 1, so it always plays the same sequence.
+    pub fn reset(&self) {
+        self.write(0x4015, 0);
+        self.noise_lfsr.set(1);
+        self.frame_cycle.set(0);
+        self.frame_reset_delay.set(0);
+        self.frame_irq.set(false);
+    }
+
+    // Whether the APU is pulling the IRQ line low
+    pub fn irq(&self) -> bool {
+        self.frame_irq.get() || self.dmc.irq.get()
+    }
+
+    // Called once every CPU cycle
+    pub fn tick(&self) {
+        if self.odd_cycle.get() {
+            for channel in 0..2 {
+                self.clock_pulse_timer(channel);
+            }
+        }
+        self.odd_cycle.set(!self.odd_cycle.get());
+        self.clock_noise_timer();
+        self.dmc.clock_timer();
+
+        let cycle = self.frame_cycle.get() + 1;
+
+        let (quarter_frames, half_frames, length) = if self.five_step.get() {
+            (
+                FIVE_STEP_QUARTER_FRAMES,
+                FIVE_STEP_HALF_FRAMES,
+                FIVE_STEP_LENGTH,
+            )
+        } else {
+            (
+                FOUR_STEP_QUARTER_FRAMES,
+                FOUR_STEP_HALF_FRAMES,
+                FOUR_STEP_LENGTH,
+            )
+        };
+        if quarter_frames.contains(&cycle) {
+            self.clock_quarter_frame();
+        }
+        if half_frames.contains(&cycle) {
+            self.clock_half_frame();
+        }
+        if !self.five_step.get() && cycle >= FOUR_STEP_IRQ && !self.irq_inhibit.get() {
+            self.frame_irq.set(true);
+        }
+
+        self.frame_cycle
+            .set(if cycle >= length { 0 } else { cycle });
+
+        match self.frame_reset_delay.get() {
+            0 => (),
+            1 => {
+                self.frame_reset_delay.set(0);
+                self.reset_frame_counter();
+            }
+            n => self.frame_reset_delay.set(n - 1),
+        }
+    }
+
+    fn reset_frame_counter(&self) {
+        self.five_step.set(self.pending_five_step.get());
+        self.frame_cycle.set(0);
+        // Switching to 5 step mode clocks everything straight away, as its sequence doesn't
+        // start with a step that would
+        if self.five_step.get() {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+
+    fn clock_pulse_timer(&self, channel: usize) {
+        let timer = &self.pulse_timers[channel];
+        if timer.get() == 0 {
+            timer.set(self.pulse_periods[channel].get());
+            let step = &self.pulse_steps[channel];
+            step.set((step.get() + 1) % 8);
+        } else {
+            timer.set(timer.get() - 1);
+        }
+    }
+
+    fn clock_noise_timer(&self) {
+        if self.noise_timer.get() > 0 {
+            self.noise_timer.set(self.noise_timer.get() - 1);
+            return;
+        }
+        self.noise_timer
+            .set(self.noise_period.get().saturating_sub(1));
+
+        let lfsr = self.noise_lfsr.get();
+        let tap = if self.noise_short_mode.get() { 6 } else { 1 };
+        let feedback = (lfsr ^ (lfsr >> tap)) & 1;
+        self.noise_lfsr.set((lfsr >> 1) | (feedback << 14));
+    }
+
+    fn clock_quarter_frame(&self) {
+        for (envelope, looping) in self.envelopes.iter().zip(&self.length_halt) {
+            envelope.clock(looping.get());
+        }
+        self.noise_envelope.clock(self.length_halt[3].get());
+    }
+
+    fn clock_half_frame(&self) {
+        for (counter, halt) in self.length_counters.iter().zip(&self.length_halt) {
+            if counter.get() > 0 && !halt.get() {
+                counter.set(counter.get() - 1);
+            }
+        }
+
+        for (channel, sweep) in self.sweeps.iter().enumerate() {
+            if sweep.divider.get() == 0
+                && sweep.enabled.get()
+                && sweep.shift.get() > 0
+                && !self.pulse_muted(channel)
+            {
+                self.pulse_periods[channel].set(self.sweep_target(channel));
+            }
+            if sweep.divider.get() == 0 || sweep.reload.get() {
+                sweep.divider.set(sweep.period.get());
+                sweep.reload.set(false);
+            } else {
+                sweep.divider.set(sweep.divider.get() - 1);
+            }
+        }
+    }
+
+    // The period the sweep is heading for, which is worked out all the time (not just when it's
+    // clocked) as it can mute the channel even with the sweep disabled
+    fn sweep_target(&self, channel: usize) -> u16 {
+        let period = self.pulse_periods[channel].get();
+        let change = period >> self.sweeps[channel].shift.get();
+        if !self.sweeps[channel].negate.get() {
+            period + change
+        } else if channel == 0 {
+            // Pulse 1 negates with ones' complement, so goes down by one more
+            period.saturating_sub(change + 1)
+        } else {
+            period - change
+        }
+    }
+
+    // Whether a pulse channel is silenced by its period being too low or its sweep's target
+    // being too high, whatever its length counter and volume are
+    pub fn pulse_muted(&self, channel: usize) -> bool {
+        self.pulse_periods[channel].get() < 8 || self.sweep_target(channel) > 0x7FF
+    }
+
+    // Mutes or unmutes a channel (indexed as in CHANNEL_NAMES) in the output, for picking out one
+    // channel while debugging or turning down a harsh one. This is separate from the enables in
+    // $4015, and the game can't tell.
+    pub fn set_channel_enabled(&self, channel: usize, enabled: bool) {
+        self.mixer_enabled[channel].set(enabled);
+    }
+
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        self.mixer_enabled[channel].get()
+    }
+
+    // A pulse channel's volume right now, from 0 to 15
+    pub fn pulse_output(&self, channel: usize) -> u8 {
+        let duty = DUTY_TABLE[self.pulse_duties[channel].get() as usize];
+        if self.length_counters[channel].get() == 0
+            || self.pulse_muted(channel)
+            || duty[self.pulse_steps[channel].get() as usize] == 0
+        {
+            0
+        } else {
+            self.envelopes[channel].output()
+        }
+    }
+
+    // The noise channel's volume right now, from 0 to 15
+    pub fn noise_output(&self) -> u8 {
+        if self.length_counters[3].get() == 0 || self.noise_lfsr.get() & 1 == 1 {
+            0
+        } else {
+            self.noise_envelope.output()
+        }
+    }
+
+    // The output of the APU's mixer from `mix`, with channels muted with `set_channel_enabled`
+    // left out. The triangle is always silent so far.
+    pub fn output(&self) -> f32 {
+        let output = |channel: usize, level: u8| {
+            if self.mixer_enabled[channel].get() {
+                level
+            } else {
+                0
+            }
+        };
+        mix(
+            output(0, self.pulse_output(0)),
+            output(1, self.pulse_output(1)),
+            0,
+            output(3, self.noise_output()),
+            output(4, self.dmc_output()),
+        )
+    }
+
+    // Reads $4015. Bit 5 isn't driven, so it's left for the caller to fill in from open bus.
+    pub fn read_status(&self) -> u8 {
+        let mut status = 0;
+        for (i, counter) in self.length_counters.iter().enumerate() {
+            if counter.get() > 0 {
+                status |= 1 << i;
+            }
+        }
+        if self.frame_irq.get() {
+            status |= 0x40;
+        }
+        if self.dmc.bytes_remaining.get() > 0 {
+            status |= 0x10;
+        }
+        if self.dmc.irq.get() {
+            status |= 0x80;
+        }
+
+        // Reading acknowledges the frame IRQ, but not the DMC's
+        self.frame_irq.set(false);
+        status
+    }
+
+    // The DMC's output level, from 0 to 127
+    pub fn dmc_output(&self) -> u8 {
+        self.dmc.level.get()
+    }
+
+    // Writes to $4000-$4013, $4015 and $4017
+    pub fn write(&self, addr: u16, value: u8) {
+        match addr {
+            // Duty, the halt flag (which is also the envelope's loop flag) and the volume
+            0x4000 | 0x4004 => {
+                let channel = (addr as usize - 0x4000) / 4;
+                self.pulse_duties[channel].set(value >> 6);
+                self.length_halt[channel].set(value & 0x20 != 0);
+                let envelope = &self.envelopes[channel];
+                envelope.constant_volume.set(value & 0x10 != 0);
+                envelope.volume.set(value & 0x0F);
+            }
+            0x400C => {
+                self.length_halt[3].set(value & 0x20 != 0);
+                self.noise_envelope.constant_volume.set(value & 0x10 != 0);
+                self.noise_envelope.volume.set(value & 0x0F);
+            }
+            0x400E => {
+                self.noise_short_mode.set(value & 0x80 != 0);
+                self.noise_period.set(NOISE_PERIODS[value as usize & 0x0F]);
+            }
+            0x4001 | 0x4005 => {
+                let sweep = &self.sweeps[(addr as usize - 0x4000) / 4];
+                sweep.enabled.set(value & 0x80 != 0);
+                sweep.period.set((value >> 4) & 0x7);
+                sweep.negate.set(value & 0x08 != 0);
+                sweep.shift.set(value & 0x7);
+                sweep.reload.set(true);
+            }
+            0x4002 | 0x4006 => {
+                let period = &self.pulse_periods[(addr as usize - 0x4000) / 4];
+                period.set((period.get() & 0x700) | value as u16);
+            }
+            0x4010 => {
+                self.dmc.irq_enabled.set(value & 0x80 != 0);
+                self.dmc.looping.set(value & 0x40 != 0);
+                self.dmc.period.set(DMC_PERIODS[value as usize & 0x0F]);
+                if !self.dmc.irq_enabled.get() {
+                    self.dmc.irq.set(false);
+                }
+            }
+            0x4011 => self.dmc.level.set(value & 0x7F),
+            0x4012 => self.dmc.sample_address.set(0xC000 | (value as u16) << 6),
+            0x4013 => self.dmc.sample_length.set((value as u16) << 4 | 1),
+            // The triangle's is also its linear counter control flag
+            0x4008 => self.length_halt[2].set(value & 0x80 != 0),
+            0x4003 | 0x4007 | 0x400B | 0x400F => {
+                let channel = (addr as usize - 0x4000) / 4;
+                if channel < 2 {
+                    let period = &self.pulse_periods[channel];
+                    period.set((period.get() & 0xFF) | (value as u16 & 0x7) << 8);
+                    // The waveform starts again, but the timer carries on as it was
+                    self.pulse_steps[channel].set(0);
+                    self.envelopes[channel].start.set(true);
+                } else if channel == 3 {
+                    self.noise_envelope.start.set(true);
+                }
+                // A disabled channel's length counter stays at 0
+                if self.enabled.get() & (1 << channel) != 0 {
+                    self.length_counters[channel].set(LENGTH_TABLE[value as usize >> 3]);
+                }
+            }
+            0x4015 => {
+                self.enabled.set(value & 0x1F);
+                for (i, counter) in self.length_counters.iter().enumerate() {
+                    if value & (1 << i) == 0 {
+                        counter.set(0);
+                    }
+                }
+                // Turning the DMC off stops the sample after the byte that's in the buffer, and
+                // turning it on starts it again if it had finished
+                if value & 0x10 == 0 {
+                    self.dmc.bytes_remaining.set(0);
+                } else if self.dmc.bytes_remaining.get() == 0 {
+                    self.dmc.restart();
+                }
+                self.dmc.irq.set(false);
+            }
+            0x4017 => {
+                // The IRQ inhibit flag takes effect straight away
+                self.irq_inhibit.set(value & 0x40 != 0);
+                if self.irq_inhibit.get() {
+                    self.frame_irq.set(false);
+                }
+                // But the new mode and the restart of the sequence wait until 3 CPU cycles after
+                // the write's cycle if that's an APU cycle, or 4 if it's between them. The write
+                // happens before the tick for its cycle, which counts down too.
+                self.pending_five_step.set(value & 0x80 != 0);
+                self.frame_reset_delay
+                    .set(if self.odd_cycle.get() { 4 } else { 5 });
+            }
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for APU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.length_counters.save_state(w);
+        self.length_halt.save_state(w);
+        self.enabled.save_state(w);
+        self.pulse_periods.save_state(w);
+        self.sweeps.save_state(w);
+        self.pulse_timers.save_state(w);
+        self.pulse_duties.save_state(w);
+        self.pulse_steps.save_state(w);
+        self.envelopes.save_state(w);
+        self.odd_cycle.save_state(w);
+        self.noise_period.save_state(w);
+        self.noise_timer.save_state(w);
+        self.noise_short_mode.save_state(w);
+        self.noise_lfsr.save_state(w);
+        self.noise_envelope.save_state(w);
+        self.frame_cycle.save_state(w);
+        self.five_step.save_state(w);
+        self.irq_inhibit.save_state(w);
+        self.frame_irq.save_state(w);
+        self.frame_reset_delay.save_state(w);
+        self.pending_five_step.save_state(w);
+        self.dmc.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.length_counters.load_state(r)?;
+        self.length_halt.load_state(r)?;
+        self.enabled.load_state(r)?;
+        self.pulse_periods.load_state(r)?;
+        self.sweeps.load_state(r)?;
+        self.pulse_timers.load_state(r)?;
+        self.pulse_duties.load_state(r)?;
+        self.pulse_steps.load_state(r)?;
+        self.envelopes.load_state(r)?;
+        self.odd_cycle.load_state(r)?;
+        self.noise_period.load_state(r)?;
+        self.noise_timer.load_state(r)?;
+        self.noise_short_mode.load_state(r)?;
+        self.noise_lfsr.load_state(r)?;
+        self.noise_envelope.load_state(r)?;
+        self.frame_cycle.load_state(r)?;
+        self.five_step.load_state(r)?;
+        self.irq_inhibit.load_state(r)?;
+        self.frame_irq.load_state(r)?;
+        self.frame_reset_delay.load_state(r)?;
+        self.pending_five_step.load_state(r)?;
+        self.dmc.load_state(r)
+    }
+}
@@ -1,4 +1,14 @@
-use std::cell::Cell;
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::cell::Cell;
+
+use crate::{
+    error::Result,
+    nes::state::{SaveState, StateReader, StateWriter},
+};
 
 bitflags! {
     pub struct Flags: u8 {
@@ -21,6 +31,10 @@ pub struct CPU {
     pub state: Cell<State>,
     pub nmi: Cell<Option<usize>>,
     pub irq: Cell<Option<usize>>,
+    // CLI, SEI and PLP change the I flag after the interrupt poll in their last cycle, so the
+    // instruction straight after them still runs with the old value as far as IRQs go. The new
+    // value waits here until the next opcode fetch has checked for interrupts.
+    delayed_i: Cell<Option<bool>>,
 }
 
 impl CPU {
@@ -37,6 +51,7 @@ impl CPU {
             state: Cell::new(State(S::Reset)),
             nmi: Cell::new(None),
             irq: Cell::new(None),
+            delayed_i: Cell::new(None),
         }
     }
 
@@ -54,6 +69,18 @@ impl CPU {
         self.nmi.set(None);
     }
 
+    // Unlike NMI, IRQ is level triggered - the host should keep calling this for as long as the
+    // line is held low
+    pub fn set_irq(&self) {
+        if self.irq.get().is_none() {
+            self.irq.set(Some(0));
+        }
+    }
+
+    pub fn clear_irq(&self) {
+        self.irq.set(None);
+    }
+
     pub fn poll_interrupts(&self) {
         match self.nmi.get() {
             Some(0) => self.nmi.set(Some(1)),
@@ -74,12 +101,25 @@ impl CPU {
         self.state.set(State(S::FetchOpcode));
     }
 
+    // The I flag is shown as CLI, SEI or PLP left it, even while it's still delayed for IRQs
     pub fn get_p(&self) -> u8 {
-        self.flags.get().bits()
+        let mut flags = self.flags.get();
+        if let Some(i) = self.delayed_i.get() {
+            flags.set(Flags::I, i);
+        }
+        flags.bits()
     }
 
     pub fn set_p(&self, value: u8) {
-        self.flags.set(Flags::from_bits_truncate(value))
+        self.flags.set(Flags::from_bits_truncate(value));
+        self.delayed_i.set(None);
+    }
+
+    // Puts the I flag back to `old` until the next opcode fetch, after it's just been changed
+    fn delay_i_flag(&self, old: bool) {
+        let new = self.get_flag(Flags::I);
+        self.set_flag(Flags::I, old);
+        self.delayed_i.set(Some(new));
     }
 
     pub fn get_flag(&self, flag: Flags) -> bool {
@@ -102,6 +142,52 @@ impl CPU {
     }
 }
 
+// Only valid between instructions, which is where `Nes::save_state` takes states
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        debug_assert!(self.is_at_instruction());
+        self.pc.save_state(w);
+        self.s.save_state(w);
+        w.u8(self.flags.get().bits());
+        self.a.save_state(w);
+        self.x.save_state(w);
+        self.y.save_state(w);
+        save_interrupt(&self.nmi, w);
+        save_interrupt(&self.irq, w);
+        w.bool(self.delayed_i.get().is_some());
+        w.bool(self.delayed_i.get().unwrap_or(false));
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.pc.load_state(r)?;
+        self.s.load_state(r)?;
+        self.set_p(r.u8()?);
+        self.a.load_state(r)?;
+        self.x.load_state(r)?;
+        self.y.load_state(r)?;
+        load_interrupt(&self.nmi, r)?;
+        load_interrupt(&self.irq, r)?;
+        let delayed = r.bool()?;
+        let i = r.bool()?;
+        self.delayed_i.set(if delayed { Some(i) } else { None });
+        self.state.set(State(S::FetchOpcode));
+        Ok(())
+    }
+}
+
+// Every state for a cartridge has to be the same size, so the cycle count is always written
+fn save_interrupt(int: &Cell<Option<usize>>, w: &mut StateWriter) {
+    w.bool(int.get().is_some());
+    w.u8(int.get().unwrap_or(0) as u8);
+}
+
+fn load_interrupt(int: &Cell<Option<usize>>, r: &mut StateReader) -> Result<()> {
+    let pending = r.bool()?;
+    let cycles = r.u8()? as usize;
+    int.set(if pending { Some(cycles) } else { None });
+    Ok(())
+}
+
 pub trait CpuHostAccess {
     fn read(&self, addr: u16) -> u8;
     fn write(&self, addr: u16, value: u8);
@@ -356,17 +442,347 @@ impl State {
     }
 }
 
+// What to do for each opcode, or None for the few that would jam a real CPU
+fn decode(opcode: u8) -> Option<S> {
+    let state = match opcode {
+        // ADC
+        0x69 => S::ImmediateR(ReadOp::ADC),
+        0x65 => S::ZeroPage(ReadOp::ADC.into()),
+        0x75 => S::ZeroPageX(ReadOp::ADC.into()),
+        0x6D => S::Absolute(ReadOp::ADC.into()),
+        0x7D => S::AbsoluteX(ReadOp::ADC.into()),
+        0x79 => S::AbsoluteY(ReadOp::ADC.into()),
+        0x61 => S::IndexedIndirect(ReadOp::ADC.into()),
+        0x71 => S::IndirectIndexed(ReadOp::ADC.into()),
+        // AND
+        0x29 => S::ImmediateR(ReadOp::AND),
+        0x25 => S::ZeroPage(ReadOp::AND.into()),
+        0x35 => S::ZeroPageX(ReadOp::AND.into()),
+        0x2D => S::Absolute(ReadOp::AND.into()),
+        0x3D => S::AbsoluteX(ReadOp::AND.into()),
+        0x39 => S::AbsoluteY(ReadOp::AND.into()),
+        0x21 => S::IndexedIndirect(ReadOp::AND.into()),
+        0x31 => S::IndirectIndexed(ReadOp::AND.into()),
+        // ASL
+        0x0A => S::AccRW(ReadWriteOp::ASL),
+        0x06 => S::ZeroPage(ReadWriteOp::ASL.into()),
+        0x16 => S::ZeroPageX(ReadWriteOp::ASL.into()),
+        0x0E => S::Absolute(ReadWriteOp::ASL.into()),
+        0x1E => S::AbsoluteX(ReadWriteOp::ASL.into()),
+        // BCC
+        0x90 => S::Relative(BranchOp::BCC),
+        // BCS
+        0xB0 => S::Relative(BranchOp::BCS),
+        // BEQ
+        0xF0 => S::Relative(BranchOp::BEQ),
+        // BIT
+        0x24 => S::ZeroPage(ReadOp::BIT.into()),
+        0x2C => S::Absolute(ReadOp::BIT.into()),
+        // BMI
+        0x30 => S::Relative(BranchOp::BMI),
+        // BNE
+        0xD0 => S::Relative(BranchOp::BNE),
+        // BPL
+        0x10 => S::Relative(BranchOp::BPL),
+        // BRK,
+        0x00 => S::Int(Interrupt::BRK),
+        // BVC
+        0x50 => S::Relative(BranchOp::BVC),
+        // BVS
+        0x70 => S::Relative(BranchOp::BVS),
+        // CLC
+        0x18 => S::Implied(ImpliedOp::CLC),
+        // CLD
+        0xD8 => S::Implied(ImpliedOp::CLD),
+        // CLI
+        0x58 => S::Implied(ImpliedOp::CLI),
+        // CLV
+        0xB8 => S::Implied(ImpliedOp::CLV),
+        // CMP
+        0xC9 => S::ImmediateR(ReadOp::CMP),
+        0xC5 => S::ZeroPage(ReadOp::CMP.into()),
+        0xD5 => S::ZeroPageX(ReadOp::CMP.into()),
+        0xCD => S::Absolute(ReadOp::CMP.into()),
+        0xDD => S::AbsoluteX(ReadOp::CMP.into()),
+        0xD9 => S::AbsoluteY(ReadOp::CMP.into()),
+        0xC1 => S::IndexedIndirect(ReadOp::CMP.into()),
+        0xD1 => S::IndirectIndexed(ReadOp::CMP.into()),
+        // CPX
+        0xE0 => S::ImmediateR(ReadOp::CPX),
+        0xE4 => S::ZeroPage(ReadOp::CPX.into()),
+        0xEC => S::Absolute(ReadOp::CPX.into()),
+        // CPY
+        0xC0 => S::ImmediateR(ReadOp::CPY),
+        0xC4 => S::ZeroPage(ReadOp::CPY.into()),
+        0xCC => S::Absolute(ReadOp::CPY.into()),
+        // DEC
+        0xC6 => S::ZeroPage(ReadWriteOp::DEC.into()),
+        0xD6 => S::ZeroPageX(ReadWriteOp::DEC.into()),
+        0xCE => S::Absolute(ReadWriteOp::DEC.into()),
+        0xDE => S::AbsoluteX(ReadWriteOp::DEC.into()),
+        // DEX
+        0xCA => S::Implied(ImpliedOp::DEX),
+        // DEY
+        0x88 => S::Implied(ImpliedOp::DEY),
+        // EOR
+        0x49 => S::ImmediateR(ReadOp::EOR),
+        0x45 => S::ZeroPage(ReadOp::EOR.into()),
+        0x55 => S::ZeroPageX(ReadOp::EOR.into()),
+        0x4D => S::Absolute(ReadOp::EOR.into()),
+        0x5D => S::AbsoluteX(ReadOp::EOR.into()),
+        0x59 => S::AbsoluteY(ReadOp::EOR.into()),
+        0x41 => S::IndexedIndirect(ReadOp::EOR.into()),
+        0x51 => S::IndirectIndexed(ReadOp::EOR.into()),
+        // INC
+        0xE6 => S::ZeroPage(ReadWriteOp::INC.into()),
+        0xF6 => S::ZeroPageX(ReadWriteOp::INC.into()),
+        0xEE => S::Absolute(ReadWriteOp::INC.into()),
+        0xFE => S::AbsoluteX(ReadWriteOp::INC.into()),
+        // INX
+        0xE8 => S::Implied(ImpliedOp::INX),
+        // INY
+        0xC8 => S::Implied(ImpliedOp::INY),
+        // JMP
+        0x4C => S::JMPAbsolute,
+        0x6C => S::JMPIndirect,
+        // JSR
+        0x20 => S::JSR,
+        // LDA
+        0xA9 => S::ImmediateR(ReadOp::LDA),
+        0xA5 => S::ZeroPage(ReadOp::LDA.into()),
+        0xB5 => S::ZeroPageX(ReadOp::LDA.into()),
+        0xAD => S::Absolute(ReadOp::LDA.into()),
+        0xBD => S::AbsoluteX(ReadOp::LDA.into()),
+        0xB9 => S::AbsoluteY(ReadOp::LDA.into()),
+        0xA1 => S::IndexedIndirect(ReadOp::LDA.into()),
+        0xB1 => S::IndirectIndexed(ReadOp::LDA.into()),
+        // LDX
+        0xA2 => S::ImmediateR(ReadOp::LDX),
+        0xA6 => S::ZeroPage(ReadOp::LDX.into()),
+        0xB6 => S::ZeroPageY(ReadOp::LDX.into()),
+        0xAE => S::Absolute(ReadOp::LDX.into()),
+        0xBE => S::AbsoluteY(ReadOp::LDX.into()),
+        // LDY
+        0xA0 => S::ImmediateR(ReadOp::LDY),
+        0xA4 => S::ZeroPage(ReadOp::LDY.into()),
+        0xB4 => S::ZeroPageX(ReadOp::LDY.into()),
+        0xAC => S::Absolute(ReadOp::LDY.into()),
+        0xBC => S::AbsoluteX(ReadOp::LDY.into()),
+        // LSR
+        0x4A => S::AccRW(ReadWriteOp::LSR),
+        0x46 => S::ZeroPage(ReadWriteOp::LSR.into()),
+        0x56 => S::ZeroPageX(ReadWriteOp::LSR.into()),
+        0x4E => S::Absolute(ReadWriteOp::LSR.into()),
+        0x5E => S::AbsoluteX(ReadWriteOp::LSR.into()),
+        // NOP
+        0xEA => S::Implied(ImpliedOp::NOP),
+        // ORA
+        0x09 => S::ImmediateR(ReadOp::ORA),
+        0x05 => S::ZeroPage(ReadOp::ORA.into()),
+        0x15 => S::ZeroPageX(ReadOp::ORA.into()),
+        0x0D => S::Absolute(ReadOp::ORA.into()),
+        0x1D => S::AbsoluteX(ReadOp::ORA.into()),
+        0x19 => S::AbsoluteY(ReadOp::ORA.into()),
+        0x01 => S::IndexedIndirect(ReadOp::ORA.into()),
+        0x11 => S::IndirectIndexed(ReadOp::ORA.into()),
+        // PHA
+        0x48 => S::PHA,
+        // PHP
+        0x08 => S::PHP,
+        // PLA
+        0x68 => S::PLA,
+        // PLP
+        0x28 => S::PLP,
+        // ROL
+        0x2A => S::AccRW(ReadWriteOp::ROL),
+        0x26 => S::ZeroPage(ReadWriteOp::ROL.into()),
+        0x36 => S::ZeroPageX(ReadWriteOp::ROL.into()),
+        0x2E => S::Absolute(ReadWriteOp::ROL.into()),
+        0x3E => S::AbsoluteX(ReadWriteOp::ROL.into()),
+        // ROR
+        0x6A => S::AccRW(ReadWriteOp::ROR),
+        0x66 => S::ZeroPage(ReadWriteOp::ROR.into()),
+        0x76 => S::ZeroPageX(ReadWriteOp::ROR.into()),
+        0x6E => S::Absolute(ReadWriteOp::ROR.into()),
+        0x7E => S::AbsoluteX(ReadWriteOp::ROR.into()),
+        // RTI
+        0x40 => S::RTI,
+        // RTS
+        0x60 => S::RTS,
+        // SBC
+        0xE9 => S::ImmediateR(ReadOp::SBC),
+        0xE5 => S::ZeroPage(ReadOp::SBC.into()),
+        0xF5 => S::ZeroPageX(ReadOp::SBC.into()),
+        0xED => S::Absolute(ReadOp::SBC.into()),
+        0xFD => S::AbsoluteX(ReadOp::SBC.into()),
+        0xF9 => S::AbsoluteY(ReadOp::SBC.into()),
+        0xE1 => S::IndexedIndirect(ReadOp::SBC.into()),
+        0xF1 => S::IndirectIndexed(ReadOp::SBC.into()),
+        // SEC
+        0x38 => S::Implied(ImpliedOp::SEC),
+        // SED
+        0xF8 => S::Implied(ImpliedOp::SED),
+        // SEI
+        0x78 => S::Implied(ImpliedOp::SEI),
+        // STA
+        0x85 => S::ZeroPage(WriteOp::STA.into()),
+        0x95 => S::ZeroPageX(WriteOp::STA.into()),
+        0x8D => S::Absolute(WriteOp::STA.into()),
+        0x9D => S::AbsoluteX(WriteOp::STA.into()),
+        0x99 => S::AbsoluteY(WriteOp::STA.into()),
+        0x81 => S::IndexedIndirect(WriteOp::STA.into()),
+        0x91 => S::IndirectIndexed(WriteOp::STA.into()),
+        // STX
+        0x86 => S::ZeroPage(WriteOp::STX.into()),
+        0x96 => S::ZeroPageY(WriteOp::STX.into()),
+        0x8E => S::Absolute(WriteOp::STX.into()),
+        // STY
+        0x84 => S::ZeroPage(WriteOp::STY.into()),
+        0x94 => S::ZeroPageX(WriteOp::STY.into()),
+        0x8C => S::Absolute(WriteOp::STY.into()),
+        // TAX
+        0xAA => S::Implied(ImpliedOp::TAX),
+        // TAY
+        0xA8 => S::Implied(ImpliedOp::TAY),
+        // TSX
+        0xBA => S::Implied(ImpliedOp::TSX),
+        // TXA
+        0x8A => S::Implied(ImpliedOp::TXA),
+        // TXS
+        0x9A => S::Implied(ImpliedOp::TXS),
+        // TYA
+        0x98 => S::Implied(ImpliedOp::TYA),
+
+        // Undocumented opcodes
+        // Various NOPs
+        0x04 | 0x44 | 0x64 => S::ZeroPage(ReadOp::NOP.into()),
+        0x0C => S::Absolute(ReadOp::NOP.into()),
+        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => S::ZeroPageX(ReadOp::NOP.into()),
+        0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => S::Implied(ImpliedOp::NOP),
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => S::AbsoluteX(ReadOp::NOP.into()),
+        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => S::ImmediateR(ReadOp::NOP),
+        // LAX
+        0xA3 => S::IndexedIndirect(ReadOp::LAX.into()),
+        0xA7 => S::ZeroPage(ReadOp::LAX.into()),
+        0xAB => S::ImmediateR(ReadOp::LAX),
+        0xAF => S::Absolute(ReadOp::LAX.into()),
+        0xB3 => S::IndirectIndexed(ReadOp::LAX.into()),
+        0xB7 => S::ZeroPageY(ReadOp::LAX.into()),
+        0xBF => S::AbsoluteY(ReadOp::LAX.into()),
+        // SAX
+        0x83 => S::IndexedIndirect(WriteOp::SAX.into()),
+        0x87 => S::ZeroPage(WriteOp::SAX.into()),
+        0x8F => S::Absolute(WriteOp::SAX.into()),
+        0x97 => S::ZeroPageY(WriteOp::SAX.into()),
+        // SBC
+        0xEB => S::ImmediateR(ReadOp::SBC),
+        // DCP
+        0xC3 => S::IndexedIndirect(ReadWriteOp::DCP.into()),
+        0xC7 => S::ZeroPage(ReadWriteOp::DCP.into()),
+        0xCF => S::Absolute(ReadWriteOp::DCP.into()),
+        0xD3 => S::IndirectIndexed(ReadWriteOp::DCP.into()),
+        0xD7 => S::ZeroPageX(ReadWriteOp::DCP.into()),
+        0xDB => S::AbsoluteY(ReadWriteOp::DCP.into()),
+        0xDF => S::AbsoluteX(ReadWriteOp::DCP.into()),
+        // ISC
+        0xE3 => S::IndexedIndirect(ReadWriteOp::ISC.into()),
+        0xE7 => S::ZeroPage(ReadWriteOp::ISC.into()),
+        0xEF => S::Absolute(ReadWriteOp::ISC.into()),
+        0xF3 => S::IndirectIndexed(ReadWriteOp::ISC.into()),
+        0xF7 => S::ZeroPageX(ReadWriteOp::ISC.into()),
+        0xFB => S::AbsoluteY(ReadWriteOp::ISC.into()),
+        0xFF => S::AbsoluteX(ReadWriteOp::ISC.into()),
+        // SLO
+        0x03 => S::IndexedIndirect(ReadWriteOp::SLO.into()),
+        0x07 => S::ZeroPage(ReadWriteOp::SLO.into()),
+        0x0F => S::Absolute(ReadWriteOp::SLO.into()),
+        0x13 => S::IndirectIndexed(ReadWriteOp::SLO.into()),
+        0x17 => S::ZeroPageX(ReadWriteOp::SLO.into()),
+        0x1B => S::AbsoluteY(ReadWriteOp::SLO.into()),
+        0x1F => S::AbsoluteX(ReadWriteOp::SLO.into()),
+        // RLA
+        0x23 => S::IndexedIndirect(ReadWriteOp::RLA.into()),
+        0x27 => S::ZeroPage(ReadWriteOp::RLA.into()),
+        0x2F => S::Absolute(ReadWriteOp::RLA.into()),
+        0x33 => S::IndirectIndexed(ReadWriteOp::RLA.into()),
+        0x37 => S::ZeroPageX(ReadWriteOp::RLA.into()),
+        0x3B => S::AbsoluteY(ReadWriteOp::RLA.into()),
+        0x3F => S::AbsoluteX(ReadWriteOp::RLA.into()),
+        // SRE
+        0x43 => S::IndexedIndirect(ReadWriteOp::SRE.into()),
+        0x47 => S::ZeroPage(ReadWriteOp::SRE.into()),
+        0x4F => S::Absolute(ReadWriteOp::SRE.into()),
+        0x53 => S::IndirectIndexed(ReadWriteOp::SRE.into()),
+        0x57 => S::ZeroPageX(ReadWriteOp::SRE.into()),
+        0x5B => S::AbsoluteY(ReadWriteOp::SRE.into()),
+        0x5F => S::AbsoluteX(ReadWriteOp::SRE.into()),
+        // RRA
+        0x63 => S::IndexedIndirect(ReadWriteOp::RRA.into()),
+        0x67 => S::ZeroPage(ReadWriteOp::RRA.into()),
+        0x6F => S::Absolute(ReadWriteOp::RRA.into()),
+        0x73 => S::IndirectIndexed(ReadWriteOp::RRA.into()),
+        0x77 => S::ZeroPageX(ReadWriteOp::RRA.into()),
+        0x7B => S::AbsoluteY(ReadWriteOp::RRA.into()),
+        0x7F => S::AbsoluteX(ReadWriteOp::RRA.into()),
+        // ANC
+        0x0B => S::ImmediateR(ReadOp::ANC),
+        0x2B => S::ImmediateR(ReadOp::ANC),
+        // ALR
+        0x4B => S::ImmediateR(ReadOp::ALR),
+        // ARR
+        0x6B => S::ImmediateR(ReadOp::ARR),
+        // AXS
+        0xCB => S::ImmediateR(ReadOp::AXS),
+        // SHY
+        0x9C => S::AbsoluteX(SHOp::SHY.into()),
+        // SHX
+        0x9E => S::AbsoluteY(SHOp::SHX.into()),
+
+        _ => return None,
+    };
+    Some(state)
+}
+
 impl CPU {
     pub fn is_at_instruction(&self) -> bool {
         self.state.get().0 == S::FetchOpcode
     }
 
+    /// A line of a trace log for the instruction at PC, as in nestest.log: the disassembly, the
+    /// registers, the PPU's `dot` and `scanline` and the CPU cycle count. Memory is read with
+    /// `read`, like `disassemble`.
+    ///
+    /// Only makes sense between instructions, when PC points at the next one.
+    pub fn trace_line(
+        &self,
+        read: impl Fn(u16) -> Option<u8>,
+        dot: u16,
+        scanline: u16,
+        cycles: u64,
+    ) -> String {
+        format!(
+            "{:<47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+            disassemble(self.pc.get(), read),
+            self.a.get(),
+            self.x.get(),
+            self.y.get(),
+            self.get_p() | 0x20,
+            self.s.get(),
+            dot,
+            scanline,
+            cycles
+        )
+    }
+
     pub fn tick<H: CpuHostAccess>(&self, host: &H) {
         let next_state = match self.state.get().0 {
             S::FetchOpcode => {
                 if self.irq.get() != None && self.get_flag(Flags::I) {
                     self.irq.set(None);
                 }
+                if let Some(i) = self.delayed_i.take() {
+                    self.set_flag(Flags::I, i);
+                }
 
                 if self.nmi.get() == Some(2) {
                     self.nmi.set(None);
@@ -379,302 +795,7 @@ impl CPU {
                     let opcode = host.read(pc);
                     self.pc.set(pc.wrapping_add(1));
 
-                    match opcode {
-                        // ADC
-                        0x69 => S::ImmediateR(ReadOp::ADC),
-                        0x65 => S::ZeroPage(ReadOp::ADC.into()),
-                        0x75 => S::ZeroPageX(ReadOp::ADC.into()),
-                        0x6D => S::Absolute(ReadOp::ADC.into()),
-                        0x7D => S::AbsoluteX(ReadOp::ADC.into()),
-                        0x79 => S::AbsoluteY(ReadOp::ADC.into()),
-                        0x61 => S::IndexedIndirect(ReadOp::ADC.into()),
-                        0x71 => S::IndirectIndexed(ReadOp::ADC.into()),
-                        // AND
-                        0x29 => S::ImmediateR(ReadOp::AND),
-                        0x25 => S::ZeroPage(ReadOp::AND.into()),
-                        0x35 => S::ZeroPageX(ReadOp::AND.into()),
-                        0x2D => S::Absolute(ReadOp::AND.into()),
-                        0x3D => S::AbsoluteX(ReadOp::AND.into()),
-                        0x39 => S::AbsoluteY(ReadOp::AND.into()),
-                        0x21 => S::IndexedIndirect(ReadOp::AND.into()),
-                        0x31 => S::IndirectIndexed(ReadOp::AND.into()),
-                        // ASL
-                        0x0A => S::AccRW(ReadWriteOp::ASL),
-                        0x06 => S::ZeroPage(ReadWriteOp::ASL.into()),
-                        0x16 => S::ZeroPageX(ReadWriteOp::ASL.into()),
-                        0x0E => S::Absolute(ReadWriteOp::ASL.into()),
-                        0x1E => S::AbsoluteX(ReadWriteOp::ASL.into()),
-                        // BCC
-                        0x90 => S::Relative(BranchOp::BCC),
-                        // BCS
-                        0xB0 => S::Relative(BranchOp::BCS),
-                        // BEQ
-                        0xF0 => S::Relative(BranchOp::BEQ),
-                        // BIT
-                        0x24 => S::ZeroPage(ReadOp::BIT.into()),
-                        0x2C => S::Absolute(ReadOp::BIT.into()),
-                        // BMI
-                        0x30 => S::Relative(BranchOp::BMI),
-                        // BNE
-                        0xD0 => S::Relative(BranchOp::BNE),
-                        // BPL
-                        0x10 => S::Relative(BranchOp::BPL),
-                        // BRK,
-                        0x00 => S::Int(Interrupt::BRK),
-                        // BVC
-                        0x50 => S::Relative(BranchOp::BVC),
-                        // BVS
-                        0x70 => S::Relative(BranchOp::BVS),
-                        // CLC
-                        0x18 => S::Implied(ImpliedOp::CLC),
-                        // CLD
-                        0xD8 => S::Implied(ImpliedOp::CLD),
-                        // CLI
-                        0x58 => S::Implied(ImpliedOp::CLI),
-                        // CLV
-                        0xB8 => S::Implied(ImpliedOp::CLV),
-                        // CMP
-                        0xC9 => S::ImmediateR(ReadOp::CMP),
-                        0xC5 => S::ZeroPage(ReadOp::CMP.into()),
-                        0xD5 => S::ZeroPageX(ReadOp::CMP.into()),
-                        0xCD => S::Absolute(ReadOp::CMP.into()),
-                        0xDD => S::AbsoluteX(ReadOp::CMP.into()),
-                        0xD9 => S::AbsoluteY(ReadOp::CMP.into()),
-                        0xC1 => S::IndexedIndirect(ReadOp::CMP.into()),
-                        0xD1 => S::IndirectIndexed(ReadOp::CMP.into()),
-                        // CPX
-                        0xE0 => S::ImmediateR(ReadOp::CPX),
-                        0xE4 => S::ZeroPage(ReadOp::CPX.into()),
-                        0xEC => S::Absolute(ReadOp::CPX.into()),
-                        // CPY
-                        0xC0 => S::ImmediateR(ReadOp::CPY),
-                        0xC4 => S::ZeroPage(ReadOp::CPY.into()),
-                        0xCC => S::Absolute(ReadOp::CPY.into()),
-                        // DEC
-                        0xC6 => S::ZeroPage(ReadWriteOp::DEC.into()),
-                        0xD6 => S::ZeroPageX(ReadWriteOp::DEC.into()),
-                        0xCE => S::Absolute(ReadWriteOp::DEC.into()),
-                        0xDE => S::AbsoluteX(ReadWriteOp::DEC.into()),
-                        // DEX
-                        0xCA => S::Implied(ImpliedOp::DEX),
-                        // DEY
-                        0x88 => S::Implied(ImpliedOp::DEY),
-                        // EOR
-                        0x49 => S::ImmediateR(ReadOp::EOR),
-                        0x45 => S::ZeroPage(ReadOp::EOR.into()),
-                        0x55 => S::ZeroPageX(ReadOp::EOR.into()),
-                        0x4D => S::Absolute(ReadOp::EOR.into()),
-                        0x5D => S::AbsoluteX(ReadOp::EOR.into()),
-                        0x59 => S::AbsoluteY(ReadOp::EOR.into()),
-                        0x41 => S::IndexedIndirect(ReadOp::EOR.into()),
-                        0x51 => S::IndirectIndexed(ReadOp::EOR.into()),
-                        // INC
-                        0xE6 => S::ZeroPage(ReadWriteOp::INC.into()),
-                        0xF6 => S::ZeroPageX(ReadWriteOp::INC.into()),
-                        0xEE => S::Absolute(ReadWriteOp::INC.into()),
-                        0xFE => S::AbsoluteX(ReadWriteOp::INC.into()),
-                        // INX
-                        0xE8 => S::Implied(ImpliedOp::INX),
-                        // INY
-                        0xC8 => S::Implied(ImpliedOp::INY),
-                        // JMP
-                        0x4C => S::JMPAbsolute,
-                        0x6C => S::JMPIndirect,
-                        // JSR
-                        0x20 => S::JSR,
-                        // LDA
-                        0xA9 => S::ImmediateR(ReadOp::LDA),
-                        0xA5 => S::ZeroPage(ReadOp::LDA.into()),
-                        0xB5 => S::ZeroPageX(ReadOp::LDA.into()),
-                        0xAD => S::Absolute(ReadOp::LDA.into()),
-                        0xBD => S::AbsoluteX(ReadOp::LDA.into()),
-                        0xB9 => S::AbsoluteY(ReadOp::LDA.into()),
-                        0xA1 => S::IndexedIndirect(ReadOp::LDA.into()),
-                        0xB1 => S::IndirectIndexed(ReadOp::LDA.into()),
-                        // LDX
-                        0xA2 => S::ImmediateR(ReadOp::LDX),
-                        0xA6 => S::ZeroPage(ReadOp::LDX.into()),
-                        0xB6 => S::ZeroPageY(ReadOp::LDX.into()),
-                        0xAE => S::Absolute(ReadOp::LDX.into()),
-                        0xBE => S::AbsoluteY(ReadOp::LDX.into()),
-                        // LDY
-                        0xA0 => S::ImmediateR(ReadOp::LDY),
-                        0xA4 => S::ZeroPage(ReadOp::LDY.into()),
-                        0xB4 => S::ZeroPageX(ReadOp::LDY.into()),
-                        0xAC => S::Absolute(ReadOp::LDY.into()),
-                        0xBC => S::AbsoluteX(ReadOp::LDY.into()),
-                        // LSR
-                        0x4A => S::AccRW(ReadWriteOp::LSR),
-                        0x46 => S::ZeroPage(ReadWriteOp::LSR.into()),
-                        0x56 => S::ZeroPageX(ReadWriteOp::LSR.into()),
-                        0x4E => S::Absolute(ReadWriteOp::LSR.into()),
-                        0x5E => S::AbsoluteX(ReadWriteOp::LSR.into()),
-                        // NOP
-                        0xEA => S::Implied(ImpliedOp::NOP),
-                        // ORA
-                        0x09 => S::ImmediateR(ReadOp::ORA),
-                        0x05 => S::ZeroPage(ReadOp::ORA.into()),
-                        0x15 => S::ZeroPageX(ReadOp::ORA.into()),
-                        0x0D => S::Absolute(ReadOp::ORA.into()),
-                        0x1D => S::AbsoluteX(ReadOp::ORA.into()),
-                        0x19 => S::AbsoluteY(ReadOp::ORA.into()),
-                        0x01 => S::IndexedIndirect(ReadOp::ORA.into()),
-                        0x11 => S::IndirectIndexed(ReadOp::ORA.into()),
-                        // PHA
-                        0x48 => S::PHA,
-                        // PHP
-                        0x08 => S::PHP,
-                        // PLA
-                        0x68 => S::PLA,
-                        // PLP
-                        0x28 => S::PLP,
-                        // ROL
-                        0x2A => S::AccRW(ReadWriteOp::ROL),
-                        0x26 => S::ZeroPage(ReadWriteOp::ROL.into()),
-                        0x36 => S::ZeroPageX(ReadWriteOp::ROL.into()),
-                        0x2E => S::Absolute(ReadWriteOp::ROL.into()),
-                        0x3E => S::AbsoluteX(ReadWriteOp::ROL.into()),
-                        // ROR
-                        0x6A => S::AccRW(ReadWriteOp::ROR),
-                        0x66 => S::ZeroPage(ReadWriteOp::ROR.into()),
-                        0x76 => S::ZeroPageX(ReadWriteOp::ROR.into()),
-                        0x6E => S::Absolute(ReadWriteOp::ROR.into()),
-                        0x7E => S::AbsoluteX(ReadWriteOp::ROR.into()),
-                        // RTI
-                        0x40 => S::RTI,
-                        // RTS
-                        0x60 => S::RTS,
-                        // SBC
-                        0xE9 => S::ImmediateR(ReadOp::SBC),
-                        0xE5 => S::ZeroPage(ReadOp::SBC.into()),
-                        0xF5 => S::ZeroPageX(ReadOp::SBC.into()),
-                        0xED => S::Absolute(ReadOp::SBC.into()),
-                        0xFD => S::AbsoluteX(ReadOp::SBC.into()),
-                        0xF9 => S::AbsoluteY(ReadOp::SBC.into()),
-                        0xE1 => S::IndexedIndirect(ReadOp::SBC.into()),
-                        0xF1 => S::IndirectIndexed(ReadOp::SBC.into()),
-                        // SEC
-                        0x38 => S::Implied(ImpliedOp::SEC),
-                        // SED
-                        0xF8 => S::Implied(ImpliedOp::SED),
-                        // SEI
-                        0x78 => S::Implied(ImpliedOp::SEI),
-                        // STA
-                        0x85 => S::ZeroPage(WriteOp::STA.into()),
-                        0x95 => S::ZeroPageX(WriteOp::STA.into()),
-                        0x8D => S::Absolute(WriteOp::STA.into()),
-                        0x9D => S::AbsoluteX(WriteOp::STA.into()),
-                        0x99 => S::AbsoluteY(WriteOp::STA.into()),
-                        0x81 => S::IndexedIndirect(WriteOp::STA.into()),
-                        0x91 => S::IndirectIndexed(WriteOp::STA.into()),
-                        // STX
-                        0x86 => S::ZeroPage(WriteOp::STX.into()),
-                        0x96 => S::ZeroPageY(WriteOp::STX.into()),
-                        0x8E => S::Absolute(WriteOp::STX.into()),
-                        // STY
-                        0x84 => S::ZeroPage(WriteOp::STY.into()),
-                        0x94 => S::ZeroPageX(WriteOp::STY.into()),
-                        0x8C => S::Absolute(WriteOp::STY.into()),
-                        // TAX
-                        0xAA => S::Implied(ImpliedOp::TAX),
-                        // TAY
-                        0xA8 => S::Implied(ImpliedOp::TAY),
-                        // TSX
-                        0xBA => S::Implied(ImpliedOp::TSX),
-                        // TXA
-                        0x8A => S::Implied(ImpliedOp::TXA),
-                        // TXS
-                        0x9A => S::Implied(ImpliedOp::TXS),
-                        // TYA
-                        0x98 => S::Implied(ImpliedOp::TYA),
-
-                        // Undocumented opcodes
-                        // Various NOPs
-                        0x04 | 0x44 | 0x64 => S::ZeroPage(ReadOp::NOP.into()),
-                        0x0C => S::Absolute(ReadOp::NOP.into()),
-                        0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => S::ZeroPageX(ReadOp::NOP.into()),
-                        0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => S::Implied(ImpliedOp::NOP),
-                        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => S::AbsoluteX(ReadOp::NOP.into()),
-                        0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => S::ImmediateR(ReadOp::NOP),
-                        // LAX
-                        0xA3 => S::IndexedIndirect(ReadOp::LAX.into()),
-                        0xA7 => S::ZeroPage(ReadOp::LAX.into()),
-                        0xAB => S::ImmediateR(ReadOp::LAX),
-                        0xAF => S::Absolute(ReadOp::LAX.into()),
-                        0xB3 => S::IndirectIndexed(ReadOp::LAX.into()),
-                        0xB7 => S::ZeroPageY(ReadOp::LAX.into()),
-                        0xBF => S::AbsoluteY(ReadOp::LAX.into()),
-                        // SAX
-                        0x83 => S::IndexedIndirect(WriteOp::SAX.into()),
-                        0x87 => S::ZeroPage(WriteOp::SAX.into()),
-                        0x8F => S::Absolute(WriteOp::SAX.into()),
-                        0x97 => S::ZeroPageY(WriteOp::SAX.into()),
-                        // SBC
-                        0xEB => S::ImmediateR(ReadOp::SBC),
-                        // DCP
-                        0xC3 => S::IndexedIndirect(ReadWriteOp::DCP.into()),
-                        0xC7 => S::ZeroPage(ReadWriteOp::DCP.into()),
-                        0xCF => S::Absolute(ReadWriteOp::DCP.into()),
-                        0xD3 => S::IndirectIndexed(ReadWriteOp::DCP.into()),
-                        0xD7 => S::ZeroPageX(ReadWriteOp::DCP.into()),
-                        0xDB => S::AbsoluteY(ReadWriteOp::DCP.into()),
-                        0xDF => S::AbsoluteX(ReadWriteOp::DCP.into()),
-                        // ISC
-                        0xE3 => S::IndexedIndirect(ReadWriteOp::ISC.into()),
-                        0xE7 => S::ZeroPage(ReadWriteOp::ISC.into()),
-                        0xEF => S::Absolute(ReadWriteOp::ISC.into()),
-                        0xF3 => S::IndirectIndexed(ReadWriteOp::ISC.into()),
-                        0xF7 => S::ZeroPageX(ReadWriteOp::ISC.into()),
-                        0xFB => S::AbsoluteY(ReadWriteOp::ISC.into()),
-                        0xFF => S::AbsoluteX(ReadWriteOp::ISC.into()),
-                        // SLO
-                        0x03 => S::IndexedIndirect(ReadWriteOp::SLO.into()),
-                        0x07 => S::ZeroPage(ReadWriteOp::SLO.into()),
-                        0x0F => S::Absolute(ReadWriteOp::SLO.into()),
-                        0x13 => S::IndirectIndexed(ReadWriteOp::SLO.into()),
-                        0x17 => S::ZeroPageX(ReadWriteOp::SLO.into()),
-                        0x1B => S::AbsoluteY(ReadWriteOp::SLO.into()),
-                        0x1F => S::AbsoluteX(ReadWriteOp::SLO.into()),
-                        // RLA
-                        0x23 => S::IndexedIndirect(ReadWriteOp::RLA.into()),
-                        0x27 => S::ZeroPage(ReadWriteOp::RLA.into()),
-                        0x2F => S::Absolute(ReadWriteOp::RLA.into()),
-                        0x33 => S::IndirectIndexed(ReadWriteOp::RLA.into()),
-                        0x37 => S::ZeroPageX(ReadWriteOp::RLA.into()),
-                        0x3B => S::AbsoluteY(ReadWriteOp::RLA.into()),
-                        0x3F => S::AbsoluteX(ReadWriteOp::RLA.into()),
-                        // SRE
-                        0x43 => S::IndexedIndirect(ReadWriteOp::SRE.into()),
-                        0x47 => S::ZeroPage(ReadWriteOp::SRE.into()),
-                        0x4F => S::Absolute(ReadWriteOp::SRE.into()),
-                        0x53 => S::IndirectIndexed(ReadWriteOp::SRE.into()),
-                        0x57 => S::ZeroPageX(ReadWriteOp::SRE.into()),
-                        0x5B => S::AbsoluteY(ReadWriteOp::SRE.into()),
-                        0x5F => S::AbsoluteX(ReadWriteOp::SRE.into()),
-                        // RRA
-                        0x63 => S::IndexedIndirect(ReadWriteOp::RRA.into()),
-                        0x67 => S::ZeroPage(ReadWriteOp::RRA.into()),
-                        0x6F => S::Absolute(ReadWriteOp::RRA.into()),
-                        0x73 => S::IndirectIndexed(ReadWriteOp::RRA.into()),
-                        0x77 => S::ZeroPageX(ReadWriteOp::RRA.into()),
-                        0x7B => S::AbsoluteY(ReadWriteOp::RRA.into()),
-                        0x7F => S::AbsoluteX(ReadWriteOp::RRA.into()),
-                        // ANC
-                        0x0B => S::ImmediateR(ReadOp::ANC),
-                        0x2B => S::ImmediateR(ReadOp::ANC),
-                        // ALR
-                        0x4B => S::ImmediateR(ReadOp::ALR),
-                        // ARR
-                        0x6B => S::ImmediateR(ReadOp::ARR),
-                        // AXS
-                        0xCB => S::ImmediateR(ReadOp::AXS),
-                        // SHY
-                        0x9C => S::AbsoluteX(SHOp::SHY.into()),
-                        // SHX
-                        0x9E => S::AbsoluteY(SHOp::SHX.into()),
-
-                        x => panic!("Illegal opcode: {:X}", x),
-                    }
+                    decode(opcode).unwrap_or_else(|| panic!("Illegal opcode: {:X}", opcode))
                 }
             }
             S::ImmediateR(oc) => {
@@ -1053,7 +1174,9 @@ impl CPU {
                 self.s.set(s);
                 let p = host.read(0x100 | s as u16) & !0x10 | 0x20;
 
+                let i = self.get_flag(Flags::I);
                 self.set_p(p);
+                self.delay_i_flag(i);
 
                 S::FetchOpcode
             }
@@ -1378,7 +1501,7 @@ impl ImpliedOp {
                 cpu.set_flag(Flags::D, false);
             }
             ImpliedOp::CLI => {
-                cpu.set_flag(Flags::I, false);
+                cpu.delayed_i.set(Some(false));
             }
             ImpliedOp::CLV => {
                 cpu.set_flag(Flags::V, false);
@@ -1411,7 +1534,7 @@ impl ImpliedOp {
                 cpu.set_flag(Flags::D, true);
             }
             ImpliedOp::SEI => {
-                cpu.set_flag(Flags::I, true);
+                cpu.delayed_i.set(Some(true));
             }
             ImpliedOp::TAX => {
                 let result = cpu.a.get();
@@ -1454,3 +1577,120 @@ impl SHOp {
         }
     }
 }
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndexedIndirect,
+    IndirectIndexed,
+    Relative,
+}
+
+impl AddressingMode {
+    fn len(self) -> u16 {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 1,
+            AddressingMode::Absolute
+            | AddressingMode::AbsoluteX
+            | AddressingMode::AbsoluteY
+            | AddressingMode::Indirect => 3,
+            _ => 2,
+        }
+    }
+}
+
+fn op_name(op: Op) -> String {
+    match op {
+        Op::Read(o) => format!("{:?}", o),
+        Op::ReadWrite(o) => format!("{:?}", o),
+        Op::Write(o) => format!("{:?}", o),
+        Op::SH(o) => format!("{:?}", o),
+    }
+}
+
+// The mnemonic and addressing mode of a freshly decoded instruction
+fn describe(state: S) -> (String, AddressingMode) {
+    use AddressingMode as M;
+
+    match state {
+        S::ZeroPage(op) => (op_name(op), M::ZeroPage),
+        S::ZeroPageX(op) => (op_name(op), M::ZeroPageX),
+        S::ZeroPageY(op) => (op_name(op), M::ZeroPageY),
+        S::Absolute(op) => (op_name(op), M::Absolute),
+        S::AbsoluteX(op) => (op_name(op), M::AbsoluteX),
+        S::AbsoluteY(op) => (op_name(op), M::AbsoluteY),
+        S::IndexedIndirect(op) => (op_name(op), M::IndexedIndirect),
+        S::IndirectIndexed(op) => (op_name(op), M::IndirectIndexed),
+        S::ImmediateR(op) => (format!("{:?}", op), M::Immediate),
+        S::AccRW(op) => (format!("{:?}", op), M::Accumulator),
+        S::Relative(op) => (format!("{:?}", op), M::Relative),
+        S::Implied(op) => (format!("{:?}", op), M::Implied),
+        S::Int(Interrupt::BRK) => ("BRK".to_string(), M::Implied),
+        S::RTI => ("RTI".to_string(), M::Implied),
+        S::RTS => ("RTS".to_string(), M::Implied),
+        S::PHA => ("PHA".to_string(), M::Implied),
+        S::PHP => ("PHP".to_string(), M::Implied),
+        S::PLA => ("PLA".to_string(), M::Implied),
+        S::PLP => ("PLP".to_string(), M::Implied),
+        S::JSR => ("JSR".to_string(), M::Absolute),
+        S::JMPAbsolute => ("JMP".to_string(), M::Absolute),
+        S::JMPIndirect => ("JMP".to_string(), M::Indirect),
+        _ => unreachable!("{:?} isn't the first state of an instruction", state),
+    }
+}
+
+// Disassembles the instruction at `pc` in the same format as nestest.log, without the registers:
+// "C000  4C F5 C5  JMP $C5F5". `read` should read memory without side effects, and any bytes it
+// can't read show up as ??.
+pub fn disassemble(pc: u16, read: impl Fn(u16) -> Option<u8>) -> String {
+    let byte = |offset: u16| read(pc.wrapping_add(offset));
+    let hex = |b: Option<u8>| b.map_or("??".to_string(), |b| format!("{:02X}", b));
+
+    let (mnemonic, mode) = match byte(0).and_then(decode) {
+        Some(state) => describe(state),
+        None => return format!("{:04X}  {:<8}  ???", pc, hex(byte(0))),
+    };
+
+    let bytes: Vec<String> = (0..mode.len()).map(|i| hex(byte(i))).collect();
+    let zero_page = hex(byte(1));
+    let absolute = format!("{}{}", hex(byte(2)), hex(byte(1)));
+    let operand = match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => " A".to_string(),
+        AddressingMode::Immediate => format!(" #${}", zero_page),
+        AddressingMode::ZeroPage => format!(" ${}", zero_page),
+        AddressingMode::ZeroPageX => format!(" ${},X", zero_page),
+        AddressingMode::ZeroPageY => format!(" ${},Y", zero_page),
+        AddressingMode::Absolute => format!(" ${}", absolute),
+        AddressingMode::AbsoluteX => format!(" ${},X", absolute),
+        AddressingMode::AbsoluteY => format!(" ${},Y", absolute),
+        AddressingMode::Indirect => format!(" (${})", absolute),
+        AddressingMode::IndexedIndirect => format!(" (${},X)", zero_page),
+        AddressingMode::IndirectIndexed => format!(" (${}),Y", zero_page),
+        // Shown as the address it branches to
+        AddressingMode::Relative => match byte(1) {
+            Some(offset) => format!(
+                " ${:04X}",
+                pc.wrapping_add(2).wrapping_add(offset as i8 as u16)
+            ),
+            None => " $????".to_string(),
+        },
+    };
+
+    format!(
+        "{:04X}  {:<8}  {}{}",
+        pc,
+        bytes.join(" "),
+        mnemonic,
+        operand
+    )
+}
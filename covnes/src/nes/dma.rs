@@ -1,10 +1,19 @@
-use std::cell::Cell;
+use core::cell::Cell;
 
-use crate::nes::{cpu::CpuHostAccess, io::IO, Nes};
+use crate::{
+    error::{Error, Result},
+    nes::{
+        cpu::CpuHostAccess,
+        io::IO,
+        state::{SaveState, StateReader, StateWriter},
+        Nes,
+    },
+};
 
 pub struct DMA {
     pub is_odd: Cell<bool>,
     pub state: Cell<DMAState>,
+    pub dmc: Cell<DmcDMAState>,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -15,9 +24,10 @@ pub enum DMAState {
     Req {
         addr_high: u8,
     },
-    // If we need to for alignment
+    // If we need to for alignment, either at the start or after the DMC's taken a read cycle
     DummyRead {
         addr_high: u8,
+        addr_low: u8,
     },
     Read {
         addr_high: u8,
@@ -30,6 +40,21 @@ pub enum DMAState {
     },
 }
 
+// The DMC asks for a byte whenever its sample buffer is empty. On its own this halts the CPU for 3
+// or 4 cycles: the halt, a dummy read, a cycle to align if needed, and the read itself. During an
+// OAM DMA it only takes the next read cycle, and the OAM DMA then needs a cycle to realign.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DmcDMAState {
+    // Not DMAing
+    No,
+    // Waiting for the CPU to be on a read cycle
+    Halt,
+    Dummy,
+    // If we need to for alignment
+    Align,
+    Read,
+}
+
 // This is not entirely accurate - we don't read the correct address when starting a DMA
 // This is because I don't want to to completely restructure the CPU just in case there happened to
 // be some kind of snoopy bus
@@ -40,11 +65,13 @@ impl DMA {
         DMA {
             is_odd: Cell::new(false),
             state: Cell::new(DMAState::No),
+            dmc: Cell::new(DmcDMAState::No),
         }
     }
 
     pub fn reset(&self) {
         self.is_odd.set(false);
+        self.dmc.set(DmcDMAState::No);
     }
 
     pub fn trigger_oamdma(&self, value: u8) {
@@ -55,6 +82,63 @@ impl DMA {
         let is_odd = self.is_odd.get();
         self.is_odd.set(!is_odd);
 
+        if self.dmc.get() == DmcDMAState::No && nes.apu.dmc.dma_address().is_some() {
+            self.dmc.set(DmcDMAState::Halt);
+        }
+
+        match self.state.get() {
+            DMAState::No | DMAState::Req { .. } if self.dmc.get() != DmcDMAState::No => {
+                self.tick_dmc(nes, is_odd)
+            }
+            DMAState::Read {
+                addr_high,
+                addr_low,
+            } if self.dmc.get() != DmcDMAState::No => {
+                // Reads are always on even cycles during an OAM DMA, so the DMC can have this one
+                self.read_dmc_sample(nes);
+                self.dmc.set(DmcDMAState::No);
+                self.state.set(DMAState::DummyRead {
+                    addr_high,
+                    addr_low,
+                });
+                false
+            }
+            _ => self.tick_oam(nes, is_odd),
+        }
+    }
+
+    fn tick_dmc<I: IO>(&self, nes: &Nes<I>, is_odd: bool) -> bool {
+        let next_state = match self.dmc.get() {
+            DmcDMAState::No => return true,
+            DmcDMAState::Halt => {
+                if nes.cpu.state.get().is_write_cycle() {
+                    // Same as OAM DMA, write cycles aren't hijacked
+                    return true;
+                }
+                DmcDMAState::Dummy
+            }
+            // The read has to be on an even cycle, so the one after an odd one
+            DmcDMAState::Dummy | DmcDMAState::Align if is_odd => DmcDMAState::Read,
+            DmcDMAState::Dummy | DmcDMAState::Align => DmcDMAState::Align,
+            DmcDMAState::Read => {
+                self.read_dmc_sample(nes);
+                DmcDMAState::No
+            }
+        };
+
+        self.dmc.set(next_state);
+        false
+    }
+
+    fn read_dmc_sample<I: IO>(&self, nes: &Nes<I>) {
+        // The DMC might have been turned off since it asked
+        if let Some(addr) = nes.apu.dmc.dma_address() {
+            let value = nes.read(addr);
+            nes.apu.dmc.fill_buffer(value);
+        }
+    }
+
+    fn tick_oam<I: IO>(&self, nes: &Nes<I>, is_odd: bool) -> bool {
         let (next_state, tick_cpu) = match self.state.get() {
             DMAState::No => (DMAState::No, true),
             DMAState::Req { addr_high } => {
@@ -72,15 +156,24 @@ impl DMA {
                     )
                 } else {
                     // We're currently on a read, we need to dummy read to be aligned at the end
-                    (DMAState::DummyRead { addr_high }, false)
+                    (
+                        DMAState::DummyRead {
+                            addr_high,
+                            addr_low: 0,
+                        },
+                        false,
+                    )
                 }
             }
-            DMAState::DummyRead { addr_high } => {
-                nes.read((addr_high as u16) << 8);
+            DMAState::DummyRead {
+                addr_high,
+                addr_low,
+            } => {
+                nes.read((addr_high as u16) << 8 | addr_low as u16);
                 (
                     DMAState::Read {
                         addr_high,
-                        addr_low: 0,
+                        addr_low,
                     },
                     false,
                 )
@@ -124,3 +217,75 @@ impl DMA {
         tick_cpu
     }
 }
+
+// Every variant is written with all three fields so that states are always the same size
+impl SaveState for DMA {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.is_odd.save_state(w);
+        let (tag, addr_high, addr_low, value) = match self.state.get() {
+            DMAState::No => (0, 0, 0, 0),
+            DMAState::Req { addr_high } => (1, addr_high, 0, 0),
+            DMAState::DummyRead {
+                addr_high,
+                addr_low,
+            } => (2, addr_high, addr_low, 0),
+            DMAState::Read {
+                addr_high,
+                addr_low,
+            } => (3, addr_high, addr_low, 0),
+            DMAState::Write {
+                addr_high,
+                addr_low,
+                value,
+            } => (4, addr_high, addr_low, value),
+        };
+        w.u8(tag);
+        w.u8(addr_high);
+        w.u8(addr_low);
+        w.u8(value);
+        w.u8(match self.dmc.get() {
+            DmcDMAState::No => 0,
+            DmcDMAState::Halt => 1,
+            DmcDMAState::Dummy => 2,
+            DmcDMAState::Align => 3,
+            DmcDMAState::Read => 4,
+        });
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.is_odd.load_state(r)?;
+        let tag = r.u8()?;
+        let addr_high = r.u8()?;
+        let addr_low = r.u8()?;
+        let value = r.u8()?;
+        let state = match tag {
+            0 => DMAState::No,
+            1 => DMAState::Req { addr_high },
+            2 => DMAState::DummyRead {
+                addr_high,
+                addr_low,
+            },
+            3 => DMAState::Read {
+                addr_high,
+                addr_low,
+            },
+            4 => DMAState::Write {
+                addr_high,
+                addr_low,
+                value,
+            },
+            _ => return Err(Error::BadSaveState),
+        };
+        let dmc = match r.u8()? {
+            0 => DmcDMAState::No,
+            1 => DmcDMAState::Halt,
+            2 => DmcDMAState::Dummy,
+            3 => DmcDMAState::Align,
+            4 => DmcDMAState::Read,
+            _ => return Err(Error::BadSaveState),
+        };
+        self.state.set(state);
+        self.dmc.set(dmc);
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use crate::nes::io::{StandardControllerButtons, TwoStandardControllersIO};
+
+/// The buttons held on both controllers for each frame, in order from the first frame recorded.
+pub type InputLog = Vec<[StandardControllerButtons; 2]>;
+
+/// Sits between `TwoStandardControllers` and the frontend's IO to record the input for each
+/// frame, or play back a recording instead of the frontend's input.
+///
+/// Input is sampled once per frame, the first time the game asks for it, and it's what every
+/// poll sees until the next vblank. Playing a recording back into a fresh `Nes` (with the same ROM
+/// and starting RAM) gives exactly the same frames, which is all that movies and TAS tools need.
+///
+/// It goes inside the controllers, as in `Nes<TwoStandardControllers<InputRecorder<I>>>`, so is
+/// reached through `nes.io.io`.
+pub struct InputRecorder<I: TwoStandardControllersIO> {
+    pub io: I,
+    // This frame's input, once something has polled it
+    frame_input: Cell<Option<[StandardControllerButtons; 2]>>,
+    recording: Cell<Option<InputLog>>,
+    // What's left to play back, in reverse so the next frame is at the end
+    playback: Cell<InputLog>,
+}
+
+impl<I: TwoStandardControllersIO> InputRecorder<I> {
+    pub fn new(io: I) -> InputRecorder<I> {
+        InputRecorder {
+            io,
+            frame_input: Cell::new(None),
+            recording: Cell::new(None),
+            playback: Cell::new(Vec::new()),
+        }
+    }
+
+    /// Starts recording from the current frame, throwing away anything recorded so far.
+    pub fn record_start(&self) {
+        self.recording.set(Some(Vec::new()));
+    }
+
+    /// Stops recording and returns the input for each frame (empty if there wasn't a recording
+    /// going).
+    pub fn record_stop(&self) -> InputLog {
+        match self.recording.take() {
+            Some(mut recording) => {
+                // `Nes::step_frame` stops just before vblank, so the frame that's under way has
+                // been run as far as it's going to be
+                recording.push(self.frame_input());
+                recording
+            }
+            None => Vec::new(),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        let recording = self.recording.take();
+        let is_recording = recording.is_some();
+        self.recording.set(recording);
+        is_recording
+    }
+
+    /// Plays back `frames` from the next time input is sampled, in place of the IO's own input.
+    /// Anything still playing is replaced. Once they run out it goes back to the IO's input.
+    pub fn play(&self, mut frames: InputLog) {
+        frames.reverse();
+        self.playback.set(frames);
+    }
+
+    pub fn frames_left_to_play(&self) -> usize {
+        let playback = self.playback.take();
+        let frames = playback.len();
+        self.playback.set(playback);
+        frames
+    }
+
+    fn frame_input(&self) -> [StandardControllerButtons; 2] {
+        if let Some(input) = self.frame_input.get() {
+            return input;
+        }
+
+        let mut playback = self.playback.take();
+        let input = playback
+            .pop()
+            .unwrap_or_else(|| [self.io.poll_buttons(0), self.io.poll_buttons(1)]);
+        self.playback.set(playback);
+        self.frame_input.set(Some(input));
+        input
+    }
+}
+
+impl<I: TwoStandardControllersIO> TwoStandardControllersIO for InputRecorder<I> {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        self.io.set_pixel(row, col, r, g, b);
+    }
+
+    fn poll_buttons(&self, port: usize) -> StandardControllerButtons {
+        self.frame_input()[port]
+    }
+
+    // Frames end at vblank (as with `Nes::step_frame`). Frames where the game never polled still
+    // take their input, so the recording and playback stay lined up through lag frames.
+    fn vblank_start(&self) {
+        let input = self.frame_input();
+        if let Some(mut recording) = self.recording.take() {
+            recording.push(input);
+            self.recording.set(Some(recording));
+        }
+        self.frame_input.set(None);
+        self.io.vblank_start();
+    }
+}
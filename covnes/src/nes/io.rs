@@ -1,4 +1,6 @@
-use std::cell::Cell;
+use core::cell::Cell;
+
+use crate::nes::palette::Palette;
 bitflags! {
     pub struct StandardControllerButtons: u8 {
         const A = 0x01;
@@ -12,9 +14,15 @@ bitflags! {
     }
 }
 
+// The data lines a read of $4016/$4017 can see, which end up as the same bits of the value read.
+// Standard controllers only use D0, but expansion devices report on the others: the Zapper's
+// light sensor and trigger are D3 and D4, the Arkanoid paddle's position is shifted out on D1 and
+// the Famicom's microphone is D2 of $4016. Any combination can be driven at once.
 bitflags! {
     pub struct ControllerPortDataLines: u8 {
         const D0 = 0x01;
+        const D1 = 0x02;
+        const D2 = 0x04;
         const D3 = 0x08;
         const D4 = 0x10;
     }
@@ -22,11 +30,33 @@ bitflags! {
 
 pub trait IO {
     fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8);
+    // The pixel as the PPU outputs it: a 6 bit palette index (with greyscale already applied) and
+    // the 3 emphasis bits from PPUMASK, red lowest. For NTSC filters and anything else that wants
+    // the colour before it's turned into RGB. By default it's converted with `palette` (the one
+    // from `Nes::set_palette`) and passed to `set_pixel`.
+    fn set_pixel_index(
+        &self,
+        row: u16,
+        col: u16,
+        palette_index: u8,
+        emphasis: u8,
+        palette: &Palette,
+    ) {
+        let (r, g, b) = palette.rgb(palette_index, emphasis);
+        self.set_pixel(row, col, r, g, b);
+    }
     // Represents a transition in the latch line from the 2A03
     // Only called on CHANGE, not every 4016 write
     fn controller_latch_change(&self, value: bool);
+    // A read of $4016 or $4017, which is what clocks a controller's shift register. Lines left
+    // empty read as 0.
     fn controller_port_1_read(&self) -> ControllerPortDataLines;
     fn controller_port_2_read(&self) -> ControllerPortDataLines;
+    // Called once per CPU cycle with the APU and any expansion audio mixed together, from -1.0 to
+    // 1.0 (more with loud expansion audio). See `apu::mix`.
+    fn push_audio_sample(&self, _sample: f32) {}
+    // Called as the PPU enters vblank, for doing per-frame work at a consistent point
+    fn vblank_start(&self) {}
 }
 
 pub struct DummyIO;
@@ -44,16 +74,59 @@ impl IO for DummyIO {
     }
 }
 
+// The shift register inside a standard controller
+struct ShiftRegister {
+    latch: Cell<u8>,
+}
+
+impl ShiftRegister {
+    fn new() -> ShiftRegister {
+        ShiftRegister {
+            latch: Cell::new(0),
+        }
+    }
+
+    // While the strobe is high the real shift register is reloaded continuously, so the buttons
+    // at the moment it goes low are what gets shifted out. Sampling them on the high-low
+    // transition means reads while high never need to touch the latch.
+    fn load(&self, buttons: StandardControllerButtons) {
+        self.latch.set(buttons.bits());
+    }
+
+    fn read(&self, strobe_high: bool, buttons: impl FnOnce() -> StandardControllerButtons) -> bool {
+        if strobe_high {
+            // We return the current A value - no need to check for impossible combinations with A
+            buttons().contains(StandardControllerButtons::A)
+        } else {
+            let latch = self.latch.get();
+            self.latch.set((latch >> 1) | 0x80); // Official NES controllers return 1 after emptying latch
+            latch & 1 == 1
+        }
+    }
+}
+
+fn d0(bit: bool) -> ControllerPortDataLines {
+    // D5-D7 are open bus, which `Nes` fills in
+    if bit {
+        ControllerPortDataLines::D0
+    } else {
+        ControllerPortDataLines::empty()
+    }
+}
+
 // The only one I want to emulate for now - deals with the latching/shift reg logic
 pub trait SingleStandardControllerIO {
     fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8);
     fn poll_buttons(&self) -> StandardControllerButtons;
+    // Forwarded from `IO::push_audio_sample`
+    fn push_audio_sample(&self, _sample: f32) {}
+    fn vblank_start(&self) {}
 }
 
 pub struct SingleStandardController<I: SingleStandardControllerIO> {
     pub io: I,
     currently_high: Cell<bool>,
-    latch: Cell<u8>,
+    shift_register: ShiftRegister,
 }
 
 impl<I: SingleStandardControllerIO> SingleStandardController<I> {
@@ -61,7 +134,7 @@ impl<I: SingleStandardControllerIO> SingleStandardController<I> {
         SingleStandardController {
             io,
             currently_high: Cell::new(false),
-            latch: Cell::new(0),
+            shift_register: ShiftRegister::new(),
         }
     }
 }
@@ -75,36 +148,194 @@ impl<I: SingleStandardControllerIO> IO for SingleStandardController<I> {
         self.currently_high.set(value);
         if !value {
             // High-low transition ==> Latch current buttons
+            self.shift_register.load(self.io.poll_buttons());
+        }
+    }
+
+    fn controller_port_1_read(&self) -> ControllerPortDataLines {
+        d0(self
+            .shift_register
+            .read(self.currently_high.get(), || self.io.poll_buttons()))
+    }
+
+    fn controller_port_2_read(&self) -> ControllerPortDataLines {
+        // Not connected - this is always 0
+        ControllerPortDataLines::empty()
+    }
+
+    fn push_audio_sample(&self, sample: f32) {
+        self.io.push_audio_sample(sample);
+    }
+
+    fn vblank_start(&self) {
+        self.io.vblank_start();
+    }
+}
+
+// Same as above, but with a second controller plugged in to port 2
+pub trait TwoStandardControllersIO {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8);
+    // `port` is 0 or 1
+    fn poll_buttons(&self, port: usize) -> StandardControllerButtons;
+    fn push_audio_sample(&self, _sample: f32) {}
+    fn vblank_start(&self) {}
+}
+
+pub struct TwoStandardControllers<I: TwoStandardControllersIO> {
+    pub io: I,
+    currently_high: Cell<bool>,
+    shift_registers: [ShiftRegister; 2],
+}
 
-            self.latch.set(self.io.poll_buttons().bits());
+impl<I: TwoStandardControllersIO> TwoStandardControllers<I> {
+    pub fn new(io: I) -> TwoStandardControllers<I> {
+        TwoStandardControllers {
+            io,
+            currently_high: Cell::new(false),
+            shift_registers: [ShiftRegister::new(), ShiftRegister::new()],
+        }
+    }
+
+    fn read(&self, port: usize) -> ControllerPortDataLines {
+        d0(self.shift_registers[port]
+            .read(self.currently_high.get(), || self.io.poll_buttons(port)))
+    }
+}
+
+impl<I: TwoStandardControllersIO> IO for TwoStandardControllers<I> {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        self.io.set_pixel(row, col, r, g, b);
+    }
+
+    fn controller_latch_change(&self, value: bool) {
+        self.currently_high.set(value);
+        if !value {
+            // The strobe goes to both ports
+            for (port, shift_register) in self.shift_registers.iter().enumerate() {
+                shift_register.load(self.io.poll_buttons(port));
+            }
         }
     }
 
     fn controller_port_1_read(&self) -> ControllerPortDataLines {
-        let bit = if self.currently_high.get() {
-            // We return the current A value - no need to check for impossible combinations with A
-            self.io
-                .poll_buttons()
-                .contains(StandardControllerButtons::A)
-        } else {
-            let latch = self.latch.get();
-            self.latch.set((latch >> 1) | 0x80); // Official NES controllers return 1 after emptying latch
-            latch & 1 == 1
-        };
+        self.read(0)
+    }
 
-        // Note technically D4 and D5 are open bus in this configuration, not 0. I'm not emulating
-        // open bus currently - when (and if) I get around to doing open bus I'll have to do that
-        // somehow - pass in the open bus value to this function?
+    fn controller_port_2_read(&self) -> ControllerPortDataLines {
+        self.read(1)
+    }
 
-        if bit {
-            ControllerPortDataLines::D0
-        } else {
-            ControllerPortDataLines::empty()
+    fn push_audio_sample(&self, sample: f32) {
+        self.io.push_audio_sample(sample);
+    }
+
+    fn vblank_start(&self) {
+        self.io.vblank_start();
+    }
+}
+
+// The Arkanoid "Vaus" paddle. It's wired differently on the Famicom, where it plugs in to the
+// expansion port, and the NES, where it goes in controller port 2.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaddleWiring {
+    // Fire button on D1 of $4016, position on D1 of $4017
+    Famicom,
+    // Both on $4017, fire button on D3 and position on D4
+    Nes,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PaddleState {
+    // Where the knob is turned to. Arkanoid only uses roughly $62-$F2, left to right.
+    pub position: u8,
+    pub fire: bool,
+}
+
+impl PaddleState {
+    // For frontends steering the paddle with the mouse: `x` is how far across the screen it is,
+    // from 0.0 to 1.0
+    pub fn from_mouse_x(x: f32, fire: bool) -> PaddleState {
+        let x = x.clamp(0.0, 1.0);
+        PaddleState {
+            position: 0x62 + (x * (0xF2 - 0x62) as f32) as u8,
+            fire,
+        }
+    }
+}
+
+pub trait ArkanoidPaddleIO {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8);
+    fn poll_paddle(&self) -> PaddleState;
+    fn push_audio_sample(&self, _sample: f32) {}
+    fn vblank_start(&self) {}
+}
+
+pub struct ArkanoidPaddle<I: ArkanoidPaddleIO> {
+    pub io: I,
+    wiring: PaddleWiring,
+    currently_high: Cell<bool>,
+    // The position is latched on the strobe like a controller's buttons, then shifted out a bit
+    // per read with the most significant bit first. The paddle sends it inverted, so this holds
+    // what's actually on the data line and 0s shift in behind it.
+    shift_register: Cell<u8>,
+}
+
+impl<I: ArkanoidPaddleIO> ArkanoidPaddle<I> {
+    pub fn new(io: I, wiring: PaddleWiring) -> ArkanoidPaddle<I> {
+        ArkanoidPaddle {
+            io,
+            wiring,
+            currently_high: Cell::new(false),
+            shift_register: Cell::new(0),
+        }
+    }
+
+    fn position_bit(&self) -> bool {
+        if self.currently_high.get() {
+            return !self.io.poll_paddle().position & 0x80 != 0;
+        }
+        let shift_register = self.shift_register.get();
+        self.shift_register.set(shift_register << 1);
+        shift_register & 0x80 != 0
+    }
+}
+
+impl<I: ArkanoidPaddleIO> IO for ArkanoidPaddle<I> {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        self.io.set_pixel(row, col, r, g, b);
+    }
+
+    fn controller_latch_change(&self, value: bool) {
+        self.currently_high.set(value);
+        if !value {
+            self.shift_register.set(!self.io.poll_paddle().position);
+        }
+    }
+
+    fn controller_port_1_read(&self) -> ControllerPortDataLines {
+        match self.wiring {
+            PaddleWiring::Famicom if self.io.poll_paddle().fire => ControllerPortDataLines::D1,
+            _ => ControllerPortDataLines::empty(),
         }
     }
 
     fn controller_port_2_read(&self) -> ControllerPortDataLines {
-        // Not connected - this is always 0
-        ControllerPortDataLines::empty()
+        let mut lines = ControllerPortDataLines::empty();
+        match self.wiring {
+            PaddleWiring::Famicom => lines.set(ControllerPortDataLines::D1, self.position_bit()),
+            PaddleWiring::Nes => {
+                lines.set(ControllerPortDataLines::D4, self.position_bit());
+                lines.set(ControllerPortDataLines::D3, self.io.poll_paddle().fire);
+            }
+        }
+        lines
+    }
+
+    fn push_audio_sample(&self, sample: f32) {
+        self.io.push_audio_sample(sample);
+    }
+
+    fn vblank_start(&self) {
+        self.io.vblank_start();
     }
 }
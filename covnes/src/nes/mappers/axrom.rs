@@ -0,0 +1,141 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// AxROM - mapper 7, used by Battletoads, Marble Madness and a lot of Rare's other games. Writes
+// to $8000-$FFFF switch 32kb of PRG with the low 3 bits, and bit 4 picks which of the two
+// nametables fills the whole screen. The header's mirroring doesn't mean anything.
+//
+// Most boards don't have bus conflicts (see CNROM), and some games write values that would clash
+// with the ROM, so they're only emulated for NES 2.0 submapper 2 (AMROM).
+
+pub fn from_rom(rom: RomFile) -> Result<AxROM> {
+    let prg_banks = rom.prg_rom.len() / 32768;
+    if !rom.prg_rom.len().is_multiple_of(32768) || !(1..=8).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 7,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr_data = match rom.chr_rom {
+        Some(d) => {
+            if d.len() != 8192 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 7,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(AxROM {
+        prg_rom: rom.prg_rom,
+        chr_data,
+        bus_conflicts: rom.submapper == 2,
+        prg_bank: Cell::new(0),
+        mirroring: Cell::new(MirrorMode::OneScreenLower),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct AxROM {
+    prg_rom: Vec<u8>,
+    chr_data: ChrMem,
+    bus_conflicts: bool,
+    // Registers
+    prg_bank: Cell<u8>,
+    mirroring: Cell<MirrorMode>,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl AxROM {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let addr = self.prg_bank.get() as usize * 32768 + (addr as usize & 0x7FFF);
+        self.prg_rom[addr % self.prg_rom.len()]
+    }
+}
+
+impl CartridgeImpl for AxROM {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.read_prg(addr)),
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            let value = if self.bus_conflicts {
+                value & self.read_prg(addr)
+            } else {
+                value
+            };
+            self.prg_bank.set(value & 7);
+            let mirroring = if value & 0x10 == 0 {
+                MirrorMode::OneScreenLower
+            } else {
+                MirrorMode::OneScreenHigher
+            };
+            self.mirroring.set(mirroring);
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.read(addr as usize),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.write(addr as usize, value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for AxROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_bank.save_state(w);
+        self.mirroring.save_state(w);
+        self.chr_data.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_bank.load_state(r)?;
+        self.mirroring.load_state(r)?;
+        self.chr_data.load_state(r)
+    }
+}
@@ -0,0 +1,142 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common,
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::{Mirroring, RomFile},
+};
+
+// Camerica/Codemasters BF909x boards - mapper 71. This is UxROM with the bank register moved to
+// $C000-$FFFF. The BF9097 variant (Fire Hawk) also has one-screen mirroring control at $9000,
+// which other games never write to, so we switch to one-screen the first time it's used.
+
+pub fn from_rom(rom: RomFile) -> Result<BF909x> {
+    let banks = rom.prg_rom.len() / 16384;
+    if !rom.prg_rom.len().is_multiple_of(16384) || !banks.is_power_of_two() || banks > 16 {
+        return Err(Error::BadPrgRomSize {
+            mapper: 71,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr_data = match rom.chr_rom {
+        Some(d) => {
+            if d.len() != 8192 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 71,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    let mirroring = match rom.mirroring {
+        Mirroring::Horizontal => MirrorMode::Horizontal,
+        Mirroring::Vertical => MirrorMode::Vertical,
+        Mirroring::FourScreen => {
+            return Err(Error::UnsupportedMirroring {
+                mapper: 71,
+                mirroring: rom.mirroring,
+            })
+        }
+    };
+
+    Ok(BF909x {
+        mirroring: Cell::new(mirroring),
+        prg_rom: rom.prg_rom,
+        bank: Cell::new(0),
+        chr_data,
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct BF909x {
+    mirroring: Cell<MirrorMode>,
+    prg_rom: Vec<u8>,
+    bank: Cell<u8>,
+    chr_data: ChrMem,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl CartridgeImpl for BF909x {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(common::read_prg_16k_fixed_last(
+                &self.prg_rom,
+                self.bank.get(),
+                addr,
+            )),
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x9000..=0x9FFF => {
+                let mirroring = if value & 0x10 == 0x10 {
+                    MirrorMode::OneScreenHigher
+                } else {
+                    MirrorMode::OneScreenLower
+                };
+                self.mirroring.set(mirroring);
+            }
+            0xC000..=0xFFFF => self.bank.set(value & 0xF),
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr_data.read(addr as usize),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr_data.write(addr as usize, value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for BF909x {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.mirroring.save_state(w);
+        self.bank.save_state(w);
+        self.chr_data.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.mirroring.load_state(r)?;
+        self.bank.load_state(r)?;
+        self.chr_data.load_state(r)
+    }
+}
@@ -0,0 +1,198 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::{Mirroring, RomFile},
+};
+
+// Mapper 34, which is two unrelated boards that ended up with the same number:
+//
+// - BNROM (Deadly Towers) switches 32kb of PRG with any write to $8000-$FFFF and has 8kb of CHR
+//   RAM
+// - NINA-001 (Impossible Mission II) has 8kb of PRG RAM, and registers at $7FFD-$7FFF (on top of
+//   the RAM) to switch 32kb of PRG and two 4kb CHR ROM banks
+//
+// iNES headers can't tell them apart, so a ROM with CHR ROM is taken to be NINA-001.
+
+pub fn from_rom(rom: RomFile) -> Result<BNROM> {
+    let prg_banks = rom.prg_rom.len() / 32768;
+    if !rom.prg_rom.len().is_multiple_of(32768) || !(1..=256).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 34,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let (board, chr) = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || !d.len().is_multiple_of(4096) || d.len() > 64 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 34,
+                    size: d.len(),
+                });
+            }
+            (Board::NINA001, ChrMem::ROM(d))
+        }
+        None => (Board::BNROM, ChrMem::RAM(vec![Cell::new(0); 8192])),
+    };
+
+    // The NINA-001's registers live in its PRG RAM, so it always has some
+    let prg_ram = if rom.provide_prg_ram || board == Board::NINA001 {
+        Some(vec![Cell::new(0); 0x2000])
+    } else {
+        None
+    };
+
+    let mirroring = match rom.mirroring {
+        Mirroring::Horizontal => MirrorMode::Horizontal,
+        Mirroring::Vertical => MirrorMode::Vertical,
+        Mirroring::FourScreen => {
+            return Err(Error::UnsupportedMirroring {
+                mapper: 34,
+                mirroring: rom.mirroring,
+            })
+        }
+    };
+
+    Ok(BNROM {
+        board,
+        mirroring,
+        prg_rom: rom.prg_rom,
+        chr,
+        prg_ram,
+        prg_bank: Cell::new(0),
+        chr_banks: Default::default(),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Board {
+    BNROM,
+    NINA001,
+}
+
+pub struct BNROM {
+    board: Board,
+    mirroring: MirrorMode,
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    prg_ram: Option<Vec<Cell<u8>>>,
+    // Registers
+    prg_bank: Cell<u8>,
+    // NINA-001 only, for $0000 and $1000
+    chr_banks: [Cell<u8>; 2],
+    forced_mirroring: ForcedMirroring,
+}
+
+impl BNROM {
+    fn chr_addr(&self, addr: u16) -> usize {
+        match self.board {
+            Board::BNROM => addr as usize,
+            Board::NINA001 => {
+                let bank = self.chr_banks[(addr >> 12) as usize & 1].get();
+                self.chr.bank_addr(bank as usize, 4096, addr)
+            }
+        }
+    }
+}
+
+impl CartridgeImpl for BNROM {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if let Some(ram) = &self.prg_ram {
+                    Some(ram[(addr - 0x6000) as usize].get())
+                } else {
+                    if cfg!(pedantic_af) {
+                        panic!("Bad read {:4X} (no PRG RAM)", addr);
+                    }
+                    None
+                }
+            }
+            0x8000..=0xFFFF => {
+                let addr = self.prg_bank.get() as usize * 32768 + (addr as usize & 0x7FFF);
+                Some(self.prg_rom[addr % self.prg_rom.len()])
+            }
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match (self.board, addr) {
+            (_, 0x6000..=0x7FFF) => {
+                if let Some(ram) = &self.prg_ram {
+                    ram[(addr - 0x6000) as usize].set(value);
+                } else if cfg!(pedantic_af) {
+                    panic!("Bad write to cartridge space when no PRGRAM {:04X}", addr);
+                }
+
+                // The registers are written as well as the RAM underneath them
+                if self.board == Board::NINA001 {
+                    match addr {
+                        0x7FFD => self.prg_bank.set(value & 1),
+                        0x7FFE => self.chr_banks[0].set(value & 0xF),
+                        0x7FFF => self.chr_banks[1].set(value & 0xF),
+                        _ => (),
+                    }
+                }
+            }
+            (Board::BNROM, 0x8000..=0xFFFF) => self.prg_bank.set(value),
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr.read(self.chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for BNROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_bank.save_state(w);
+        self.chr_banks.save_state(w);
+        self.chr.save_state(w);
+        self.prg_ram.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_bank.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.prg_ram.load_state(r)
+    }
+}
@@ -0,0 +1,179 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::{Mirroring, RomFile},
+};
+
+// CNROM - mapper 3, used by Arkanoid, Cybernoid, Gradius and plenty of other early games. It's
+// NROM with any write to $8000-$FFFF switching in an 8kb bank of CHR ROM.
+//
+// The register isn't disconnected from the data bus while the ROM drives it, so the value written
+// gets ANDed with the PRG byte at the same address (a bus conflict). Games work around that by
+// writing to a byte that already holds the value, and a few rely on it. NES 2.0 submapper 1 is a
+// board without the conflicts.
+
+pub fn from_rom(rom: RomFile) -> Result<CNROM> {
+    if !(rom.prg_rom.len() == 16384 || rom.prg_rom.len() == 16384 * 2) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 3,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let prg_ram = if rom.provide_prg_ram {
+        Some(vec![Cell::new(0); 0x2000])
+    } else {
+        None
+    };
+
+    let chr_data = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || d.len() % 8192 != 0 || d.len() > 256 * 8192 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 3,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    let mirroring = match rom.mirroring {
+        Mirroring::Horizontal => MirrorMode::Horizontal,
+        Mirroring::Vertical => MirrorMode::Vertical,
+        Mirroring::FourScreen => {
+            return Err(Error::UnsupportedMirroring {
+                mapper: 3,
+                mirroring: rom.mirroring,
+            })
+        }
+    };
+
+    Ok(CNROM {
+        mirroring,
+        prg_rom: rom.prg_rom,
+        chr_data,
+        prg_ram,
+        bus_conflicts: rom.submapper != 1,
+        chr_bank: Cell::new(0),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct CNROM {
+    mirroring: MirrorMode,
+    prg_rom: Vec<u8>,
+    chr_data: ChrMem,
+    prg_ram: Option<Vec<Cell<u8>>>,
+    bus_conflicts: bool,
+    // Registers
+    chr_bank: Cell<u8>,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl CNROM {
+    // 16kb of PRG ROM is mirrored into both halves
+    fn read_prg(&self, addr: u16) -> u8 {
+        self.prg_rom[(addr as usize - 0x8000) % self.prg_rom.len()]
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        self.chr_data
+            .bank_addr(self.chr_bank.get() as usize, 8192, addr)
+    }
+}
+
+impl CartridgeImpl for CNROM {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if let Some(ram) = &self.prg_ram {
+                    Some(ram[(addr - 0x6000) as usize].get())
+                } else {
+                    if cfg!(pedantic_af) {
+                        panic!("Bad read {:4X} (no PRG RAM)", addr);
+                    }
+                    None
+                }
+            }
+            0x8000..=0xFFFF => Some(self.read_prg(addr)),
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if let Some(ram) = &self.prg_ram {
+                    ram[(addr - 0x6000) as usize].set(value);
+                } else if cfg!(pedantic_af) {
+                    panic!("Bad write to cartridge space when no PRGRAM {:04X}", addr);
+                }
+            }
+            0x8000..=0xFFFF => {
+                let value = if self.bus_conflicts {
+                    value & self.read_prg(addr)
+                } else {
+                    value
+                };
+                self.chr_bank.set(value);
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.read(self.chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for CNROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.chr_bank.save_state(w);
+        self.chr_data.save_state(w);
+        self.prg_ram.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.chr_bank.load_state(r)?;
+        self.chr_data.load_state(r)?;
+        self.prg_ram.load_state(r)
+    }
+}
@@ -1,7 +1,14 @@
 // Common utilities for all mappers to use
 
-use std::cell::Cell;
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
 
+use crate::{
+    error::{Error, Result},
+    nes::state::{SaveState, StateReader, StateWriter},
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MirrorMode {
     OneScreenLower,
     OneScreenHigher,
@@ -9,6 +16,109 @@ pub enum MirrorMode {
     Horizontal,
 }
 
+impl SaveState for Cell<MirrorMode> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.get() as u8);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        let mode = match r.u8()? {
+            0 => MirrorMode::OneScreenLower,
+            1 => MirrorMode::OneScreenHigher,
+            2 => MirrorMode::Vertical,
+            3 => MirrorMode::Horizontal,
+            _ => return Err(Error::BadSaveState),
+        };
+        self.set(mode);
+        Ok(())
+    }
+}
+
+// CHR memory on the cartridge, which is either ROM or (when the iNES file has no CHR data) RAM.
+// Addresses are offsets into the memory, so mappers with banking should use `bank_addr` first.
+pub enum ChrMem {
+    ROM(Vec<u8>),
+    RAM(Vec<Cell<u8>>),
+}
+
+impl ChrMem {
+    pub fn new(chr_rom: Option<Vec<u8>>, ram_size: usize) -> ChrMem {
+        match chr_rom {
+            Some(r) => ChrMem::ROM(r),
+            None => ChrMem::RAM(vec![Cell::new(0); ram_size]),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ChrMem::ROM(r) => r.len(),
+            ChrMem::RAM(r) => r.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn read(&self, addr: usize) -> u8 {
+        match self {
+            ChrMem::ROM(r) => r[addr],
+            ChrMem::RAM(r) => r[addr].get(),
+        }
+    }
+
+    pub fn write(&self, addr: usize, value: u8) {
+        match self {
+            ChrMem::ROM(_) => {
+                if cfg!(pedantic_af) {
+                    panic!("Attempt to write to CHRROM")
+                }
+            }
+            ChrMem::RAM(r) => r[addr].set(value),
+        }
+    }
+
+    // Maps a PPU address into `bank` (counted in units of `bank_size`). Carts only connect as many
+    // bank lines as they have banks for, so out of range banks wrap around. The address still
+    // wraps too, for CHR smaller than a single bank.
+    pub fn bank_addr(&self, bank: usize, bank_size: usize, addr: u16) -> usize {
+        let banks = (self.len() / bank_size).max(1);
+        ((bank % banks) * bank_size + (addr as usize % bank_size)) % self.len()
+    }
+}
+
+// Only RAM needs saving, ROM can't have changed
+impl SaveState for ChrMem {
+    fn save_state(&self, w: &mut StateWriter) {
+        if let ChrMem::RAM(r) = self {
+            r.save_state(w);
+        }
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        match self {
+            ChrMem::ROM(_) => Ok(()),
+            ChrMem::RAM(ram) => ram.load_state(r),
+        }
+    }
+}
+
+// A debugging override for the nametable mirroring (see `Nes::set_mirroring`). Mappers that use
+// `get_vram_cell` keep one of these and go through `vram_cell` instead, so the forced mode wins
+// over their own whenever it's set. It's not part of the cartridge's state, so isn't saved.
+#[derive(Default)]
+pub struct ForcedMirroring(Cell<Option<MirrorMode>>);
+
+impl ForcedMirroring {
+    pub fn set(&self, mode: Option<MirrorMode>) {
+        self.0.set(mode);
+    }
+
+    pub fn vram_cell<'a>(&self, mode: MirrorMode, vram: &'a [Cell<u8>], addr: u16) -> &'a Cell<u8> {
+        get_vram_cell(&self.0.get().unwrap_or(mode), vram, addr)
+    }
+}
+
 pub fn get_vram_cell<'a>(
     mirror_mode: &MirrorMode,
     vram: &'a [Cell<u8>],
@@ -38,3 +148,16 @@ pub fn get_vram_cell<'a>(
 
     &vram[base + offset]
 }
+
+// A switchable 16kb PRG bank at $8000-$BFFF with the last bank fixed at $C000-$FFFF, as used by
+// UxROM and its clones
+pub fn read_prg_16k_fixed_last(prg_rom: &[u8], bank: u8, addr: u16) -> u8 {
+    let bank = match addr {
+        0x8000..=0xBFFF => bank as usize,
+        0xC000..=0xFFFF => prg_rom.len() / 16384 - 1,
+        _ => panic!("Not in PRG ROM range"),
+    };
+
+    let addr = (bank * 16384 + (addr as usize & 0x3FFF)) % prg_rom.len();
+    prg_rom[addr]
+}
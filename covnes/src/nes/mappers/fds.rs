@@ -0,0 +1,466 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    fds::FdsImage,
+    nes::mappers::{
+        common::{ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+};
+
+// The Famicom Disk System's RAM adapter, which plugs into the cartridge slot with the BIOS, 32kb
+// of PRG RAM, 8kb of CHR RAM, the disk drive's registers and a timer IRQ.
+//
+// The drive is emulated a byte at a time, rather than as a stream of bits: with the motor on the
+// head moves from the start of the disk to the end, one byte every `BYTE_CYCLES`, and then goes
+// back to the start once the motor is turned off. The game (well, the BIOS) reads each byte from
+// $4031 as it comes under the head.
+//
+// Disks are read only. The drive reports them as write protected, so games that save get the
+// BIOS's error for that instead of losing their save. The CRC after each block is never
+// checked, and the expansion audio isn't there yet.
+
+// How long the head takes to get back to the start of the disk, and between each byte after that
+const REWIND_CYCLES: u16 = 50000;
+const BYTE_CYCLES: u16 = 149;
+
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr_ram: Vec<Cell<u8>>,
+    // Each side as the head reads it, see `FdsImage::disk`
+    disks: Vec<Vec<u8>>,
+    // Which side is in the drive, and which will go in next
+    inserted: Cell<Option<u8>>,
+    selected: Cell<u8>,
+
+    // $4023: disk registers (and the timer) enabled
+    disk_io_enabled: Cell<bool>,
+
+    // The timer IRQ counts down every CPU cycle, firing when it gets past 0
+    timer_reload: Cell<u16>,
+    timer_counter: Cell<u16>,
+    timer_repeat: Cell<bool>,
+    timer_enabled: Cell<bool>,
+    timer_irq: Cell<bool>,
+
+    // $4025
+    motor_on: Cell<bool>,
+    transfer_reset: Cell<bool>,
+    read_mode: Cell<bool>,
+    horizontal_mirroring: Cell<bool>,
+    // Looking for the start of a block, rather than in the gap before one
+    block_started: Cell<bool>,
+    transfer_irq_enabled: Cell<bool>,
+
+    // The drive
+    head: Cell<usize>,
+    head_delay: Cell<u16>,
+    at_end: Cell<bool>,
+    scanning: Cell<bool>,
+    // Past the $80 at the end of the gap, so bytes are being handed over
+    gap_ended: Cell<bool>,
+    read_data: Cell<u8>,
+    byte_transferred: Cell<bool>,
+    transfer_irq: Cell<bool>,
+
+    forced_mirroring: ForcedMirroring,
+}
+
+impl Fds {
+    // The first side starts off in the drive
+    pub fn new(bios: Vec<u8>, image: &FdsImage) -> Result<Fds> {
+        if bios.len() != 8192 {
+            return Err(Error::BadFdsBiosSize(bios.len()));
+        }
+
+        Ok(Fds {
+            bios,
+            prg_ram: vec![Cell::new(0); 0x8000],
+            chr_ram: vec![Cell::new(0); 0x2000],
+            disks: (0..image.sides.len()).map(|s| image.disk(s)).collect(),
+            inserted: Cell::new(Some(0)),
+            selected: Cell::new(0),
+            disk_io_enabled: Cell::new(false),
+            timer_reload: Cell::new(0),
+            timer_counter: Cell::new(0),
+            timer_repeat: Cell::new(false),
+            timer_enabled: Cell::new(false),
+            timer_irq: Cell::new(false),
+            motor_on: Cell::new(false),
+            transfer_reset: Cell::new(false),
+            read_mode: Cell::new(false),
+            horizontal_mirroring: Cell::new(false),
+            block_started: Cell::new(false),
+            transfer_irq_enabled: Cell::new(false),
+            head: Cell::new(0),
+            head_delay: Cell::new(0),
+            at_end: Cell::new(true),
+            scanning: Cell::new(false),
+            gap_ended: Cell::new(false),
+            read_data: Cell::new(0),
+            byte_transferred: Cell::new(false),
+            transfer_irq: Cell::new(false),
+            forced_mirroring: ForcedMirroring::default(),
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.disks.len()
+    }
+
+    // The side in the drive, if there is one
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.inserted.get().map(|s| s as usize)
+    }
+
+    // The side that goes in next time a disk is inserted
+    pub fn selected_side(&self) -> usize {
+        self.selected.get() as usize
+    }
+
+    // Ejects the disk, or puts the selected side in if the drive is empty. This is FM2's
+    // `FDS_DISK_INSERT`.
+    pub fn insert_or_eject(&self) {
+        match self.inserted.get() {
+            Some(_) => self.inserted.set(None),
+            None => self.inserted.set(Some(self.selected.get())),
+        }
+    }
+
+    // Selects the next side (going back to the first after the last) for the next insert. It
+    // only works with the drive empty, as the disk has to come out to be turned over. This is
+    // FM2's `FDS_DISK_SELECT`.
+    pub fn select_next_side(&self) {
+        if self.inserted.get().is_none() {
+            self.selected
+                .set((self.selected.get() + 1) % self.disks.len() as u8);
+        }
+    }
+
+    fn mirroring(&self) -> MirrorMode {
+        if self.horizontal_mirroring.get() {
+            MirrorMode::Horizontal
+        } else {
+            MirrorMode::Vertical
+        }
+    }
+
+    fn tick_timer(&self) {
+        if !self.timer_enabled.get() {
+            return;
+        }
+
+        match self.timer_counter.get() {
+            0 => {
+                self.timer_irq.set(true);
+                self.timer_counter.set(self.timer_reload.get());
+                if !self.timer_repeat.get() {
+                    self.timer_enabled.set(false);
+                }
+            }
+            n => self.timer_counter.set(n - 1),
+        }
+    }
+
+    fn tick_drive(&self) {
+        let disk = match self.inserted.get() {
+            Some(side) if self.motor_on.get() => &self.disks[side as usize],
+            _ => {
+                self.at_end.set(true);
+                self.scanning.set(false);
+                return;
+            }
+        };
+        if self.transfer_reset.get() && !self.scanning.get() {
+            return;
+        }
+        if self.at_end.get() {
+            self.head_delay.set(REWIND_CYCLES);
+            self.at_end.set(false);
+            self.head.set(0);
+            self.gap_ended.set(false);
+            return;
+        }
+        if self.head_delay.get() > 0 {
+            self.head_delay.set(self.head_delay.get() - 1);
+            return;
+        }
+
+        self.scanning.set(true);
+        if self.read_mode.get() {
+            let data = disk[self.head.get()];
+            // The $80 that ends a gap is handed over, but without an IRQ, so the BIOS's IRQ
+            // handler only sees the block itself
+            let mut irq = self.transfer_irq_enabled.get();
+            if !self.block_started.get() {
+                self.gap_ended.set(false);
+            } else if data != 0 && !self.gap_ended.get() {
+                self.gap_ended.set(true);
+                irq = false;
+            }
+
+            if self.gap_ended.get() {
+                self.byte_transferred.set(true);
+                self.read_data.set(data);
+                if irq {
+                    self.transfer_irq.set(true);
+                }
+            }
+        }
+
+        self.head.set(self.head.get() + 1);
+        if self.head.get() >= disk.len() {
+            // Off the end of the disk, which stops the motor until it's turned on again
+            self.motor_on.set(false);
+            self.at_end.set(true);
+        } else {
+            self.head_delay.set(BYTE_CYCLES);
+        }
+    }
+
+    // Reading the status or the data acknowledges the IRQs they're for
+    fn read_register(&self, addr: u16) -> Option<u8> {
+        let value = self.peek_register(addr);
+        if value.is_some() {
+            match addr {
+                0x4030 => {
+                    self.timer_irq.set(false);
+                    self.transfer_irq.set(false);
+                    self.byte_transferred.set(false);
+                }
+                0x4031 => {
+                    self.byte_transferred.set(false);
+                    self.transfer_irq.set(false);
+                }
+                _ => (),
+            }
+        }
+        value
+    }
+
+    fn peek_register(&self, addr: u16) -> Option<u8> {
+        if !self.disk_io_enabled.get() {
+            return None;
+        }
+
+        match addr {
+            0x4030 => {
+                let mut status = 0;
+                if self.timer_irq.get() {
+                    status |= 0x01;
+                }
+                if self.byte_transferred.get() {
+                    status |= 0x02;
+                }
+                if self.at_end.get() {
+                    status |= 0x40;
+                }
+                Some(status)
+            }
+            0x4031 => Some(self.read_data.get()),
+            0x4032 => {
+                let inserted = self.inserted.get().is_some();
+                let mut status = 0x04; // Write protected
+                if !inserted {
+                    status |= 0x01;
+                }
+                if !inserted || !self.scanning.get() {
+                    status |= 0x02;
+                }
+                Some(status)
+            }
+            // Only the battery is connected to the expansion port, and it's always good
+            0x4033 => Some(0x80),
+            _ => None,
+        }
+    }
+
+    fn write_register(&self, addr: u16, value: u8) {
+        match addr {
+            0x4020 => self
+                .timer_reload
+                .set(self.timer_reload.get() & 0xFF00 | value as u16),
+            0x4021 => self
+                .timer_reload
+                .set(self.timer_reload.get() & 0x00FF | (value as u16) << 8),
+            0x4022 if self.disk_io_enabled.get() => {
+                self.timer_repeat.set(value & 1 == 1);
+                self.timer_enabled.set(value & 2 == 2);
+                if self.timer_enabled.get() {
+                    self.timer_counter.set(self.timer_reload.get());
+                } else {
+                    self.timer_irq.set(false);
+                }
+            }
+            0x4023 => {
+                // Bit 1 is for the expansion audio
+                self.disk_io_enabled.set(value & 1 == 1);
+                if !self.disk_io_enabled.get() {
+                    self.timer_enabled.set(false);
+                    self.timer_irq.set(false);
+                    self.transfer_irq.set(false);
+                }
+            }
+            // Writing isn't supported
+            0x4024 if self.disk_io_enabled.get() => {
+                self.byte_transferred.set(false);
+                self.transfer_irq.set(false);
+            }
+            0x4025 if self.disk_io_enabled.get() => {
+                self.motor_on.set(value & 0x01 == 0x01);
+                self.transfer_reset.set(value & 0x02 == 0x02);
+                self.read_mode.set(value & 0x04 == 0x04);
+                self.horizontal_mirroring.set(value & 0x08 == 0x08);
+                // Bit 4 is for the CRC, and bit 5 is unused
+                self.block_started.set(value & 0x40 == 0x40);
+                self.transfer_irq_enabled.set(value & 0x80 == 0x80);
+                self.transfer_irq.set(false);
+            }
+            _ => (),
+        }
+    }
+}
+
+impl CartridgeImpl for Fds {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4020..=0x40FF => self.read_register(addr),
+            _ => self.peek_cpu(addr),
+        }
+    }
+
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4020..=0x40FF => self.peek_register(addr),
+            0x6000..=0xDFFF => Some(self.prg_ram[(addr - 0x6000) as usize].get()),
+            0xE000..=0xFFFF => Some(self.bios[(addr - 0xE000) as usize]),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x4020..=0x40FF => self.write_register(addr, value),
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize].set(value),
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr_ram[addr as usize].get(),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr_ram[addr as usize].set(value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn cpu_tick(&self) {
+        self.tick_timer();
+        self.tick_drive();
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq.get() || self.transfer_irq.get()
+    }
+
+    fn missing_features(&self) -> &'static [&'static str] {
+        &["expansion audio", "writing to disks"]
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+
+    fn as_fds(&self) -> Option<&Fds> {
+        Some(self)
+    }
+}
+
+impl SaveState for Fds {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr_ram.save_state(w);
+        // Every state is the same size, so the side is always written
+        w.bool(self.inserted.get().is_some());
+        w.u8(self.inserted.get().unwrap_or(0));
+        self.selected.save_state(w);
+        self.disk_io_enabled.save_state(w);
+        self.timer_reload.save_state(w);
+        self.timer_counter.save_state(w);
+        self.timer_repeat.save_state(w);
+        self.timer_enabled.save_state(w);
+        self.timer_irq.save_state(w);
+        self.motor_on.save_state(w);
+        self.transfer_reset.save_state(w);
+        self.read_mode.save_state(w);
+        self.horizontal_mirroring.save_state(w);
+        self.block_started.save_state(w);
+        self.transfer_irq_enabled.save_state(w);
+        w.u64(self.head.get() as u64);
+        self.head_delay.save_state(w);
+        self.at_end.save_state(w);
+        self.scanning.save_state(w);
+        self.gap_ended.save_state(w);
+        self.read_data.save_state(w);
+        self.byte_transferred.save_state(w);
+        self.transfer_irq.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr_ram.load_state(r)?;
+        let inserted = r.bool()?;
+        let side = r.u8()?;
+        self.inserted.set(if inserted { Some(side) } else { None });
+        self.selected.load_state(r)?;
+        let sides = self.disks.len() as u8;
+        if side >= sides || self.selected.get() >= sides {
+            return Err(Error::BadSaveState);
+        }
+        self.disk_io_enabled.load_state(r)?;
+        self.timer_reload.load_state(r)?;
+        self.timer_counter.load_state(r)?;
+        self.timer_repeat.load_state(r)?;
+        self.timer_enabled.load_state(r)?;
+        self.timer_irq.load_state(r)?;
+        self.motor_on.load_state(r)?;
+        self.transfer_reset.load_state(r)?;
+        self.read_mode.load_state(r)?;
+        self.horizontal_mirroring.load_state(r)?;
+        self.block_started.load_state(r)?;
+        self.transfer_irq_enabled.load_state(r)?;
+        self.head.set(r.u64()? as usize);
+        self.head_delay.load_state(r)?;
+        self.at_end.load_state(r)?;
+        self.scanning.load_state(r)?;
+        self.gap_ended.load_state(r)?;
+        self.read_data.load_state(r)?;
+        self.byte_transferred.load_state(r)?;
+        self.transfer_irq.load_state(r)?;
+
+        // The head is only used when it's somewhere on the disk in the drive
+        let head_off_disk = self.head.get() >= self.disks[side as usize].len();
+        if inserted && !self.at_end.get() && head_off_disk {
+            return Err(Error::BadSaveState);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::{Mirroring, RomFile},
+};
+
+// GxROM - mapper 66, used by Dragon Power and the Super Mario Bros. / Duck Hunt multicart. A
+// single write to $8000-$FFFF switches 32kb of PRG with the high nibble and 8kb of CHR with the
+// low one, and the mirroring's fixed by the board.
+
+pub fn from_rom(rom: RomFile) -> Result<GxROM> {
+    let prg_banks = rom.prg_rom.len() / 32768;
+    if !rom.prg_rom.len().is_multiple_of(32768) || !(1..=16).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 66,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr_data = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || !d.len().is_multiple_of(8192) || d.len() > 16 * 8192 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 66,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    let mirroring = match rom.mirroring {
+        Mirroring::Horizontal => MirrorMode::Horizontal,
+        Mirroring::Vertical => MirrorMode::Vertical,
+        Mirroring::FourScreen => {
+            return Err(Error::UnsupportedMirroring {
+                mapper: 66,
+                mirroring: rom.mirroring,
+            })
+        }
+    };
+
+    Ok(GxROM {
+        mirroring,
+        prg_rom: rom.prg_rom,
+        chr_data,
+        bank: Cell::new(0),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct GxROM {
+    mirroring: MirrorMode,
+    prg_rom: Vec<u8>,
+    chr_data: ChrMem,
+    // Registers
+    bank: Cell<u8>,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl GxROM {
+    fn chr_addr(&self, addr: u16) -> usize {
+        self.chr_data
+            .bank_addr(self.bank.get() as usize & 0xF, 8192, addr)
+    }
+}
+
+impl CartridgeImpl for GxROM {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let bank = self.bank.get() as usize >> 4;
+                let addr = bank * 32768 + (addr as usize & 0x7FFF);
+                Some(self.prg_rom[addr % self.prg_rom.len()])
+            }
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        if addr >= 0x8000 {
+            self.bank.set(value);
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.read(self.chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr_data.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for GxROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.bank.save_state(w);
+        self.chr_data.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.bank.load_state(r)?;
+        self.chr_data.load_state(r)
+    }
+}
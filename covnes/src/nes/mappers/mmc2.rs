@@ -0,0 +1,199 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Nintendo MMC2 (PxROM) - mapper 9, only used by Punch-Out!!
+//
+// Each half of the pattern table has two CHR banks registers, and which one is used is picked by
+// a latch that flips when the PPU fetches particular tiles. The game uses this to switch banks
+// part way through a scanline without any IRQs.
+
+pub fn from_rom(rom: RomFile) -> Result<MMC2> {
+    let prg_banks = rom.prg_rom.len() / 8192;
+    if !rom.prg_rom.len().is_multiple_of(8192) || !(4..=16).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 9,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || !d.len().is_multiple_of(4096) || d.len() > 128 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 9,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(MMC2 {
+        prg_rom: rom.prg_rom,
+        chr,
+        prg_bank: Cell::new(0),
+        chr_banks: Default::default(),
+        latches: [Cell::new(Latch::FE), Cell::new(Latch::FE)],
+        mirroring: Cell::new(MirrorMode::Vertical),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Latch {
+    FD,
+    FE,
+}
+
+pub struct MMC2 {
+    prg_rom: Vec<u8>,
+    chr: ChrMem,
+    // Registers
+    prg_bank: Cell<u8>,
+    // $0000 FD, $0000 FE, $1000 FD, $1000 FE
+    chr_banks: [Cell<u8>; 4],
+    latches: [Cell<Latch>; 2],
+    mirroring: Cell<MirrorMode>,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl MMC2 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let banks = self.prg_rom.len() / 8192;
+        let bank = match addr {
+            0x8000..=0x9FFF => self.prg_bank.get() as usize,
+            0xA000..=0xBFFF => banks - 3,
+            0xC000..=0xDFFF => banks - 2,
+            0xE000..=0xFFFF => banks - 1,
+            _ => panic!("Not in PRG ROM range"),
+        };
+
+        self.prg_rom[(bank * 8192 + (addr as usize & 0x1FFF)) % self.prg_rom.len()]
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let half = (addr >> 12) as usize & 1;
+        let reg = match self.latches[half].get() {
+            Latch::FD => half * 2,
+            Latch::FE => half * 2 + 1,
+        };
+
+        self.chr
+            .bank_addr(self.chr_banks[reg].get() as usize, 4096, addr)
+    }
+
+    // The latches are updated after the fetched byte has been read, so the tile that triggers a
+    // switch is still drawn from the old bank
+    fn update_latches(&self, addr: u16) {
+        match addr {
+            0x0FD8 => self.latches[0].set(Latch::FD),
+            0x0FE8 => self.latches[0].set(Latch::FE),
+            0x1FD8..=0x1FDF => self.latches[1].set(Latch::FD),
+            0x1FE8..=0x1FEF => self.latches[1].set(Latch::FE),
+            _ => (),
+        }
+    }
+}
+
+impl CartridgeImpl for MMC2 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.read_prg(addr)),
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0xA000..=0xAFFF => self.prg_bank.set(value & 0xF),
+            0xB000..=0xBFFF => self.chr_banks[0].set(value & 0x1F),
+            0xC000..=0xCFFF => self.chr_banks[1].set(value & 0x1F),
+            0xD000..=0xDFFF => self.chr_banks[2].set(value & 0x1F),
+            0xE000..=0xEFFF => self.chr_banks[3].set(value & 0x1F),
+            0xF000..=0xFFFF => {
+                let mirroring = if value & 1 == 1 {
+                    MirrorMode::Horizontal
+                } else {
+                    MirrorMode::Vertical
+                };
+                self.mirroring.set(mirroring);
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => {
+                let value = self.chr.read(self.chr_addr(addr));
+                self.update_latches(addr);
+                value
+            }
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for MMC2 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.chr.save_state(w);
+        self.prg_bank.save_state(w);
+        self.chr_banks.save_state(w);
+        for latch in &self.latches {
+            w.u8(latch.get() as u8);
+        }
+        self.mirroring.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.chr.load_state(r)?;
+        self.prg_bank.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        for latch in &self.latches {
+            let value = match r.u8()? {
+                0 => Latch::FD,
+                1 => Latch::FE,
+                _ => return Err(Error::BadSaveState),
+            };
+            latch.set(value);
+        }
+        self.mirroring.load_state(r)
+    }
+}
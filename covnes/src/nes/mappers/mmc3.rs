@@ -0,0 +1,303 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Nintendo MMC3 (TxROM) - mapper 4, used by Super Mario Bros. 3, Kirby's Adventure and hundreds
+// more
+//
+// Two switchable 8kb PRG banks and six CHR banks (two of 2kb and four of 1kb), with either half of
+// each able to swap places, switchable mirroring, 8kb of PRG RAM with write protection, and a
+// scanline counter.
+//
+// The counter is clocked by rising edges of the PPU's A12 address line, which (with the
+// background and sprites using different pattern tables) happens once a scanline. A12 also
+// toggles between the tiles of a single fetch group, so like the real chip edges only count after
+// A12's been low for a few CPU cycles. The PPU's address is only seen when it reads or writes,
+// not when $2006 sets it, which a few games use to clock the counter by hand.
+//
+// There are two revisions of the counter, which behave differently when it's reloaded with 0.
+// NES 2.0 submapper 4 picks the older one, MMC3A, and everything else gets the more common MMC3B.
+
+// How many CPU cycles A12 has to be low before a rising edge counts
+const A12_LOW_CYCLES: u8 = 3;
+
+pub fn from_rom(rom: RomFile) -> Result<MMC3> {
+    // The last two banks are fixed, so it needs at least them
+    let prg_banks = rom.prg_rom.len() / 8192;
+    if !rom.prg_rom.len().is_multiple_of(8192) || !(2..=64).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 4,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || !d.len().is_multiple_of(1024) || d.len() > 256 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 4,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    let revision = if rom.submapper == 4 {
+        IrqRevision::A
+    } else {
+        IrqRevision::B
+    };
+
+    Ok(MMC3 {
+        prg_rom: rom.prg_rom,
+        // Plenty of boards have PRG RAM without a battery, which iNES headers often leave out
+        prg_ram: vec![Cell::new(0); 8192],
+        chr,
+        revision,
+        bank_select: Cell::new(0),
+        banks: Default::default(),
+        mirroring: Cell::new(MirrorMode::Vertical),
+        // Enabled and writable, for games that never touch $A001
+        prg_ram_protect: Cell::new(0x80),
+        irq_latch: Cell::new(0),
+        irq_counter: Cell::new(0),
+        irq_reload: Cell::new(false),
+        irq_enabled: Cell::new(false),
+        irq_pending: Cell::new(false),
+        a12_high: Cell::new(false),
+        a12_low_cycles: Cell::new(0),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum IrqRevision {
+    // Only raises an IRQ when the counter gets to 0 from something else, or from a $C001 reload
+    A,
+    // Raises an IRQ whenever it's clocked and ends up at 0, so every scanline with a latch of 0
+    B,
+}
+
+pub struct MMC3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr: ChrMem,
+    revision: IrqRevision,
+    // Registers
+    // $8000: which of `banks` $8001 writes, and the PRG and CHR modes in bits 6 and 7
+    bank_select: Cell<u8>,
+    // R0-R5 for CHR, R6 and R7 for PRG
+    banks: [Cell<u8>; 8],
+    mirroring: Cell<MirrorMode>,
+    // $A001: bit 7 enables PRG RAM, and bit 6 stops writes to it
+    prg_ram_protect: Cell<u8>,
+    irq_latch: Cell<u8>,
+    irq_counter: Cell<u8>,
+    irq_reload: Cell<bool>,
+    irq_enabled: Cell<bool>,
+    irq_pending: Cell<bool>,
+    // Following along with the PPU
+    a12_high: Cell<bool>,
+    a12_low_cycles: Cell<u8>,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl MMC3 {
+    fn read_prg(&self, addr: u16) -> u8 {
+        let banks = self.prg_rom.len() / 8192;
+        let swapped = self.bank_select.get() & 0x40 != 0;
+        let bank = match (addr, swapped) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.banks[6].get() as usize,
+            (0xA000..=0xBFFF, _) => self.banks[7].get() as usize,
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => banks - 2,
+            (0xE000..=0xFFFF, _) => banks - 1,
+            _ => panic!("Not in PRG ROM range"),
+        };
+
+        self.prg_rom[(bank % banks) * 8192 + (addr as usize & 0x1FFF)]
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        // Bit 7 swaps the 2kb banks over to $1000-$1FFF and the 1kb ones to $0000-$0FFF
+        let addr = if self.bank_select.get() & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let bank = match addr {
+            0x0000..=0x07FF => (self.banks[0].get() & !1) | ((addr >> 10) as u8 & 1),
+            0x0800..=0x0FFF => (self.banks[1].get() & !1) | ((addr >> 10) as u8 & 1),
+            0x1000..=0x13FF => self.banks[2].get(),
+            0x1400..=0x17FF => self.banks[3].get(),
+            0x1800..=0x1BFF => self.banks[4].get(),
+            0x1C00..=0x1FFF => self.banks[5].get(),
+            _ => panic!("Not in CHR range"),
+        };
+
+        self.chr.bank_addr(bank as usize, 1024, addr)
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect.get() & 0x80 != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_protect.get() & 0x40 == 0
+    }
+
+    fn clock_irq_counter(&self) {
+        let before = self.irq_counter.get();
+        let reload = self.irq_reload.get();
+        if before == 0 || reload {
+            self.irq_counter.set(self.irq_latch.get());
+            self.irq_reload.set(false);
+        } else {
+            self.irq_counter.set(before - 1);
+        }
+
+        let fire = match self.revision {
+            IrqRevision::A => before != 0 || reload,
+            IrqRevision::B => true,
+        };
+        if fire && self.irq_counter.get() == 0 && self.irq_enabled.get() {
+            self.irq_pending.set(true);
+        }
+    }
+}
+
+impl CartridgeImpl for MMC3 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                Some(self.prg_ram[addr as usize & 0x1FFF].get())
+            }
+            0x8000..=0xFFFF => Some(self.read_prg(addr)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        let even = addr & 1 == 0;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_writable() => {
+                self.prg_ram[addr as usize & 0x1FFF].set(value)
+            }
+            0x8000..=0x9FFF if even => self.bank_select.set(value),
+            0x8000..=0x9FFF => self.banks[self.bank_select.get() as usize & 7].set(value),
+            0xA000..=0xBFFF if even => {
+                let mirroring = if value & 1 == 1 {
+                    MirrorMode::Horizontal
+                } else {
+                    MirrorMode::Vertical
+                };
+                self.mirroring.set(mirroring);
+            }
+            0xA000..=0xBFFF => self.prg_ram_protect.set(value & 0xC0),
+            0xC000..=0xDFFF if even => self.irq_latch.set(value),
+            0xC000..=0xDFFF => {
+                self.irq_counter.set(0);
+                self.irq_reload.set(true);
+            }
+            0xE000..=0xFFFF if even => {
+                self.irq_enabled.set(false);
+                self.irq_pending.set(false);
+            }
+            0xE000..=0xFFFF => self.irq_enabled.set(true),
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr.read(self.chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring.get(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn notify_ppu_addr(&self, addr: u16) {
+        let high = addr & 0x1000 != 0;
+        if high && !self.a12_high.get() && self.a12_low_cycles.get() >= A12_LOW_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !high && self.a12_high.get() {
+            self.a12_low_cycles.set(0);
+        }
+        self.a12_high.set(high);
+    }
+
+    fn cpu_tick(&self) {
+        if !self.a12_high.get() {
+            self.a12_low_cycles
+                .set(self.a12_low_cycles.get().saturating_add(1));
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending.get()
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for MMC3 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr.save_state(w);
+        self.bank_select.save_state(w);
+        self.banks.save_state(w);
+        self.mirroring.save_state(w);
+        self.prg_ram_protect.save_state(w);
+        self.irq_latch.save_state(w);
+        self.irq_counter.save_state(w);
+        self.irq_reload.save_state(w);
+        self.irq_enabled.save_state(w);
+        self.irq_pending.save_state(w);
+        self.a12_high.save_state(w);
+        self.a12_low_cycles.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.bank_select.load_state(r)?;
+        self.banks.load_state(r)?;
+        self.mirroring.load_state(r)?;
+        self.prg_ram_protect.load_state(r)?;
+        self.irq_latch.load_state(r)?;
+        self.irq_counter.load_state(r)?;
+        self.irq_reload.load_state(r)?;
+        self.irq_enabled.load_state(r)?;
+        self.irq_pending.load_state(r)?;
+        self.a12_high.load_state(r)?;
+        self.a12_low_cycles.load_state(r)
+    }
+}
@@ -0,0 +1,464 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{common::ChrMem, CartridgeImpl},
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Nintendo MMC5 (ExROM) - mapper 5, used by Castlevania III and the later Koei games
+//
+// Emulated: all four PRG and CHR banking modes, PRG RAM and its write protection, the 1kb of
+// ExRAM in all of its modes (including extended attributes), nametable mapping with fill mode,
+// the scanline IRQ and the multiplier.
+//
+// Not emulated yet: the vertical split ($5200-$5202 are ignored) and the expansion audio (the two
+// pulse channels and the PCM channel, $5000-$5015).
+//
+// The MMC5 can't see what the PPU is doing, so it works it out from the PPU's reads. Three reads
+// in a row from the same nametable address are the dummy fetches at the end of a scanline, so
+// they mark the start of the next one. From there, counting fetches tells background and sprite
+// fetches apart, which is needed to pick CHR banks for 8x16 sprites and extended attributes. This
+// relies on the PPU doing exactly 2 reads per sprite between the background fetches, which ours
+// does.
+
+// Fetch numbers within a scanline, counted from the first nametable fetch (at dot 2)
+const SPRITE_FETCHES: core::ops::Range<u8> = 128..144;
+// How many CPU cycles without a PPU read it takes to decide the PPU has stopped rendering
+const IDLE_CYCLES: u8 = 3;
+
+pub fn from_rom(rom: RomFile) -> Result<MMC5> {
+    let prg_banks = rom.prg_rom.len() / 8192;
+    if !rom.prg_rom.len().is_multiple_of(8192) || !(1..=128).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 5,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if d.is_empty() || !d.len().is_multiple_of(1024) || d.len() > 1024 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: 5,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(MMC5 {
+        prg_rom: rom.prg_rom,
+        // The most any board has, which iNES headers can't describe anyway
+        prg_ram: vec![Cell::new(0); 64 * 1024],
+        chr,
+        exram: vec![Cell::new(0); 1024],
+        prg_mode: Cell::new(3),
+        chr_mode: Cell::new(0),
+        prg_ram_protect: Default::default(),
+        exram_mode: Cell::new(0),
+        nametables: Cell::new(0),
+        fill_tile: Cell::new(0),
+        fill_attribute: Cell::new(0),
+        prg_banks: [
+            Cell::new(0),
+            Cell::new(0),
+            Cell::new(0),
+            Cell::new(0),
+            Cell::new(0xFF),
+        ],
+        chr_banks: Default::default(),
+        chr_upper: Cell::new(0),
+        last_chr_set_b: Cell::new(false),
+        sprites_8x16: Cell::new(false),
+        irq_compare: Cell::new(0),
+        irq_enabled: Cell::new(false),
+        irq_pending: Cell::new(false),
+        in_frame: Cell::new(false),
+        scanline: Cell::new(0),
+        multiplicands: Default::default(),
+        last_ppu_addr: Cell::new(0),
+        same_addr_reads: Cell::new(0),
+        fetch: Cell::new(0),
+        idle_cycles: Cell::new(0),
+        ex_attribute: Cell::new(0),
+    })
+}
+
+pub struct MMC5 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr: ChrMem,
+    exram: Vec<Cell<u8>>,
+    // Registers
+    prg_mode: Cell<u8>,
+    chr_mode: Cell<u8>,
+    // $5102 and $5103, which have to be 2 and 1 for PRG RAM to be writable
+    prg_ram_protect: [Cell<u8>; 2],
+    exram_mode: Cell<u8>,
+    nametables: Cell<u8>,
+    fill_tile: Cell<u8>,
+    fill_attribute: Cell<u8>,
+    // $5113-$5117. Bit 7 picks ROM over RAM, apart from $5113 which is always RAM and $5117
+    // which is always ROM.
+    prg_banks: [Cell<u8>; 5],
+    // $5120-$5127 (set A) then $5128-$512B (set B), with the bits from $5130 above them
+    chr_banks: [Cell<u16>; 12],
+    chr_upper: Cell<u8>,
+    last_chr_set_b: Cell<bool>,
+    // Snooped from PPUCTRL
+    sprites_8x16: Cell<bool>,
+    irq_compare: Cell<u8>,
+    irq_enabled: Cell<bool>,
+    irq_pending: Cell<bool>,
+    in_frame: Cell<bool>,
+    scanline: Cell<u8>,
+    multiplicands: [Cell<u8>; 2],
+    // Following along with the PPU
+    last_ppu_addr: Cell<u16>,
+    same_addr_reads: Cell<u8>,
+    fetch: Cell<u8>,
+    idle_cycles: Cell<u8>,
+    // The ExRAM byte for the background tile being fetched, in extended attribute mode
+    ex_attribute: Cell<u8>,
+}
+
+impl MMC5 {
+    // Which 8kb bank is mapped at `addr` ($6000-$FFFF), and whether it's ROM
+    fn prg_bank(&self, addr: u16) -> (bool, usize) {
+        if addr < 0x8000 {
+            return (false, self.prg_banks[0].get() as usize & 7);
+        }
+
+        let slot = (addr as usize - 0x8000) / 8192;
+        let (reg, size) = match (self.prg_mode.get(), slot) {
+            (0, _) => (4, 4),
+            (1, 0..=1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0..=1) => (2, 2),
+            (2, _) => (slot + 1, 1),
+            _ => (slot + 1, 1),
+        };
+        let value = self.prg_banks[reg].get();
+        let rom = reg == 4 || value & 0x80 != 0;
+        // Big banks ignore their low bits, and take the slot within them from the address
+        let bank = (value as usize & 0x7F & !(size - 1)) + slot % size;
+        if rom {
+            (true, bank)
+        } else {
+            (false, bank & 7)
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0].get() == 2 && self.prg_ram_protect[1].get() == 1
+    }
+
+    fn is_sprite_fetch(&self) -> bool {
+        self.in_frame.get() && SPRITE_FETCHES.contains(&self.fetch.get())
+    }
+
+    // The byte of CHR memory for a pattern table fetch from `addr`
+    fn chr_addr(&self, addr: u16) -> usize {
+        if self.exram_mode.get() == 1 && self.in_frame.get() && !self.is_sprite_fetch() {
+            let bank =
+                (self.ex_attribute.get() as usize & 0x3F) | (self.chr_upper.get() as usize) << 6;
+            return self.chr.bank_addr(bank, 4096, addr);
+        }
+
+        let set_b = if self.sprites_8x16.get() && self.in_frame.get() {
+            !self.is_sprite_fetch()
+        } else {
+            self.last_chr_set_b.get()
+        };
+
+        // Banks are 8kb in mode 0, down to 1kb in mode 3. Each bank uses the last register of
+        // the ones it covers, and set B only has 4 registers so it repeats for both halves.
+        let slots = 8 >> self.chr_mode.get();
+        let slot = addr as usize / 1024;
+        let reg = slot / slots * slots + slots - 1;
+        let bank = if set_b {
+            self.chr_banks[8 + reg % 4].get()
+        } else {
+            self.chr_banks[reg].get()
+        };
+        self.chr.bank_addr(bank as usize, slots * 1024, addr)
+    }
+
+    fn nametable_read(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        let offset = addr as usize & 0x3FF;
+        match (self.nametables.get() >> (((addr >> 10) & 3) << 1)) & 3 {
+            0 => vram[offset].get(),
+            1 => vram[0x400 + offset].get(),
+            2 if self.exram_mode.get() <= 1 => self.exram[offset].get(),
+            2 => 0,
+            _ if offset >= 0x3C0 => self.fill_attribute.get() * 0x55,
+            _ => self.fill_tile.get(),
+        }
+    }
+
+    fn nametable_write(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        let offset = addr as usize & 0x3FF;
+        match (self.nametables.get() >> (((addr >> 10) & 3) << 1)) & 3 {
+            0 => vram[offset].set(value),
+            1 => vram[0x400 + offset].set(value),
+            2 if self.exram_mode.get() <= 1 => self.exram[offset].set(value),
+            _ => (),
+        }
+    }
+
+    // Keeps track of where the PPU is, from the address of each read it does
+    fn follow_ppu(&self, addr: u16) {
+        self.idle_cycles.set(0);
+
+        if addr == self.last_ppu_addr.get() && (0x2000..=0x2FFF).contains(&addr) {
+            self.same_addr_reads.set(self.same_addr_reads.get() + 1);
+            if self.same_addr_reads.get() == 2 {
+                self.start_scanline();
+                return;
+            }
+        } else {
+            self.same_addr_reads.set(0);
+        }
+        self.last_ppu_addr.set(addr);
+        self.fetch.set(self.fetch.get().saturating_add(1));
+    }
+
+    fn start_scanline(&self) {
+        if self.in_frame.get() {
+            let scanline = self.scanline.get().wrapping_add(1);
+            self.scanline.set(scanline);
+            if scanline == self.irq_compare.get() && scanline != 0 {
+                self.irq_pending.set(true);
+            }
+        } else {
+            self.in_frame.set(true);
+            self.scanline.set(0);
+        }
+        // This read is the first nametable fetch of the line
+        self.fetch.set(0);
+    }
+
+    // $6000-$FFFF
+    fn read_prg(&self, addr: u16) -> u8 {
+        let (rom, bank) = self.prg_bank(addr);
+        let addr = addr as usize & 0x1FFF;
+        if rom {
+            self.prg_rom[(bank * 8192 + addr) % self.prg_rom.len()]
+        } else {
+            self.prg_ram[bank * 8192 + addr].get()
+        }
+    }
+
+    // $5204, which acknowledges the IRQ when it's read
+    fn irq_status(&self) -> u8 {
+        let mut status = 0;
+        if self.irq_pending.get() {
+            status |= 0x80;
+        }
+        if self.in_frame.get() {
+            status |= 0x40;
+        }
+        status
+    }
+
+    fn end_frame(&self) {
+        self.in_frame.set(false);
+        self.same_addr_reads.set(0);
+    }
+}
+
+impl CartridgeImpl for MMC5 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => {
+                let status = self.irq_status();
+                self.irq_pending.set(false);
+                Some(status)
+            }
+            // The CPU fetching the NMI vector means the frame is over
+            0xFFFA | 0xFFFB => {
+                self.end_frame();
+                Some(self.read_prg(addr))
+            }
+            _ => self.peek_cpu(addr),
+        }
+    }
+
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5204 => Some(self.irq_status()),
+            0x5205 | 0x5206 => {
+                let product =
+                    self.multiplicands[0].get() as u16 * self.multiplicands[1].get() as u16;
+                Some(if addr == 0x5205 {
+                    product as u8
+                } else {
+                    (product >> 8) as u8
+                })
+            }
+            0x5C00..=0x5FFF if self.exram_mode.get() >= 2 => {
+                Some(self.exram[addr as usize - 0x5C00].get())
+            }
+            0x6000..=0xFFFF => Some(self.read_prg(addr)),
+            _ => None,
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x5100 => self.prg_mode.set(value & 3),
+            0x5101 => self.chr_mode.set(value & 3),
+            0x5102 => self.prg_ram_protect[0].set(value & 3),
+            0x5103 => self.prg_ram_protect[1].set(value & 3),
+            0x5104 => self.exram_mode.set(value & 3),
+            0x5105 => self.nametables.set(value),
+            0x5106 => self.fill_tile.set(value),
+            0x5107 => self.fill_attribute.set(value & 3),
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113].set(value),
+            0x5120..=0x512B => {
+                let bank = value as u16 | (self.chr_upper.get() as u16) << 8;
+                self.chr_banks[addr as usize - 0x5120].set(bank);
+                self.last_chr_set_b.set(addr >= 0x5128);
+            }
+            0x5130 => self.chr_upper.set(value & 3),
+            0x5203 => self.irq_compare.set(value),
+            0x5204 => self.irq_enabled.set(value & 0x80 != 0),
+            0x5205 => self.multiplicands[0].set(value),
+            0x5206 => self.multiplicands[1].set(value),
+            // Mode 3 is read only
+            0x5C00..=0x5FFF if self.exram_mode.get() <= 2 => {
+                self.exram[addr as usize - 0x5C00].set(value)
+            }
+            0x6000..=0xFFFF => {
+                let (rom, bank) = self.prg_bank(addr);
+                if !rom && self.prg_ram_writable() {
+                    self.prg_ram[bank * 8192 + (addr as usize & 0x1FFF)].set(value);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        let addr = addr % 0x4000;
+        self.follow_ppu(addr);
+
+        match addr {
+            0x0000..=0x1FFF => self.chr.read(self.chr_addr(addr)),
+            0x2000..=0x3FFF => {
+                // With extended attributes each tile's ExRAM byte replaces its attribute bits
+                if self.exram_mode.get() == 1 && self.in_frame.get() && !self.is_sprite_fetch() {
+                    match self.fetch.get() % 4 {
+                        0 => self
+                            .ex_attribute
+                            .set(self.exram[addr as usize & 0x3FF].get()),
+                        1 => return (self.ex_attribute.get() >> 6) * 0x55,
+                        _ => (),
+                    }
+                }
+                self.nametable_read(vram, addr)
+            }
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr % 0x4000 {
+            0x0000..=0x1FFF => self.chr.write(self.chr_addr(addr), value),
+            0x2000..=0x3FFF => self.nametable_write(vram, addr, value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn ppu_register_write(&self, reg: u8, value: u8) {
+        match reg {
+            0 => self.sprites_8x16.set(value & 0x20 != 0),
+            // Turning rendering off stops the PPU's reads, which we'd notice anyway
+            1 if value & 0x18 == 0 => self.end_frame(),
+            _ => (),
+        }
+    }
+
+    fn cpu_tick(&self) {
+        if self.idle_cycles.get() < IDLE_CYCLES {
+            self.idle_cycles.set(self.idle_cycles.get() + 1);
+            if self.idle_cycles.get() == IDLE_CYCLES {
+                self.end_frame();
+            }
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled.get() && self.irq_pending.get()
+    }
+
+    fn missing_features(&self) -> &'static [&'static str] {
+        &["vertical split", "expansion audio"]
+    }
+}
+
+impl SaveState for MMC5 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr.save_state(w);
+        self.exram.save_state(w);
+        self.prg_mode.save_state(w);
+        self.chr_mode.save_state(w);
+        self.prg_ram_protect.save_state(w);
+        self.exram_mode.save_state(w);
+        self.nametables.save_state(w);
+        self.fill_tile.save_state(w);
+        self.fill_attribute.save_state(w);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.chr_upper.save_state(w);
+        self.last_chr_set_b.save_state(w);
+        self.sprites_8x16.save_state(w);
+        self.irq_compare.save_state(w);
+        self.irq_enabled.save_state(w);
+        self.irq_pending.save_state(w);
+        self.in_frame.save_state(w);
+        self.scanline.save_state(w);
+        self.multiplicands.save_state(w);
+        self.last_ppu_addr.save_state(w);
+        self.same_addr_reads.save_state(w);
+        self.fetch.save_state(w);
+        self.idle_cycles.save_state(w);
+        self.ex_attribute.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.exram.load_state(r)?;
+        self.prg_mode.load_state(r)?;
+        self.chr_mode.load_state(r)?;
+        self.prg_ram_protect.load_state(r)?;
+        self.exram_mode.load_state(r)?;
+        self.nametables.load_state(r)?;
+        self.fill_tile.load_state(r)?;
+        self.fill_attribute.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.chr_upper.load_state(r)?;
+        self.last_chr_set_b.load_state(r)?;
+        self.sprites_8x16.load_state(r)?;
+        self.irq_compare.load_state(r)?;
+        self.irq_enabled.load_state(r)?;
+        self.irq_pending.load_state(r)?;
+        self.in_frame.load_state(r)?;
+        self.scanline.load_state(r)?;
+        self.multiplicands.load_state(r)?;
+        self.last_ppu_addr.load_state(r)?;
+        self.same_addr_reads.load_state(r)?;
+        self.fetch.load_state(r)?;
+        self.idle_cycles.load_state(r)?;
+        self.ex_attribute.load_state(r)
+    }
+}
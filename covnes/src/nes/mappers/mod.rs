@@ -1,72 +1,324 @@
-use std::cell::Cell;
+use alloc::{boxed::Box, collections::BTreeMap};
+use core::cell::Cell;
 
-use anyhow::{bail, Result};
+use crate::{
+    error::{Error, Result},
+    nes::{
+        mappers::common::MirrorMode,
+        state::{SaveState, StateReader, StateWriter},
+    },
+    romfiles::RomFile,
+};
 
-use crate::romfiles::RomFile;
-
-mod common;
+mod axrom;
+mod bf909x;
+mod bnrom;
+mod cnrom;
+pub mod common;
+#[cfg(feature = "fds")]
+pub mod fds;
+mod gxrom;
+mod mmc2;
+mod mmc3;
+mod mmc5;
+mod namco163;
 mod nrom;
 mod sxrom;
 mod uxrom;
+mod vrc4;
+mod vrc6;
+mod vrc_irq;
 
 pub enum Cartridge {
     NotConnected,
-    NROM(nrom::NROM),
-    SxROM(sxrom::SxROM),
-    UxROM(uxrom::UxROM),
+    // Any mapper, built in or from outside this crate (see `MapperRegistry`)
+    Boxed(Box<dyn CartridgeImpl + Send>),
 }
 
+// Loads a ROM with one of the built in mappers
 pub fn from_rom(rom: RomFile) -> Result<Cartridge> {
-    Ok(match rom.mapper {
-        0 => Cartridge::NROM(nrom::from_rom(rom)?),
-        1 => Cartridge::SxROM(sxrom::from_rom(rom)?),
-        2 => Cartridge::UxROM(uxrom::from_rom(rom)?),
-        i => bail!("Unsupported mapper: {}", i),
-    })
+    MapperRegistry::default().from_rom(rom)
+}
+
+// Creates the cartridge for a ROM, or fails if the ROM doesn't fit the mapper
+pub type MapperFactory = fn(RomFile) -> Result<Cartridge>;
+
+// Which mapper to use for each iNES mapper number. The default registry has all of the built in
+// mappers, and more can be added with `register_mapper` to try out mappers without changing this
+// crate.
+//
+// Mappers can also be registered for just one NES 2.0 submapper with `register_submapper`. Those
+// win when the ROM has that submapper, and the one for the mapper number as a whole is used
+// otherwise.
+pub struct MapperRegistry {
+    factories: BTreeMap<(usize, Option<u8>), MapperFactory>,
 }
 
-pub trait CartridgeImpl {
-    fn read_cpu(&self, addr: u16) -> u8;
+impl MapperRegistry {
+    // A registry without any mappers, not even the built in ones
+    pub fn empty() -> MapperRegistry {
+        MapperRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    // Adds a mapper, replacing any that was already registered for `number`
+    pub fn register_mapper(&mut self, number: usize, factory: MapperFactory) {
+        self.factories.insert((number, None), factory);
+    }
+
+    // Adds a mapper for one submapper of `number`, replacing any that was already registered for
+    // it
+    pub fn register_submapper(&mut self, number: usize, submapper: u8, factory: MapperFactory) {
+        self.factories.insert((number, Some(submapper)), factory);
+    }
+
+    pub fn from_rom(&self, rom: RomFile) -> Result<Cartridge> {
+        let factory = self
+            .factories
+            .get(&(rom.mapper, Some(rom.submapper)))
+            .or_else(|| self.factories.get(&(rom.mapper, None)));
+        match factory {
+            Some(factory) => factory(rom),
+            None => Err(Error::UnsupportedMapper {
+                mapper: rom.mapper,
+                submapper: rom.submapper,
+            }),
+        }
+    }
+}
+
+impl Default for MapperRegistry {
+    fn default() -> Self {
+        let mut registry = MapperRegistry::empty();
+        registry.register_mapper(0, |rom| Cartridge::boxed(nrom::from_rom(rom)?));
+        registry.register_mapper(1, |rom| Cartridge::boxed(sxrom::from_rom(rom)?));
+        registry.register_mapper(2, |rom| Cartridge::boxed(uxrom::from_rom(rom)?));
+        registry.register_mapper(3, |rom| Cartridge::boxed(cnrom::from_rom(rom)?));
+        registry.register_mapper(4, |rom| Cartridge::boxed(mmc3::from_rom(rom)?));
+        registry.register_mapper(5, |rom| Cartridge::boxed(mmc5::from_rom(rom)?));
+        registry.register_mapper(7, |rom| Cartridge::boxed(axrom::from_rom(rom)?));
+        registry.register_mapper(9, |rom| Cartridge::boxed(mmc2::from_rom(rom)?));
+        registry.register_mapper(19, |rom| Cartridge::boxed(namco163::from_rom(rom)?));
+        registry.register_mapper(21, |rom| Cartridge::boxed(vrc4::from_rom(rom)?));
+        registry.register_mapper(22, |rom| Cartridge::boxed(vrc4::from_rom(rom)?));
+        registry.register_mapper(23, |rom| Cartridge::boxed(vrc4::from_rom(rom)?));
+        registry.register_mapper(24, |rom| Cartridge::boxed(vrc6::from_rom(rom)?));
+        registry.register_mapper(25, |rom| Cartridge::boxed(vrc4::from_rom(rom)?));
+        // The VRC2 and VRC4 submappers say which of the wirings the board has
+        registry.register_submapper(21, 1, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC4A)?)
+        });
+        registry.register_submapper(21, 2, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC4C)?)
+        });
+        registry.register_submapper(23, 1, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC2B_VRC4F)?)
+        });
+        registry.register_submapper(23, 2, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC4E)?)
+        });
+        registry.register_submapper(23, 3, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC2B_VRC4F)?)
+        });
+        registry.register_submapper(25, 1, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC2C_VRC4B)?)
+        });
+        registry.register_submapper(25, 2, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC4D)?)
+        });
+        registry.register_submapper(25, 3, |rom| {
+            Cartridge::boxed(vrc4::from_rom_with_lines(rom, vrc4::VRC2C_VRC4B)?)
+        });
+        registry.register_mapper(26, |rom| Cartridge::boxed(vrc6::from_rom(rom)?));
+        registry.register_mapper(34, |rom| Cartridge::boxed(bnrom::from_rom(rom)?));
+        registry.register_mapper(66, |rom| Cartridge::boxed(gxrom::from_rom(rom)?));
+        registry.register_mapper(71, |rom| Cartridge::boxed(bf909x::from_rom(rom)?));
+        registry
+    }
+}
+
+// Mappers also save any registers and RAM they have in save states
+pub trait CartridgeImpl: SaveState {
+    // Gets every access from $4020 up, so the expansion area below $6000 (MMC5's registers and
+    // ExRAM, for example) is the mapper's to use too. None if nothing on the cartridge responds
+    // to `addr`, leaving the last value on the bus.
+    fn read_cpu(&self, addr: u16) -> Option<u8>;
     fn write_cpu(&self, addr: u16, value: u8);
 
+    // What `read_cpu` would return, but without any of the side effects, for debuggers and memory
+    // dumps. Mappers with registers that change when they're read (like acknowledging an IRQ) have
+    // to override this.
+    fn peek_cpu(&self, addr: u16) -> Option<u8> {
+        self.read_cpu(addr)
+    }
+
     fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8;
     fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8);
+
+    // Called when the CPU writes a PPU register, for mappers that snoop on them (`reg` is 0-7)
+    fn ppu_register_write(&self, _reg: u8, _value: u8) {}
+
+    // Called with the address of every PPU read and write, before `read_ppu` or `write_ppu`, for
+    // mappers that watch the address bus (MMC3 clocks its scanline counter off A12)
+    fn notify_ppu_addr(&self, _addr: u16) {}
+
+    // Called once every CPU cycle, for mappers with IRQ counters or expansion audio
+    fn cpu_tick(&self) {}
+
+    // Whether the cartridge is currently pulling the IRQ line low
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // The current output of any expansion audio on the cartridge, which is added to the APU's
+    // output. It's on the scale of the APU's DACs before `apu::mix` normalises them, where one APU
+    // pulse channel at full volume is about 0.15 and everything at once is about 1.0. Each
+    // mapper's channels should be set relative to that, going by how loud they are on hardware
+    // (the nesdev wiki has the measurements for most chips) - VRC6's pulses are as loud as the
+    // APU's, for example.
+    fn audio_sample(&self) -> f32 {
+        0.0
+    }
+
+    // Anything on the chip that isn't emulated yet, for frontends to warn about. Games that don't
+    // use these still run fine, so mappers that are only partly done can be used in the meantime.
+    fn missing_features(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    // Mirrors the nametables as `mode` whatever the mapper would do, or goes back to normal with
+    // None. It's only for debugging (see `Nes::set_mirroring`), so mappers that map nametables some
+    // other way can leave it doing nothing.
+    fn force_mirroring(&self, _mode: Option<MirrorMode>) {}
+
+    // The Famicom Disk System isn't really a cartridge, but it goes in the same slot. This is how
+    // frontends get to its disk drive.
+    #[cfg(feature = "fds")]
+    fn as_fds(&self) -> Option<&fds::Fds> {
+        None
+    }
 }
 
 impl Cartridge {
-    pub fn read_cpu(&self, addr: u16) -> u8 {
+    // For returning from a `MapperFactory`
+    pub fn boxed<C: CartridgeImpl + Send + 'static>(cartridge: C) -> Result<Cartridge> {
+        Ok(Cartridge::Boxed(Box::new(cartridge)))
+    }
+
+    pub fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match self {
+            Cartridge::NotConnected => None,
+            Cartridge::Boxed(c) => c.read_cpu(addr),
+        }
+    }
+
+    pub fn peek_cpu(&self, addr: u16) -> Option<u8> {
         match self {
-            Cartridge::NotConnected => unimplemented!(),
-            Cartridge::NROM(c) => c.read_cpu(addr),
-            Cartridge::SxROM(c) => c.read_cpu(addr),
-            Cartridge::UxROM(c) => c.read_cpu(addr),
+            Cartridge::NotConnected => None,
+            Cartridge::Boxed(c) => c.peek_cpu(addr),
         }
     }
 
     pub fn write_cpu(&self, addr: u16, value: u8) {
         match self {
-            Cartridge::NotConnected => unimplemented!(),
-            Cartridge::NROM(c) => c.write_cpu(addr, value),
-            Cartridge::SxROM(c) => c.write_cpu(addr, value),
-            Cartridge::UxROM(c) => c.write_cpu(addr, value),
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.write_cpu(addr, value),
         }
     }
 
     pub fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
         match self {
-            Cartridge::NotConnected => unimplemented!(),
-            Cartridge::NROM(c) => c.read_ppu(vram, addr),
-            Cartridge::SxROM(c) => c.read_ppu(vram, addr),
-            Cartridge::UxROM(c) => c.read_ppu(vram, addr),
+            // The cartridge is also responsible for selecting the internal VRAM, so with nothing
+            // connected there's nothing to read
+            Cartridge::NotConnected => 0,
+            Cartridge::Boxed(c) => c.read_ppu(vram, addr),
         }
     }
 
     pub fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
         match self {
-            Cartridge::NotConnected => unimplemented!(),
-            Cartridge::NROM(c) => c.write_ppu(vram, addr, value),
-            Cartridge::SxROM(c) => c.write_ppu(vram, addr, value),
-            Cartridge::UxROM(c) => c.write_ppu(vram, addr, value),
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.write_ppu(vram, addr, value),
+        }
+    }
+
+    pub fn ppu_register_write(&self, reg: u8, value: u8) {
+        match self {
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.ppu_register_write(reg, value),
+        }
+    }
+
+    pub fn notify_ppu_addr(&self, addr: u16) {
+        match self {
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.notify_ppu_addr(addr),
+        }
+    }
+
+    pub fn cpu_tick(&self) {
+        match self {
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.cpu_tick(),
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        match self {
+            Cartridge::NotConnected => false,
+            Cartridge::Boxed(c) => c.irq(),
+        }
+    }
+
+    pub fn audio_sample(&self) -> f32 {
+        match self {
+            Cartridge::NotConnected => 0.0,
+            Cartridge::Boxed(c) => c.audio_sample(),
+        }
+    }
+
+    pub fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        match self {
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.force_mirroring(mode),
+        }
+    }
+
+    pub fn missing_features(&self) -> &'static [&'static str] {
+        match self {
+            Cartridge::NotConnected => &[],
+            Cartridge::Boxed(c) => c.missing_features(),
+        }
+    }
+
+    #[cfg(feature = "fds")]
+    pub fn as_fds(&self) -> Option<&fds::Fds> {
+        match self {
+            Cartridge::NotConnected => None,
+            Cartridge::Boxed(c) => c.as_fds(),
+        }
+    }
+
+    // Whether every game using the mapper should work, rather than only the ones that stay away
+    // from its missing features
+    pub fn is_fully_supported(&self) -> bool {
+        self.missing_features().is_empty()
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, w: &mut StateWriter) {
+        match self {
+            Cartridge::NotConnected => {}
+            Cartridge::Boxed(c) => c.save_state(w),
+        }
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        match self {
+            Cartridge::NotConnected => Ok(()),
+            Cartridge::Boxed(c) => c.load_state(r),
         }
     }
 }
@@ -0,0 +1,326 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{common::ChrMem, CartridgeImpl},
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Namco 163 - mapper 19, used by a lot of Namco's later Famicom games (Megami Tensei II, Final
+// Lap, Rolling Thunder). The Namco 129 is the same chip without the sound, so runs here too.
+//
+// Banking is in 8kb PRG and 1kb CHR pages. Any CHR page or nametable can be one of the console's
+// nametables instead of CHR ROM, which is how the mirroring is set.
+//
+// The sound is up to 8 wavetable channels with their registers and waveforms sharing 128 bytes of
+// RAM inside the chip, reached through $F800 (address) and $4800 (data). There's only one DAC, so
+// the chip updates one channel every 15 CPU cycles and outputs that channel until the next one,
+// which means the more channels are enabled, the slower each one runs. On hardware the switching
+// is fast enough to be heard as a whine with 8 channels, but here the output is the average of
+// the enabled channels, which is what it sounds like once that's filtered out.
+
+// How many CPU cycles the sound spends on each channel
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+// A channel at full volume swings from -120 to 105. Carts put the chip's output through different
+// resistors, so how loud it is against the APU varies a lot between games (NES 2.0 submappers
+// exist just to say which). This puts a full volume channel at about the level of an APU pulse
+// channel, which is on the quiet side but never drowns anything out.
+const MIX_LEVEL: f32 = 0.15 / 120.0;
+
+pub fn from_rom(rom: RomFile) -> Result<Namco163> {
+    let prg_banks = rom.prg_rom.len() / 8192;
+    if !rom.prg_rom.len().is_multiple_of(8192) || !(1..=64).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: rom.mapper,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if !d.len().is_multiple_of(1024) || d.len() > 256 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: rom.mapper,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(Namco163 {
+        prg_rom: rom.prg_rom,
+        prg_ram: vec![Cell::new(0); 0x2000],
+        chr,
+        prg_banks: Default::default(),
+        chr_banks: Default::default(),
+        nametable_banks: Default::default(),
+        write_protect: Cell::new(0),
+        irq_counter: Cell::new(0),
+        irq_enabled: Cell::new(false),
+        irq_pending: Cell::new(false),
+        sound_ram: vec![Cell::new(0); 128],
+        sound_addr: Cell::new(0),
+        sound_channel: Cell::new(0),
+        sound_timer: Cell::new(0),
+        sound_outputs: Default::default(),
+    })
+}
+
+pub struct Namco163 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr: ChrMem,
+    // Registers. Bit 6 of the first PRG bank disables the sound, and bits 6 and 7 of the second
+    // stop the CHR banks at $0000 and $1000 from using the nametables.
+    prg_banks: [Cell<u8>; 3],
+    chr_banks: [Cell<u8>; 8],
+    nametable_banks: [Cell<u8>; 4],
+    // The last write to $F800, which is also the sound address
+    write_protect: Cell<u8>,
+    // Counts up every cycle while enabled, and holds the IRQ once it gets to $7FFF
+    irq_counter: Cell<u16>,
+    irq_enabled: Cell<bool>,
+    irq_pending: Cell<bool>,
+    // Sound
+    sound_ram: Vec<Cell<u8>>,
+    // Bits 0-6 are the address, and bit 7 makes it go up after every access through $4800
+    sound_addr: Cell<u8>,
+    // Which of the enabled channels is next, counting down from the last one
+    sound_channel: Cell<u8>,
+    sound_timer: Cell<u8>,
+    sound_outputs: [Cell<i16>; 8],
+}
+
+enum PpuPage<'a> {
+    Nametable(&'a Cell<u8>),
+    // An address in `chr`
+    Chr(usize),
+}
+
+impl Namco163 {
+    fn sound_enabled(&self) -> bool {
+        self.prg_banks[0].get() & 0x40 == 0
+    }
+
+    fn enabled_channels(&self) -> u8 {
+        ((self.sound_ram[0x7F].get() >> 4) & 7) + 1
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        // Writes only go through with $4x in the top of $F800, and then not to the 2kb pages
+        // whose bits are set
+        let protect = self.write_protect.get();
+        let page = (addr - 0x6000) >> 11;
+        protect & 0xF0 == 0x40 && protect & (1 << page) == 0
+    }
+
+    // Either a 1kb page of CHR, or one of the console's nametables. Bank numbers $E0 and up are
+    // the nametables, where even is the first and odd the second.
+    fn ppu_page<'a>(&self, vram: &'a [Cell<u8>], addr: u16) -> PpuPage<'a> {
+        let slot = (addr as usize >> 10) & 7;
+        let (bank, nametables_allowed) = match addr {
+            0x0000..=0x0FFF => (
+                self.chr_banks[slot].get(),
+                self.prg_banks[1].get() & 0x40 == 0,
+            ),
+            0x1000..=0x1FFF => (
+                self.chr_banks[slot].get(),
+                self.prg_banks[1].get() & 0x80 == 0,
+            ),
+            _ => (self.nametable_banks[slot & 3].get(), true),
+        };
+
+        if nametables_allowed && bank >= 0xE0 {
+            let offset = (bank as usize & 1) * 0x400 + (addr as usize & 0x3FF);
+            PpuPage::Nametable(&vram[offset])
+        } else {
+            PpuPage::Chr(self.chr.bank_addr(bank as usize, 0x400, addr))
+        }
+    }
+
+    fn sound_data(&self) -> &Cell<u8> {
+        let addr = self.sound_addr.get();
+        if addr & 0x80 == 0x80 {
+            self.sound_addr.set(0x80 | (addr.wrapping_add(1) & 0x7F));
+        }
+        &self.sound_ram[(addr & 0x7F) as usize]
+    }
+
+    // Moves a channel's phase on by its frequency and works out its output from the waveform
+    fn update_channel(&self, channel: usize) {
+        let regs = &self.sound_ram[0x40 + channel * 8..0x48 + channel * 8];
+        let reg = |i: usize| regs[i].get() as u32;
+
+        let frequency = reg(0) | (reg(2) << 8) | ((reg(4) & 3) << 16);
+        let phase = reg(1) | (reg(3) << 8) | (reg(5) << 16);
+        let length = 256 - (reg(4) & 0xFC);
+        let phase = (phase + frequency) % (length << 16);
+        regs[1].set(phase as u8);
+        regs[3].set((phase >> 8) as u8);
+        regs[5].set((phase >> 16) as u8);
+
+        // 4 bit samples, two to a byte with the first in the low bits
+        let sample = ((phase >> 16) + reg(6)) & 0xFF;
+        let byte = self.sound_ram[sample as usize >> 1].get();
+        let sample = (byte >> ((sample & 1) * 4)) & 0xF;
+        let volume = reg(7) & 0xF;
+        self.sound_outputs[channel].set((sample as i16 - 8) * volume as i16);
+    }
+}
+
+impl CartridgeImpl for Namco163 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => Some(self.sound_data().get()),
+            0x5000..=0x57FF => Some(self.irq_counter.get() as u8),
+            0x5800..=0x5FFF => {
+                let high = (self.irq_counter.get() >> 8) as u8;
+                Some(high | if self.irq_enabled.get() { 0x80 } else { 0 })
+            }
+            0x6000..=0x7FFF => Some(self.prg_ram[(addr - 0x6000) as usize].get()),
+            0x8000..=0xDFFF => {
+                let slot = (addr - 0x8000) as usize >> 13;
+                let bank = (self.prg_banks[slot].get() & 0x3F) as usize;
+                let addr = (bank * 8192 + (addr as usize & 0x1FFF)) % self.prg_rom.len();
+                Some(self.prg_rom[addr])
+            }
+            0xE000..=0xFFFF => {
+                // Fixed to the last 8kb bank
+                let base = self.prg_rom.len() - 8192;
+                Some(self.prg_rom[base + (addr - 0xE000) as usize])
+            }
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.sound_data().set(value),
+            0x5000..=0x57FF => {
+                self.irq_counter
+                    .set((self.irq_counter.get() & 0x7F00) | value as u16);
+                self.irq_pending.set(false);
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter
+                    .set((self.irq_counter.get() & 0xFF) | ((value as u16 & 0x7F) << 8));
+                self.irq_enabled.set(value & 0x80 == 0x80);
+                self.irq_pending.set(false);
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(addr) => {
+                self.prg_ram[(addr - 0x6000) as usize].set(value);
+            }
+            0x8000..=0xBFFF => self.chr_banks[(addr - 0x8000) as usize >> 11].set(value),
+            0xC000..=0xDFFF => self.nametable_banks[(addr - 0xC000) as usize >> 11].set(value),
+            0xE000..=0xF7FF => self.prg_banks[(addr - 0xE000) as usize >> 11].set(value),
+            0xF800..=0xFFFF => {
+                self.write_protect.set(value);
+                self.sound_addr.set(value);
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match self.ppu_page(vram, addr % 0x3000) {
+            PpuPage::Nametable(cell) => cell.get(),
+            PpuPage::Chr(chr_addr) => self.chr.read(chr_addr),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match self.ppu_page(vram, addr % 0x3000) {
+            PpuPage::Nametable(cell) => cell.set(value),
+            PpuPage::Chr(chr_addr) => self.chr.write(chr_addr, value),
+        }
+    }
+
+    fn cpu_tick(&self) {
+        let counter = self.irq_counter.get();
+        if self.irq_enabled.get() && counter < 0x7FFF {
+            self.irq_counter.set(counter + 1);
+            if counter + 1 == 0x7FFF {
+                self.irq_pending.set(true);
+            }
+        }
+
+        if !self.sound_enabled() {
+            return;
+        }
+        let timer = self.sound_timer.get() + 1;
+        if timer < CYCLES_PER_CHANNEL {
+            self.sound_timer.set(timer);
+            return;
+        }
+        self.sound_timer.set(0);
+
+        // The enabled channels are always the last ones, and they're run from the end backwards
+        let channels = self.enabled_channels();
+        let next = self.sound_channel.get() % channels;
+        self.update_channel(7 - next as usize);
+        self.sound_channel.set((next + 1) % channels);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending.get()
+    }
+
+    fn audio_sample(&self) -> f32 {
+        if !self.sound_enabled() {
+            return 0.0;
+        }
+        let channels = self.enabled_channels() as usize;
+        let total: i16 = self.sound_outputs[8 - channels..]
+            .iter()
+            .map(Cell::get)
+            .sum();
+        total as f32 / channels as f32 * MIX_LEVEL
+    }
+}
+
+impl SaveState for Namco163 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr.save_state(w);
+        self.prg_banks.save_state(w);
+        self.chr_banks.save_state(w);
+        self.nametable_banks.save_state(w);
+        self.write_protect.save_state(w);
+        self.irq_counter.save_state(w);
+        self.irq_enabled.save_state(w);
+        self.irq_pending.save_state(w);
+        self.sound_ram.save_state(w);
+        self.sound_addr.save_state(w);
+        self.sound_channel.save_state(w);
+        self.sound_timer.save_state(w);
+    }
+
+    // The channel outputs aren't saved, as they're all worked out again within 120 cycles
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.nametable_banks.load_state(r)?;
+        self.write_protect.load_state(r)?;
+        self.irq_counter.load_state(r)?;
+        self.irq_enabled.load_state(r)?;
+        self.irq_pending.load_state(r)?;
+        self.sound_ram.load_state(r)?;
+        self.sound_addr.load_state(r)?;
+        self.sound_channel.load_state(r)?;
+        self.sound_timer.load_state(r)
+    }
+}
@@ -1,9 +1,13 @@
-use std::cell::Cell;
-
-use anyhow::Result;
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
 
 use crate::{
-    nes::mappers::{common, common::MirrorMode, CartridgeImpl},
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
     romfiles::RomFile,
 };
 
@@ -16,30 +20,38 @@ pub fn from_rom(rom: RomFile) -> Result<SxROM> {
     // when (/if?) I get to the point of doing other sxrom games I can do all the special casing
     // on the high address lines
 
-    // These assertions are false in general
-    println!(
-        "{} {:?}",
-        rom.prg_rom.len(),
-        rom.chr_rom.as_ref().map(|x| x.len())
-    );
+    // These checks are stricter than they need to be in general
     let prg_banks = rom.prg_rom.len() / 16384;
-    assert!(
-        prg_banks == 2 || prg_banks == 4 || prg_banks == 8 || prg_banks == 16 || prg_banks == 32
-    );
+    if !(prg_banks == 2 || prg_banks == 4 || prg_banks == 8 || prg_banks == 16 || prg_banks == 32) {
+        return Err(Error::BadPrgRomSize {
+            mapper: 1,
+            size: rom.prg_rom.len(),
+        });
+    }
     if let Some(r) = &rom.chr_rom {
         let rom_banks = r.len() / 8192;
-        assert!(
-            rom_banks == 1 || rom_banks == 2 || rom_banks == 4 || rom_banks == 8 || rom_banks == 16
-        );
+        if !(rom_banks == 1
+            || rom_banks == 2
+            || rom_banks == 4
+            || rom_banks == 8
+            || rom_banks == 16)
+        {
+            return Err(Error::BadChrRomSize {
+                mapper: 1,
+                size: r.len(),
+            });
+        }
     }
 
-    let chr = match rom.chr_rom {
-        None => ChrData::RAM(vec![Cell::new(0); 0x2000]),
-        Some(r) => ChrData::ROM(r),
-    };
+    let chr = ChrMem::new(rom.chr_rom, 0x2000);
 
+    // SOROM has 16kb and SXROM 32kb, anything else is treated as 8kb
     let prg_ram = if rom.provide_prg_ram {
-        Some(vec![Cell::new(0); 0x2000])
+        let size = match rom.prg_ram_size {
+            0x4000 | 0x8000 => rom.prg_ram_size,
+            _ => 0x2000,
+        };
+        Some(vec![Cell::new(0); size])
     } else {
         None
     };
@@ -53,12 +65,13 @@ pub fn from_rom(rom: RomFile) -> Result<SxROM> {
         chr_bank_0: Cell::new(0),
         chr_bank_1: Cell::new(0),
         prg_bank: Cell::new(0),
+        forced_mirroring: ForcedMirroring::default(),
     })
 }
 
 pub struct SxROM {
     prg_rom: Vec<u8>,
-    chr: ChrData,
+    chr: ChrMem,
     prg_ram: Option<Vec<Cell<u8>>>,
     // Registers
     load_reg: Cell<u8>,
@@ -66,11 +79,7 @@ pub struct SxROM {
     chr_bank_0: Cell<u8>,
     chr_bank_1: Cell<u8>,
     prg_bank: Cell<u8>,
-}
-
-enum ChrData {
-    ROM(Vec<u8>),
-    RAM(Vec<Cell<u8>>),
+    forced_mirroring: ForcedMirroring,
 }
 
 impl SxROM {
@@ -83,36 +92,52 @@ impl SxROM {
         }
     }
 
-    fn get_mapped_chr_addr(&self, addr: u16) -> usize {
-        let chr_size = match &self.chr {
-            ChrData::ROM(r) => r.len(),
-            ChrData::RAM(r) => r.len(),
+    // Boards with more than 8kb of PRG RAM bank it with the CHR bank 0 register's high bits, which
+    // aren't connected to the CHR with the 8kb of CHR RAM these boards have. SXROM uses
+    // bits 2-3, and SOROM just bit 3.
+    fn get_prg_ram_addr(&self, ram: &[Cell<u8>], addr: u16) -> usize {
+        let bank = match ram.len() {
+            0x8000 => (self.chr_bank_0.get() as usize >> 2) & 3,
+            0x4000 => (self.chr_bank_0.get() as usize >> 3) & 1,
+            _ => 0,
         };
+        bank * 0x2000 + (addr as usize - 0x6000)
+    }
+
+    // Bit 4 of the PRG bank register turns the RAM off, so a crash during a reset can't scribble
+    // over saves. Reads then see open bus, and writes are dropped. (The first MMC1 revision had
+    // no way to turn it off, but games made for it never set the bit.)
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank.get() & 0x10 == 0
+    }
 
-        // Because we're only doing 8kb we can simplify this logic significantly
+    fn get_mapped_chr_addr(&self, addr: u16) -> usize {
+        // Banks past the end of smaller CHR wrap around in `bank_addr`
         if self.control.get() & 0x10 == 0x10 {
             // Two separate 4kb bytes
             if addr < 0x1000 {
-                (self.chr_bank_0.get() as usize * 0x1000) % chr_size + (addr as usize)
+                self.chr
+                    .bank_addr(self.chr_bank_0.get() as usize, 0x1000, addr)
             } else {
-                (self.chr_bank_1.get() as usize * 0x1000) % chr_size + (addr as usize - 0x1000)
+                self.chr
+                    .bank_addr(self.chr_bank_1.get() as usize, 0x1000, addr)
             }
         } else {
-            ((self.chr_bank_0.get() as usize & !1) * 0x2000) % chr_size + addr as usize
+            self.chr
+                .bank_addr(self.chr_bank_0.get() as usize & !1, 0x2000, addr)
         }
     }
 }
 
 impl CartridgeImpl for SxROM {
-    fn read_cpu(&self, addr: u16) -> u8 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
         match addr {
-            0x0000..=0x5FFF => {
-                panic!("Bad cpu read to cartridge: {:04X}", addr)
-            }
-            0x6000..=0x7FFF => match &self.prg_ram {
-                None => 0x0,
-                Some(r) => r[(addr - 0x6000) as usize].get(),
-            },
+            0x0000..=0x5FFF => None,
+            0x6000..=0x7FFF if !self.prg_ram_enabled() => None,
+            0x6000..=0x7FFF => self
+                .prg_ram
+                .as_ref()
+                .map(|r| r[self.get_prg_ram_addr(r, addr)].get()),
             0x8000..=0xFFFF => {
                 let control_h = self.control.get() & 8 == 8;
                 let control_l = self.control.get() & 4 == 4;
@@ -138,7 +163,7 @@ impl CartridgeImpl for SxROM {
 
                 let addr = ((bank as usize) << 14) | (offset as usize);
                 let index = addr % self.prg_rom.len();
-                self.prg_rom[index]
+                Some(self.prg_rom[index])
             }
         }
     }
@@ -148,9 +173,10 @@ impl CartridgeImpl for SxROM {
             0x0000..=0x5FFF => {
                 panic!("Bad cpu write to cartridge: {:04X}", addr);
             }
+            0x6000..=0x7FFF if !self.prg_ram_enabled() => (),
             0x6000..=0x7FFF => match &self.prg_ram {
                 None => (),
-                Some(r) => r[(addr - 0x6000) as usize].set(value),
+                Some(r) => r[self.get_prg_ram_addr(r, addr)].set(value),
             },
             0x8000..=0xFFFF => {
                 if value & 0x80 == 0x80 {
@@ -180,23 +206,49 @@ impl CartridgeImpl for SxROM {
 
     fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
         match addr {
-            0x0000..=0x1FFF => match &self.chr {
-                ChrData::ROM(r) => r[self.get_mapped_chr_addr(addr)],
-                ChrData::RAM(r) => r[self.get_mapped_chr_addr(addr)].get(),
-            },
-            0x1000..=0x3FFF => common::get_vram_cell(&self.get_mirroring(), vram, addr).get(),
+            0x0000..=0x1FFF => self.chr.read(self.get_mapped_chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .get(),
             _ => panic!("Invalid ppu read address"),
         }
     }
 
     fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
         match addr {
-            0x0000..=0x1FFF => match &self.chr {
-                ChrData::ROM(_) => (),
-                ChrData::RAM(r) => r[self.get_mapped_chr_addr(addr)].set(value),
-            },
-            0x1000..=0x3FFF => common::get_vram_cell(&self.get_mirroring(), vram, addr).set(value),
+            0x0000..=0x1FFF => self.chr.write(self.get_mapped_chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .set(value),
             _ => panic!("Invalid ppu write address"),
         }
     }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for SxROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.chr.save_state(w);
+        self.prg_ram.save_state(w);
+        self.load_reg.save_state(w);
+        self.control.save_state(w);
+        self.chr_bank_0.save_state(w);
+        self.chr_bank_1.save_state(w);
+        self.prg_bank.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.chr.load_state(r)?;
+        self.prg_ram.load_state(r)?;
+        self.load_reg.load_state(r)?;
+        self.control.load_state(r)?;
+        self.chr_bank_0.load_state(r)?;
+        self.chr_bank_1.load_state(r)?;
+        self.prg_bank.load_state(r)
+    }
 }
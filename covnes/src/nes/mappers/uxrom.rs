@@ -1,9 +1,14 @@
-use std::cell::Cell;
-
-use anyhow::{bail, Result};
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
 
 use crate::{
-    nes::mappers::{common, common::MirrorMode, CartridgeImpl},
+    error::{Error, Result},
+    nes::mappers::{
+        common,
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
     romfiles::{Mirroring, RomFile},
 };
 
@@ -18,7 +23,10 @@ pub fn from_rom(rom: RomFile) -> Result<UxROM> {
         || banks == 64
         || banks == 128)
     {
-        bail!("Badly sized prg_rom for mapper 2 (not power of 2)");
+        return Err(Error::BadPrgRomSize {
+            mapper: 2,
+            size: rom.prg_rom.len(),
+        });
     }
 
     let prg_ram = if rom.provide_prg_ram {
@@ -30,18 +38,26 @@ pub fn from_rom(rom: RomFile) -> Result<UxROM> {
     let chr_data = match rom.chr_rom {
         Some(d) => {
             if d.len() != 8192 {
-                bail!("Badly sized chr_rom for mapper 2")
+                return Err(Error::BadChrRomSize {
+                    mapper: 2,
+                    size: d.len(),
+                });
             } else {
-                Chr::ROM(d)
+                ChrMem::ROM(d)
             }
         }
-        None => Chr::RAM(vec![Cell::new(0); 8192]),
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
     };
 
     let mirroring = match rom.mirroring {
         Mirroring::Horizontal => MirrorMode::Horizontal,
         Mirroring::Vertical => MirrorMode::Vertical,
-        Mirroring::FourScreen => panic!("Can't do FourScreen on mapper 2/NROM"),
+        Mirroring::FourScreen => {
+            return Err(Error::UnsupportedMirroring {
+                mapper: 2,
+                mirroring: rom.mirroring,
+            })
+        }
     };
 
     Ok(UxROM {
@@ -50,53 +66,43 @@ pub fn from_rom(rom: RomFile) -> Result<UxROM> {
         bank: Cell::new(0),
         chr_data,
         prg_ram,
+        forced_mirroring: ForcedMirroring::default(),
     })
 }
 
-enum Chr {
-    ROM(Vec<u8>),
-    RAM(Vec<Cell<u8>>),
-}
-
 pub struct UxROM {
     mirroring: common::MirrorMode,
     prg_rom: Vec<u8>,
     bank: Cell<u8>,
-    chr_data: Chr,
+    chr_data: ChrMem,
     prg_ram: Option<Vec<Cell<u8>>>,
+    forced_mirroring: ForcedMirroring,
     // We store the PPU VRAM here in the mapper to allow for cartridges to choose
 }
 
 impl CartridgeImpl for UxROM {
-    fn read_cpu(&self, addr: u16) -> u8 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
         match addr {
             0x6000..=0x7FFF => {
                 if let Some(ram) = &self.prg_ram {
-                    ram[(addr - 0x6000) as usize].get()
+                    Some(ram[(addr - 0x6000) as usize].get())
                 } else {
                     if cfg!(pedantic_af) {
                         panic!("Bad read {:4X} (no PRG RAM)", addr);
                     }
-                    0
+                    None
                 }
             }
-            0x8000..=0xBFFF => {
-                let addr = (addr - 0x8000) as usize;
-                let base = self.bank.get() as usize * 16384;
-                let addr = (base + addr) % self.prg_rom.len();
-                self.prg_rom[addr]
-            }
-            0xC000..=0xFFFF => {
-                let addr = (addr - 0xC000) as usize;
-                let base = 255 * 16384; // Fix to what is always the last bank
-                let addr = (base + addr) % self.prg_rom.len();
-                self.prg_rom[addr]
-            }
+            0x8000..=0xFFFF => Some(common::read_prg_16k_fixed_last(
+                &self.prg_rom,
+                self.bank.get(),
+                addr,
+            )),
             _ => {
                 if cfg!(pedantic_af) {
                     panic!("Bad read {:4X}", addr)
                 } else {
-                    0
+                    None
                 }
             }
         }
@@ -120,27 +126,41 @@ impl CartridgeImpl for UxROM {
 
     fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
         match addr % 0x4000 {
-            0x0000..=0x1FFF => match &self.chr_data {
-                Chr::ROM(r) => r[addr as usize],
-                Chr::RAM(r) => r[addr as usize].get(),
-            },
-            0x1000..=0x3FFF => common::get_vram_cell(&self.mirroring, vram, addr).get(),
+            0x0000..=0x1FFF => self.chr_data.read(addr as usize),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .get(),
             _ => panic!("Invalid ppu read address"),
         }
     }
 
     fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
         match addr % 0x4000 {
-            0x0000..=0x1FFF => match &self.chr_data {
-                Chr::ROM(_) => {
-                    if cfg!(pedantic_af) {
-                        panic!("Attempt to write to CHRROM")
-                    }
-                }
-                Chr::RAM(r) => r[addr as usize].set(value),
-            },
-            0x1000..=0x3FFF => common::get_vram_cell(&self.mirroring, vram, addr).set(value),
+            0x0000..=0x1FFF => self.chr_data.write(addr as usize, value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.mirroring, vram, addr)
+                .set(value),
             _ => panic!("Invalid ppu write address"),
         }
     }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for UxROM {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.bank.save_state(w);
+        self.chr_data.save_state(w);
+        self.prg_ram.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.bank.load_state(r)?;
+        self.chr_data.load_state(r)?;
+        self.prg_ram.load_state(r)
+    }
 }
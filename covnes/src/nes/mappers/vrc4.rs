@@ -0,0 +1,266 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        vrc_irq::Irq,
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Konami VRC2 and VRC4 - mappers 21, 22, 23 and 25. VRC4 is VRC2 with a second PRG layout, more
+// mirroring modes, bigger CHR bank numbers and the same IRQ counter as VRC6.
+//
+// Each register is picked by the top 4 address lines plus two more, and which two depends on how
+// the board was wired. The mapper numbers each cover a couple of wirings, which NES 2.0 submappers
+// tell apart (see `MapperRegistry::default`). Most ROMs don't have one, so then both are decoded
+// at once. Games only write to their own board's addresses so they never clash.
+//
+// Mapper 22 is only ever VRC2a. 23 and 25 can be either chip, and are run as VRC4 as that does
+// everything the VRC2 games need. VRC2's microwire interface at $6000 (only used for an EEPROM
+// that was never fitted) is left out, and there's always 8kb of RAM there instead.
+
+// The address lines connected to the chip's A0 and A1 pins for each board
+pub const VRC4A: (u16, u16) = (0x02, 0x04);
+pub const VRC4C: (u16, u16) = (0x40, 0x80);
+pub const VRC2A: (u16, u16) = (0x02, 0x01);
+pub const VRC2B_VRC4F: (u16, u16) = (0x01, 0x02);
+pub const VRC4E: (u16, u16) = (0x04, 0x08);
+pub const VRC2C_VRC4B: (u16, u16) = (0x02, 0x01);
+pub const VRC4D: (u16, u16) = (0x08, 0x04);
+
+// For when the header doesn't say which board it is, which decodes every wiring the mapper number
+// could mean at once
+pub fn from_rom(rom: RomFile) -> Result<VRC4> {
+    let either = |(a0, a1): (u16, u16), (b0, b1): (u16, u16)| (a0 | b0, a1 | b1);
+    let register_lines = match rom.mapper {
+        21 => either(VRC4A, VRC4C),
+        22 => VRC2A,
+        23 => either(VRC2B_VRC4F, VRC4E),
+        _ => either(VRC2C_VRC4B, VRC4D),
+    };
+    from_rom_with_lines(rom, register_lines)
+}
+
+pub fn from_rom_with_lines(rom: RomFile, register_lines: (u16, u16)) -> Result<VRC4> {
+    let prg_banks = rom.prg_rom.len() / 8192;
+    if !rom.prg_rom.len().is_multiple_of(8192) || !(2..=32).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: rom.mapper,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if !d.len().is_multiple_of(1024) || d.len() > 512 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: rom.mapper,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(VRC4 {
+        vrc2: rom.mapper == 22,
+        register_lines,
+        prg_rom: rom.prg_rom,
+        prg_ram: vec![Cell::new(0); 0x2000],
+        chr,
+        prg_banks: Default::default(),
+        mirroring: Cell::new(0),
+        prg_swap_mode: Cell::new(false),
+        chr_banks: Default::default(),
+        irq: Irq::new(),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct VRC4 {
+    vrc2: bool,
+    register_lines: (u16, u16),
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr: ChrMem,
+    // Registers
+    prg_banks: [Cell<u8>; 2],
+    mirroring: Cell<u8>,
+    // Swaps the first PRG bank register to $C000, with $8000 fixed instead. VRC4 only.
+    prg_swap_mode: Cell<bool>,
+    // Written a nibble at a time
+    chr_banks: [Cell<u16>; 8],
+    irq: Irq,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl VRC4 {
+    // Turns the address into $x000-$x003, whichever lines the board uses
+    fn register(&self, addr: u16) -> u16 {
+        let (a0, a1) = self.register_lines;
+        let a0 = if addr & a0 != 0 { 1 } else { 0 };
+        let a1 = if addr & a1 != 0 { 2 } else { 0 };
+        (addr & 0xF000) | a0 | a1
+    }
+
+    fn get_mirroring(&self) -> MirrorMode {
+        // VRC2 only has the one bit
+        let mirroring = if self.vrc2 {
+            self.mirroring.get() & 1
+        } else {
+            self.mirroring.get() & 3
+        };
+        match mirroring {
+            0 => MirrorMode::Vertical,
+            1 => MirrorMode::Horizontal,
+            2 => MirrorMode::OneScreenLower,
+            _ => MirrorMode::OneScreenHigher,
+        }
+    }
+
+    fn get_mapped_prg_addr(&self, addr: u16) -> usize {
+        let banks = self.prg_rom.len() / 8192;
+        let bank = |i: usize| (self.prg_banks[i].get() & 0x1F) as usize;
+        let bank = match (addr, self.prg_swap_mode.get()) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => bank(0),
+            (0xA000..=0xBFFF, _) => bank(1),
+            (0x8000..=0x9FFF, true) | (0xC000..=0xDFFF, false) => banks - 2,
+            _ => banks - 1,
+        };
+        ((bank % banks) * 8192) | (addr as usize & 0x1FFF)
+    }
+
+    fn get_mapped_chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7].get() as usize;
+        // VRC2a ignores the lowest bit of the bank number
+        let bank = if self.vrc2 { bank >> 1 } else { bank };
+        self.chr.bank_addr(bank, 0x400, addr)
+    }
+
+    fn write_chr_bank(&self, register: u16, value: u8) {
+        // $B000-$E003, two banks to each and the low nibble first
+        let index = ((register >> 12) - 0xB) * 2 + ((register >> 1) & 1);
+        let bank = &self.chr_banks[index as usize];
+        let value = value as u16 & 0x1F;
+        if register & 1 == 0 {
+            bank.set((bank.get() & 0x1F0) | (value & 0xF));
+        } else {
+            bank.set((bank.get() & 0xF) | (value << 4));
+        }
+    }
+}
+
+impl CartridgeImpl for VRC4 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg_ram[(addr - 0x6000) as usize].get()),
+            0x8000..=0xFFFF => Some(self.prg_rom[self.get_mapped_prg_addr(addr)]),
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        match addr {
+            0x6000..=0x7FFF => self.prg_ram[(addr - 0x6000) as usize].set(value),
+            0x8000..=0xFFFF => {
+                let register = self.register(addr);
+                match register {
+                    0x8000..=0x8003 => self.prg_banks[0].set(value),
+                    0x9000..=0x9001 => self.mirroring.set(value),
+                    0x9002..=0x9003 => {
+                        if self.vrc2 {
+                            self.mirroring.set(value);
+                        } else {
+                            self.prg_swap_mode.set(value & 2 == 2);
+                        }
+                    }
+                    0xA000..=0xA003 => self.prg_banks[1].set(value),
+                    0xB000..=0xEFFF => self.write_chr_bank(register, value),
+                    // VRC2 has no IRQ
+                    0xF000..=0xFFFF if self.vrc2 => (),
+                    0xF000 => self
+                        .irq
+                        .latch
+                        .set((self.irq.latch.get() & 0xF0) | (value & 0xF)),
+                    0xF001 => self
+                        .irq
+                        .latch
+                        .set((self.irq.latch.get() & 0xF) | (value << 4)),
+                    0xF002 => self.irq.write_control(value),
+                    0xF003 => self.irq.acknowledge(),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr.read(self.get_mapped_chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr.write(self.get_mapped_chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn cpu_tick(&self) {
+        self.irq.tick();
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending.get()
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for VRC4 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr.save_state(w);
+        self.prg_banks.save_state(w);
+        self.mirroring.save_state(w);
+        self.prg_swap_mode.save_state(w);
+        self.chr_banks.save_state(w);
+        self.irq.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.prg_banks.load_state(r)?;
+        self.mirroring.load_state(r)?;
+        self.prg_swap_mode.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.irq.load_state(r)
+    }
+}
@@ -0,0 +1,444 @@
+use alloc::{vec, vec::Vec};
+use core::cell::Cell;
+
+use crate::{
+    error::{Error, Result},
+    nes::mappers::{
+        common::{ChrMem, ForcedMirroring, MirrorMode},
+        vrc_irq::Irq,
+        CartridgeImpl,
+    },
+    nes::state::{SaveState, StateReader, StateWriter},
+    romfiles::RomFile,
+};
+
+// Konami VRC6 - mapper 24 (VRC6a) and mapper 26 (VRC6b), which only differ by having the A0 and
+// A1 lines swapped.
+//
+// Not emulated: the nametable-from-CHR-ROM mode ($B003 bit 4), as no released game uses it.
+
+// An APU pulse channel at full volume comes out at about 0.15 after it's been through the APU's
+// mixer, and the VRC6 pulse channels at full volume are about as loud as that
+const MIX_LEVEL: f32 = 0.15 / 15.0;
+
+pub fn from_rom(rom: RomFile) -> Result<VRC6> {
+    let prg_banks = rom.prg_rom.len() / 16384;
+    if !rom.prg_rom.len().is_multiple_of(16384) || !(1..=16).contains(&prg_banks) {
+        return Err(Error::BadPrgRomSize {
+            mapper: rom.mapper,
+            size: rom.prg_rom.len(),
+        });
+    }
+
+    let chr = match rom.chr_rom {
+        Some(d) => {
+            if !d.len().is_multiple_of(1024) || d.len() > 256 * 1024 {
+                return Err(Error::BadChrRomSize {
+                    mapper: rom.mapper,
+                    size: d.len(),
+                });
+            } else {
+                ChrMem::ROM(d)
+            }
+        }
+        None => ChrMem::RAM(vec![Cell::new(0); 8192]),
+    };
+
+    Ok(VRC6 {
+        swap_address_lines: rom.mapper == 26,
+        prg_rom: rom.prg_rom,
+        prg_ram: vec![Cell::new(0); 0x2000],
+        chr,
+        prg_bank_16k: Cell::new(0),
+        prg_bank_8k: Cell::new(0),
+        ppu_control: Cell::new(0),
+        chr_banks: Default::default(),
+        irq: Irq::new(),
+        audio_control: Cell::new(0),
+        pulse1: Pulse::new(),
+        pulse2: Pulse::new(),
+        sawtooth: Sawtooth::new(),
+        forced_mirroring: ForcedMirroring::default(),
+    })
+}
+
+pub struct VRC6 {
+    swap_address_lines: bool,
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<Cell<u8>>,
+    chr: ChrMem,
+    // Registers
+    prg_bank_16k: Cell<u8>,
+    prg_bank_8k: Cell<u8>,
+    ppu_control: Cell<u8>,
+    chr_banks: [Cell<u8>; 8],
+    irq: Irq,
+    // Expansion audio
+    audio_control: Cell<u8>,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    sawtooth: Sawtooth,
+    forced_mirroring: ForcedMirroring,
+}
+
+impl VRC6 {
+    fn get_mirroring(&self) -> MirrorMode {
+        match (self.ppu_control.get() >> 2) & 0b11 {
+            0 => MirrorMode::Vertical,
+            1 => MirrorMode::Horizontal,
+            2 => MirrorMode::OneScreenLower,
+            _ => MirrorMode::OneScreenHigher,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.ppu_control.get() & 0x80 == 0x80
+    }
+
+    fn get_mapped_chr_addr(&self, addr: u16) -> usize {
+        let control = self.ppu_control.get();
+        let bank = |i: usize| self.chr_banks[i].get() as usize;
+        // 2kb banks either take A10 from the PPU or just repeat the same 1kb twice
+        let bank_2k = |i: usize| {
+            if control & 0x20 == 0x20 {
+                (bank(i) & !1) | ((addr as usize >> 10) & 1)
+            } else {
+                bank(i)
+            }
+        };
+
+        let slot = (addr as usize >> 10) & 7;
+        let bank = match (control & 0b11, slot) {
+            (0, _) => bank(slot),
+            (1, _) => bank_2k(slot >> 1),
+            (_, 0..=3) => bank(slot),
+            (_, _) => bank_2k(4 + ((slot - 4) >> 1)),
+        };
+
+        self.chr.bank_addr(bank, 0x400, addr)
+    }
+
+    fn audio_frequency_shift(&self) -> u8 {
+        let control = self.audio_control.get();
+        if control & 4 == 4 {
+            8
+        } else if control & 2 == 2 {
+            4
+        } else {
+            0
+        }
+    }
+}
+
+impl CartridgeImpl for VRC6 {
+    fn read_cpu(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_ram_enabled() {
+                    Some(self.prg_ram[(addr - 0x6000) as usize].get())
+                } else {
+                    None
+                }
+            }
+            0x8000..=0xBFFF => {
+                let base = (self.prg_bank_16k.get() & 0xF) as usize * 16384;
+                let addr = (base + (addr - 0x8000) as usize) % self.prg_rom.len();
+                Some(self.prg_rom[addr])
+            }
+            0xC000..=0xDFFF => {
+                let base = (self.prg_bank_8k.get() & 0x1F) as usize * 8192;
+                let addr = (base + (addr - 0xC000) as usize) % self.prg_rom.len();
+                Some(self.prg_rom[addr])
+            }
+            0xE000..=0xFFFF => {
+                // Fixed to the last 8kb bank
+                let base = self.prg_rom.len() - 8192;
+                Some(self.prg_rom[base + (addr - 0xE000) as usize])
+            }
+            _ => {
+                if cfg!(pedantic_af) {
+                    panic!("Bad read {:4X}", addr)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    fn write_cpu(&self, addr: u16, value: u8) {
+        let addr = if self.swap_address_lines {
+            (addr & !3) | ((addr & 1) << 1) | ((addr & 2) >> 1)
+        } else {
+            addr
+        };
+
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.prg_ram[(addr - 0x6000) as usize].set(value);
+            }
+            0x8000..=0xFFFF => match addr & 0xF003 {
+                0x8000..=0x8003 => self.prg_bank_16k.set(value),
+                0x9000..=0x9002 => self.pulse1.write((addr & 3) as u8, value),
+                0x9003 => self.audio_control.set(value),
+                0xA000..=0xA002 => self.pulse2.write((addr & 3) as u8, value),
+                0xB000..=0xB002 => self.sawtooth.write((addr & 3) as u8, value),
+                0xB003 => self.ppu_control.set(value),
+                0xC000..=0xC003 => self.prg_bank_8k.set(value),
+                0xD000..=0xD003 => self.chr_banks[(addr & 3) as usize].set(value),
+                0xE000..=0xE003 => self.chr_banks[4 + (addr & 3) as usize].set(value),
+                0xF000 => self.irq.latch.set(value),
+                0xF001 => self.irq.write_control(value),
+                0xF002 => self.irq.acknowledge(),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn read_ppu(&self, vram: &[Cell<u8>], addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr.read(self.get_mapped_chr_addr(addr)),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .get(),
+            _ => panic!("Invalid ppu read address"),
+        }
+    }
+
+    fn write_ppu(&self, vram: &[Cell<u8>], addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr.write(self.get_mapped_chr_addr(addr), value),
+            0x2000..=0x3FFF => self
+                .forced_mirroring
+                .vram_cell(self.get_mirroring(), vram, addr)
+                .set(value),
+            _ => panic!("Invalid ppu write address"),
+        }
+    }
+
+    fn cpu_tick(&self) {
+        self.irq.tick();
+
+        // The halt bit freezes all of the channels where they are
+        if self.audio_control.get() & 1 == 0 {
+            let shift = self.audio_frequency_shift();
+            self.pulse1.tick(shift);
+            self.pulse2.tick(shift);
+            self.sawtooth.tick(shift);
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq.pending.get()
+    }
+
+    fn audio_sample(&self) -> f32 {
+        let output = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        output as f32 * MIX_LEVEL
+    }
+
+    fn force_mirroring(&self, mode: Option<MirrorMode>) {
+        self.forced_mirroring.set(mode);
+    }
+}
+
+impl SaveState for VRC6 {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.prg_ram.save_state(w);
+        self.chr.save_state(w);
+        self.prg_bank_16k.save_state(w);
+        self.prg_bank_8k.save_state(w);
+        self.ppu_control.save_state(w);
+        self.chr_banks.save_state(w);
+        self.irq.save_state(w);
+        self.audio_control.save_state(w);
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.sawtooth.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.prg_ram.load_state(r)?;
+        self.chr.load_state(r)?;
+        self.prg_bank_16k.load_state(r)?;
+        self.prg_bank_8k.load_state(r)?;
+        self.ppu_control.load_state(r)?;
+        self.chr_banks.load_state(r)?;
+        self.irq.load_state(r)?;
+        self.audio_control.load_state(r)?;
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.sawtooth.load_state(r)
+    }
+}
+
+struct Pulse {
+    // MDDD VVVV - mode, duty, volume
+    control: Cell<u8>,
+    period: Cell<u16>,
+    enabled: Cell<bool>,
+    divider: Cell<u16>,
+    step: Cell<u8>,
+}
+
+impl Pulse {
+    fn new() -> Pulse {
+        Pulse {
+            control: Cell::new(0),
+            period: Cell::new(0),
+            enabled: Cell::new(false),
+            divider: Cell::new(0),
+            step: Cell::new(15),
+        }
+    }
+
+    fn write(&self, reg: u8, value: u8) {
+        match reg {
+            0 => self.control.set(value),
+            1 => self.period.set((self.period.get() & 0xF00) | value as u16),
+            _ => {
+                self.period
+                    .set((self.period.get() & 0xFF) | ((value as u16 & 0xF) << 8));
+                self.enabled.set(value & 0x80 == 0x80);
+                if !self.enabled.get() {
+                    self.step.set(15);
+                }
+            }
+        }
+    }
+
+    fn tick(&self, shift: u8) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        let divider = self.divider.get();
+        if divider == 0 {
+            self.divider.set(self.period.get() >> shift);
+            self.step.set(self.step.get().wrapping_sub(1) & 0xF);
+        } else {
+            self.divider.set(divider - 1);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        let control = self.control.get();
+        let volume = control & 0xF;
+        let duty = (control >> 4) & 0x7;
+        let constant = control & 0x80 == 0x80;
+
+        if self.enabled.get() && (constant || self.step.get() <= duty) {
+            volume
+        } else {
+            0
+        }
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.control.save_state(w);
+        self.period.save_state(w);
+        self.enabled.save_state(w);
+        self.divider.save_state(w);
+        self.step.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.control.load_state(r)?;
+        self.period.load_state(r)?;
+        self.enabled.load_state(r)?;
+        self.divider.load_state(r)?;
+        self.step.load_state(r)
+    }
+}
+
+struct Sawtooth {
+    rate: Cell<u8>,
+    period: Cell<u16>,
+    enabled: Cell<bool>,
+    divider: Cell<u16>,
+    step: Cell<u8>,
+    accumulator: Cell<u8>,
+}
+
+impl Sawtooth {
+    fn new() -> Sawtooth {
+        Sawtooth {
+            rate: Cell::new(0),
+            period: Cell::new(0),
+            enabled: Cell::new(false),
+            divider: Cell::new(0),
+            step: Cell::new(0),
+            accumulator: Cell::new(0),
+        }
+    }
+
+    fn write(&self, reg: u8, value: u8) {
+        match reg {
+            0 => self.rate.set(value & 0x3F),
+            1 => self.period.set((self.period.get() & 0xF00) | value as u16),
+            _ => {
+                self.period
+                    .set((self.period.get() & 0xFF) | ((value as u16 & 0xF) << 8));
+                self.enabled.set(value & 0x80 == 0x80);
+                if !self.enabled.get() {
+                    self.step.set(0);
+                    self.accumulator.set(0);
+                }
+            }
+        }
+    }
+
+    fn tick(&self, shift: u8) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        let divider = self.divider.get();
+        if divider != 0 {
+            self.divider.set(divider - 1);
+            return;
+        }
+
+        self.divider.set(self.period.get() >> shift);
+
+        // The accumulator goes up by the rate on every other step, and after 7 levels resets
+        let step = self.step.get() + 1;
+        if step == 14 {
+            self.step.set(0);
+            self.accumulator.set(0);
+        } else {
+            self.step.set(step);
+            if step.is_multiple_of(2) {
+                self.accumulator
+                    .set(self.accumulator.get().wrapping_add(self.rate.get()));
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator.get() >> 3
+    }
+}
+
+impl SaveState for Sawtooth {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.rate.save_state(w);
+        self.period.save_state(w);
+        self.enabled.save_state(w);
+        self.divider.save_state(w);
+        self.step.save_state(w);
+        self.accumulator.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.rate.load_state(r)?;
+        self.period.load_state(r)?;
+        self.enabled.load_state(r)?;
+        self.divider.load_state(r)?;
+        self.step.load_state(r)?;
+        self.accumulator.load_state(r)
+    }
+}
@@ -0,0 +1,99 @@
+use core::cell::Cell;
+
+use crate::{
+    error::Result,
+    nes::state::{SaveState, StateReader, StateWriter},
+};
+
+// The IRQ counter shared by the later VRCs (VRC4 and VRC6). In scanline mode the prescaler
+// approximates the 113 2/3 CPU cycles per scanline by counting down by 3 from 341.
+pub struct Irq {
+    pub latch: Cell<u8>,
+    counter: Cell<u8>,
+    prescaler: Cell<i16>,
+    enabled: Cell<bool>,
+    enabled_after_ack: Cell<bool>,
+    cycle_mode: Cell<bool>,
+    pub pending: Cell<bool>,
+}
+
+impl Irq {
+    pub fn new() -> Irq {
+        Irq {
+            latch: Cell::new(0),
+            counter: Cell::new(0),
+            prescaler: Cell::new(341),
+            enabled: Cell::new(false),
+            enabled_after_ack: Cell::new(false),
+            cycle_mode: Cell::new(false),
+            pending: Cell::new(false),
+        }
+    }
+
+    pub fn write_control(&self, value: u8) {
+        self.pending.set(false);
+        self.enabled_after_ack.set(value & 1 == 1);
+        self.enabled.set(value & 2 == 2);
+        self.cycle_mode.set(value & 4 == 4);
+
+        if self.enabled.get() {
+            self.counter.set(self.latch.get());
+            self.prescaler.set(341);
+        }
+    }
+
+    pub fn acknowledge(&self) {
+        self.pending.set(false);
+        self.enabled.set(self.enabled_after_ack.get());
+    }
+
+    pub fn tick(&self) {
+        if !self.enabled.get() {
+            return;
+        }
+
+        if self.cycle_mode.get() {
+            self.clock();
+        } else {
+            let prescaler = self.prescaler.get() - 3;
+            if prescaler <= 0 {
+                self.prescaler.set(prescaler + 341);
+                self.clock();
+            } else {
+                self.prescaler.set(prescaler);
+            }
+        }
+    }
+
+    fn clock(&self) {
+        let counter = self.counter.get();
+        if counter == 0xFF {
+            self.counter.set(self.latch.get());
+            self.pending.set(true);
+        } else {
+            self.counter.set(counter + 1);
+        }
+    }
+}
+
+impl SaveState for Irq {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.latch.save_state(w);
+        self.counter.save_state(w);
+        self.prescaler.save_state(w);
+        self.enabled.save_state(w);
+        self.enabled_after_ack.save_state(w);
+        self.cycle_mode.save_state(w);
+        self.pending.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.latch.load_state(r)?;
+        self.counter.load_state(r)?;
+        self.prescaler.load_state(r)?;
+        self.enabled.load_state(r)?;
+        self.enabled_after_ack.load_state(r)?;
+        self.cycle_mode.load_state(r)?;
+        self.pending.load_state(r)
+    }
+}
@@ -1,36 +1,161 @@
+pub mod apu;
 pub mod cpu;
 pub mod dma;
+pub mod input_recorder;
 pub mod io;
 pub mod mappers;
 pub mod palette;
 pub mod ppu;
+mod region;
+pub mod state;
+pub mod test_pattern;
 
-use std::cell::Cell;
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::{cell::Cell, ops::Range};
 
+use apu::APU;
 use cpu::{CpuHostAccess, CPU};
 use dma::DMA;
 use io::IO;
+use palette::Palette;
 use ppu::{PPUHostAccess, PPU};
+use state::{SaveState, StateReader, StateWriter};
 
-use self::mappers::Cartridge;
+#[cfg(feature = "fds")]
+use self::mappers::fds::Fds;
+use self::mappers::{common::MirrorMode, Cartridge};
+pub use self::region::Region;
+#[cfg(feature = "fds")]
+use crate::fds::FdsImage;
+use crate::{
+    error::{Error, Result},
+    romfiles::RomFile,
+};
 
+/// Size in bytes of a full RGB24 frame (256x240 pixels)
+pub const FRAME_BUFFER_SIZE: usize = 256 * 240 * 3;
+
+/// How many pixels to crop from each edge of the frame when presenting it.
+///
+/// The PPU always renders the full 256x240 picture, but TVs hid some of it behind the bezel.
+/// Cropping is only ever applied to the output, see `Nes::step_frame_into_cropped`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    /// No cropping at all
+    pub const NONE: Overscan = Overscan {
+        top: 0,
+        bottom: 0,
+        left: 0,
+        right: 0,
+    };
+
+    /// The usual 256x224 crop, hiding the top and bottom 8 scanlines
+    pub const NTSC: Overscan = Overscan {
+        top: 8,
+        bottom: 8,
+        left: 0,
+        right: 0,
+    };
+
+    pub fn width(&self) -> usize {
+        256 - self.left - self.right
+    }
+
+    pub fn height(&self) -> usize {
+        240 - self.top - self.bottom
+    }
+
+    /// Size in bytes of a cropped RGB24 frame
+    pub fn frame_size(&self) -> usize {
+        self.width() * self.height() * 3
+    }
+}
+
+/// A single read or write on the CPU bus, see `Nes::bus_hook`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+/// A callback for `Nes::bus_hook`
+pub type BusHook = Box<dyn Fn(BusAccess) + Send>;
+
+/// A callback for `Nes::instruction_hook`, given the instruction's `Nes::trace_line`
+pub type InstructionHook = Box<dyn Fn(&str) + Send>;
+
+/// Which accesses to a watched address get logged, see `Nes::watch`
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
-pub enum Cycle {
-    T1,
-    T2,
-    T3,
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::ReadWrite => true,
+        }
+    }
+}
+
+/// An access to a watched address, from `Nes::take_watch_log`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct WatchEvent {
+    pub addr: u16,
+    pub write: bool,
+    /// The address of the instruction that made the access
+    pub pc: u16,
+    /// The value before the access. For reads this is the same as `new`. It's None for writes to
+    /// the PPU and APU registers, which can't be read back without side effects.
+    pub old: Option<u8>,
+    pub new: u8,
+    /// CPU cycles since power on
+    pub cycle: u64,
 }
 
 pub struct Nes<I: IO> {
     pub io: I,
     pub cpu: CPU,
     pub ppu: PPU,
+    pub apu: APU,
     pub dma: DMA,
     pub cartridge: Cartridge,
     pub cpu_ram: Cell<[u8; 2048]>,
-    pub cycle: Cell<Cycle>,
+    // What RAM is filled with on power on, from `new_seeded`. Without a seed it's all zeros.
+    ram_seed: Option<u64>,
     pub vram: Cell<[u8; 2048]>,
     pub controller_latch: Cell<bool>,
+    // Called on every CPU bus access when set, including DMA and `read_u8`/`write_u8`. Reads
+    // see the value that was read.
+    pub bus_hook: Option<BusHook>,
+    // Called just before each instruction starts when set, with its trace line. Making the line
+    // takes much longer than running the instruction, so this slows everything down a lot.
+    pub instruction_hook: Option<InstructionHook>,
+    watches: Vec<(u16, WatchKind)>,
+    watch_log: Cell<Vec<WatchEvent>>,
+    // Where the instruction currently being run started, for the watch log
+    instruction_pc: Cell<u16>,
+    cpu_cycles: Cell<u64>,
+    // Master clock ticks since the start of the current CPU cycle. The CPU and PPU clocks are both
+    // divided down from the master clock, by amounts that depend on the region.
+    master_clock: Cell<u8>,
+    // The last value read or written by the CPU, which is what reads of unmapped addresses see
+    open_bus: Cell<u8>,
+    frame_count: Cell<u64>,
+    frame_buffer: Box<Cell<[u8; FRAME_BUFFER_SIZE]>>,
+    palette: Box<Palette>,
 }
 
 impl<I: IO> Nes<I> {
@@ -38,6 +163,7 @@ impl<I: IO> Nes<I> {
         let cartridge = Cartridge::NotConnected;
         let cpu = CPU::new();
         let ppu = PPU::new();
+        let apu = APU::new();
         let dma = DMA::new();
         let cpu_ram = Cell::new([0; 2048]);
         let vram = Cell::new([0; 2048]);
@@ -45,20 +171,276 @@ impl<I: IO> Nes<I> {
         Nes {
             io,
             cpu_ram,
+            ram_seed: None,
             ppu,
+            apu,
             dma,
             cartridge,
             cpu,
             vram,
-            cycle: Cell::new(Cycle::T1),
             controller_latch: Cell::new(false),
+            bus_hook: None,
+            instruction_hook: None,
+            watches: Vec::new(),
+            watch_log: Cell::new(Vec::new()),
+            instruction_pc: Cell::new(0),
+            cpu_cycles: Cell::new(0),
+            master_clock: Cell::new(0),
+            open_bus: Cell::new(0),
+            frame_count: Cell::new(0),
+            frame_buffer: Box::new(Cell::new([0; FRAME_BUFFER_SIZE])),
+            palette: Box::new(Palette::BUILT_IN),
         }
     }
 
+    /// Like `new`, but RAM starts off filled with a pseudo-random pattern instead of zeros.
+    ///
+    /// Real consoles power on with nearly random RAM, which some games (mostly by accident) depend
+    /// on. The pattern only depends on `seed`, so the same seed always gives the same RAM, and
+    /// movies and test runs made with it play back exactly. `power_cycle` uses the same seed
+    /// again. It isn't any other emulator's pattern, so a movie made elsewhere that depends on
+    /// what's in RAM at power on still won't line up.
+    pub fn new_seeded(io: I, seed: u64) -> Nes<I> {
+        let mut nes = Nes::new(io);
+        nes.ram_seed = Some(seed);
+        nes.cpu_ram.set(nes.power_on_ram());
+        nes
+    }
+
+    /// Presses the reset button.
+    ///
+    /// RAM, VRAM and the palette are kept. The PPU goes back to the start of a frame (scanline 0,
+    /// dot 0) and the CPU runs its 7 cycle reset sequence from the next tick, so the first
+    /// instruction starts on dot 21.
     pub fn reset(&self) {
         self.cpu.reset();
         self.ppu.reset();
+        self.apu.reset();
         self.dma.reset();
+        // So the reset sequence lines up with the PPU the same way every time
+        self.master_clock.set(0);
+    }
+
+    /// Turns the console off and on again.
+    ///
+    /// Unlike `reset` this clears RAM (or refills it, with `new_seeded`) and the frame count. The
+    /// cartridge is left as it is.
+    pub fn power_cycle(&mut self) {
+        self.cpu = CPU::new();
+        let trace_hook = self.ppu.trace_hook.take();
+        let region = self.region();
+        self.ppu = PPU::new();
+        self.ppu.trace_hook = trace_hook;
+        self.ppu.region.set(region);
+        self.apu = APU::new();
+        self.dma = DMA::new();
+        self.cpu_ram.set(self.power_on_ram());
+        self.vram.set([0; 2048]);
+        self.controller_latch.set(false);
+        self.open_bus.set(0);
+        self.frame_count.set(0);
+        self.cpu_cycles.set(0);
+        self.reset();
+    }
+
+    fn power_on_ram(&self) -> [u8; 2048] {
+        let mut ram = [0; 2048];
+        if let Some(seed) = self.ram_seed {
+            // xorshift64*, after a splitmix64 step so that nearby seeds (and 0) still give
+            // unrelated patterns
+            let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            state ^= state >> 31;
+            for chunk in ram.chunks_mut(8) {
+                state ^= state >> 12;
+                state ^= state << 25;
+                state ^= state >> 27;
+                let bytes = state.wrapping_mul(0x2545_F491_4F6C_DD1D).to_le_bytes();
+                chunk.copy_from_slice(&bytes);
+            }
+        }
+        ram
+    }
+
+    pub fn region(&self) -> Region {
+        self.ppu.region.get()
+    }
+
+    /// Switches between NTSC and Dendy timing. This is best done before running anything, as the
+    /// PPU isn't moved to a valid scanline for the new region.
+    pub fn set_region(&self, region: Region) {
+        self.ppu.region.set(region);
+    }
+
+    /// Changes the colours the picture is drawn in, from the next pixel on. It isn't part of save
+    /// states, and stays the same when the cartridge is changed.
+    pub fn set_palette(&mut self, palette: Palette) {
+        *self.palette = palette;
+    }
+
+    /// The colours pixels are drawn in, which is `Palette::BUILT_IN` unless it's been changed.
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// The number of frames run by `step_frame` since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count.get()
+    }
+
+    /// The number of CPU cycles run since power on
+    pub fn cpu_cycles(&self) -> u64 {
+        self.cpu_cycles.get()
+    }
+
+    /// The value left on the CPU's data bus by the last read or write
+    pub fn open_bus(&self) -> u8 {
+        self.open_bus.get()
+    }
+
+    /// Saves the state of the console, so it can be restored later with `load_state`.
+    ///
+    /// This first runs the console to the end of the current CPU instruction, as states are only
+    /// taken between instructions. The IO, region and frame buffer aren't part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        while !(self.at_cpu_cycle_start() && self.cpu.is_at_instruction()) {
+            self.tick();
+        }
+
+        self.write_state()
+    }
+
+    /// Restores a state from `save_state`.
+    ///
+    /// The state must have been saved with the same cartridge inserted, and fails with
+    /// `Error::BadSaveState` otherwise. If it fails the console carries on from where it was.
+    pub fn load_state(&self, data: &[u8]) -> Result<()> {
+        // The current state is the same size as any valid state for this cartridge, and acts as
+        // a backup if something in `data` turns out to be invalid
+        let backup = self.save_state();
+        if data.len() != backup.len() || !data.starts_with(state::MAGIC) {
+            return Err(Error::BadSaveState);
+        }
+
+        let result = self.read_state(data);
+        if result.is_err() {
+            self.read_state(&backup)
+                .expect("Couldn't restore the backup state");
+        }
+        result
+    }
+
+    fn write_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(state::MAGIC);
+        w.u8(state::VERSION);
+        self.controller_latch.save_state(&mut w);
+        self.open_bus.save_state(&mut w);
+        self.frame_count.save_state(&mut w);
+        self.cpu_cycles.save_state(&mut w);
+        self.master_clock.save_state(&mut w);
+        self.cpu_ram.save_state(&mut w);
+        self.vram.save_state(&mut w);
+        self.cpu.save_state(&mut w);
+        self.apu.save_state(&mut w);
+        self.dma.save_state(&mut w);
+        self.ppu.save_state(&mut w);
+        self.cartridge.save_state(&mut w);
+        w.into_inner()
+    }
+
+    fn read_state(&self, data: &[u8]) -> Result<()> {
+        let mut r = StateReader::new(data);
+        if r.bytes(state::MAGIC.len())? != state::MAGIC || r.u8()? != state::VERSION {
+            return Err(Error::BadSaveState);
+        }
+        self.controller_latch.load_state(&mut r)?;
+        self.open_bus.load_state(&mut r)?;
+        self.frame_count.load_state(&mut r)?;
+        self.cpu_cycles.load_state(&mut r)?;
+        self.master_clock.load_state(&mut r)?;
+        self.cpu_ram.load_state(&mut r)?;
+        self.vram.load_state(&mut r)?;
+        self.cpu.load_state(&mut r)?;
+        self.apu.load_state(&mut r)?;
+        self.dma.load_state(&mut r)?;
+        self.ppu.load_state(&mut r)?;
+        self.cartridge.load_state(&mut r)?;
+        r.finish()
+    }
+
+    /// Starts logging accesses to `addr` to the watch log, replacing any existing watch on it.
+    ///
+    /// Only `addr` itself is watched, not any mirrors of it. Accesses made through `read_u8` and
+    /// `write_u8` are logged too.
+    pub fn watch(&mut self, addr: u16, kind: WatchKind) {
+        self.unwatch(addr);
+        self.watches.push((addr, kind));
+    }
+
+    pub fn unwatch(&mut self, addr: u16) {
+        self.watches.retain(|&(a, _)| a != addr);
+    }
+
+    /// Returns everything logged since the last call, oldest first
+    pub fn take_watch_log(&self) -> Vec<WatchEvent> {
+        self.watch_log.take()
+    }
+
+    fn is_watched(&self, addr: u16, write: bool) -> bool {
+        self.watches
+            .iter()
+            .any(|&(a, kind)| a == addr && kind.matches(write))
+    }
+
+    fn log_watched(&self, addr: u16, write: bool, old: Option<u8>, new: u8) {
+        let mut log = self.watch_log.take();
+        log.push(WatchEvent {
+            addr,
+            write,
+            pc: self.instruction_pc.get(),
+            old,
+            new,
+            cycle: self.cpu_cycles.get(),
+        });
+        self.watch_log.set(log);
+    }
+
+    // What's at `addr`, if it can be read without any side effects
+    pub(crate) fn peek(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..=0x1FFF => Some(self.ram()[addr as usize % 0x800].get()),
+            0x4020..=0xFFFF => self.cartridge.peek_cpu(addr),
+            _ => None,
+        }
+    }
+
+    /// Inserts a ROM with one of the built in mappers and presses reset, which is all most
+    /// frontends need. The cartridge already inserted is kept if the ROM's mapper isn't supported.
+    ///
+    /// For mappers from outside this crate, make the cartridge with a `MapperRegistry` and use
+    /// `insert_cartridge` instead.
+    pub fn load_rom(&mut self, rom: RomFile) -> Result<()> {
+        self.insert_cartridge(mappers::from_rom(rom)?);
+        self.reset();
+        Ok(())
+    }
+
+    /// Plugs in a Famicom Disk System with `bios` (the 8kb ROM from the RAM adapter), puts the
+    /// first side of `image` in the drive and presses reset. The cartridge already inserted is
+    /// kept if the BIOS is the wrong size.
+    #[cfg(feature = "fds")]
+    pub fn load_fds(&mut self, bios: Vec<u8>, image: &FdsImage) -> Result<()> {
+        self.insert_cartridge(Cartridge::boxed(Fds::new(bios, image)?)?);
+        self.reset();
+        Ok(())
+    }
+
+    /// The disk system's drive, for changing disks, if that's what's plugged in
+    #[cfg(feature = "fds")]
+    pub fn fds(&self) -> Option<&Fds> {
+        self.cartridge.as_fds()
     }
 
     pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
@@ -69,6 +451,44 @@ impl<I: IO> Nes<I> {
         self.cartridge = Cartridge::NotConnected;
     }
 
+    /// Whether there's a cartridge in. Without one there's nothing for the CPU to run, so
+    /// frontends might want to show `test_pattern` instead.
+    pub fn has_cartridge(&self) -> bool {
+        !matches!(self.cartridge, Cartridge::NotConnected)
+    }
+
+    /// Forces the nametable mirroring to `mode` whatever the cartridge says, or hands control back
+    /// to the cartridge with `None`. This is a debugging aid for checking whether something is a
+    /// mirroring bug, not a setting - games that switch mirroring themselves will break with it
+    /// on. It lasts until the cartridge is changed, isn't saved in save states and doesn't do
+    /// anything with mappers that map the nametables themselves (MMC5).
+    pub fn set_mirroring(&self, mode: Option<MirrorMode>) {
+        self.cartridge.force_mirroring(mode);
+    }
+
+    /// Reads a byte through the CPU bus, exactly as the CPU would.
+    ///
+    /// This has all of the side effects of a real read, e.g. reading $2002 clears the vblank
+    /// flag and reading $4016 shifts the controller.
+    pub fn read_u8(&self, addr: u16) -> u8 {
+        CpuHostAccess::read(self, addr)
+    }
+
+    /// Writes a byte through the CPU bus, exactly as the CPU would.
+    pub fn write_u8(&self, addr: u16, value: u8) {
+        CpuHostAccess::write(self, addr, value)
+    }
+
+    /// Reads a little-endian word (e.g. a pointer or interrupt vector) through the CPU bus.
+    ///
+    /// Like `read_u8` this has side effects. The high byte is read from `addr + 1`, wrapping
+    /// around at the top of the address space.
+    pub fn read_u16_le(&self, addr: u16) -> u16 {
+        let lo = self.read_u8(addr) as u16;
+        let hi = self.read_u8(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
     fn ram(&self) -> &[Cell<u8>] {
         let ram: &Cell<[u8]> = &self.cpu_ram;
         ram.as_slice_of_cells()
@@ -79,32 +499,33 @@ impl<I: IO> Nes<I> {
         ram.as_slice_of_cells()
     }
 
+    // Runs one PPU dot, and a CPU cycle if one starts during it
     pub fn tick(&self) {
-        let next = match self.cycle.get() {
-            Cycle::T1 => {
-                self.perform_cpu_cycle();
-                self.ppu.tick(self);
-                // println!("{:02X} ({}, {}) {:02X}: {:?}", self.cpu.pc.get(), self.ppu.dot.get(), self.ppu.scanline.get(), self.cpu.s.get(), self.cpu.state.get());
-                Cycle::T2
-            }
-            Cycle::T2 => {
-                self.cpu.poll_interrupts();
-                self.ppu.tick(self);
-                Cycle::T3
-            }
-            Cycle::T3 => {
-                self.ppu.tick(self);
-                Cycle::T1
-            }
-        };
+        let region = self.region();
+        let dot = region.master_clocks_per_dot();
+        let phase = self.master_clock.get();
 
-        self.cycle.set(next)
+        if phase < dot {
+            self.perform_cpu_cycle();
+        } else if phase < 2 * dot {
+            // Interrupts are polled a dot into the CPU cycle
+            self.cpu.poll_interrupts();
+        }
+        self.ppu.tick(self);
+
+        self.master_clock
+            .set((phase + dot) % region.master_clocks_per_cpu_cycle());
+    }
+
+    // Whether the next tick starts a CPU cycle
+    fn at_cpu_cycle_start(&self) -> bool {
+        self.master_clock.get() < self.region().master_clocks_per_dot()
     }
 
     pub fn tick_cpu(&self) {
         self.tick();
 
-        while self.cycle.get() != Cycle::T1 {
+        while !self.at_cpu_cycle_start() {
             self.tick();
         }
     }
@@ -121,6 +542,114 @@ impl<I: IO> Nes<I> {
         ticks
     }
 
+    /// Runs `n` CPU instructions, returning the disassembly of each one from just before it ran
+    /// (see `disassemble`).
+    ///
+    /// If the CPU is part way through an instruction that one is finished first, and isn't
+    /// counted. An interrupt being taken counts as an instruction, but shows up as the
+    /// instruction it interrupted.
+    pub fn step_instructions(&self, n: usize) -> Vec<String> {
+        while !(self.at_cpu_cycle_start() && self.cpu.is_at_instruction()) {
+            self.tick();
+        }
+
+        (0..n)
+            .map(|_| {
+                let text = self.disassemble(self.cpu.pc.get());
+                self.step_cpu_instruction();
+                text
+            })
+            .collect()
+    }
+
+    /// Disassembles the instruction at `addr`, in the same format as the start of each line of
+    /// nestest.log: "C000  4C F5 C5  JMP $C5F5".
+    ///
+    /// Only RAM and the cartridge are read, as reading anything else could have side effects.
+    /// Bytes that couldn't be read show up as "??".
+    pub fn disassemble(&self, addr: u16) -> String {
+        cpu::disassemble(addr, |addr| self.peek(addr))
+    }
+
+    /// A line of a trace log for the instruction about to run, in the same format as nestest.log
+    /// (see `CPU::trace_line`). The cycle count is `cpu_cycles`, so it starts at 7 after the
+    /// reset sequence like nestest.log does.
+    ///
+    /// The instruction is disassembled like `disassemble`, so this has no side effects.
+    pub fn trace_line(&self) -> String {
+        self.trace_line_at(self.cpu_cycles.get())
+    }
+
+    fn trace_line_at(&self, cycles: u64) -> String {
+        self.cpu.trace_line(
+            |addr| self.peek(addr),
+            self.ppu.dot.get(),
+            self.ppu.scanline.get(),
+            cycles,
+        )
+    }
+
+    /// Copies out `range` of the CPU's address space, for attaching to bug reports.
+    ///
+    /// Like `disassemble` only RAM and the cartridge are read, and the cartridge through
+    /// `CartridgeImpl::peek_cpu`, so this has no side effects.
+    /// Anything else (the PPU and APU registers, and unmapped addresses) comes out as 0.
+    pub fn dump_memory(&self, range: Range<u16>) -> Vec<u8> {
+        range.map(|addr| self.peek(addr).unwrap_or(0)).collect()
+    }
+
+    /// Copies out the console's 2kb of nametable RAM. CHR and any nametables on the cartridge
+    /// aren't included.
+    pub fn dump_vram(&self) -> Vec<u8> {
+        self.vram.get().to_vec()
+    }
+
+    /// Copies out all 256 bytes of OAM exactly as they are, 4 bytes to a sprite.
+    pub fn dump_oam(&self) -> Vec<u8> {
+        self.ppu.oam.get().to_vec()
+    }
+
+    /// Copies out the 32 palette entries, as in `PPU::palette_ram`.
+    pub fn dump_cgram(&self) -> Vec<u8> {
+        self.ppu.palette_ram().to_vec()
+    }
+
+    /// Runs until the PPU moves on to the next scanline, returning the number of ticks taken.
+    ///
+    /// This is usually 341, but will be 340 for the pre-render line of odd frames when the
+    /// skipped dot kicks in.
+    pub fn step_scanline(&self) -> usize {
+        let scanline = self.ppu.scanline.get();
+        self.tick();
+        let mut ticks = 1;
+
+        while self.ppu.scanline.get() == scanline {
+            self.tick();
+            ticks += 1;
+        }
+
+        ticks
+    }
+
+    /// Runs until the PPU has just entered vblank, returning the number of ticks taken.
+    ///
+    /// At this point the vblank flag in $2002 is set and any NMI has been raised, but not yet
+    /// handled by the CPU. `IO::vblank_start` is called on the same tick. `step_frame` stops one
+    /// tick earlier, just before vblank starts, so calling this after `step_frame` only takes
+    /// one tick.
+    pub fn step_to_vblank(&self) -> usize {
+        let vblank_scanline = self.ppu.region.get().vblank_scanline();
+        self.tick();
+        let mut ticks = 1;
+
+        while !(self.ppu.scanline.get() == vblank_scanline && self.ppu.dot.get() == 2) {
+            self.tick();
+            ticks += 1;
+        }
+
+        ticks
+    }
+
     pub fn step_frame(&self) -> usize {
         self.tick();
         let mut ticks = 1;
@@ -129,23 +658,86 @@ impl<I: IO> Nes<I> {
             self.tick();
             ticks += 1;
         }
+        self.frame_count.set(self.frame_count.get() + 1);
         // println!("{} {:?} {} {}", self.cpu.pc.get(), self.ppu.ppuctrl.get(), self.ppu.dot.get(), self.ppu.scanline.get());
 
         ticks
     }
 
+    /// Runs until the end of the next frame and copies it into `buf` as RGB24, row by row.
+    ///
+    /// This is an alternative to handling `IO::set_pixel` for embedders that just want whole
+    /// frames. `buf` must be exactly `FRAME_BUFFER_SIZE` bytes long.
+    pub fn step_frame_into(&self, buf: &mut [u8]) -> usize {
+        self.step_frame_into_cropped(buf, Overscan::NONE)
+    }
+
+    /// Like `step_frame_into`, but leaves out the pixels hidden by `overscan`.
+    ///
+    /// `buf` must be exactly `overscan.frame_size()` bytes long.
+    pub fn step_frame_into_cropped(&self, buf: &mut [u8], overscan: Overscan) -> usize {
+        assert!(
+            overscan.top + overscan.bottom < 240 && overscan.left + overscan.right < 256,
+            "Overscan crops the whole frame"
+        );
+        assert_eq!(
+            buf.len(),
+            overscan.frame_size(),
+            "Frame buffer is the wrong size"
+        );
+
+        let ticks = self.step_frame();
+        let frame = self.frame_buffer();
+        let row_len = overscan.width() * 3;
+        for (row, out) in buf.chunks_exact_mut(row_len).enumerate() {
+            let start = ((overscan.top + row) * 256 + overscan.left) * 3;
+            for (b, c) in out.iter_mut().zip(&frame[start..start + row_len]) {
+                *b = c.get();
+            }
+        }
+
+        ticks
+    }
+
+    fn frame_buffer(&self) -> &[Cell<u8>] {
+        let buf: &Cell<[u8]> = &*self.frame_buffer;
+        buf.as_slice_of_cells()
+    }
+
     fn perform_cpu_cycle(&self) {
+        self.cpu_cycles.set(self.cpu_cycles.get() + 1);
         let should_tick_cpu = self.dma.tick(&self);
         if should_tick_cpu {
+            if self.cpu.is_at_instruction() {
+                self.instruction_pc.set(self.cpu.pc.get());
+                if let Some(hook) = &self.instruction_hook {
+                    // The cycle that's starting has already been counted
+                    hook(&self.trace_line_at(self.cpu_cycles.get() - 1));
+                }
+            }
             self.cpu.tick(self);
         }
+
+        self.apu.tick();
+        self.cartridge.cpu_tick();
+        if self.cartridge.irq() || self.apu.irq() {
+            self.cpu.set_irq();
+        } else {
+            self.cpu.clear_irq();
+        }
+
+        // Expansion audio comes back into the console through the cartridge connector and is
+        // mixed with the APU's output there
+        self.io.push_audio_sample(
+            self.apu.output() + self.cartridge.audio_sample() * apu::LEVEL_SCALE,
+        );
     }
 }
 
 impl<I: IO> CpuHostAccess for Nes<I> {
     fn read(&self, addr: u16) -> u8 {
         let ram = self.ram();
-        match addr {
+        let value = match addr {
             0x0000..=0x07FF => ram[addr as usize].get(),
             0x0800..=0x0FFF => ram[(addr - 0x800) as usize].get(),
             0x1000..=0x17FF => ram[(addr - 0x1000) as usize].get(),
@@ -154,23 +746,46 @@ impl<I: IO> CpuHostAccess for Nes<I> {
                 let ppu_reg = ((addr - 0x2000) % 8) as u8;
                 self.ppu.reg_read(self, ppu_reg)
             }
-            0x4016 => {
-                // TODO open bus if I ever implement that
-                self.io.controller_port_1_read().bits()
-            }
-            0x4017 => self.io.controller_port_2_read().bits(),
-            0x4000..=0x4017 => {
-                // println!("APU Read: 0x{:04x}", addr);
-                0
-            }
-            0x4018..=0x401F => {
-                panic!("Read from CPU test stuff");
-            }
-            0x4020..=0xFFFF => self.cartridge.read_cpu(addr),
+            // Only D0-D4 are connected to the controller ports, the rest is open bus
+            0x4016 => self.io.controller_port_1_read().bits() | (self.open_bus.get() & 0xE0),
+            0x4017 => self.io.controller_port_2_read().bits() | (self.open_bus.get() & 0xE0),
+            0x4015 => self.apu.read_status() | (self.open_bus.get() & 0x20),
+            // The rest of the APU's registers are write only, and $4018-$401F is only wired up
+            // to the CPU's test mode, which retail consoles can't enter
+            0x4000..=0x4014 | 0x4018..=0x401F => self.open_bus.get(),
+            0x4020..=0xFFFF => self
+                .cartridge
+                .read_cpu(addr)
+                .unwrap_or_else(|| self.open_bus.get()),
+        };
+
+        self.open_bus.set(value);
+        if let Some(hook) = &self.bus_hook {
+            hook(BusAccess {
+                addr,
+                value,
+                write: false,
+            });
+        }
+        if self.is_watched(addr, false) {
+            self.log_watched(addr, false, Some(value), value);
         }
+        value
     }
 
     fn write(&self, addr: u16, value: u8) {
+        self.open_bus.set(value);
+        if let Some(hook) = &self.bus_hook {
+            hook(BusAccess {
+                addr,
+                value,
+                write: true,
+            });
+        }
+        // Only peeking for a watched address, as it's on every write
+        if self.is_watched(addr, true) {
+            self.log_watched(addr, true, self.peek(addr), value);
+        }
         let ram = self.ram();
         match addr {
             0x0000..=0x07FF => ram[addr as usize].set(value),
@@ -180,6 +795,7 @@ impl<I: IO> CpuHostAccess for Nes<I> {
             0x2000..=0x3FFF => {
                 let ppu_reg = ((addr - 0x2000) % 8) as u8;
                 self.ppu.reg_write(self, ppu_reg, value);
+                self.cartridge.ppu_register_write(ppu_reg, value);
             }
             0x4014 => self.dma.trigger_oamdma(value),
             0x4016 => {
@@ -190,12 +806,9 @@ impl<I: IO> CpuHostAccess for Nes<I> {
                     self.io.controller_latch_change(new_l);
                 }
             }
-            0x4000..=0x4017 => {
-                // println!("APU Write: 0x{:04x} {}", addr, value);
-            }
-            0x4018..=0x401F => {
-                panic!("Write to CPU test stuff");
-            }
+            0x4000..=0x4017 => self.apu.write(addr, value),
+            // CPU test mode registers, which do nothing on retail consoles
+            0x4018..=0x401F => (),
             0x4020..=0xFFFF => {
                 self.cartridge.write_cpu(addr, value);
             }
@@ -205,10 +818,12 @@ impl<I: IO> CpuHostAccess for Nes<I> {
 
 impl<I: IO> PPUHostAccess for Nes<I> {
     fn ppu_read(&self, addr: u16) -> u8 {
+        self.cartridge.notify_ppu_addr(addr);
         self.cartridge.read_ppu(self.vram(), addr)
     }
 
     fn ppu_write(&self, addr: u16, value: u8) {
+        self.cartridge.notify_ppu_addr(addr);
         self.cartridge.write_ppu(self.vram(), addr, value)
     }
 
@@ -220,7 +835,19 @@ impl<I: IO> PPUHostAccess for Nes<I> {
         self.cpu.clear_nmi();
     }
 
-    fn ppu_set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
-        self.io.set_pixel(row, col, r, g, b);
+    fn ppu_set_pixel(&self, row: u16, col: u16, palette_index: u8, emphasis: u8) {
+        let (r, g, b) = self.palette.rgb(palette_index, emphasis);
+        let buf = self.frame_buffer();
+        let i = (row as usize * 256 + col as usize) * 3;
+        buf[i].set(r);
+        buf[i + 1].set(g);
+        buf[i + 2].set(b);
+
+        self.io
+            .set_pixel_index(row, col, palette_index, emphasis, &self.palette);
+    }
+
+    fn ppu_vblank_start(&self) {
+        self.io.vblank_start();
     }
 }
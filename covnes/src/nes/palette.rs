@@ -1,3 +1,8 @@
+use crate::{
+    error::{Error, Result},
+    nes::ppu::PPUMASK,
+};
+
 const PALLETTE: [(u8, u8, u8); 64] = [
     (84, 84, 84),
     (0, 30, 116),
@@ -65,6 +70,124 @@ const PALLETTE: [(u8, u8, u8); 64] = [
     (0, 0, 0),
 ];
 
+/// The colours the PPU's output is turned into, with a copy of all 64 for each combination of the
+/// emphasis bits in PPUMASK.
+///
+/// The built in one is the default. Others can be loaded from `.pal` files and given to
+/// `Nes::set_palette`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Palette {
+    // Indexed by the top 3 bits of PPUMASK, then the palette entry
+    colours: [[(u8, u8, u8); 64]; 8],
+}
+
+impl Palette {
+    /// The built in palette, with the emphasis variants worked out as in `from_pal`.
+    pub const BUILT_IN: Palette = Palette {
+        colours: emphasis_palettes(&PALLETTE),
+    };
+
+    /// Reads a `.pal` file, which is RGB triples one after the other.
+    ///
+    /// There are two sizes: 192 bytes for the 64 plain colours, or 1536 bytes with all 512, which
+    /// is 64 for each combination of the emphasis bits (red lowest, so in the same order as
+    /// PPUMASK). For the smaller files the emphasised colours are worked out by dimming the
+    /// other two colours for each bit set, like the built in palette does. Anything else is
+    /// `Error::BadPaletteSize`.
+    pub fn from_pal(data: &[u8]) -> Result<Palette> {
+        let mut colours = [[(0, 0, 0); 64]; 8];
+        match data.len() {
+            192 | 1536 => {
+                for (i, rgb) in data.chunks_exact(3).enumerate() {
+                    colours[i / 64][i % 64] = (rgb[0], rgb[1], rgb[2]);
+                }
+            }
+            size => return Err(Error::BadPaletteSize(size)),
+        }
+
+        if data.len() == 192 {
+            colours = emphasis_palettes(&colours[0]);
+        }
+        Ok(Palette { colours })
+    }
+
+    /// The colour of palette entry `idx` with the emphasis bits `emphasis`, red lowest. Greyscale
+    /// should already have been applied to `idx`.
+    pub fn rgb(&self, idx: u8, emphasis: u8) -> (u8, u8, u8) {
+        self.colours[(emphasis & 7) as usize][(idx as usize) % 64]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::BUILT_IN
+    }
+}
+
+// How much the other colours are dimmed by each emphasis bit, out of 256
+const EMPHASIS_DIM: u16 = 209;
+
+// `base` with every combination of the emphasis bits applied. Each emphasis bit darkens the other
+// two colours, and doesn't affect the blacks in columns $E and $F.
+const fn emphasis_palettes(base: &[(u8, u8, u8); 64]) -> [[(u8, u8, u8); 64]; 8] {
+    let mut palettes = [[(0, 0, 0); 64]; 8];
+    let mut emphasis = 0;
+    while emphasis < 8 {
+        let mut idx = 0;
+        while idx < 64 {
+            let (r, g, b) = base[idx];
+            palettes[emphasis][idx] = if idx & 0xE == 0xE {
+                (r, g, b)
+            } else {
+                // Bits are red, green, blue from lowest to highest
+                (
+                    dim(r, emphasis & 0b110),
+                    dim(g, emphasis & 0b101),
+                    dim(b, emphasis & 0b011),
+                )
+            };
+            idx += 1;
+        }
+        emphasis += 1;
+    }
+    palettes
+}
+
+const fn dim(colour: u8, other_emphasis: usize) -> u8 {
+    let mut colour = colour as u16;
+    let mut i = 0;
+    while i < other_emphasis.count_ones() {
+        colour = colour * EMPHASIS_DIM / 256;
+        i += 1;
+    }
+    colour as u8
+}
+
 pub fn get_rgb(idx: u8) -> (u8, u8, u8) {
     PALLETTE[(idx as usize) % 64]
 }
+
+// The colour that's output for palette entry `idx` with the greyscale and emphasis bits in `mask`.
+// Like on hardware greyscale is applied first, so emphasis still tints greyscale pictures.
+pub fn get_rgb_with_mask(idx: u8, mask: PPUMASK) -> (u8, u8, u8) {
+    get_rgb_with_emphasis(greyscale(idx, mask), emphasis(mask))
+}
+
+// `idx` with greyscale applied if it's on in `mask`, which picks the grey from the same row
+pub fn greyscale(idx: u8, mask: PPUMASK) -> u8 {
+    if mask.contains(PPUMASK::GREYSCALE) {
+        idx & 0x30
+    } else {
+        idx
+    }
+}
+
+// The emphasis bits of `mask` as a number from 0-7, with red in the lowest bit
+pub fn emphasis(mask: PPUMASK) -> u8 {
+    mask.bits() >> 5
+}
+
+// The built in palette's colour, see `Palette::rgb`
+pub fn get_rgb_with_emphasis(idx: u8, emphasis: u8) -> (u8, u8, u8) {
+    Palette::BUILT_IN.rgb(idx, emphasis)
+}
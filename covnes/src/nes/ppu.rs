@@ -1,6 +1,14 @@
-use std::cell::Cell;
-
-use crate::nes::palette;
+use alloc::boxed::Box;
+use core::cell::Cell;
+
+use crate::{
+    error::Result,
+    nes::{
+        palette,
+        state::{SaveState, StateReader, StateWriter},
+        Region,
+    },
+};
 
 // I got a *LOT* of help from reading https://github.com/AndreaOrru/LaiNES/blob/master/src/ppu.cpp
 // in addition to (of course) NesDEV
@@ -52,14 +60,33 @@ pub struct PPU {
     pub sprite_in_range: Cell<bool>,
     pub sprite_evaluation_done: Cell<bool>,
     pub sprite_zero_next_scanline: Cell<bool>,
+    pub overflow_bug_counter: Cell<u8>,
 
-    // Sprite rendering
-    pub sprites: [SpriteToRender; 8],
+    // Sprite rendering. Only the first 8 are used unless `sprite_limit` is off.
+    pub sprites: [SpriteToRender; 64],
     pub sprite_zero_current_scanline: Cell<bool>,
     pub num_sprites: Cell<usize>,
 
     // Obscure timing fixes
     pub perform_skip: Cell<bool>,
+    // Which of them are switched on, for bisecting a game that breaks because of one
+    pub accuracy: Cell<PpuAccuracy>,
+
+    // Emulate the extra OAM reads after a sprite overflow - turning this off stops evaluation as
+    // soon as the overflow is detected instead, which is slightly faster but less accurate
+    pub strict_sprite_overflow: Cell<bool>,
+
+    // Only draw 8 sprites on each scanline like the hardware, which is what makes sprites flicker
+    // in busy scenes. With it off, the rest of the sprites on the scanline are fetched at the end
+    // of the sprite fetches (so the mapper sees those reads too) and drawn behind the first 8.
+    // Sprite evaluation and overflow aren't affected.
+    pub sprite_limit: Cell<bool>,
+
+    // Decides the number of scanlines in a frame and where vblank is
+    pub region: Cell<Region>,
+
+    // Called at the start of every dot when set, for watching mid-frame changes to scrolling etc.
+    pub trace_hook: Option<TraceHook>,
 }
 
 pub trait PPUHostAccess {
@@ -67,7 +94,10 @@ pub trait PPUHostAccess {
     fn ppu_write(&self, addr: u16, value: u8);
     fn ppu_trigger_nmi(&self);
     fn ppu_suppress_nmi(&self);
-    fn ppu_set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8);
+    // `palette_index` already has greyscale applied, and `emphasis` is the 3 emphasis bits
+    fn ppu_set_pixel(&self, row: u16, col: u16, palette_index: u8, emphasis: u8);
+    // Called on the dot where vblank starts, even if reading $2002 stopped the flag being set
+    fn ppu_vblank_start(&self) {}
 }
 
 // Contains sprite info for the current scanline
@@ -91,6 +121,73 @@ impl Default for SpriteToRender {
     }
 }
 
+impl SaveState for SpriteToRender {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.x.save_state(w);
+        self.low_pattern.save_state(w);
+        self.high_pattern.save_state(w);
+        w.u8(self.attributes.get().bits());
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.x.load_state(r)?;
+        self.low_pattern.load_state(r)?;
+        self.high_pattern.load_state(r)?;
+        self.attributes
+            .set(SpriteAttributes::from_bits_truncate(r.u8()?));
+        Ok(())
+    }
+}
+
+/// Switches for the timing details that only a few games (and the blargg `ppu_vbl_nmi` tests)
+/// depend on. They're all on by default - turning one off is for narrowing down whether it's what
+/// a game is tripping over, not something that's more correct for any game.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PpuAccuracy {
+    /// Reading $2002, or turning NMIs off in PPUCTRL, a dot or two after vblank starts stops that
+    /// frame's NMI. Needed for `06-suppression` and `08-nmi_off_timing`.
+    pub nmi_suppression: bool,
+    /// Skip the last dot of the pre-render line on odd frames while rendering is on. Needed for
+    /// `09-even_odd_frames` and `10-even_odd_timing`.
+    pub odd_frame_skip: bool,
+    /// Reading $2002 on the dot just before vblank starts returns the flag clear and stops it
+    /// being set (and so the NMI) for that frame. Needed for `02-vbl_set_time` and
+    /// `06-suppression`.
+    pub vbl_race: bool,
+}
+
+impl Default for PpuAccuracy {
+    fn default() -> Self {
+        PpuAccuracy {
+            nmi_suppression: true,
+            odd_frame_skip: true,
+            vbl_race: true,
+        }
+    }
+}
+
+/// A callback for `PPU::trace_hook`
+pub type TraceHook = Box<dyn Fn(&PpuDebug) + Send>;
+
+/// A snapshot of the PPU's timing and scrolling state, see `PPU::debug_state`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PpuDebug {
+    pub dot: u16,
+    pub scanline: u16,
+    pub odd_frame: bool,
+    pub ctrl: PPUCTRL,
+    pub mask: PPUMASK,
+    pub status: PPUSTATUS,
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub w: bool,
+    pub bg_low_shift: u16,
+    pub bg_high_shift: u16,
+    pub bg_pattern_low: u8,
+    pub bg_pattern_high: u8,
+}
+
 bitflags! {
     pub struct PPUCTRL: u8 {
         const BASE_0 = 0x1;
@@ -170,27 +267,36 @@ impl PPU {
             oam_value_latch: Cell::new(0),
             sprite_in_range: Cell::new(false),
             sprite_evaluation_done: Cell::new(false),
+            overflow_bug_counter: Cell::new(0),
             perform_skip: Cell::new(false),
-            sprites: Default::default(),
+            accuracy: Cell::new(PpuAccuracy::default()),
+            strict_sprite_overflow: Cell::new(true),
+            sprite_limit: Cell::new(true),
+            region: Cell::new(Region::Ntsc),
+            sprites: core::array::from_fn(|_| SpriteToRender::default()),
             sprite_zero_next_scanline: Cell::new(false),
             sprite_zero_current_scanline: Cell::new(false),
             num_sprites: Cell::new(0),
+            trace_hook: None,
         }
     }
 
+    // What the reset line does, going by the power up state table on the nesdev wiki. PPUCTRL,
+    // PPUMASK, the $2005/$2006 write latch and the read buffer are cleared and the frame starts
+    // again from an even frame. v, t and fine x keep their values, as does the vblank flag - a
+    // game that resets during vblank can still see it set. OAM and OAMADDR aren't touched.
     pub fn reset(&self) {
         self.ppuctrl.set(PPUCTRL::empty());
         self.ppumask.set(PPUMASK::empty());
+        self.latch_w.set(false);
+        self.read_buffer.set(0);
         self.scanline.set(0);
         self.dot.set(0);
-
-        // hmm - this doesn't make sense
-        // see what mesen does
         self.odd_frame.set(false);
     }
 
     pub fn is_at_frame_end(&self) -> bool {
-        self.dot.get() == 1 && self.scanline.get() == 241
+        self.dot.get() == 1 && self.scanline.get() == self.region.get().vblank_scanline()
     }
 
     pub fn is_rendering(&self) -> bool {
@@ -202,11 +308,47 @@ impl PPU {
         self.scanline.get() < 240 || self.scanline.get() == 321
     }
 
+    /// Copies out the internal state that's useful for comparing against other emulators' logs.
+    ///
+    /// Prefer this to reading the fields directly, their layout may change.
+    pub fn debug_state(&self) -> PpuDebug {
+        PpuDebug {
+            dot: self.dot.get(),
+            scanline: self.scanline.get(),
+            odd_frame: self.odd_frame.get(),
+            ctrl: self.ppuctrl.get(),
+            mask: self.ppumask.get(),
+            status: self.ppustatus.get(),
+            v: self.addr_v.get(),
+            t: self.addr_t.get(),
+            fine_x: self.fine_x.get(),
+            w: self.latch_w.get(),
+            bg_low_shift: self.bg_low_shift.get(),
+            bg_high_shift: self.bg_high_shift.get(),
+            bg_pattern_low: self.fetched_bg_pattern_low.get(),
+            bg_pattern_high: self.fetched_bg_pattern_high.get(),
+        }
+    }
+
     pub fn cgram(&self) -> &[Cell<u8>] {
         let ram: &Cell<[u8]> = &self.cgram;
         ram.as_slice_of_cells()
     }
 
+    /// The 32 palette entries as they'd be read back through $3F00-$3F1F, for palette viewers.
+    ///
+    /// The sprite backdrop entries ($3F10/$3F14/$3F18/$3F1C) are the background ones they mirror.
+    /// Greyscale isn't applied, and reading it has no side effects, so it can be called at any
+    /// time.
+    pub fn palette_ram(&self) -> [u8; 32] {
+        let cgram = self.cgram();
+        let mut palette = [0; 32];
+        for (idx, entry) in palette.iter_mut().enumerate() {
+            *entry = cgram[Self::cgram_mirror_idx(idx as u16)].get();
+        }
+        palette
+    }
+
     pub fn oam(&self) -> &[Cell<u8>] {
         let ram: &Cell<[u8]> = &self.oam;
         ram.as_slice_of_cells()
@@ -231,14 +373,16 @@ impl PPU {
                 if !old_ctrl.contains(PPUCTRL::NMI)
                     && new_ctrl.contains(PPUCTRL::NMI)
                     && self.ppustatus.get().contains(PPUSTATUS::VBLANK)
-                    && !(self.scanline.get() == 261 && self.dot.get() == 1)
+                    && !(self.scanline.get() == self.region.get().pre_render_scanline()
+                        && self.dot.get() == 1)
                 {
                     host.ppu_trigger_nmi();
                 }
 
-                if old_ctrl.contains(PPUCTRL::NMI)
+                if self.accuracy.get().nmi_suppression
+                    && old_ctrl.contains(PPUCTRL::NMI)
                     && !new_ctrl.contains(PPUCTRL::NMI)
-                    && self.scanline.get() == 241
+                    && self.scanline.get() == self.region.get().vblank_scanline()
                     && (self.dot.get() == 2 || self.dot.get() == 3)
                 {
                     host.ppu_suppress_nmi();
@@ -321,7 +465,10 @@ impl PPU {
                 let s = self.ppustatus.get();
                 let n = (self.last_read.get() & 0x1F) | s.bits();
                 self.clear_vblank.set(true);
-                if self.scanline.get() == 241 && (self.dot.get() == 2 || self.dot.get() == 3) {
+                if self.accuracy.get().nmi_suppression
+                    && self.scanline.get() == self.region.get().vblank_scanline()
+                    && (self.dot.get() == 2 || self.dot.get() == 3)
+                {
                     host.ppu_suppress_nmi();
                 }
 
@@ -348,10 +495,10 @@ impl PPU {
                     self.read(host, v)
                 };
 
-                // This is odd - normally we go through the self.read not host.read which handles
-                // palette data - but the oddness is that the buffer gets the thing from the address
-                // line which is normally a mirror of the nametable stuff. I'd imagine one of the
-                // obscure tests I'm later going to use will test this.
+                // Palette reads don't go through the buffer, but the read still happens on the
+                // bus underneath. The cartridge sees $3Fxx, which its nametable mirroring maps
+                // down to $2Fxx, and that's what ends up in the buffer. The address isn't masked
+                // here so that cartridges which map $3000-$3FFF some other way still work.
                 self.read_buffer.set(host.ppu_read(v));
 
                 let incr = if self.ppuctrl.get().contains(PPUCTRL::VRAM_INC) {
@@ -375,7 +522,7 @@ impl PPU {
             0x3F00..=0x3FFF => {
                 let idx = (addr - 0x3F00) % 32;
 
-                // Greyscale is done here on read
+                // Greyscale also applies to palette reads through $2007
                 if self.ppumask.get().contains(PPUMASK::GREYSCALE) {
                     self.cgram()[Self::cgram_mirror_idx(idx)].get() & 0x30
                 } else {
@@ -409,93 +556,98 @@ impl PPU {
         }
     }
 
-    // Outputs a pixel to the screen
+    // Outputs the pixel for the current dot to the screen, which is at x = dot - 1. It's done at
+    // the end of the dot, once the shift registers have been shifted (and maybe reloaded) for it.
+    //
+    // On hardware the colour itself takes a few more dots to come out, but sprite 0 hit is set on
+    // the dot the pixel is worked out. Having both happen here gets the hit timing exactly right,
+    // and nothing outside the PPU can tell when the pixel reaches the screen.
     fn pixel<P: PPUHostAccess>(&self, host: &P) {
-        // The docs say that the first pixel is output at dot 4
-        // but sprite hit triggers at dot 2 because there's a 2 dot pipeline for palette lookup
-        // However, we can probably get away with just pretending that it happens instantly I hope?
-
-        let x = self.dot.get() as i16 - 2;
-
-        // Check if we're in the visible
-        if self.scanline.get() < 240 && x >= 0 && x < 256 {
-            let x = x as u16;
-            let bg_palette = if self.ppumask.get().contains(PPUMASK::SHOW_BG)
-                && !(!self.ppumask.get().contains(PPUMASK::BG_LEFTMOST) && x < 8)
-            {
-                let fx = self.fine_x.get();
-                let pattern = (((self.bg_high_shift.get() >> (15 - fx as u16)) & 1) << 1)
-                    | ((self.bg_low_shift.get() >> (15 - fx as u16)) & 1);
-                // Now we shift up to find the palette index - we only do this if the pattern isn't
-                // 0 (which means we fall back to index 0 in the palette data)
-                if pattern == 0 {
-                    0
-                } else {
-                    // The code from LaiNES explains this better as it's less rusty (in a bad way)
-                    //
-                    //   palette |= ((NTH_BIT(atShiftH,  7 - fX) << 1) |
-                    //                NTH_BIT(atShiftL,  7 - fX))      << 2;
-                    pattern
-                        | (((((self.at_shift_h.get() >> (7 - fx)) & 1) << 1)
-                            | ((self.at_shift_l.get() >> (7 - fx)) & 1))
-                            as u16)
-                            << 2
-                }
-            } else {
+        let x = self.dot.get() - 1;
+        let bg_palette = if self.ppumask.get().contains(PPUMASK::SHOW_BG)
+            && (self.ppumask.get().contains(PPUMASK::BG_LEFTMOST) || x >= 8)
+        {
+            let fx = self.fine_x.get();
+            let pattern = (((self.bg_high_shift.get() >> (15 - fx as u16)) & 1) << 1)
+                | ((self.bg_low_shift.get() >> (15 - fx as u16)) & 1);
+            // Now we shift up to find the palette index - we only do this if the pattern isn't
+            // 0 (which means we fall back to index 0 in the palette data)
+            if pattern == 0 {
                 0
-            };
+            } else {
+                // The code from LaiNES explains this better as it's less rusty (in a bad way)
+                //
+                //   palette |= ((NTH_BIT(atShiftH,  7 - fX) << 1) |
+                //                NTH_BIT(atShiftL,  7 - fX))      << 2;
+                pattern
+                    | (((((self.at_shift_h.get() >> (7 - fx)) & 1) << 1)
+                        | ((self.at_shift_l.get() >> (7 - fx)) & 1)) as u16)
+                        << 2
+            }
+        } else {
+            0
+        };
 
-            let (fg_palette, priority_behind) = if self.scanline.get() >= 1
-                && self.ppumask.get().contains(PPUMASK::SHOW_SPRITES)
-                && !(!self.ppumask.get().contains(PPUMASK::SPRITE_LEFTMOST) && x < 8)
-            {
-                let mut palette = 0;
-                let mut prio_behind = true;
-                for i in (0..self.num_sprites.get()).rev() {
-                    let sprite_x = self.sprites[i].x.get() as u16;
-                    if sprite_x <= x && x < sprite_x + 8 {
-                        let mut offset = (x - sprite_x) as u8;
-                        let attr = self.sprites[i].attributes.get();
-                        if attr.contains(SpriteAttributes::FLIP_HORIZ) {
-                            offset = 7 - offset;
+        let (fg_palette, priority_behind) = if self.scanline.get() >= 1
+            && self.ppumask.get().contains(PPUMASK::SHOW_SPRITES)
+            && (self.ppumask.get().contains(PPUMASK::SPRITE_LEFTMOST) || x >= 8)
+        {
+            let mut palette = 0;
+            let mut prio_behind = true;
+            for i in (0..self.num_sprites.get()).rev() {
+                let sprite_x = self.sprites[i].x.get() as u16;
+                if sprite_x <= x && x < sprite_x + 8 {
+                    let mut offset = (x - sprite_x) as u8;
+                    let attr = self.sprites[i].attributes.get();
+                    if attr.contains(SpriteAttributes::FLIP_HORIZ) {
+                        offset = 7 - offset;
+                    }
+                    let hs = self.sprites[i].high_pattern.get();
+                    let ls = self.sprites[i].low_pattern.get();
+                    let sprite_palette =
+                        ((hs >> (7 - offset)) & 1) << 1 | ((ls >> (7 - offset)) & 1);
+
+                    if sprite_palette != 0 {
+                        // Check for sprite zero hit
+                        if self.sprite_zero_current_scanline.get()
+                            && x != 255
+                            && bg_palette != 0
+                            && i == 0
+                        {
+                            let mut status = self.ppustatus.get();
+                            status.insert(PPUSTATUS::SPRITE_0_HIT);
+                            self.ppustatus.set(status);
                         }
-                        let hs = self.sprites[i].high_pattern.get();
-                        let ls = self.sprites[i].low_pattern.get();
-                        let sprite_palette =
-                            ((hs >> (7 - offset)) & 1) << 1 | ((ls >> (7 - offset)) & 1);
-
-                        if sprite_palette != 0 {
-                            // Check for sprite zero hit
-                            if self.sprite_zero_current_scanline.get()
-                                && x != 255
-                                && bg_palette != 0
-                                && i == 0
-                            {
-                                let mut status = self.ppustatus.get();
-                                status.insert(PPUSTATUS::SPRITE_0_HIT);
-                                self.ppustatus.set(status);
-                            }
 
-                            palette = (attr.bits() & 3) << 2 | sprite_palette;
-                            palette += 16;
-                            prio_behind = attr.contains(SpriteAttributes::PRIORITY_BEHIND)
-                        }
+                        palette = (attr.bits() & 3) << 2 | sprite_palette;
+                        palette += 16;
+                        prio_behind = attr.contains(SpriteAttributes::PRIORITY_BEHIND)
                     }
                 }
-                (palette as u16, prio_behind)
-            } else {
-                (0, true)
-            };
+            }
+            (palette as u16, prio_behind)
+        } else {
+            (0, true)
+        };
 
-            let palette_index = if fg_palette != 0 && (bg_palette == 0 || !priority_behind) {
-                fg_palette
-            } else {
-                bg_palette
-            };
-            let (r, g, b) = palette::get_rgb(self.read(host, 0x3F00 + palette_index));
-            host.ppu_set_pixel(self.scanline.get(), x, r, g, b);
-        }
+        let palette_index = if fg_palette != 0 && (bg_palette == 0 || !priority_behind) {
+            fg_palette
+        } else {
+            bg_palette
+        };
+        // Greyscale is applied here rather than by `read`
+        let colour = self.cgram()[Self::cgram_mirror_idx(palette_index)].get();
+        let mask = self.ppumask.get();
+        host.ppu_set_pixel(
+            self.scanline.get(),
+            x,
+            palette::greyscale(colour, mask),
+            palette::emphasis(mask),
+        );
+    }
 
+    // Shifts the background shift registers along by a pixel, on dots 2-257 and 322-337
+    fn shift_bg(&self) {
         self.bg_low_shift.set(self.bg_low_shift.get() << 1);
         self.bg_high_shift.set(self.bg_high_shift.get() << 1);
         self.at_shift_l
@@ -505,13 +657,19 @@ impl PPU {
     }
 
     pub fn tick<P: PPUHostAccess>(&self, host: &P) {
-        // Sprite evaluation and loading - only on visible scanlines
+        if let Some(hook) = &self.trace_hook {
+            hook(&self.debug_state());
+        }
+
+        // Sprite evaluation and loading - only on visible scanlines, although the pre-render line
+        // still does the pattern fetches (without loading any sprites)
         if self.is_rendering() && self.dot.get() == 257 {
             self.num_sprites.set(0)
         }
-        if self.is_rendering() && self.scanline.get() <= 239 {
+        let prerender = self.scanline.get() == self.region.get().pre_render_scanline();
+        if self.is_rendering() && (self.scanline.get() <= 239 || prerender) {
             match self.dot.get() {
-                1..=256 => self.perform_sprite_evaluation(),
+                1..=256 if !prerender => self.perform_sprite_evaluation(),
                 257..=320 => {
                     let s = self.dot.get() - 257;
                     let sprite_no = (s / 8) as usize;
@@ -532,37 +690,12 @@ impl PPU {
                                 self.secondary_oam()[base + 2].get(),
                             );
                             let x = self.secondary_oam()[base + 3].get();
-                            let addr = if self.get_sprite_size() == 16 {
-                                let bank = if tile_index & 1 == 1 { 0x1000 } else { 0x0000 };
-
-                                let tileno = (tile_index as u16 & !1) * 16;
-
-                                bank + tileno
-                            } else {
-                                let base = if self.ppuctrl.get().contains(PPUCTRL::SPRITE_BANK_1000)
-                                {
-                                    0x1000
-                                } else {
-                                    0x0000
-                                };
-
-                                base + tile_index as u16 * 16
-                            };
-
-                            if y < 240 {
-                                let mut y_offset = self.scanline.get().wrapping_sub(y as u16)
-                                    % self.get_sprite_size() as u16;
-
-                                if attributes.contains(SpriteAttributes::FLIP_VERT) {
-                                    y_offset = self.get_sprite_size() as u16 - y_offset - 1;
-                                }
-
-                                if y_offset > 8 {
-                                    self.fetch_addr.set(addr + 16 + (y_offset - 8));
-                                } else {
-                                    self.fetch_addr.set(addr + y_offset)
-                                }
 
+                            // Empty slots are all $FF, and still fetch tile $FF's pattern, which
+                            // mappers watching the address bus (like MMC3) rely on
+                            self.fetch_addr
+                                .set(self.sprite_pattern_addr(y, tile_index, attributes));
+                            if y < 240 && !prerender {
                                 self.sprites[sprite_no].x.set(x);
                                 self.sprites[sprite_no].attributes.set(attributes);
 
@@ -580,7 +713,10 @@ impl PPU {
                         }
                     }
                 }
-                321 => {
+                321 if !prerender => {
+                    if !self.sprite_limit.get() && self.num_sprites.get() == 8 {
+                        self.fetch_extra_sprites(host);
+                    }
                     self.sprite_zero_current_scanline
                         .set(self.sprite_zero_next_scanline.get());
                 }
@@ -590,19 +726,23 @@ impl PPU {
 
         // Actual rendering
         // This section especially really has assistance from LaiNES's source code
+        let region = self.region.get();
+        let pre_render = region.pre_render_scanline();
         match self.scanline.get() {
             // Pre render and visible
-            0..=239 | 261 => {
-                if self.scanline.get() == 261 && self.dot.get() == 0 {
-                    // Clear overflow
+            line if line <= 239 || line == pre_render => {
+                // Sprite 0 hit and overflow are documented as clearing along with vblank at dot 1,
+                // but this is a dot early to pass the end of vblank checks in blargg's sprite hit
+                // and sprite overflow timing tests. It's probably making up for how reads line up
+                // with the PPU's dots.
+                if line == pre_render && self.dot.get() == 0 {
                     let mut s = self.ppustatus.get();
-                    s.remove(PPUSTATUS::SPRITE_OVERFLOW);
+                    s.remove(PPUSTATUS::SPRITE_0_HIT | PPUSTATUS::SPRITE_OVERFLOW);
                     self.ppustatus.set(s);
                 }
-                if self.scanline.get() == 261 && self.dot.get() == 1 {
-                    // Clear vblank
+                if line == pre_render && self.dot.get() == 1 {
                     let mut s = self.ppustatus.get();
-                    s.remove(PPUSTATUS::VBLANK | PPUSTATUS::SPRITE_0_HIT);
+                    s.remove(PPUSTATUS::VBLANK);
                     self.ppustatus.set(s);
                 }
 
@@ -610,7 +750,7 @@ impl PPU {
                 match self.dot.get() {
                     1 | 321 => self.fetch_addr.set(self.nt_addr()), // "NT byte 1" below without shift reloading
                     2..=255 | 321..=337 => {
-                        self.pixel(host);
+                        self.shift_bg();
                         match self.dot.get() % 8 {
                             // NT byte 1
                             1 => {
@@ -654,46 +794,57 @@ impl PPU {
                         }
                     }
                     256 => {
-                        self.pixel(host);
+                        self.shift_bg();
                         self.fetched_bg_pattern_high
                             .set(self.read(host, self.fetch_addr.get()));
                         self.v_scroll();
                     }
                     257 => {
-                        self.pixel(host);
+                        self.shift_bg();
                         self.reload_bg_shift();
                         self.h_update();
                     }
-                    280..=304 if self.scanline.get() == 261 => self.v_update(),
+                    280..=304 if line == pre_render => self.v_update(),
                     338 | 340 => {
                         self.read(host, self.fetch_addr.get());
                     }
                     _ => (),
                 }
 
-                match self.dot.get() {
-                    257..=320 => {
-                        self.oamaddr.set(0);
-                    }
-                    _ => (),
+                if line <= 239 && (1..=256).contains(&self.dot.get()) {
+                    self.pixel(host);
                 }
 
-                if self.scanline.get() == 261
+                // Only while rendering, otherwise OAM DMA in vblank would be knocked back to the
+                // start of OAM part way through
+                if self.is_rendering() && (257..=320).contains(&self.dot.get()) {
+                    self.oamaddr.set(0);
+                }
+
+                if line == pre_render
                     && self.dot.get() == 338
                     && self.is_rendering()
                     && self.odd_frame.get()
+                    && region.skips_odd_frame_dot()
+                    && self.accuracy.get().odd_frame_skip
                 {
                     self.perform_skip.set(true)
                 }
-                if self.scanline.get() == 261 && self.dot.get() == 339 && self.perform_skip.get() {
+                if line == pre_render && self.dot.get() == 339 && self.perform_skip.get() {
                     self.dot.set(self.dot.get() + 1);
                     self.perform_skip.set(false)
                 }
             }
-            // Idle for 240 (post-render)
-            241 => {
-                // Set vblank in 241
+            // Idle for 240 (post-render), and the extra post-render lines on a Dendy
+            line if line == region.vblank_scanline() => {
+                // Set vblank in 241 (291 on a Dendy)
                 if self.dot.get() == 1 {
+                    host.ppu_vblank_start();
+                    if !self.accuracy.get().vbl_race {
+                        // Without the race the read just before saw the flag clear, so has
+                        // nothing to clear
+                        self.clear_vblank.set(false);
+                    }
                     if !self.clear_vblank.get() {
                         // VBLANK Scanline
                         let mut s = self.ppustatus.get();
@@ -720,8 +871,8 @@ impl PPU {
         if dot > 340 {
             self.dot.set(dot % 341);
             let scanline = self.scanline.get() + 1;
-            if scanline > 261 {
-                self.scanline.set(scanline % 262);
+            if scanline > pre_render {
+                self.scanline.set(0);
                 self.odd_frame.set(!self.odd_frame.get());
             } else {
                 self.scanline.set(scanline);
@@ -834,6 +985,66 @@ impl PPU {
         self.addr_v.set((v & !0x7BE0) | (t & 0x7BE0));
     }
 
+    // The address of the low plane of the row of a sprite's pattern for the next scanline
+    fn sprite_pattern_addr(&self, y: u8, tile_index: u8, attributes: SpriteAttributes) -> u16 {
+        let addr = if self.get_sprite_size() == 16 {
+            let bank = if tile_index & 1 == 1 { 0x1000 } else { 0x0000 };
+
+            let tileno = (tile_index as u16 & !1) * 16;
+
+            bank + tileno
+        } else {
+            let base = if self.ppuctrl.get().contains(PPUCTRL::SPRITE_BANK_1000) {
+                0x1000
+            } else {
+                0x0000
+            };
+
+            base + tile_index as u16 * 16
+        };
+
+        let mut y_offset =
+            self.scanline.get().wrapping_sub(y as u16) % self.get_sprite_size() as u16;
+
+        if attributes.contains(SpriteAttributes::FLIP_VERT) {
+            y_offset = self.get_sprite_size() as u16 - y_offset - 1;
+        }
+
+        // Rows 8-15 of a 8x16 sprite come from the bottom tile
+        if y_offset >= 8 {
+            addr + 16 + (y_offset - 8)
+        } else {
+            addr + y_offset
+        }
+    }
+
+    // For when `sprite_limit` is off: loads every sprite on the next scanline after the 8 that
+    // evaluation found, in OAM order
+    fn fetch_extra_sprites<P: PPUHostAccess>(&self, host: &P) {
+        let scanline = self.scanline.get();
+        let size = self.get_sprite_size() as u16;
+        let mut in_range = 0;
+        for sprite in self.oam().chunks_exact(4) {
+            let y = sprite[0].get();
+            if y >= 240 || scanline < y as u16 || scanline >= y as u16 + size {
+                continue;
+            }
+            in_range += 1;
+            if in_range <= 8 {
+                continue;
+            }
+
+            let attributes = SpriteAttributes::from_bits_truncate(sprite[2].get());
+            let addr = self.sprite_pattern_addr(y, sprite[1].get(), attributes);
+            let extra = &self.sprites[self.num_sprites.get()];
+            extra.x.set(sprite[3].get());
+            extra.attributes.set(attributes);
+            extra.low_pattern.set(self.read(host, addr));
+            extra.high_pattern.set(self.read(host, addr + 8));
+            self.num_sprites.set(self.num_sprites.get() + 1);
+        }
+    }
+
     fn perform_sprite_evaluation(&self) {
         // Todo - revisit this section and get the OAM reads more accurately done
         let dot = self.dot.get();
@@ -847,6 +1058,7 @@ impl PPU {
             self.secondary_oam_addr.set(0);
             self.sprite_in_range.set(false);
             self.sprite_evaluation_done.set(false);
+            self.overflow_bug_counter.set(0);
 
             self.oam_value_latch
                 .set(self.oam()[self.oamaddr.get() as usize].get());
@@ -921,8 +1133,28 @@ impl PPU {
                                     let mut status = self.ppustatus.get();
                                     status.insert(PPUSTATUS::SPRITE_OVERFLOW);
                                     self.ppustatus.set(status);
-                                    self.sprite_evaluation_done.set(true);
-                                    // We should read 3 more times but eh we can't all be mesen level yet
+
+                                    if self.strict_sprite_overflow.get() {
+                                        // sprite_in_range stays set, so we come back here for
+                                        // each of the 3 reads before realigning m and going to 4
+                                        m += 1;
+                                        if m == 4 {
+                                            m = 0;
+                                            n = (n + 1) % 64;
+                                        }
+
+                                        match self.overflow_bug_counter.get() {
+                                            0 => self.overflow_bug_counter.set(3),
+                                            1 => {
+                                                self.overflow_bug_counter.set(0);
+                                                self.sprite_evaluation_done.set(true);
+                                                m = 0;
+                                            }
+                                            c => self.overflow_bug_counter.set(c - 1),
+                                        }
+                                    } else {
+                                        self.sprite_evaluation_done.set(true);
+                                    }
                                 } else {
                                     // 3b. If the value is not in range, increment n and m (without
                                     // carry). If n overflows to 0, go to 4; otherwise go to 3
@@ -950,3 +1182,89 @@ impl PPU {
         }
     }
 }
+
+// The region and the options above aren't part of the state, they stay as the embedder set them
+impl SaveState for PPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.cgram.save_state(w);
+        self.oam.save_state(w);
+        self.secondary_oam.save_state(w);
+        self.scanline.save_state(w);
+        self.dot.save_state(w);
+        self.odd_frame.save_state(w);
+        w.u8(self.ppuctrl.get().bits());
+        w.u8(self.ppumask.get().bits());
+        w.u8(self.ppustatus.get().bits());
+        self.oamaddr.save_state(w);
+        self.read_buffer.save_state(w);
+        self.last_read.save_state(w);
+        self.clear_vblank.save_state(w);
+        self.addr_v.save_state(w);
+        self.addr_t.save_state(w);
+        self.fine_x.save_state(w);
+        self.latch_w.save_state(w);
+        self.fetch_addr.save_state(w);
+        self.fetched_nametable.save_state(w);
+        self.fetched_attribute_table.save_state(w);
+        self.fetched_bg_pattern_low.save_state(w);
+        self.fetched_bg_pattern_high.save_state(w);
+        self.at_latch_l.save_state(w);
+        self.at_latch_h.save_state(w);
+        self.bg_high_shift.save_state(w);
+        self.bg_low_shift.save_state(w);
+        self.at_shift_l.save_state(w);
+        self.at_shift_h.save_state(w);
+        self.secondary_oam_addr.save_state(w);
+        self.oam_value_latch.save_state(w);
+        self.sprite_in_range.save_state(w);
+        self.sprite_evaluation_done.save_state(w);
+        self.sprite_zero_next_scanline.save_state(w);
+        self.overflow_bug_counter.save_state(w);
+        self.sprites.save_state(w);
+        self.sprite_zero_current_scanline.save_state(w);
+        w.u8(self.num_sprites.get() as u8);
+        self.perform_skip.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.cgram.load_state(r)?;
+        self.oam.load_state(r)?;
+        self.secondary_oam.load_state(r)?;
+        self.scanline.load_state(r)?;
+        self.dot.load_state(r)?;
+        self.odd_frame.load_state(r)?;
+        self.ppuctrl.set(PPUCTRL::from_bits_truncate(r.u8()?));
+        self.ppumask.set(PPUMASK::from_bits_truncate(r.u8()?));
+        self.ppustatus.set(PPUSTATUS::from_bits_truncate(r.u8()?));
+        self.oamaddr.load_state(r)?;
+        self.read_buffer.load_state(r)?;
+        self.last_read.load_state(r)?;
+        self.clear_vblank.load_state(r)?;
+        self.addr_v.load_state(r)?;
+        self.addr_t.load_state(r)?;
+        self.fine_x.load_state(r)?;
+        self.latch_w.load_state(r)?;
+        self.fetch_addr.load_state(r)?;
+        self.fetched_nametable.load_state(r)?;
+        self.fetched_attribute_table.load_state(r)?;
+        self.fetched_bg_pattern_low.load_state(r)?;
+        self.fetched_bg_pattern_high.load_state(r)?;
+        self.at_latch_l.load_state(r)?;
+        self.at_latch_h.load_state(r)?;
+        self.bg_high_shift.load_state(r)?;
+        self.bg_low_shift.load_state(r)?;
+        self.at_shift_l.load_state(r)?;
+        self.at_shift_h.load_state(r)?;
+        self.secondary_oam_addr.load_state(r)?;
+        self.oam_value_latch.load_state(r)?;
+        self.sprite_in_range.load_state(r)?;
+        self.sprite_evaluation_done.load_state(r)?;
+        self.sprite_zero_next_scanline.load_state(r)?;
+        self.overflow_bug_counter.load_state(r)?;
+        self.sprites.load_state(r)?;
+        self.sprite_zero_current_scanline.load_state(r)?;
+        self.num_sprites.set((r.u8()? as usize).min(64));
+        self.perform_skip.load_state(r)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+// Which kind of console is being emulated. This only affects timing - the NES and its clones all
+// run the same software.
+//
+// PAL isn't here yet. `Nes::tick` can run its CPU at 1/3.2 of the PPU clock, but the PPU and APU
+// differences aren't emulated.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    // Famiclones like the Dendy run 50Hz frames, but with NTSC-like CPU/PPU clocks. The extra 50
+    // lines go between the picture and vblank, so vblank still lasts 20 lines like on NTSC.
+    Dendy,
+}
+
+impl Region {
+    pub fn scanlines_per_frame(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Dendy => 312,
+        }
+    }
+
+    // The scanline where the vblank flag is set (on dot 1), and where `step_frame` stops
+    pub fn vblank_scanline(self) -> u16 {
+        match self {
+            Region::Ntsc => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn pre_render_scanline(self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+
+    // Whether a dot is skipped on the pre-render line of odd frames when rendering is on
+    pub fn skips_odd_frame_dot(self) -> bool {
+        match self {
+            Region::Ntsc => true,
+            Region::Dendy => false,
+        }
+    }
+
+    // The CPU and PPU clocks are divided down from the master clock by these
+    pub fn master_clocks_per_cpu_cycle(self) -> u8 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Dendy => 15,
+        }
+    }
+
+    pub fn master_clocks_per_dot(self) -> u8 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_hz(self) -> f64 {
+        match self {
+            Region::Ntsc => 1_789_772.727_272_7,
+            Region::Dendy => 1_773_447.5,
+        }
+    }
+
+    pub fn frame_rate(self) -> f64 {
+        let dots_per_cpu_cycle =
+            self.master_clocks_per_cpu_cycle() as f64 / self.master_clocks_per_dot() as f64;
+        let dots_per_frame = self.scanlines_per_frame() as f64 * 341.0;
+        let dots_per_frame = if self.skips_odd_frame_dot() {
+            dots_per_frame - 0.5
+        } else {
+            dots_per_frame
+        };
+        self.cpu_clock_hz() * dots_per_cpu_cycle / dots_per_frame
+    }
+}
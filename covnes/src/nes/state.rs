@@ -0,0 +1,230 @@
+// Save states. Each part of the console writes out its fields in a fixed order and reads them
+// back in the same order, so there's no versioning between components - bump `VERSION` whenever
+// anything changes. Every state for a given cartridge is the same size, which `Nes::load_state`
+// relies on to check that a state fits before loading it.
+//
+// States are always taken at the start of a CPU instruction (see `Nes::save_state`) so that the
+// CPU's internal state machine doesn't need saving. Things owned by the embedder, like the IO
+// and any controller shift registers, aren't included.
+
+use alloc::vec::Vec;
+use core::cell::Cell;
+
+use crate::error::{Error, Result};
+
+pub(crate) const MAGIC: &[u8; 4] = b"CVNS";
+pub(crate) const VERSION: u8 = 12;
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(Error::BadSaveState);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::BadSaveState),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    // Errors if there's anything left over, which means the state wasn't for this console
+    pub fn finish(&self) -> Result<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::BadSaveState)
+        }
+    }
+}
+
+// Something which can be saved to and restored from a save state
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&self, r: &mut StateReader) -> Result<()>;
+}
+
+impl SaveState for Cell<u8> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.get());
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.set(r.u8()?);
+        Ok(())
+    }
+}
+
+impl SaveState for Cell<bool> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.get());
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.set(r.bool()?);
+        Ok(())
+    }
+}
+
+impl SaveState for Cell<u16> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.get());
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.set(r.u16()?);
+        Ok(())
+    }
+}
+
+impl SaveState for Cell<i16> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u16(self.get() as u16);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.set(r.u16()? as i16);
+        Ok(())
+    }
+}
+
+impl SaveState for Cell<u64> {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u64(self.get());
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self.set(r.u64()?);
+        Ok(())
+    }
+}
+
+impl<const N: usize> SaveState for Cell<[u8; N]> {
+    fn save_state(&self, w: &mut StateWriter) {
+        let cells: &Cell<[u8]> = self;
+        cells.as_slice_of_cells().save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        let cells: &Cell<[u8]> = self;
+        cells.as_slice_of_cells().load_state(r)
+    }
+}
+
+impl<T: SaveState> SaveState for [T] {
+    fn save_state(&self, w: &mut StateWriter) {
+        for t in self {
+            t.save_state(w);
+        }
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        for t in self {
+            t.load_state(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: SaveState> SaveState for Vec<T> {
+    fn save_state(&self, w: &mut StateWriter) {
+        self[..].save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        self[..].load_state(r)
+    }
+}
+
+impl<T: SaveState> SaveState for Option<T> {
+    // Whether the option is set is decided by the cartridge, so it's not saved
+    fn save_state(&self, w: &mut StateWriter) {
+        if let Some(t) = self {
+            t.save_state(w);
+        }
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> Result<()> {
+        match self {
+            Some(t) => t.load_state(r),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+// Colour bars for frontends to show while there's no cartridge in, so there's something better
+// than a blank screen before a ROM is picked. They're drawn straight in to a frame buffer rather
+// than by the PPU, so nothing about running real games changes.
+
+use super::{palette, FRAME_BUFFER_SIZE};
+
+// The greys along the bottom, from black to white
+const GREYS: [u8; 6] = [0x0F, 0x2D, 0x00, 0x10, 0x3D, 0x20];
+
+/// The palette index of a pixel of the test pattern: the 12 hues bright along the top, then
+/// darker, then a strip of greys.
+pub fn palette_index(row: u16, col: u16) -> u8 {
+    let hue = 1 + (col as usize * 12 / 256) as u8;
+    match row {
+        0..=167 => 0x20 | hue,
+        168..=191 => 0x10 | hue,
+        _ => GREYS[col as usize * GREYS.len() / 256],
+    }
+}
+
+/// Draws the test pattern in to `buf` as RGB24, in the same layout as `Nes::step_frame_into`.
+///
+/// `buf` must be exactly `FRAME_BUFFER_SIZE` bytes long.
+pub fn draw_into(buf: &mut [u8]) {
+    assert_eq!(
+        buf.len(),
+        FRAME_BUFFER_SIZE,
+        "Frame buffer is the wrong size"
+    );
+
+    for (i, pixel) in buf.chunks_exact_mut(3).enumerate() {
+        let (r, g, b) = palette::get_rgb(palette_index((i / 256) as u16, (i % 256) as u16));
+        pixel.copy_from_slice(&[r, g, b]);
+    }
+}
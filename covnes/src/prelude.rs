@@ -0,0 +1,19 @@
+// Everything a frontend needs to get a game running, in one place: load a `RomFile`, give a `Nes`
+// an IO (usually one of the standard controller adapters wrapped around the frontend's own) and
+// step it. Anything more specialised is still in its own module.
+//
+// `Result` is left out, as glob importing it would hide the standard library's.
+
+pub use crate::{
+    emulator::Emulator,
+    error::Error,
+    nes::{
+        io::{
+            SingleStandardController, SingleStandardControllerIO, StandardControllerButtons,
+            TwoStandardControllers, TwoStandardControllersIO, IO,
+        },
+        mappers::from_rom,
+        Nes, Region,
+    },
+    romfiles::RomFile,
+};
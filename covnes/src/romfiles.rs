@@ -1,7 +1,11 @@
-use std::{fs::File, io, path::Path};
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::{fs::File, io::Read, path::Path};
 
-use anyhow::{bail, Result};
-use io::Read;
+use crate::{
+    checksum,
+    error::{Error, Result},
+};
 
 #[derive(Debug)]
 pub enum Mirroring {
@@ -10,33 +14,57 @@ pub enum Mirroring {
     FourScreen,
 }
 
+// Which console the game was made for. Multi-region games run on any of them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
 #[derive(Debug)]
 pub struct RomFile {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Option<Vec<u8>>,
     pub provide_prg_ram: bool,
+    // How much PRG RAM there is if `provide_prg_ram` is set, from byte 8 of the header (or byte
+    // 10 for NES 2.0). It's 8kb unless the header says otherwise, and only mappers that can bank
+    // it use more.
+    pub prg_ram_size: usize,
     pub mirroring: Mirroring,
     pub mapper: usize,
+    // Which variant of the mapper the board has, from NES 2.0 headers. It's 0 for iNES ones.
+    pub submapper: u8,
+    // From byte 12 of NES 2.0 headers, or bit 0 of byte 9 for iNES ones (which hardly any dumps
+    // set, so most say NTSC whatever they are)
+    pub timing: Timing,
 }
 
 const MAGIC_BYTES: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 
 impl RomFile {
+    #[cfg(feature = "std")]
     pub fn from_filename<P: AsRef<Path>>(path: P) -> Result<RomFile> {
         let mut f = File::open(path)?;
         Self::from_read(&mut f)
     }
 
+    #[cfg(feature = "std")]
     pub fn from_read<R: Read>(f: &mut R) -> Result<RomFile> {
-        let mut header = [0; 16];
-        let bytes_read = f.read(&mut header)?;
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        Self::from_bytes(&data)
+    }
 
-        if bytes_read < 16 {
-            bail!("Could not read header");
+    pub fn from_bytes(data: &[u8]) -> Result<RomFile> {
+        if data.len() < 16 {
+            return Err(Error::TruncatedHeader);
         }
+        let (header, data) = data.split_at(16);
 
-        if &header[0..4] != &MAGIC_BYTES {
-            bail!("File is not in the iNES format");
+        if header[0..4] != MAGIC_BYTES {
+            return Err(Error::NotINes);
         }
 
         let prg_rom_size = (header[4] as usize) * 16384;
@@ -46,7 +74,7 @@ impl RomFile {
         let provide_trainer = header[6] & 4 == 4;
 
         if provide_trainer {
-            bail!("What's a trainer?")
+            return Err(Error::TrainerUnsupported);
         }
 
         let mirroring = if header[6] & 0x8 == 0x8 {
@@ -59,35 +87,99 @@ impl RomFile {
             }
         };
 
+        // Old ("archaic") iNES dumps used bytes 7-15 for junk like a ripper's name, so anything
+        // in the unused bytes at the end (and not there for NES 2.0) means nothing after byte 6
+        // can be trusted. "DiskDude!" is the famous one, which makes everything look like mapper
+        // 64 or above.
+        let junk_at_end = header[12..16].iter().any(|&b| b != 0);
+        let nes_2 = header[7] & 0x0C == 0x08;
+        let archaic = junk_at_end && !nes_2;
+
         let mapper_low = header[6] >> 4;
-        let mapper = (header[7] & 0xF0) | mapper_low;
+        let mapper_high = if archaic { 0 } else { header[7] & 0xF0 };
+        let mut mapper = (mapper_high | mapper_low) as usize;
+        // NES 2.0 puts bits 8-11 of the mapper number in the low nibble of byte 8, and the
+        // submapper in the high one
+        let submapper = if nes_2 {
+            mapper |= ((header[8] & 0x0F) as usize) << 8;
+            header[8] >> 4
+        } else {
+            0
+        };
 
-        // TODO other flags, NES 2.0, detect DiskDude!, etc.
+        let prg_ram_size = if nes_2 {
+            // Byte 10 has shift counts for the volatile (low nibble) and battery backed (high
+            // nibble) RAM, where the size is 64 << n and 0 means there isn't any. Mappers here
+            // only have the one kind at $6000, so it's whichever is bigger.
+            let size = |shift: u8| if shift == 0 { 0 } else { 64 << shift };
+            match size(header[10] & 0x0F).max(size(header[10] >> 4)) {
+                0 => 8192,
+                size => size,
+            }
+        } else if junk_at_end {
+            // Headers with junk at the end can't be trusted with it
+            8192
+        } else {
+            // 0 means 8kb, as most dumps leave it out
+            header[8].max(1) as usize * 8192
+        };
 
-        let mut prg_rom = vec![0; prg_rom_size];
-        let read = f.read(&mut prg_rom[..])?;
-        if read != prg_rom_size {
-            bail!("Could not read all of the prg_rom");
+        let timing = if nes_2 {
+            match header[12] & 3 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            }
+        } else if !junk_at_end && header[9] & 1 == 1 {
+            Timing::Pal
+        } else {
+            Timing::Ntsc
         };
 
+        if data.len() < prg_rom_size {
+            return Err(Error::TruncatedPrgRom);
+        }
+        let (prg_rom, data) = data.split_at(prg_rom_size);
+
         let chr_rom = if chr_rom_size == 0 {
             None
         } else {
-            let mut chr_rom = vec![0; chr_rom_size];
-            let read = f.read(&mut chr_rom[..])?;
-            if read != chr_rom_size {
-                bail!("Could not read all of the chr_rom");
+            if data.len() < chr_rom_size {
+                return Err(Error::TruncatedChrRom);
             }
 
-            Some(chr_rom)
+            Some(data[..chr_rom_size].to_vec())
         };
 
         Ok(RomFile {
             mirroring,
-            prg_rom,
+            prg_rom: prg_rom.to_vec(),
             chr_rom,
             provide_prg_ram,
-            mapper: mapper as usize,
+            prg_ram_size,
+            mapper,
+            submapper,
+            timing,
         })
     }
+
+    // CRC-32 of the PRG ROM followed by the CHR ROM, leaving out the header. This is what ROM
+    // databases like NesCartDB list, so it doesn't change with header fixes.
+    pub fn prg_chr_crc32(&self) -> u32 {
+        checksum::crc32(&self.prg_chr())
+    }
+
+    // MD5 of the same thing, which is how FCEUX identifies ROMs (e.g. `romChecksum` in FM2 files)
+    pub fn rom_md5(&self) -> [u8; 16] {
+        checksum::md5(&self.prg_chr())
+    }
+
+    fn prg_chr(&self) -> Vec<u8> {
+        let mut data = self.prg_rom.clone();
+        if let Some(chr_rom) = &self.chr_rom {
+            data.extend_from_slice(chr_rom);
+        }
+        data
+    }
 }
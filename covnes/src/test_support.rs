@@ -0,0 +1,125 @@
+use alloc::string::String;
+
+use thiserror::Error;
+
+use crate::nes::{io::IO, Nes};
+
+// Helpers for running test ROMs, used by this crate's tests but usable for anyone else's
+
+// Where blargg's test ROMs report their progress, in cartridge RAM
+const STATUS: u16 = 0x6000;
+const SIGNATURE: u16 = 0x6001;
+const TEXT: u16 = 0x6004;
+
+const SIGNATURE_BYTES: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+
+// The ROMs want the reset to come at least 100ms after they ask for it
+const RESET_DELAY_CYCLES: u64 = 200_000;
+
+// How often the status is checked
+const POLL_CYCLES: u64 = 1000;
+
+#[derive(Debug, Error)]
+pub enum TestFailure {
+    #[error("Test failed with code {code}:\n{output}")]
+    Failed { code: u8, output: String },
+
+    #[error("Test didn't finish in time:\n{output}")]
+    Timeout { output: String },
+}
+
+#[derive(Debug, Error)]
+#[error("PC didn't reach ${target:04X} in {max_instructions} instructions, it's at ${pc:04X}")]
+pub struct Timeout {
+    pub target: u16,
+    pub max_instructions: usize,
+    pub pc: u16,
+}
+
+/// Runs a test ROM that uses blargg's protocol for reporting results at $6000, until it finishes
+/// or `timeout_cycles` CPU cycles have gone by.
+///
+/// The ROM needs PRG RAM. Returns the text the test printed if it passed.
+pub fn run_blargg<I: IO>(nes: &Nes<I>, timeout_cycles: u64) -> Result<String, TestFailure> {
+    let deadline = nes.cpu_cycles() + timeout_cycles;
+
+    loop {
+        run_for(nes, POLL_CYCLES);
+
+        // Until the signature is there, the status byte is just whatever was in RAM
+        if signed(nes) {
+            match nes.peek(STATUS).unwrap_or(0) {
+                RUNNING => (),
+                NEEDS_RESET => {
+                    run_for(nes, RESET_DELAY_CYCLES);
+                    nes.reset();
+                }
+                0 => return Ok(output(nes)),
+                code => {
+                    return Err(TestFailure::Failed {
+                        code,
+                        output: output(nes),
+                    })
+                }
+            }
+        }
+
+        if nes.cpu_cycles() >= deadline {
+            return Err(TestFailure::Timeout {
+                output: output(nes),
+            });
+        }
+    }
+}
+
+/// Runs instructions until the CPU is about to run the one at `pc`, for getting a test ROM to a
+/// known point. Gives up after `max_instructions`, so a test can't hang if it never gets there.
+///
+/// As with `Nes::step_instructions`, an instruction that's under way is finished first and
+/// interrupts count as instructions. If the CPU is already at `pc` nothing more is run.
+pub fn run_to_pc<I: IO>(nes: &Nes<I>, pc: u16, max_instructions: usize) -> Result<(), Timeout> {
+    // Just finishes off the instruction that's under way
+    nes.step_instructions(0);
+
+    let mut instructions = 0;
+    while nes.cpu.pc.get() != pc {
+        if instructions == max_instructions {
+            return Err(Timeout {
+                target: pc,
+                max_instructions,
+                pc: nes.cpu.pc.get(),
+            });
+        }
+        nes.step_cpu_instruction();
+        instructions += 1;
+    }
+
+    Ok(())
+}
+
+fn run_for<I: IO>(nes: &Nes<I>, cycles: u64) {
+    for _ in 0..cycles {
+        nes.tick_cpu();
+    }
+}
+
+fn signed<I: IO>(nes: &Nes<I>) -> bool {
+    (0..3).all(|i| nes.peek(SIGNATURE + i) == Some(SIGNATURE_BYTES[i as usize]))
+}
+
+// The NUL terminated text after the signature
+fn output<I: IO>(nes: &Nes<I>) -> String {
+    let mut text = String::new();
+    let mut addr = TEXT;
+    while let Some(c) = nes.peek(addr) {
+        if c == 0 || addr == 0x7FFF {
+            break;
+        }
+        text.push(c as char);
+        addr += 1;
+    }
+    text
+}
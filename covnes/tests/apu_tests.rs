@@ -0,0 +1,519 @@
+use covnes::nes::apu::{self, APU};
+
+fn tick(apu: &APU, cycles: usize) {
+    for _ in 0..cycles {
+        apu.tick();
+    }
+}
+
+// Writes $4017 and runs the APU through the delay before the sequence restarts, so that cycles
+// can be counted from the start of the new one
+fn write_frame_counter(apu: &APU, value: u8) {
+    apu.write(0x4017, value);
+    while apu.frame_reset_delay.get() > 0 {
+        apu.tick();
+    }
+}
+
+#[test]
+fn length_counters_follow_channel_enables() {
+    let apu = APU::new();
+
+    // Loading a length counter does nothing while the channel is disabled
+    apu.write(0x4003, 0x08);
+    assert_eq!(apu.read_status() & 0x1F, 0);
+
+    apu.write(0x4015, 0x0F);
+    apu.write(0x4003, 0x08);
+    apu.write(0x400B, 0x08);
+    assert_eq!(apu.read_status() & 0x1F, 0b0101);
+
+    // Disabling a channel clears its counter straight away
+    apu.write(0x4015, 0x0E);
+    assert_eq!(apu.read_status() & 0x1F, 0b0100);
+}
+
+#[test]
+fn length_counters_count_down_unless_halted() {
+    let apu = APU::new();
+    write_frame_counter(&apu, 0x40);
+    apu.write(0x4015, 0x03);
+    // Pulse 2 is halted, and both start at 10
+    apu.write(0x4004, 0x20);
+    apu.write(0x4003, 0x00);
+    apu.write(0x4007, 0x00);
+
+    // Two half frames every 4 step sequence, the last of which is 1 cycle before the end
+    tick(&apu, 29830 * 5 - 2);
+    assert_eq!(apu.read_status() & 0x03, 0b11);
+    tick(&apu, 1);
+    assert_eq!(apu.read_status() & 0x03, 0b10);
+}
+
+#[test]
+fn frame_irq() {
+    let apu = APU::new();
+
+    tick(&apu, 29827);
+    assert!(!apu.irq());
+    tick(&apu, 1);
+    assert!(apu.irq());
+
+    // Reading $4015 acknowledges it
+    assert_eq!(apu.read_status() & 0x40, 0x40);
+    assert!(!apu.irq());
+    assert_eq!(apu.read_status() & 0x40, 0);
+
+    // Inhibiting the IRQ clears it and stops it being set again
+    tick(&apu, 29830);
+    assert!(apu.irq());
+    write_frame_counter(&apu, 0x40);
+    assert!(!apu.irq());
+    tick(&apu, 29830 * 2);
+    assert!(!apu.irq());
+
+    // And there's never one in 5 step mode
+    write_frame_counter(&apu, 0x80);
+    tick(&apu, 37282 * 2);
+    assert!(!apu.irq());
+}
+
+#[test]
+fn length_counters_load_from_the_table_and_clock_on_half_frames() {
+    let apu = APU::new();
+    write_frame_counter(&apu, 0x40);
+    apu.write(0x4015, 0x01);
+    for (index, length) in [(0x00, 10), (0x01, 254), (0x10, 12), (0x1F, 30)] {
+        apu.write(0x4003, index << 3);
+        assert_eq!(apu.length_counters[0].get(), length);
+    }
+
+    // Starting from 2, it's clocked on the first and third quarter frames
+    write_frame_counter(&apu, 0x40);
+    apu.write(0x4003, 0x03 << 3);
+    tick(&apu, 14912);
+    assert_eq!(apu.length_counters[0].get(), 2);
+    tick(&apu, 1);
+    assert_eq!(apu.length_counters[0].get(), 1);
+    tick(&apu, 29829 - 14913 - 1);
+    assert_eq!(apu.length_counters[0].get(), 1);
+    tick(&apu, 1);
+    assert_eq!(apu.length_counters[0].get(), 0);
+
+    // In 5 step mode the second one is later (and writing $4017 clocks them straight away)
+    apu.write(0x4003, 0x05 << 3);
+    write_frame_counter(&apu, 0xC0);
+    assert_eq!(apu.length_counters[0].get(), 3);
+    tick(&apu, 14913);
+    assert_eq!(apu.length_counters[0].get(), 2);
+    tick(&apu, 29829 - 14913);
+    assert_eq!(apu.length_counters[0].get(), 2);
+    tick(&apu, 37281 - 29829);
+    assert_eq!(apu.length_counters[0].get(), 1);
+}
+
+#[test]
+fn sweeps_move_the_pulse_periods() {
+    let apu = APU::new();
+    write_frame_counter(&apu, 0x40);
+    for (channel, base) in [(0, 0x4000), (1, 0x4004)] {
+        // Period 0x100, then adding a quarter of it every other half frame
+        apu.write(base + 2, 0x00);
+        apu.write(base + 3, 0x01);
+        apu.write(base + 1, 0x92);
+        assert_eq!(apu.pulse_periods[channel].get(), 0x100);
+    }
+    // Pulse 2 goes down instead
+    apu.write(0x4005, 0x9A);
+
+    // The dividers start at 0, so the first half frame moves the periods and the next one only
+    // counts down
+    tick(&apu, 14913);
+    assert_eq!(apu.pulse_periods[0].get(), 0x140);
+    assert_eq!(apu.pulse_periods[1].get(), 0xC0);
+    tick(&apu, 29829 - 14913);
+    assert_eq!(apu.pulse_periods[0].get(), 0x140);
+
+    // Pulse 1 goes down by 1 more than pulse 2 would
+    apu.write(0x4001, 0x8A);
+    write_frame_counter(&apu, 0xC0);
+    assert_eq!(apu.pulse_periods[0].get(), 0x140 - 0x50 - 1);
+
+    // A target past $7FF mutes the channel and stops the sweep, even when it's disabled
+    apu.write(0x4002, 0xFF);
+    apu.write(0x4003, 0x07);
+    apu.write(0x4001, 0x01);
+    assert!(apu.pulse_muted(0));
+    apu.write(0x4001, 0x81);
+    tick(&apu, 29830 * 2);
+    assert_eq!(apu.pulse_periods[0].get(), 0x7FF);
+    // As does a period under 8
+    apu.write(0x4002, 0x07);
+    apu.write(0x4003, 0x00);
+    assert!(apu.pulse_muted(0));
+}
+
+#[test]
+fn muting_a_channel_is_invisible_to_the_game() {
+    let apu = APU::new();
+    apu.write(0x4015, 0x0F);
+    apu.write(0x400F, 0x08);
+
+    apu.set_channel_enabled(3, false);
+    assert!(!apu.channel_enabled(3));
+    assert!(apu.channel_enabled(0));
+    assert_eq!(apu.read_status() & 0x1F, 0b1000);
+
+    // It's up to the frontend, so it stays muted through a reset
+    apu.reset();
+    assert!(!apu.channel_enabled(3));
+    apu.set_channel_enabled(3, true);
+    assert!(apu.channel_enabled(3));
+}
+
+#[test]
+fn pulses_step_through_their_duty_cycles() {
+    let apu = APU::new();
+    apu.write(0x4015, 0x03);
+    // 50% duty at a constant full volume, and a period of 8 so each step is 9 APU cycles
+    apu.write(0x4000, 0x9F);
+    apu.write(0x4002, 0x08);
+    apu.write(0x4003, 0x08);
+    // 12.5% duty, negated, at volume 5
+    apu.write(0x4004, 0xD5);
+    apu.write(0x4006, 0x08);
+    apu.write(0x4007, 0x08);
+
+    // The timers start at 0, so the first step is on the first APU cycle
+    tick(&apu, 2);
+    let mut outputs = vec![];
+    for _ in 0..8 {
+        outputs.push((apu.pulse_output(0), apu.pulse_output(1)));
+        tick(&apu, 18);
+    }
+    assert_eq!(
+        outputs,
+        [
+            (15, 0),
+            (15, 0),
+            (15, 5),
+            (15, 5),
+            (0, 5),
+            (0, 5),
+            (0, 5),
+            (0, 5),
+        ]
+    );
+
+    // Writing the 4th register starts the waveform again
+    apu.write(0x4003, 0x08);
+    assert_eq!(apu.pulse_steps[0].get(), 0);
+}
+
+#[test]
+fn envelopes_decay_and_loop() {
+    let apu = APU::new();
+    write_frame_counter(&apu, 0x40);
+    apu.write(0x4015, 0x03);
+    // Decaying every 4 quarter frames from when the 4th register is written
+    apu.write(0x4000, 0x03);
+    apu.write(0x4003, 0x00);
+    apu.write(0x4004, 0x23);
+    apu.write(0x4007, 0x00);
+
+    tick(&apu, 7457);
+    assert_eq!(apu.envelopes[0].output(), 15);
+    // A whole 4 step sequence is 4 quarter frames
+    tick(&apu, 29830);
+    assert_eq!(apu.envelopes[0].output(), 14);
+    tick(&apu, 29830 * 14);
+    assert_eq!(apu.envelopes[0].output(), 0);
+    assert_eq!(apu.envelopes[1].output(), 0);
+
+    // Pulse 1 stays at 0 (and is silenced by its length counter too by now), while pulse 2's
+    // halt flag also loops its envelope back to 15
+    tick(&apu, 29830);
+    assert_eq!(apu.envelopes[0].output(), 0);
+    assert_eq!(apu.envelopes[1].output(), 15);
+    assert_eq!(apu.read_status() & 0x03, 0b10);
+
+    // Constant volume ignores the decay
+    apu.write(0x4004, 0x37);
+    assert_eq!(apu.envelopes[1].output(), 7);
+}
+
+#[test]
+fn pulses_are_mixed_together() {
+    let apu = APU::new();
+    apu.write(0x4015, 0x03);
+    // 75% duty at full volume, which is high from the start. The sweeps are set to go down so
+    // that their targets don't mute the channels.
+    for base in [0x4000, 0x4004] {
+        apu.write(base, 0xFF);
+        apu.write(base + 1, 0x08);
+        apu.write(base + 2, 0x00);
+        apu.write(base + 3, 0x0E);
+    }
+    assert_eq!(apu.output(), apu::mix(15, 15, 0, 0, 0));
+
+    apu.set_channel_enabled(1, false);
+    assert_eq!(apu.output(), apu::mix(15, 0, 0, 0, 0));
+    assert_eq!(apu.pulse_output(1), 15);
+    apu.set_channel_enabled(1, true);
+
+    // A sweep target past $7FF silences pulse 1 even with its sweep disabled
+    apu.write(0x4001, 0x01);
+    assert!(apu.pulse_muted(0));
+    assert_eq!(apu.pulse_output(0), 0);
+    apu.write(0x4001, 0x08);
+    assert_eq!(apu.pulse_output(0), 15);
+
+    // As does turning the channel off in $4015
+    apu.write(0x4015, 0x00);
+    assert_eq!(apu.output(), -1.0);
+}
+
+// How many times the noise channel's LFSR shifts before it's back where it started
+fn noise_sequence_length(apu: &APU) -> usize {
+    let start = apu.noise_lfsr.get();
+    let mut shifts = 0;
+    loop {
+        // The shortest period is 4 CPU cycles
+        tick(apu, 4);
+        shifts += 1;
+        if apu.noise_lfsr.get() == start {
+            return shifts;
+        }
+    }
+}
+
+#[test]
+fn mixer_levels() {
+    // The nesdev wiki's lookup tables, scaled so that silence is -1.0 and everything at full
+    // volume is 1.0
+    let pulse = |n: f32| 95.52 / (8128.0 / n + 100.0);
+    let tnd = |n: f32| 163.67 / (24329.0 / n + 100.0);
+    let max = pulse(30.0) + tnd(202.0);
+    let level = |pulse, tnd| (pulse + tnd) / max * 2.0 - 1.0;
+    let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+
+    assert_eq!(apu::mix(0, 0, 0, 0, 0), -1.0);
+    assert!(close(apu::mix(15, 15, 15, 15, 127), 1.0));
+    // One pulse at full volume is about 0.15 before scaling, and two aren't twice as loud
+    assert!(close(apu::mix(15, 0, 0, 0, 0), level(pulse(15.0), 0.0)));
+    assert!(close(apu::mix(15, 0, 0, 0, 0), -0.70236));
+    assert_eq!(apu::mix(0, 15, 0, 0, 0), apu::mix(15, 0, 0, 0, 0));
+    assert!(close(apu::mix(15, 15, 0, 0, 0), level(pulse(30.0), 0.0)));
+    assert!(apu::mix(15, 15, 0, 0, 0) + 1.0 < 2.0 * (apu::mix(15, 0, 0, 0, 0) + 1.0));
+    // The triangle, noise and DMC share a DAC, weighted 3:2:1
+    assert!(close(apu::mix(0, 0, 15, 0, 0), level(0.0, tnd(45.0))));
+    assert_eq!(apu::mix(0, 0, 2, 0, 0), apu::mix(0, 0, 0, 3, 0));
+    assert_eq!(apu::mix(0, 0, 0, 1, 0), apu::mix(0, 0, 0, 0, 2));
+    assert!(close(
+        apu::mix(8, 4, 7, 9, 64),
+        level(pulse(12.0), tnd(103.0))
+    ));
+}
+
+#[test]
+fn noise_lfsr_sequences() {
+    let apu = APU::new();
+    assert_eq!(apu.noise_lfsr.get(), 1);
+
+    // The first shift feeds bit 0 (set) xor bit 1 (clear) into bit 14
+    tick(&apu, 1);
+    assert_eq!(apu.noise_lfsr.get(), 0x4000);
+    tick(&apu, 3);
+    assert_eq!(apu.noise_lfsr.get(), 0x4000);
+    tick(&apu, 1);
+    assert_eq!(apu.noise_lfsr.get(), 0x2000);
+
+    apu.reset();
+    assert_eq!(apu.noise_lfsr.get(), 1);
+    assert_eq!(noise_sequence_length(&apu), 32767);
+
+    // Short mode, from bit 6
+    apu.write(0x400E, 0x80);
+    apu.reset();
+    assert_eq!(noise_sequence_length(&apu), 93);
+
+    // Slower periods
+    apu.write(0x400E, 0x0F);
+    tick(&apu, 4);
+    let lfsr = apu.noise_lfsr.get();
+    tick(&apu, 4067);
+    assert_eq!(apu.noise_lfsr.get(), lfsr);
+    tick(&apu, 1);
+    assert_ne!(apu.noise_lfsr.get(), lfsr);
+}
+
+#[test]
+fn noise_output() {
+    let apu = APU::new();
+    write_frame_counter(&apu, 0x40);
+    apu.write(0x4015, 0x08);
+    apu.write(0x400C, 0x1A);
+    apu.write(0x400E, 0x00);
+    apu.write(0x400F, 0x08);
+
+    // Bit 0 of the LFSR mutes the channel
+    let mut heard = 0;
+    for _ in 0..1000 {
+        tick(&apu, 4);
+        let expected = if apu.noise_lfsr.get() & 1 == 1 { 0 } else { 10 };
+        assert_eq!(apu.noise_output(), expected);
+        heard += (apu.noise_output() > 0) as usize;
+    }
+    assert!(heard > 300 && heard < 700);
+
+    // It's mixed in with the other DAC's curve
+    while apu.noise_output() == 0 {
+        tick(&apu, 4);
+    }
+    assert_eq!(apu.output(), apu::mix(0, 0, 0, 10, 0));
+    apu.set_channel_enabled(3, false);
+    assert_eq!(apu.output(), -1.0);
+    apu.set_channel_enabled(3, true);
+
+    // It has an envelope, started by writing $400F, like the pulses
+    apu.write(0x400C, 0x00);
+    assert_eq!(apu.noise_envelope.output(), 0);
+    apu.write(0x400F, 0x08);
+    assert!(apu.noise_envelope.start.get());
+    tick(&apu, 7457);
+    assert_eq!(apu.noise_envelope.output(), 15);
+
+    // And a length counter
+    apu.write(0x4015, 0x00);
+    assert_eq!(apu.noise_output(), 0);
+}
+
+#[test]
+fn frame_counter_writes_take_effect_a_few_cycles_late() {
+    // 3 cycles after the write's cycle when it's an APU cycle, and 4 when it's between them
+    for (odd_cycle, delay) in [(true, 4), (false, 5)] {
+        let apu = APU::new();
+        apu.odd_cycle.set(odd_cycle);
+        apu.write(0x4015, 0x01);
+        apu.write(0x4003, 0x08);
+        tick(&apu, 100);
+
+        // Until then the old sequence carries on, in the old mode
+        apu.write(0x4017, 0x80);
+        tick(&apu, delay - 1);
+        assert_eq!(apu.frame_cycle.get(), 100 + delay as u16 - 1);
+        assert!(!apu.five_step.get());
+        assert_eq!(apu.length_counters[0].get(), 254);
+
+        // And then it restarts, with the clock from going to 5 step mode
+        tick(&apu, 1);
+        assert_eq!(apu.frame_cycle.get(), 0);
+        assert!(apu.five_step.get());
+        assert_eq!(apu.length_counters[0].get(), 253);
+    }
+
+    // The IRQ inhibit flag doesn't wait
+    let apu = APU::new();
+    tick(&apu, 29828);
+    assert!(apu.irq());
+    apu.write(0x4017, 0x40);
+    assert!(!apu.irq());
+    tick(&apu, 1);
+    assert!(!apu.irq());
+
+    // Nor does an IRQ that would've fired before the restart
+    let apu = APU::new();
+    tick(&apu, 29826);
+    apu.write(0x4017, 0x00);
+    tick(&apu, 2);
+    assert!(apu.irq());
+}
+
+#[test]
+fn dmc_plays_samples_a_bit_at_a_time() {
+    let apu = APU::new();
+    apu.write(0x4010, 0x0F);
+    apu.write(0x4011, 0x7A);
+    apu.write(0x4012, 0x10);
+    apu.write(0x4013, 0x00);
+    assert_eq!(apu.dmc.dma_address(), None);
+
+    // A one byte sample at $C400, which is fetched straight away
+    apu.write(0x4015, 0x10);
+    assert_eq!(apu.read_status() & 0x10, 0x10);
+    assert_eq!(apu.dmc.dma_address(), Some(0xC400));
+    apu.dmc.fill_buffer(0b0000_1111);
+    assert_eq!(apu.dmc.dma_address(), None);
+    assert_eq!(apu.read_status() & 0x10, 0);
+
+    // The output unit finishes the 8 silent bits it was on, then plays the byte lowest bit first,
+    // stopping at 127
+    let mut levels = vec![];
+    for _ in 0..16 * 54 {
+        apu.tick();
+        if levels.last() != Some(&apu.dmc_output()) {
+            levels.push(apu.dmc_output());
+        }
+    }
+    assert_eq!(levels, [122, 124, 126, 124, 122, 120, 118]);
+    assert_eq!(apu.dmc.dma_address(), None);
+    assert!(!apu.irq());
+    assert!(apu.output() > -1.0);
+
+    // $4011 sets the level directly
+    apu.write(0x4011, 0xFF);
+    assert_eq!(apu.dmc_output(), 0x7F);
+}
+
+// Fills the DMC's buffer whenever it asks, and empties it again, until the sample's done or
+// `max_bytes` have been read
+fn dmc_sample_addresses(apu: &APU, max_bytes: usize) -> Vec<u16> {
+    let mut addresses = vec![];
+    while let Some(address) = apu.dmc.dma_address() {
+        if addresses.len() == max_bytes {
+            break;
+        }
+        addresses.push(address);
+        apu.dmc.fill_buffer(0);
+        apu.dmc.buffer.set(None);
+    }
+    addresses
+}
+
+#[test]
+fn dmc_samples_wrap_loop_and_raise_irqs() {
+    let apu = APU::new();
+    // 65 bytes from $FFC0, which wraps around to $8000 for the last one
+    apu.write(0x4010, 0x80);
+    apu.write(0x4012, 0xFF);
+    apu.write(0x4013, 0x04);
+    apu.write(0x4015, 0x10);
+    let addresses = dmc_sample_addresses(&apu, 100);
+    assert_eq!(addresses.len(), 65);
+    assert_eq!(addresses[0], 0xFFC0);
+    assert_eq!(addresses[63], 0xFFFF);
+    assert_eq!(addresses[64], 0x8000);
+
+    assert!(apu.irq());
+    assert_eq!(apu.read_status() & 0x90, 0x80);
+    // Writing $4015 acknowledges it, and turning the IRQ off in $4010 does too
+    apu.write(0x4015, 0x10);
+    assert!(!apu.irq());
+    dmc_sample_addresses(&apu, 100);
+    assert!(apu.irq());
+    apu.write(0x4010, 0x00);
+    assert!(!apu.irq());
+
+    // Looping goes back to the start with no IRQ
+    apu.write(0x4010, 0xC0);
+    apu.write(0x4015, 0x10);
+    let addresses = dmc_sample_addresses(&apu, 130);
+    assert_eq!(addresses.len(), 130);
+    assert_eq!(addresses[65], 0xFFC0);
+    assert!(!apu.irq());
+
+    // Turning the channel off stops it
+    apu.write(0x4015, 0x00);
+    assert_eq!(apu.dmc.dma_address(), None);
+    assert_eq!(apu.read_status() & 0x10, 0);
+}
@@ -2,10 +2,14 @@ use std::fs::File;
 
 use anyhow::Result;
 use covnes::{
-    nes::{cpu::CpuHostAccess, io::DummyIO, mappers, Nes},
+    nes::{io::DummyIO, mappers, Nes},
     romfiles::RomFile,
+    test_support::{self, TestFailure},
 };
 
+// A minute of emulated time, which is well over what any of these take
+const TIMEOUT_CYCLES: u64 = 60 * 1_789_773;
+
 fn do_rom(name: &str) -> Result<()> {
     // Load up the rom
     let path = format!("../roms/test/{}.nes", name);
@@ -21,41 +25,21 @@ fn do_rom(name: &str) -> Result<()> {
 
     nes.insert_cartridge(cart);
 
-    loop {
-        for _ in 0..1000 {
-            nes.tick_cpu();
-        }
-        let code = (&nes).read(0x6000);
-        if code != 0 {
-            break;
-        }
-    }
-
-    loop {
-        for _ in 0..1000 {
-            nes.tick_cpu();
-        }
-
-        let mut status = String::new();
-        let mut p = 0x6004;
-        loop {
-            let c = (&nes).read(p);
-            if c == 0 {
-                break;
-            }
-
-            p += 1;
-
-            status.push(c as char);
-        }
-
-        let code = (&nes).read(0x6000);
-        if code == 0 {
-            break;
-        } else if code != 0x80 {
-            panic!("Status: {:2X}\n{}", code, status)
-        }
-    }
+    test_support::run_blargg(&nes, TIMEOUT_CYCLES)?;
+
+    Ok(())
+}
+
+#[test]
+fn roms_that_never_report_time_out() -> Result<()> {
+    let mut nes = Nes::new(DummyIO);
+    nes.insert_cartridge(mappers::from_rom(RomFile::from_filename(
+        "../roms/test/nestest.nes",
+    )?)?);
+
+    let result = test_support::run_blargg(&nes, 100_000);
+    assert!(matches!(result, Err(TestFailure::Timeout { .. })));
+    assert!(nes.cpu_cycles() < 110_000);
 
     Ok(())
 }
@@ -79,6 +63,55 @@ fn ppu_vbl_nmi() -> Result<()> {
     do_rom("ppu_vbl_nmi")
 }
 
+#[test]
+fn oam_stress() -> Result<()> {
+    do_rom("oam_stress")
+}
+
+// blargg's apu_test ROMs aren't in roms/test yet. This one only needs the length counters and
+// $4015, so should pass once it's there.
+#[test]
+#[ignore = "needs roms/test/apu_test"]
+fn apu_len_ctr() -> Result<()> {
+    do_rom("apu_test/rom_singles/1-len_ctr")
+}
+
+// blargg's cpu_interrupts_v2 isn't in roms/test yet either. Until it is, the only coverage of the
+// interrupt timing is `cli_takes_effect_an_instruction_late` in nes_tests.
+fn do_rom_cpu_interrupts_v2(name: &str) -> Result<()> {
+    do_rom(format!("cpu_interrupts_v2/rom_singles/{}", name).as_str())
+}
+
+#[test]
+#[ignore = "needs roms/test/cpu_interrupts_v2"]
+fn cpu_interrupts_cli_latency() -> Result<()> {
+    do_rom_cpu_interrupts_v2("1-cli_latency")
+}
+
+#[test]
+#[ignore = "needs roms/test/cpu_interrupts_v2"]
+fn cpu_interrupts_nmi_and_brk() -> Result<()> {
+    do_rom_cpu_interrupts_v2("2-nmi_and_brk")
+}
+
+#[test]
+#[ignore = "needs roms/test/cpu_interrupts_v2"]
+fn cpu_interrupts_nmi_and_irq() -> Result<()> {
+    do_rom_cpu_interrupts_v2("3-nmi_and_irq")
+}
+
+#[test]
+#[ignore = "needs roms/test/cpu_interrupts_v2"]
+fn cpu_interrupts_irq_and_dma() -> Result<()> {
+    do_rom_cpu_interrupts_v2("4-irq_and_dma")
+}
+
+#[test]
+#[ignore = "needs roms/test/cpu_interrupts_v2"]
+fn cpu_interrupts_branch_delays_irq() -> Result<()> {
+    do_rom_cpu_interrupts_v2("5-branch_delays_irq")
+}
+
 #[test]
 fn instr_test_v5() -> Result<()> {
     do_rom("instr_test-v5")
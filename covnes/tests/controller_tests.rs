@@ -0,0 +1,259 @@
+use std::cell::Cell;
+
+use anyhow::Result;
+use covnes::{
+    nes::{
+        input_recorder::InputRecorder,
+        io::{
+            ArkanoidPaddle, ArkanoidPaddleIO, ControllerPortDataLines, PaddleState, PaddleWiring,
+            SingleStandardController, SingleStandardControllerIO, StandardControllerButtons,
+            TwoStandardControllers, TwoStandardControllersIO, IO,
+        },
+        Nes, FRAME_BUFFER_SIZE,
+    },
+    romfiles::RomFile,
+};
+
+struct Buttons(Cell<StandardControllerButtons>);
+
+impl SingleStandardControllerIO for Buttons {
+    fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+    fn poll_buttons(&self) -> StandardControllerButtons {
+        self.0.get()
+    }
+}
+
+fn new_nes() -> Nes<SingleStandardController<Buttons>> {
+    let io = Buttons(Cell::new(StandardControllerButtons::empty()));
+    Nes::new(SingleStandardController::new(io))
+}
+
+fn set_buttons(nes: &Nes<SingleStandardController<Buttons>>, buttons: StandardControllerButtons) {
+    nes.io.io.0.set(buttons);
+}
+
+#[test]
+fn strobe_high_reads_live_a() {
+    let nes = new_nes();
+    nes.write_u8(0x4016, 1);
+
+    set_buttons(
+        &nes,
+        StandardControllerButtons::A | StandardControllerButtons::START,
+    );
+    for _ in 0..4 {
+        assert_eq!(nes.read_u8(0x4016) & 1, 1);
+    }
+    set_buttons(&nes, StandardControllerButtons::START);
+    for _ in 0..4 {
+        assert_eq!(nes.read_u8(0x4016) & 1, 0);
+    }
+
+    // None of those reads should have shifted anything out
+    set_buttons(
+        &nes,
+        StandardControllerButtons::A
+            | StandardControllerButtons::SELECT
+            | StandardControllerButtons::RIGHT,
+    );
+    nes.write_u8(0x4016, 0);
+    // The buttons read back in the order A, B, Select, Start, Up, Down, Left, Right
+    let bits: Vec<u8> = (0..8).map(|_| nes.read_u8(0x4016) & 1).collect();
+    assert_eq!(bits, [1, 0, 1, 0, 0, 0, 0, 1]);
+
+    // Official controllers read 1 once everything has been shifted out
+    for _ in 0..4 {
+        assert_eq!(nes.read_u8(0x4016) & 1, 1);
+    }
+}
+
+#[test]
+fn buttons_latched_when_strobe_goes_low() {
+    let nes = new_nes();
+    set_buttons(&nes, StandardControllerButtons::B);
+    nes.write_u8(0x4016, 1);
+    nes.write_u8(0x4016, 0);
+
+    // Changes after the strobe don't show up until the next one
+    set_buttons(&nes, StandardControllerButtons::A);
+    let bits: Vec<u8> = (0..8).map(|_| nes.read_u8(0x4016) & 1).collect();
+    assert_eq!(bits, [0, 1, 0, 0, 0, 0, 0, 0]);
+
+    // Writing 0 again isn't a transition, so doesn't reload
+    nes.write_u8(0x4016, 0);
+    assert_eq!(nes.read_u8(0x4016) & 1, 1);
+
+    nes.write_u8(0x4016, 1);
+    nes.write_u8(0x4016, 0);
+    assert_eq!(nes.read_u8(0x4016) & 1, 1);
+    assert_eq!(nes.read_u8(0x4016) & 1, 0);
+}
+
+struct TwoButtons([Cell<StandardControllerButtons>; 2]);
+
+impl TwoStandardControllersIO for TwoButtons {
+    fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+    fn poll_buttons(&self, port: usize) -> StandardControllerButtons {
+        self.0[port].get()
+    }
+}
+
+#[test]
+fn two_controllers_shift_independently() {
+    let io = TwoButtons([
+        Cell::new(StandardControllerButtons::A | StandardControllerButtons::UP),
+        Cell::new(StandardControllerButtons::B | StandardControllerButtons::RIGHT),
+    ]);
+    let nes = Nes::new(TwoStandardControllers::new(io));
+
+    nes.write_u8(0x4016, 1);
+    assert_eq!(nes.read_u8(0x4016) & 1, 1);
+    assert_eq!(nes.read_u8(0x4017) & 1, 0);
+    nes.write_u8(0x4016, 0);
+
+    let mut port1 = vec![];
+    let mut port2 = vec![];
+    for _ in 0..8 {
+        // Interleaving the reads shouldn't matter
+        port2.push(nes.read_u8(0x4017) & 1);
+        port1.push(nes.read_u8(0x4016) & 1);
+    }
+    assert_eq!(port1, [1, 0, 0, 0, 1, 0, 0, 0]);
+    assert_eq!(port2, [0, 1, 0, 0, 0, 0, 0, 1]);
+}
+
+// An expansion device that drives whichever lines it's told to on each port
+struct DataLines([Cell<ControllerPortDataLines>; 2]);
+
+impl IO for DataLines {
+    fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+    fn controller_latch_change(&self, _value: bool) {}
+
+    fn controller_port_1_read(&self) -> ControllerPortDataLines {
+        self.0[0].get()
+    }
+
+    fn controller_port_2_read(&self) -> ControllerPortDataLines {
+        self.0[1].get()
+    }
+}
+
+#[test]
+fn expansion_data_lines_come_through() {
+    let empty = || Cell::new(ControllerPortDataLines::empty());
+    let nes = Nes::new(DataLines([empty(), empty()]));
+    let lines = &nes.io.0;
+
+    lines[0].set(ControllerPortDataLines::D3 | ControllerPortDataLines::D4);
+    lines[1].set(ControllerPortDataLines::D0 | ControllerPortDataLines::D3);
+    assert_eq!(nes.read_u8(0x4016) & 0x1F, 0x18);
+    assert_eq!(nes.read_u8(0x4017) & 0x1F, 0x09);
+
+    lines[0].set(ControllerPortDataLines::all());
+    lines[1].set(ControllerPortDataLines::D4);
+    assert_eq!(nes.read_u8(0x4016) & 0x1F, 0x1F);
+    assert_eq!(nes.read_u8(0x4017) & 0x1F, 0x10);
+}
+
+struct Paddle(Cell<PaddleState>);
+
+impl ArkanoidPaddleIO for Paddle {
+    fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+    fn poll_paddle(&self) -> PaddleState {
+        self.0.get()
+    }
+}
+
+#[test]
+fn arkanoid_paddle_shifts_out_its_position() {
+    for &(wiring, position_line, fire_addr, fire_line) in &[
+        (PaddleWiring::Famicom, 0x02, 0x4016, 0x02),
+        (PaddleWiring::Nes, 0x10, 0x4017, 0x08),
+    ] {
+        let state = PaddleState {
+            position: 0xA5,
+            fire: true,
+        };
+        let nes = Nes::new(ArkanoidPaddle::new(Paddle(Cell::new(state)), wiring));
+        assert_eq!(nes.read_u8(fire_addr) & fire_line, fire_line);
+
+        nes.write_u8(0x4016, 1);
+        nes.write_u8(0x4016, 0);
+        // Moving it after the strobe doesn't change what's shifted out
+        nes.io.io.0.set(PaddleState {
+            position: 0x10,
+            fire: false,
+        });
+
+        // Most significant bit first, and inverted
+        let bits: Vec<u8> = (0..8)
+            .map(|_| (nes.read_u8(0x4017) & position_line != 0) as u8)
+            .collect();
+        assert_eq!(bits, [0, 1, 0, 1, 1, 0, 1, 0], "{:?}", wiring);
+        assert_eq!(nes.read_u8(0x4017) & position_line, 0);
+        // The button isn't latched
+        assert_eq!(nes.read_u8(fire_addr) & fire_line, 0);
+    }
+
+    assert_eq!(PaddleState::from_mouse_x(0.0, false).position, 0x62);
+    assert_eq!(PaddleState::from_mouse_x(2.0, false).position, 0xF2);
+}
+
+fn nestest_with_recorder() -> Result<Nes<TwoStandardControllers<InputRecorder<TwoButtons>>>> {
+    let io = TwoButtons([
+        Cell::new(StandardControllerButtons::empty()),
+        Cell::new(StandardControllerButtons::empty()),
+    ]);
+    let mut nes = Nes::new(TwoStandardControllers::new(InputRecorder::new(io)));
+    nes.load_rom(RomFile::from_filename("../roms/test/nestest.nes")?)?;
+    Ok(nes)
+}
+
+fn last_frame(
+    nes: &Nes<TwoStandardControllers<InputRecorder<TwoButtons>>>,
+    frames: usize,
+) -> Vec<u8> {
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..frames {
+        nes.step_frame_into(&mut buf);
+    }
+    buf
+}
+
+#[test]
+fn recorded_input_plays_back_the_same_frames() -> Result<()> {
+    // Move down nestest's menu and start the tests
+    let nes = nestest_with_recorder()?;
+    let recorder = &nes.io.io;
+    recorder.record_start();
+    let mut recorded = vec![0; FRAME_BUFFER_SIZE];
+    for frame in 0..100 {
+        let buttons = match frame {
+            20..=24 => StandardControllerButtons::DOWN,
+            40..=44 => StandardControllerButtons::START,
+            _ => StandardControllerButtons::empty(),
+        };
+        recorder.io.0[0].set(buttons);
+        nes.step_frame_into(&mut recorded);
+    }
+    let log = recorder.record_stop();
+    assert!(!recorder.is_recording());
+    assert_eq!(log.len(), 100);
+    assert_eq!(log[40][0], StandardControllerButtons::START);
+
+    // The live input is ignored while playing
+    let replay = nestest_with_recorder()?;
+    replay.io.io.io.0[0].set(StandardControllerButtons::SELECT);
+    replay.io.io.play(log);
+    assert_eq!(last_frame(&replay, 100), recorded);
+    assert_eq!(replay.io.io.frames_left_to_play(), 0);
+
+    // Which wouldn't be much of a test if the input made no difference
+    assert_ne!(last_frame(&nestest_with_recorder()?, 100), recorded);
+
+    Ok(())
+}
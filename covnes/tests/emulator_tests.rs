@@ -0,0 +1,55 @@
+use std::fs::File;
+
+use anyhow::Result;
+use covnes::{
+    nes::{
+        io::{DummyIO, StandardControllerButtons},
+        test_pattern, Nes, FRAME_BUFFER_SIZE,
+    },
+    romfiles::RomFile,
+    Emulator,
+};
+
+fn nestest() -> Result<RomFile> {
+    let mut f = File::open("../roms/test/nestest.nes")?;
+    Ok(RomFile::from_read(&mut f)?)
+}
+
+#[test]
+fn shows_the_test_pattern_until_a_rom_is_loaded() -> Result<()> {
+    let mut emulator = Emulator::new();
+    let mut pattern = vec![0; FRAME_BUFFER_SIZE];
+    test_pattern::draw_into(&mut pattern);
+
+    assert_eq!(emulator.step_frame(), 0);
+    assert!(emulator.frame_buffer() == &pattern[..]);
+
+    emulator.load_rom(nestest()?)?;
+    assert!(emulator.frame_buffer().iter().all(|&b| b == 0));
+
+    Ok(())
+}
+
+#[test]
+fn frames_match_the_nes_underneath() -> Result<()> {
+    let mut emulator = Emulator::new();
+    emulator.load_rom(nestest()?)?;
+    let mut nes = Nes::new(DummyIO);
+    nes.load_rom(nestest()?)?;
+
+    let mut frame = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..10 {
+        let ticks = emulator.step_frame();
+        assert_eq!(nes.step_frame_into(&mut frame), ticks);
+        assert!(emulator.frame_buffer() == &frame[..]);
+    }
+
+    // Resetting lets go of the buttons
+    emulator.reset();
+    emulator.nes().write_u8(0x4016, 1);
+    assert_eq!(emulator.nes().read_u8(0x4016) & 1, 0);
+    emulator.set_buttons(StandardControllerButtons::A);
+    assert_eq!(emulator.nes().read_u8(0x4016) & 1, 1);
+
+    Ok(())
+}
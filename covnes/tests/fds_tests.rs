@@ -0,0 +1,287 @@
+// Run with `cargo test --features fds`
+#![cfg(feature = "fds")]
+
+use std::cell::Cell;
+
+use anyhow::Result;
+use covnes::{
+    error::Error,
+    fds::{FdsImage, SIDE_SIZE},
+    nes::{
+        io::DummyIO,
+        mappers::{common::MirrorMode, fds::Fds, Cartridge},
+        state::{SaveState, StateReader, StateWriter},
+        Nes,
+    },
+};
+
+// A side with the disk info block, the file count and a single file of `data`
+fn side(data: &[u8]) -> Vec<u8> {
+    let mut side = vec![0x01];
+    side.extend_from_slice(b"*NINTENDO-HVC*");
+    side.resize(56, 0);
+    side.extend_from_slice(&[0x02, 1]);
+    let mut header = vec![0x03, 0, 0];
+    header.extend_from_slice(b"FILENAME");
+    header.extend_from_slice(&[0x00, 0x60, data.len() as u8, (data.len() >> 8) as u8, 0]);
+    side.extend_from_slice(&header);
+    side.push(0x04);
+    side.extend_from_slice(data);
+    side.resize(SIDE_SIZE, 0);
+    side
+}
+
+// A BIOS that does nothing but sit in a loop at $E000
+fn bios() -> Vec<u8> {
+    let mut bios = vec![0; 8192];
+    bios[..3].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+    for vector in (0x1FFA..0x2000).step_by(2) {
+        bios[vector..vector + 2].copy_from_slice(&[0x00, 0xE0]);
+    }
+    bios
+}
+
+fn fds(sides: &[&[u8]]) -> Result<Cartridge> {
+    let image: Vec<u8> = sides.iter().flat_map(|data| side(data)).collect();
+    let image = FdsImage::from_bytes(&image)?;
+    Ok(Cartridge::boxed(Fds::new(bios(), &image)?)?)
+}
+
+fn tick(cart: &Cartridge, cycles: usize) {
+    for _ in 0..cycles {
+        cart.cpu_tick();
+    }
+}
+
+// Runs the drive until a byte comes in, which has to be within `max_cycles`
+fn next_byte(cart: &Cartridge, max_cycles: usize) -> Option<u8> {
+    for _ in 0..max_cycles {
+        cart.cpu_tick();
+        if cart.read_cpu(0x4030)? & 0x02 == 0x02 {
+            return cart.read_cpu(0x4031);
+        }
+    }
+    None
+}
+
+#[test]
+fn images_with_and_without_a_header() -> Result<()> {
+    let raw: Vec<u8> = [side(b"A"), side(b"B")].concat();
+    let image = FdsImage::from_bytes(&raw)?;
+    assert_eq!(image.sides.len(), 2);
+    assert_eq!(image.sides[1], side(b"B"));
+
+    let mut headed = b"FDS\x1A\x02".to_vec();
+    headed.resize(16, 0);
+    headed.extend_from_slice(&raw);
+    assert_eq!(FdsImage::from_bytes(&headed)?.sides, image.sides);
+    assert_eq!(FdsImage::from_bytes(&headed)?.crc32(), image.crc32());
+
+    assert!(matches!(
+        FdsImage::from_bytes(&raw[1..]),
+        Err(Error::BadFdsImageSize(size)) if size == 2 * SIDE_SIZE - 1
+    ));
+    assert!(matches!(
+        FdsImage::from_bytes(&[]),
+        Err(Error::BadFdsImageSize(0))
+    ));
+    assert!(matches!(
+        FdsImage::from_bytes(&vec![0; SIDE_SIZE]),
+        Err(Error::NotFds)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn disks_have_gaps_before_every_block() -> Result<()> {
+    let image = FdsImage::from_bytes(&side(b"hello"))?;
+    let disk = image.disk(0);
+
+    // Each block starts after a gap ending in $80, and the file data block's length comes from
+    // the header before it
+    let starts: Vec<usize> = (1..disk.len())
+        .filter(|&i| disk[i - 1] == 0x80 && disk[i - 2] == 0)
+        .collect();
+    assert_eq!(starts.len(), 4);
+    assert_eq!(
+        starts.iter().map(|&i| disk[i]).collect::<Vec<_>>(),
+        [1, 2, 3, 4]
+    );
+    assert_eq!(&disk[starts[3] + 1..starts[3] + 6], b"hello");
+    assert!(disk[..starts[0] - 1].iter().all(|&b| b == 0));
+    assert!(disk.len() >= SIDE_SIZE);
+
+    Ok(())
+}
+
+#[test]
+fn fds_memory_map() -> Result<()> {
+    let cart = fds(&[b"A"])?;
+
+    for &addr in [0x6000, 0x9ABC, 0xDFFF].iter() {
+        cart.write_cpu(addr, addr as u8);
+        assert_eq!(cart.read_cpu(addr), Some(addr as u8));
+    }
+    // The BIOS can't be written over
+    assert_eq!(cart.read_cpu(0xE000), Some(0x4C));
+    cart.write_cpu(0xE000, 0);
+    assert_eq!(cart.read_cpu(0xE000), Some(0x4C));
+    assert_eq!(cart.read_cpu(0xFFFD), Some(0xE0));
+    // The disk registers stay off the bus until $4023 turns them on
+    assert_eq!(cart.read_cpu(0x4033), None);
+    cart.write_cpu(0x4023, 0x01);
+    assert_eq!(cart.read_cpu(0x4033), Some(0x80));
+
+    // CHR RAM, and mirroring from bit 3 of $4025
+    let vram = vec![Cell::new(0); 2048];
+    cart.write_ppu(&vram, 0x1234, 0x56);
+    assert_eq!(cart.read_ppu(&vram, 0x1234), 0x56);
+    cart.write_ppu(&vram, 0x2000, 0x11);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0x11);
+    cart.write_cpu(0x4025, 0x08);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 0x11);
+    cart.force_mirroring(Some(MirrorMode::OneScreenHigher));
+    assert_eq!(cart.read_ppu(&vram, 0x2000), 0x00);
+
+    Ok(())
+}
+
+#[test]
+fn fds_timer_irq() -> Result<()> {
+    let cart = fds(&[b"A"])?;
+    cart.write_cpu(0x4023, 0x01);
+    cart.write_cpu(0x4020, 0x10);
+    cart.write_cpu(0x4021, 0x00);
+
+    // One shot: counts down from $10 through 0
+    cart.write_cpu(0x4022, 0x02);
+    tick(&cart, 16);
+    assert!(!cart.irq());
+    tick(&cart, 1);
+    assert!(cart.irq());
+    // Peeking at the status and data leaves it pending
+    assert_eq!(cart.peek_cpu(0x4030).map(|s| s & 1), Some(1));
+    cart.peek_cpu(0x4031);
+    assert!(cart.irq());
+    assert_eq!(cart.read_cpu(0x4030).map(|s| s & 1), Some(1));
+    assert!(!cart.irq());
+    tick(&cart, 100);
+    assert!(!cart.irq());
+
+    // Repeating
+    cart.write_cpu(0x4022, 0x03);
+    for _ in 0..3 {
+        tick(&cart, 16);
+        assert!(!cart.irq());
+        tick(&cart, 1);
+        assert!(cart.irq());
+        cart.read_cpu(0x4030);
+    }
+
+    // Turning the disk registers off stops it
+    cart.write_cpu(0x4023, 0x00);
+    tick(&cart, 100);
+    assert!(!cart.irq());
+
+    Ok(())
+}
+
+#[test]
+fn fds_reads_the_disk() -> Result<()> {
+    let cart = fds(&[b"hello"])?;
+    cart.write_cpu(0x4023, 0x01);
+    // Not ready until the motor's going
+    assert_eq!(cart.read_cpu(0x4032), Some(0x06));
+
+    // Motor on with the transfer reset, then let the head go looking for a block
+    cart.write_cpu(0x4025, 0x27);
+    tick(&cart, 10);
+    cart.write_cpu(0x4025, 0x65);
+
+    // The head has to get back to the start, then through the lead-in. Polling sees the $80 that
+    // ends the gap, then the block.
+    assert_eq!(next_byte(&cart, 1_000_000), Some(0x80));
+    assert_eq!(cart.read_cpu(0x4032), Some(0x04));
+    assert_eq!(next_byte(&cart, 200), Some(0x01));
+    let mut block = vec![0x01];
+    while block.len() < 15 {
+        block.push(next_byte(&cart, 200).unwrap());
+    }
+    assert_eq!(&block[1..], b"*NINTENDO-HVC*");
+
+    // Back in the gap, and then on to the next block with IRQs for each byte this time
+    cart.write_cpu(0x4025, 0x25);
+    tick(&cart, 200 * 50);
+    cart.write_cpu(0x4025, 0xE5);
+    let mut cycles = 0;
+    while !cart.irq() {
+        cart.cpu_tick();
+        cycles += 1;
+        assert!(cycles < 200 * 100);
+    }
+    assert_eq!(cart.read_cpu(0x4031), Some(0x02));
+    assert!(!cart.irq());
+
+    Ok(())
+}
+
+#[test]
+fn fds_disk_changes() -> Result<()> {
+    let cart = fds(&[b"A", b"B", b"C"])?;
+    let drive = cart.as_fds().unwrap();
+    cart.write_cpu(0x4023, 0x01);
+    assert_eq!(drive.side_count(), 3);
+    assert_eq!(drive.inserted_side(), Some(0));
+
+    // Sides can't be changed with the disk in
+    drive.select_next_side();
+    assert_eq!(drive.selected_side(), 0);
+
+    drive.insert_or_eject();
+    assert_eq!(drive.inserted_side(), None);
+    assert_eq!(cart.read_cpu(0x4032), Some(0x07));
+    for &side in [1, 2, 0, 1].iter() {
+        drive.select_next_side();
+        assert_eq!(drive.selected_side(), side);
+    }
+    drive.insert_or_eject();
+    assert_eq!(drive.inserted_side(), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn fds_state_round_trip() -> Result<()> {
+    let mut nes = Nes::new(DummyIO);
+    let image = FdsImage::from_bytes(&[side(b"A"), side(b"B")].concat())?;
+    nes.load_fds(bios(), &image)?;
+    assert!(nes.has_cartridge());
+
+    nes.write_u8(0x6000, 0x12);
+    nes.fds().unwrap().insert_or_eject();
+    nes.fds().unwrap().select_next_side();
+    nes.step_frame();
+    let state = nes.save_state();
+
+    nes.write_u8(0x6000, 0x34);
+    nes.fds().unwrap().insert_or_eject();
+    nes.load_state(&state)?;
+    assert_eq!(nes.read_u8(0x6000), 0x12);
+    assert_eq!(nes.fds().unwrap().inserted_side(), None);
+    assert_eq!(nes.fds().unwrap().selected_side(), 1);
+
+    // A bad side number is caught
+    let cart = fds(&[b"A"])?;
+    let mut w = StateWriter::new();
+    nes.cartridge.save_state(&mut w);
+    let state = w.into_inner();
+    assert!(cart.load_state(&mut StateReader::new(&state)).is_err());
+
+    assert!(matches!(
+        nes.load_fds(vec![0; 100], &image),
+        Err(Error::BadFdsBiosSize(100))
+    ));
+
+    Ok(())
+}
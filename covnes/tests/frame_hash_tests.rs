@@ -0,0 +1,71 @@
+use std::fs::File;
+
+use anyhow::Result;
+use covnes::{
+    nes::{io::DummyIO, mappers, Nes, FRAME_BUFFER_SIZE},
+    romfiles::RomFile,
+};
+
+// Renders a fixed number of frames of a test rom and compares a hash of the last one, to catch
+// rendering changes that the CPU and register level tests don't see.
+//
+// If rendering changes on purpose, get the new hashes with
+//
+//     cargo test --test frame_hash_tests -- --ignored --nocapture
+
+// (rom, frames to run, hash of the last frame)
+const GOLDEN: &[(&str, usize, u64)] = &[("nestest", 60, 0x309b_b29b_7ca0_9c7f)];
+
+fn render(name: &str, frames: usize) -> Result<Vec<u8>> {
+    let path = format!("../roms/test/{}.nes", name);
+    let mut f = File::open(path)?;
+    let rom = RomFile::from_read(&mut f)?;
+    let cart = mappers::from_rom(rom)?;
+
+    let mut nes = Nes::new(DummyIO);
+    nes.insert_cartridge(cart);
+    nes.reset();
+
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..frames {
+        nes.step_frame_into(&mut buf);
+    }
+
+    Ok(buf)
+}
+
+// 64 bit FNV-1a
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[test]
+fn frame_hashes_match() -> Result<()> {
+    for &(name, frames, expected) in GOLDEN {
+        let frame = render(name, frames)?;
+        // A blank screen would make for a pretty useless test
+        assert!(frame.iter().any(|&b| b != frame[0]), "{} is blank", name);
+
+        let actual = hash(&frame);
+        assert_eq!(
+            actual, expected,
+            "{} after {} frames hashed to {:#018x}",
+            name, frames, actual
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+#[ignore]
+fn print_frame_hashes() -> Result<()> {
+    for &(name, frames, _) in GOLDEN {
+        let actual = hash(&render(name, frames)?);
+        println!("    ({:?}, {}, {:#018x}),", name, frames, actual);
+    }
+
+    Ok(())
+}
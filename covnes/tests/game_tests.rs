@@ -5,7 +5,7 @@ use std::{
 
 use anyhow::Result;
 use covnes::{
-    nes::{cpu::CpuHostAccess, io::DummyIO, mappers, Nes},
+    nes::{io::DummyIO, mappers, Nes},
     romfiles::RomFile,
 };
 use regex::Regex;
@@ -47,9 +47,6 @@ fn log_cmp(game: &str) -> Result<()> {
 
     nes.insert_cartridge(cart);
 
-    // Annoyingly, nestest doesn't do the right thing with the PPU after reset
-    nes.ppu.dot.set(0);
-
     // It FFs the ram
     nes.cpu_ram.set([0xFF; 2048]);
 
@@ -88,15 +85,16 @@ fn log_cmp(game: &str) -> Result<()> {
             let expected_bsh = u16::from_str_radix(&cap[8], 16).unwrap();
             let expected_bgl = u8::from_str_radix(&cap[9], 16).unwrap();
 
-            let actual_dot = nes.ppu.dot.get();
-            let actual_sl = nes.ppu.scanline.get();
-            let actual_ctrl = nes.ppu.ppuctrl.get().bits();
-            let actual_status = nes.ppu.ppustatus.get().bits();
-            let actual_v = nes.ppu.addr_v.get();
-            let actual_t = nes.ppu.addr_t.get();
-            let actual_bsl = nes.ppu.bg_low_shift.get();
-            let actual_bsh = nes.ppu.bg_high_shift.get();
-            let actual_bgl = nes.ppu.fetched_bg_pattern_low.get();
+            let state = nes.ppu.debug_state();
+            let actual_dot = state.dot;
+            let actual_sl = state.scanline;
+            let actual_ctrl = state.ctrl.bits();
+            let actual_status = state.status.bits();
+            let actual_v = state.v;
+            let actual_t = state.t;
+            let actual_bsl = state.bg_low_shift;
+            let actual_bsh = state.bg_high_shift;
+            let actual_bgl = state.bg_pattern_low;
 
             nes.tick();
 
@@ -183,7 +181,7 @@ fn log_cmp(game: &str) -> Result<()> {
             let actual_y = nes.cpu.y.get();
             let actual_p = nes.cpu.get_p() | 0x20;
             let actual_s = nes.cpu.s.get();
-            let actual_tos = nes.read(0x100 | actual_s as u16);
+            let actual_tos = nes.read_u8(0x100 | actual_s as u16);
 
             let mut fail = false;
 
@@ -0,0 +1,1289 @@
+use std::cell::Cell;
+
+use anyhow::Result;
+use covnes::{
+    error::Error,
+    nes::{
+        mappers::{self, common::MirrorMode, Cartridge, CartridgeImpl, MapperRegistry},
+        state::{SaveState, StateReader, StateWriter},
+    },
+    romfiles::{Mirroring, RomFile, Timing},
+};
+
+// Builds a rom where every byte of each bank holds that bank's number, so reads show which bank
+// is mapped in
+fn banked_rom(mapper: usize, prg_bank_size: usize, prg_banks: usize, chr_banks: usize) -> RomFile {
+    let prg_rom = (0..prg_banks)
+        .flat_map(|b| vec![b as u8; prg_bank_size])
+        .collect();
+    let chr_rom = (0..chr_banks).flat_map(|b| vec![b as u8; 1024]).collect();
+
+    RomFile {
+        prg_rom,
+        chr_rom: Some(chr_rom),
+        provide_prg_ram: false,
+        prg_ram_size: 0x2000,
+        mirroring: Mirroring::Horizontal,
+        mapper,
+        submapper: 0,
+        timing: Timing::Ntsc,
+    }
+}
+
+fn tick(cart: &Cartridge, cycles: usize) {
+    for _ in 0..cycles {
+        cart.cpu_tick();
+    }
+}
+
+fn snapshot(cart: &Cartridge) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    cart.save_state(&mut w);
+    w.into_inner()
+}
+
+fn restore(cart: &Cartridge, state: &[u8]) -> Result<()> {
+    let mut r = StateReader::new(state);
+    cart.load_state(&mut r)?;
+    r.finish()?;
+    Ok(())
+}
+
+// MMC1 registers are written a bit at a time, lowest first
+fn mmc1_write(cart: &Cartridge, addr: u16, value: u8) {
+    for i in 0..5 {
+        cart.write_cpu(addr, value >> i & 1);
+    }
+}
+
+#[test]
+fn mmc1_chr_banks_wrap_around() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    for chr_kb in [8, 16, 32] {
+        let cart = mappers::from_rom(banked_rom(1, 16384, 2, chr_kb))?;
+
+        // 4kb bank 5 is past the end of anything under 24kb, so only the bank lines that are
+        // there count
+        mmc1_write(&cart, 0x8000, 0b11100);
+        mmc1_write(&cart, 0xA000, 5);
+        let bank = 5 % (chr_kb / 4);
+        assert_eq!(cart.read_ppu(&vram, 0x0000), 4 * bank as u8, "{}kb", chr_kb);
+        assert_eq!(
+            cart.read_ppu(&vram, 0x0FFF),
+            4 * bank as u8 + 3,
+            "{}kb",
+            chr_kb
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mmc1_prg_ram_banks() -> Result<()> {
+    // SXROM with 32kb uses bits 2-3 of CHR bank 0, SOROM with 16kb bit 3
+    for &(size, shift) in &[(0x8000, 2), (0x4000, 3)] {
+        let mut rom = banked_rom(1, 16384, 2, 8);
+        rom.provide_prg_ram = true;
+        rom.prg_ram_size = size;
+        let cart = mappers::from_rom(rom)?;
+        let banks = size / 0x2000;
+
+        for bank in 0..banks {
+            mmc1_write(&cart, 0xA000, (bank as u8) << shift);
+            cart.write_cpu(0x6000, 0x10 + bank as u8);
+            cart.write_cpu(0x7FFF, 0x20 + bank as u8);
+        }
+        for bank in 0..banks {
+            mmc1_write(&cart, 0xA000, (bank as u8) << shift);
+            assert_eq!(cart.read_cpu(0x6000), Some(0x10 + bank as u8), "{:x}", size);
+            assert_eq!(cart.read_cpu(0x7FFF), Some(0x20 + bank as u8), "{:x}", size);
+        }
+    }
+
+    // And with 8kb the bits are ignored
+    let mut rom = banked_rom(1, 16384, 2, 8);
+    rom.provide_prg_ram = true;
+    let cart = mappers::from_rom(rom)?;
+    cart.write_cpu(0x6000, 0x55);
+    mmc1_write(&cart, 0xA000, 0x0C);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x55));
+
+    Ok(())
+}
+
+#[test]
+fn mmc1_prg_ram_can_be_disabled() -> Result<()> {
+    let mut rom = banked_rom(1, 16384, 2, 8);
+    rom.provide_prg_ram = true;
+    let cart = mappers::from_rom(rom)?;
+    cart.write_cpu(0x6000, 0x55);
+
+    // Bit 4 of the PRG bank turns the RAM off, leaving open bus and dropping writes
+    mmc1_write(&cart, 0xE000, 0x10);
+    assert_eq!(cart.read_cpu(0x6000), None);
+    cart.write_cpu(0x6000, 0xAA);
+
+    mmc1_write(&cart, 0xE000, 0x00);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x55));
+
+    Ok(())
+}
+
+#[test]
+fn mmc1_state_round_trip() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(1, 16384, 8, 16))?;
+
+    // Fixed last PRG bank and two 4kb CHR banks
+    mmc1_write(&cart, 0x8000, 0b11100);
+    mmc1_write(&cart, 0xE000, 5);
+    mmc1_write(&cart, 0xA000, 2);
+    mmc1_write(&cart, 0xC000, 3);
+    // And the first two bits of another PRG bank switch, to 6
+    cart.write_cpu(0xE000, 0);
+    cart.write_cpu(0xE000, 1);
+    let state = snapshot(&cart);
+
+    cart.write_cpu(0x8000, 0x80);
+    mmc1_write(&cart, 0x8000, 0b00000);
+    mmc1_write(&cart, 0xE000, 0);
+    mmc1_write(&cart, 0xA000, 0);
+    mmc1_write(&cart, 0xC000, 0);
+    assert_eq!(cart.read_cpu(0x8000), Some(0));
+
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_cpu(0x8000), Some(5));
+    assert_eq!(cart.read_cpu(0xC000), Some(7));
+    // 1kb CHR banks, so 4kb bank n reads back as 4n
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 12);
+
+    // The half written bank switch carries on from where it was
+    for bit in [1, 0, 0] {
+        cart.write_cpu(0xE000, bit);
+    }
+    assert_eq!(cart.read_cpu(0x8000), Some(6));
+
+    Ok(())
+}
+
+#[test]
+fn uxrom_state_round_trip() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(2, 16384, 8, 8))?;
+
+    cart.write_cpu(0x8000, 3);
+    let state = snapshot(&cart);
+    cart.write_cpu(0x8000, 5);
+    assert_eq!(cart.read_cpu(0x8000), Some(5));
+
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+    assert_eq!(cart.read_cpu(0xC000), Some(7));
+
+    Ok(())
+}
+
+#[test]
+fn cnrom_chr_banking_and_bus_conflicts() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    // 1kb CHR banks, so 8kb bank n reads back as 8n
+    let mut rom = banked_rom(3, 32768, 1, 32);
+    rom.prg_rom = vec![0xFF; 32768];
+    rom.prg_rom[0x1234] = 0x01;
+    let cart = mappers::from_rom(rom)?;
+
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 0);
+    cart.write_cpu(0x8000, 2);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 16);
+    assert_eq!(cart.read_ppu(&vram, 0x1FFF), 23);
+
+    // Only the bits the ROM also has set get through
+    cart.write_cpu(0x9234, 3);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+
+    // There are only 4 banks
+    cart.write_cpu(0x8000, 6);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 16);
+
+    // Without the conflicts on submapper 1
+    let mut rom = banked_rom(3, 16384, 1, 32);
+    rom.prg_rom[0x1234] = 0x01;
+    rom.submapper = 1;
+    let cart = mappers::from_rom(rom)?;
+    cart.write_cpu(0xD234, 3);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 24);
+    // 16kb of PRG ROM is mirrored
+    assert_eq!(cart.read_cpu(0xD234), Some(1));
+
+    let state = snapshot(&cart);
+    cart.write_cpu(0x8000, 0);
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 24);
+
+    Ok(())
+}
+
+#[test]
+fn axrom_banking_and_one_screen_mirroring() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let mut rom = banked_rom(7, 32768, 8, 0);
+    rom.chr_rom = None;
+    let cart = mappers::from_rom(rom)?;
+
+    assert_eq!(cart.read_cpu(0x8000), Some(0));
+    cart.write_cpu(0x8000, 0x05);
+    assert_eq!(cart.read_cpu(0x8000), Some(5));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(5));
+
+    // Every nametable is the lower one...
+    cart.write_ppu(&vram, 0x2000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2C00), 1);
+
+    // ...until bit 4 switches over to the higher one
+    cart.write_cpu(0xC000, 0x13);
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+    assert_eq!(cart.read_ppu(&vram, 0x2000), 0);
+    cart.write_ppu(&vram, 0x2800, 2);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 2);
+    assert_eq!(vram[0x400].get(), 2);
+
+    let state = snapshot(&cart);
+    cart.write_cpu(0x8000, 0);
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+    assert_eq!(cart.read_ppu(&vram, 0x2000), 2);
+
+    // CHR RAM
+    cart.write_ppu(&vram, 0x1234, 0x42);
+    assert_eq!(cart.read_ppu(&vram, 0x1234), 0x42);
+
+    Ok(())
+}
+
+#[test]
+fn axrom_bus_conflicts_only_on_submapper_2() -> Result<()> {
+    for (submapper, bank) in [(0, 7), (1, 7), (2, 3)] {
+        let mut rom = banked_rom(7, 32768, 8, 0);
+        rom.chr_rom = None;
+        rom.submapper = submapper;
+        // Bank 0's ROM has bit 2 clear where it's written to
+        rom.prg_rom[0x1234] = 0xFB;
+        let cart = mappers::from_rom(rom)?;
+
+        cart.write_cpu(0x9234, 7);
+        assert_eq!(cart.read_cpu(0x8000), Some(bank));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn gxrom_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    // 1kb CHR banks, so 8kb bank n reads back as 8n
+    let mut rom = banked_rom(66, 32768, 4, 32);
+    rom.mirroring = Mirroring::Vertical;
+    let cart = mappers::from_rom(rom)?;
+
+    assert_eq!(cart.read_cpu(0x8000), Some(0));
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 0);
+
+    cart.write_cpu(0x8000, 0x21);
+    assert_eq!(cart.read_cpu(0x8000), Some(2));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(2));
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+    assert_eq!(cart.read_ppu(&vram, 0x1FFF), 15);
+
+    cart.write_cpu(0xFFFF, 0x13);
+    assert_eq!(cart.read_cpu(0x8000), Some(1));
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 24);
+
+    // Mirroring from the header
+    cart.write_ppu(&vram, 0x2000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 0);
+
+    let state = snapshot(&cart);
+    cart.write_cpu(0x8000, 0);
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_cpu(0x8000), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_prg_banking() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(24, 8192, 32, 8))?;
+
+    // Last bank fixed at $E000
+    assert_eq!(cart.read_cpu(0xE000), Some(31));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(31));
+
+    // 16kb bank at $8000
+    cart.write_cpu(0x8000, 3);
+    assert_eq!(cart.read_cpu(0x8000), Some(6));
+    assert_eq!(cart.read_cpu(0xBFFF), Some(7));
+
+    // 8kb bank at $C000
+    cart.write_cpu(0xC000, 9);
+    assert_eq!(cart.read_cpu(0xC000), Some(9));
+    assert_eq!(cart.read_cpu(0xDFFF), Some(9));
+
+    // PRG RAM only responds once enabled
+    cart.write_cpu(0x6000, 0x42);
+    assert_eq!(cart.read_cpu(0x6000), None);
+    cart.write_cpu(0xB003, 0x80);
+    cart.write_cpu(0x6000, 0x42);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x42));
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_chr_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+
+    for &(mapper, reg_1) in &[(24, 0xD001), (26, 0xD002)] {
+        let cart = mappers::from_rom(banked_rom(mapper, 8192, 4, 32))?;
+
+        // Mode 0 - 1kb banks
+        cart.write_cpu(0xD000, 10);
+        cart.write_cpu(reg_1, 11);
+        cart.write_cpu(0xE003, 17);
+        assert_eq!(cart.read_ppu(&vram, 0x0000), 10);
+        assert_eq!(cart.read_ppu(&vram, 0x0400), 11);
+        assert_eq!(cart.read_ppu(&vram, 0x1C00), 17);
+
+        // Mode 1 - 2kb banks, with A10 coming from the PPU
+        cart.write_cpu(0xB003, 0x21);
+        assert_eq!(cart.read_ppu(&vram, 0x0000), 10);
+        assert_eq!(cart.read_ppu(&vram, 0x0400), 11);
+        assert_eq!(cart.read_ppu(&vram, 0x0800), 10);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_mirroring() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(24, 8192, 4, 8))?;
+
+    // Vertical
+    cart.write_cpu(0xB003, 0x00);
+    cart.write_ppu(&vram, 0x2000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 0);
+
+    // Horizontal
+    cart.write_cpu(0xB003, 0x04);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0);
+
+    Ok(())
+}
+
+#[test]
+fn forced_mirroring_wins_until_it_is_turned_off() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(24, 8192, 4, 8))?;
+    cart.write_ppu(&vram, 0x2000, 1);
+
+    cart.force_mirroring(Some(MirrorMode::OneScreenLower));
+    // Even if the game picks something else afterwards
+    cart.write_cpu(0xB003, 0x04);
+    for addr in [0x2400, 0x2800, 0x2C00] {
+        assert_eq!(cart.read_ppu(&vram, addr), 1);
+    }
+
+    // Back to the horizontal mirroring the game asked for
+    cart.force_mirroring(None);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0);
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_irq_cycle_mode() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(24, 8192, 4, 8))?;
+
+    cart.write_cpu(0xF000, 0xFD);
+    cart.write_cpu(0xF001, 0b111);
+
+    tick(&cart, 2);
+    assert!(!cart.irq());
+    tick(&cart, 1);
+    assert!(cart.irq());
+
+    // Acknowledging clears the IRQ, and the counter has been reloaded from the latch
+    cart.write_cpu(0xF002, 0);
+    assert!(!cart.irq());
+    tick(&cart, 2);
+    assert!(!cart.irq());
+    tick(&cart, 1);
+    assert!(cart.irq());
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_irq_scanline_mode() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(24, 8192, 4, 8))?;
+
+    cart.write_cpu(0xF000, 0xFE);
+    cart.write_cpu(0xF001, 0b010);
+
+    // Counter is clocked every 113 2/3 CPU cycles
+    tick(&cart, 227);
+    assert!(!cart.irq());
+    tick(&cart, 1);
+    assert!(cart.irq());
+
+    // Disabled on acknowledge as the E bit wasn't set
+    cart.write_cpu(0xF002, 0);
+    tick(&cart, 1000);
+    assert!(!cart.irq());
+
+    Ok(())
+}
+
+#[test]
+fn vrc6_audio() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(24, 8192, 4, 8))?;
+    assert_eq!(cart.audio_sample(), 0.0);
+
+    // Pulse 1 at constant volume
+    cart.write_cpu(0x9000, 0x8F);
+    cart.write_cpu(0x9002, 0x80);
+    tick(&cart, 1);
+    let pulse = cart.audio_sample();
+    assert!(pulse > 0.0);
+
+    // Halting freezes the output, disabling silences it
+    cart.write_cpu(0x9003, 1);
+    tick(&cart, 100);
+    assert_eq!(cart.audio_sample(), pulse);
+    cart.write_cpu(0x9002, 0x00);
+    assert_eq!(cart.audio_sample(), 0.0);
+
+    Ok(())
+}
+
+// Each VRC2/VRC4 board, with the address lines for the chip's A0 and A1
+const VRC4_WIRINGS: [(&str, usize, u16, u16); 7] = [
+    ("VRC4a", 21, 0x02, 0x04),
+    ("VRC4c", 21, 0x40, 0x80),
+    ("VRC2a", 22, 0x02, 0x01),
+    ("VRC4f", 23, 0x01, 0x02),
+    ("VRC4e", 23, 0x04, 0x08),
+    ("VRC4b", 25, 0x02, 0x01),
+    ("VRC4d", 25, 0x08, 0x04),
+];
+
+#[test]
+fn vrc4_banking_for_each_wiring() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    for &(board, mapper, a0, a1) in VRC4_WIRINGS.iter() {
+        let cart = mappers::from_rom(banked_rom(mapper, 8192, 32, 256))?;
+        let reg = |base: u16, n: u16| {
+            base | if n & 1 == 1 { a0 } else { 0 } | if n & 2 == 2 { a1 } else { 0 }
+        };
+
+        cart.write_cpu(reg(0x8000, 0), 5);
+        cart.write_cpu(reg(0xA000, 0), 6);
+        assert_eq!(cart.read_cpu(0x8000), Some(5), "{}", board);
+        assert_eq!(cart.read_cpu(0xA000), Some(6), "{}", board);
+        assert_eq!(cart.read_cpu(0xC000), Some(30), "{}", board);
+        assert_eq!(cart.read_cpu(0xE000), Some(31), "{}", board);
+
+        // CHR bank 1 is $B002 (low nibble) and $B003, and VRC2a drops the bottom bit
+        cart.write_cpu(reg(0xB000, 2), 0x3);
+        cart.write_cpu(reg(0xB000, 3), 0x1);
+        cart.write_cpu(reg(0xE000, 3), 0xF);
+        let (bank_1, bank_7) = if mapper == 22 {
+            (0x13 >> 1, 0xF0 >> 1)
+        } else {
+            (0x13, 0xF0)
+        };
+        assert_eq!(cart.read_ppu(&vram, 0x0400), bank_1, "{}", board);
+        assert_eq!(cart.read_ppu(&vram, 0x1C00), bank_7, "{}", board);
+
+        // Mirroring
+        cart.write_cpu(reg(0x9000, 0), 1);
+        cart.write_ppu(&vram, 0x2000, 1);
+        assert_eq!(cart.read_ppu(&vram, 0x2400), 1, "{}", board);
+        assert_eq!(cart.read_ppu(&vram, 0x2800), 0, "{}", board);
+
+        if mapper != 22 {
+            // Swapping the first PRG bank to $C000
+            cart.write_cpu(reg(0x9000, 2), 2);
+            assert_eq!(cart.read_cpu(0x8000), Some(30), "{}", board);
+            assert_eq!(cart.read_cpu(0xC000), Some(5), "{}", board);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn vrc4_submappers_pick_one_wiring() -> Result<()> {
+    for (submapper, swapped) in [(0, true), (1, false), (2, true)] {
+        let mut rom = banked_rom(23, 8192, 32, 256);
+        rom.submapper = submapper;
+        let cart = mappers::from_rom(rom)?;
+
+        // $9008 is VRC4e's PRG swap register, and VRC4f's mirroring one
+        cart.write_cpu(0x8000, 5);
+        cart.write_cpu(0x9008, 2);
+        let expected = if swapped { Some(5) } else { Some(30) };
+        assert_eq!(cart.read_cpu(0xC000), expected, "submapper {}", submapper);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn vrc4_irq() -> Result<()> {
+    for &(board, mapper, a0, a1) in VRC4_WIRINGS.iter().filter(|w| w.1 != 22) {
+        let cart = mappers::from_rom(banked_rom(mapper, 8192, 32, 256))?;
+        let reg =
+            |n: u16| 0xF000 | if n & 1 == 1 { a0 } else { 0 } | if n & 2 == 2 { a1 } else { 0 };
+
+        // The latch is written a nibble at a time, then the IRQ is enabled in cycle mode
+        cart.write_cpu(reg(0), 0xD);
+        cart.write_cpu(reg(1), 0xF);
+        cart.write_cpu(reg(2), 0b111);
+        tick(&cart, 2);
+        assert!(!cart.irq(), "{}", board);
+        tick(&cart, 1);
+        assert!(cart.irq(), "{}", board);
+
+        cart.write_cpu(reg(3), 0);
+        assert!(!cart.irq(), "{}", board);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn namco163_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(19, 8192, 8, 32))?;
+
+    cart.write_cpu(0xE000, 3);
+    cart.write_cpu(0xE800, 4);
+    cart.write_cpu(0xF000, 5);
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+    assert_eq!(cart.read_cpu(0xA000), Some(4));
+    assert_eq!(cart.read_cpu(0xC000), Some(5));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(7));
+
+    // CHR banks of $E0 and up are the console's nametables, unless $E800 says otherwise
+    cart.write_cpu(0x8000, 5);
+    cart.write_cpu(0x8800, 0xE1);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 5);
+    cart.write_ppu(&vram, 0x0400, 9);
+    assert_eq!(vram[0x400].get(), 9);
+    cart.write_cpu(0xE800, 0x40);
+    assert_eq!(cart.read_ppu(&vram, 0x0400), 0xE1 % 32);
+
+    // And the same for the nametables, which can be CHR ROM too
+    cart.write_cpu(0xC000, 0xE1);
+    cart.write_cpu(0xC800, 0xE1);
+    cart.write_cpu(0xD000, 2);
+    assert_eq!(cart.read_ppu(&vram, 0x2000), 9);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 9);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 2);
+
+    // PRG RAM only takes writes with $4x in $F800, and not to the 2kb pages it protects
+    cart.write_cpu(0x6000, 1);
+    assert_eq!(cart.read_cpu(0x6000), Some(0));
+    cart.write_cpu(0xF800, 0x41);
+    cart.write_cpu(0x6000, 1);
+    cart.write_cpu(0x6800, 1);
+    assert_eq!(cart.read_cpu(0x6000), Some(0));
+    assert_eq!(cart.read_cpu(0x6800), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn namco163_irq() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(19, 8192, 8, 32))?;
+
+    // Counting up from $7FFD with the enable bit set
+    cart.write_cpu(0x5000, 0xFD);
+    cart.write_cpu(0x5800, 0xFF);
+    tick(&cart, 1);
+    assert!(!cart.irq());
+    tick(&cart, 1);
+    assert!(cart.irq());
+
+    // It stops at $7FFF, and writing either half acknowledges
+    tick(&cart, 10);
+    assert_eq!(cart.read_cpu(0x5000), Some(0xFF));
+    assert_eq!(cart.read_cpu(0x5800), Some(0xFF));
+    cart.write_cpu(0x5800, 0x7F);
+    assert!(!cart.irq());
+
+    Ok(())
+}
+
+#[test]
+fn namco163_audio() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(19, 8192, 8, 32))?;
+    assert_eq!(cart.audio_sample(), 0.0);
+
+    // A waveform starting at 15, then channel 7 at full volume on its own. It doesn't play until
+    // the chip gets around to it.
+    cart.write_cpu(0xF800, 0x80);
+    cart.write_cpu(0x4800, 0x0F);
+    cart.write_cpu(0xF800, 0xFC);
+    for value in [0xFC, 0x00, 0x00, 0x0F] {
+        cart.write_cpu(0x4800, value);
+    }
+    assert_eq!(cart.audio_sample(), 0.0);
+    tick(&cart, 15);
+    assert!(cart.audio_sample() > 0.0);
+
+    // The sound RAM reads back through the same port
+    cart.write_cpu(0xF800, 0x7F);
+    assert_eq!(cart.read_cpu(0x4800), Some(0x0F));
+
+    // Bit 6 of $E000 turns it off
+    cart.write_cpu(0xE000, 0x40);
+    assert_eq!(cart.audio_sample(), 0.0);
+
+    Ok(())
+}
+
+#[test]
+fn bf909x_banking_and_mirroring() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let mut rom = banked_rom(71, 16384, 8, 0);
+    rom.chr_rom = None;
+    let cart = mappers::from_rom(rom)?;
+
+    assert_eq!(cart.read_cpu(0x8000), Some(0));
+    assert_eq!(cart.read_cpu(0xC000), Some(7));
+
+    cart.write_cpu(0xC000, 5);
+    assert_eq!(cart.read_cpu(0xBFFF), Some(5));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(7));
+
+    // Horizontal from the header until $9000 is written
+    cart.write_ppu(&vram, 0x2000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0);
+
+    cart.write_cpu(0x9000, 0x10);
+    assert_eq!(cart.read_ppu(&vram, 0x2000), 0);
+    cart.write_cpu(0x9000, 0x00);
+    assert_eq!(cart.read_ppu(&vram, 0x2C00), 1);
+
+    Ok(())
+}
+
+#[test]
+fn mmc2_prg_banking() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(9, 8192, 16, 128))?;
+
+    // Last three banks fixed
+    assert_eq!(cart.read_cpu(0xA000), Some(13));
+    assert_eq!(cart.read_cpu(0xC000), Some(14));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(15));
+
+    cart.write_cpu(0xA000, 6);
+    assert_eq!(cart.read_cpu(0x8000), Some(6));
+    assert_eq!(cart.read_cpu(0x9FFF), Some(6));
+
+    Ok(())
+}
+
+#[test]
+fn mmc2_chr_latches() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    // 1kb CHR banks, so 4kb bank n reads back as 4n
+    let cart = mappers::from_rom(banked_rom(9, 8192, 16, 128))?;
+    cart.write_cpu(0xB000, 1);
+    cart.write_cpu(0xC000, 2);
+    cart.write_cpu(0xD000, 3);
+    cart.write_cpu(0xE000, 4);
+
+    // Both latches start at $FE
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 16);
+
+    // The fetch that trips the latch still comes from the old bank
+    assert_eq!(cart.read_ppu(&vram, 0x0FD8), 11);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 4);
+    // Only the exact address works for the lower latch
+    cart.read_ppu(&vram, 0x0FE9);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 4);
+    cart.read_ppu(&vram, 0x0FE8);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+
+    // The upper latch responds to a whole tile row, and is separate from the lower one
+    cart.read_ppu(&vram, 0x1FDD);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 12);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 8);
+    cart.read_ppu(&vram, 0x1FEF);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 16);
+
+    // Bank register writes take effect straight away for whichever latch is active
+    cart.write_cpu(0xE000, 7);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 28);
+
+    Ok(())
+}
+
+#[test]
+fn mmc2_mirroring() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(9, 8192, 16, 128))?;
+
+    cart.write_ppu(&vram, 0x2000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 1);
+
+    cart.write_cpu(0xF000, 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 1);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0);
+
+    Ok(())
+}
+
+#[test]
+fn bnrom_prg_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let mut rom = banked_rom(34, 32768, 4, 0);
+    rom.chr_rom = None;
+    let cart = mappers::from_rom(rom)?;
+
+    assert_eq!(cart.read_cpu(0x8000), Some(0));
+    cart.write_cpu(0xC123, 2);
+    assert_eq!(cart.read_cpu(0x8000), Some(2));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(2));
+
+    // No PRG RAM, and so no NINA-001 registers
+    cart.write_cpu(0x7FFD, 1);
+    assert_eq!(cart.read_cpu(0x7FFD), None);
+    assert_eq!(cart.read_cpu(0x8000), Some(2));
+
+    // CHR RAM
+    cart.write_ppu(&vram, 0x1234, 0x42);
+    assert_eq!(cart.read_ppu(&vram, 0x1234), 0x42);
+
+    Ok(())
+}
+
+#[test]
+fn nina001_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    // 1kb CHR banks, so 4kb bank n reads back as 4n
+    let cart = mappers::from_rom(banked_rom(34, 32768, 2, 64))?;
+
+    cart.write_cpu(0x7FFD, 1);
+    assert_eq!(cart.read_cpu(0x8000), Some(1));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(1));
+    // The registers are backed by PRG RAM
+    assert_eq!(cart.read_cpu(0x7FFD), Some(1));
+
+    cart.write_cpu(0x7FFE, 3);
+    cart.write_cpu(0x7FFF, 15);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 12);
+    assert_eq!(cart.read_ppu(&vram, 0x1FFF), 63);
+
+    // Writes to $8000-$FFFF don't do anything on this board
+    cart.write_cpu(0x8000, 0);
+    assert_eq!(cart.read_cpu(0x8000), Some(1));
+
+    Ok(())
+}
+
+#[test]
+fn mmc5_prg_banking() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(5, 8192, 32, 8))?;
+
+    // Starts in 8kb mode with the last bank at $E000
+    assert_eq!(cart.read_cpu(0xE000), Some(31));
+    cart.write_cpu(0x5114, 0x85);
+    assert_eq!(cart.read_cpu(0x8000), Some(5));
+
+    // 16kb banks ignore the bottom bit
+    cart.write_cpu(0x5100, 1);
+    cart.write_cpu(0x5115, 0x87);
+    assert_eq!(cart.read_cpu(0x8000), Some(6));
+    assert_eq!(cart.read_cpu(0xA000), Some(7));
+    assert_eq!(cart.read_cpu(0xC000), Some(30));
+    assert_eq!(cart.read_cpu(0xE000), Some(31));
+
+    // 32kb
+    cart.write_cpu(0x5100, 0);
+    cart.write_cpu(0x5117, 0x87);
+    assert_eq!(cart.read_cpu(0x8000), Some(4));
+    assert_eq!(cart.read_cpu(0xFFFF), Some(7));
+
+    // PRG RAM is write protected until $5102 and $5103 are set up
+    cart.write_cpu(0x5113, 1);
+    cart.write_cpu(0x6000, 0x42);
+    assert_eq!(cart.read_cpu(0x6000), Some(0));
+    cart.write_cpu(0x5102, 2);
+    cart.write_cpu(0x5103, 1);
+    cart.write_cpu(0x6000, 0x42);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x42));
+    cart.write_cpu(0x5113, 0);
+    assert_eq!(cart.read_cpu(0x6000), Some(0));
+
+    // And can be mapped into $8000-$DFFF
+    cart.write_cpu(0x5100, 3);
+    cart.write_cpu(0x5114, 1);
+    assert_eq!(cart.read_cpu(0x8000), Some(0x42));
+
+    Ok(())
+}
+
+#[test]
+fn mmc5_chr_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(5, 8192, 4, 256))?;
+
+    cart.write_cpu(0x5101, 3);
+    for i in 0..8 {
+        cart.write_cpu(0x5120 + i, 10 + i as u8);
+    }
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 10);
+    assert_eq!(cart.read_ppu(&vram, 0x1C00), 17);
+
+    // Writing set B switches over to it, and its 4 registers cover both pattern tables
+    for i in 0..4 {
+        cart.write_cpu(0x5128 + i, 30 + i as u8);
+    }
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 30);
+    assert_eq!(cart.read_ppu(&vram, 0x1000), 30);
+    assert_eq!(cart.read_ppu(&vram, 0x1C00), 33);
+
+    // 8kb banks come from the last register
+    cart.write_cpu(0x5101, 0);
+    cart.write_cpu(0x5127, 2);
+    assert_eq!(cart.read_ppu(&vram, 0x0000), 16);
+    assert_eq!(cart.read_ppu(&vram, 0x1FFF), 23);
+
+    Ok(())
+}
+
+// Does the PPU reads for one rendered scanline, ending with the dummy nametable reads that the
+// MMC5 uses to spot the start of the next one
+fn mmc5_scanline(cart: &Cartridge, vram: &[Cell<u8>]) -> Vec<u8> {
+    let mut addrs = vec![];
+    let tile = |addrs: &mut Vec<u16>, t: u16| addrs.extend([0x2000 + t, 0x23C0, 0x0000, 0x0008]);
+    for t in 0..32 {
+        tile(&mut addrs, t);
+    }
+    for _ in 0..8 {
+        addrs.extend([0x1000, 0x1008]);
+    }
+    tile(&mut addrs, 32);
+    tile(&mut addrs, 33);
+    addrs.extend([0x2000, 0x2000]);
+
+    addrs
+        .into_iter()
+        .map(|addr| cart.read_ppu(vram, addr))
+        .collect()
+}
+
+#[test]
+fn mmc5_scanline_irq() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(5, 8192, 4, 8))?;
+    cart.write_cpu(0x5203, 2);
+    cart.write_cpu(0x5204, 0x80);
+
+    // The end of the pre-render line, then the first line starts the frame
+    cart.read_ppu(&vram, 0x2000);
+    cart.read_ppu(&vram, 0x2000);
+    mmc5_scanline(&cart, &vram);
+    assert_eq!(cart.read_cpu(0x5204), Some(0x40));
+    mmc5_scanline(&cart, &vram);
+    assert!(!cart.irq());
+    mmc5_scanline(&cart, &vram);
+    assert!(cart.irq());
+
+    // Peeking at the status and the NMI vector doesn't change anything
+    assert_eq!(cart.peek_cpu(0x5204), Some(0xC0));
+    cart.peek_cpu(0xFFFA);
+    assert_eq!(cart.peek_cpu(0x5204), Some(0xC0));
+    assert!(cart.irq());
+
+    // Reading the status acknowledges it
+    assert_eq!(cart.read_cpu(0x5204), Some(0xC0));
+    assert!(!cart.irq());
+
+    // Fetching the NMI vector ends the frame
+    cart.read_cpu(0xFFFA);
+    assert_eq!(cart.read_cpu(0x5204), Some(0x00));
+    cart.read_ppu(&vram, 0x2000);
+    cart.read_ppu(&vram, 0x2000);
+    mmc5_scanline(&cart, &vram);
+    assert_eq!(cart.read_cpu(0x5204), Some(0x40));
+
+    // So does the PPU going quiet
+    tick(&cart, 3);
+    assert_eq!(cart.read_cpu(0x5204), Some(0x00));
+
+    Ok(())
+}
+
+#[test]
+fn mmc5_8x16_sprite_banks() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(5, 8192, 4, 64))?;
+    cart.write_cpu(0x5101, 3);
+    for i in 0..8 {
+        cart.write_cpu(0x5120 + i, 10 + i as u8);
+    }
+    for i in 0..4 {
+        cart.write_cpu(0x5128 + i, 30 + i as u8);
+    }
+    cart.ppu_register_write(0, 0x20);
+
+    cart.read_ppu(&vram, 0x2000);
+    cart.read_ppu(&vram, 0x2000);
+    let reads = mmc5_scanline(&cart, &vram);
+    // Backgrounds use set B and sprites set A
+    assert_eq!(reads[2], 30);
+    assert_eq!(reads[128], 14);
+
+    Ok(())
+}
+
+#[test]
+fn mmc5_exram() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(5, 8192, 4, 64))?;
+
+    // The four nametables from CIRAM, CIRAM, ExRAM and fill mode
+    cart.write_cpu(0x5105, 0b11_10_01_00);
+    cart.write_ppu(&vram, 0x2400, 1);
+    assert_eq!(vram[0x400].get(), 1);
+    cart.write_ppu(&vram, 0x2800, 0x42);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0x42);
+    cart.write_cpu(0x5106, 0x33);
+    cart.write_cpu(0x5107, 2);
+    assert_eq!(cart.read_ppu(&vram, 0x2C00), 0x33);
+    assert_eq!(cart.read_ppu(&vram, 0x2FC0), 0xAA);
+
+    // The CPU can only read ExRAM in modes 2 and 3
+    assert_eq!(cart.read_cpu(0x5C00), None);
+    cart.write_cpu(0x5104, 2);
+    assert_eq!(cart.read_cpu(0x5C00), Some(0x42));
+    cart.write_cpu(0x5C00, 0x43);
+    assert_eq!(cart.read_cpu(0x5C00), Some(0x43));
+    // And mode 3 is read only
+    cart.write_cpu(0x5104, 3);
+    cart.write_cpu(0x5C00, 0x44);
+    assert_eq!(cart.read_cpu(0x5C00), Some(0x43));
+
+    // The multiplier
+    cart.write_cpu(0x5205, 200);
+    cart.write_cpu(0x5206, 100);
+    assert_eq!(cart.read_cpu(0x5205), Some(0x20));
+    assert_eq!(cart.read_cpu(0x5206), Some(0x4E));
+
+    Ok(())
+}
+
+#[test]
+fn mmc5_extended_attributes() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(5, 8192, 4, 64))?;
+    cart.write_cpu(0x5104, 1);
+    // Palette 3 and 4kb CHR bank 5 for the first tile
+    cart.write_cpu(0x5C00, 0xC5);
+
+    cart.read_ppu(&vram, 0x2000);
+    cart.read_ppu(&vram, 0x2000);
+    let reads = mmc5_scanline(&cart, &vram);
+    assert_eq!(reads[1], 0xFF);
+    assert_eq!(reads[2], 20);
+    // Sprites still use the normal banks
+    assert_eq!(reads[128], 4);
+
+    Ok(())
+}
+
+// A mapper that just reads back the last value written anywhere
+struct LastWrite(Cell<u8>);
+
+impl CartridgeImpl for LastWrite {
+    fn read_cpu(&self, _addr: u16) -> Option<u8> {
+        Some(self.0.get())
+    }
+
+    fn write_cpu(&self, _addr: u16, value: u8) {
+        self.0.set(value);
+    }
+
+    fn read_ppu(&self, _vram: &[Cell<u8>], _addr: u16) -> u8 {
+        self.0.get()
+    }
+
+    fn write_ppu(&self, _vram: &[Cell<u8>], _addr: u16, _value: u8) {}
+}
+
+impl SaveState for LastWrite {
+    fn save_state(&self, w: &mut StateWriter) {
+        self.0.save_state(w);
+    }
+
+    fn load_state(&self, r: &mut StateReader) -> covnes::error::Result<()> {
+        self.0.load_state(r)
+    }
+}
+
+// Writes one of MMC3's bank registers through $8000/$8001, keeping the mode bits in `mode`
+fn mmc3_bank(cart: &Cartridge, mode: u8, reg: u8, bank: u8) {
+    cart.write_cpu(0x8000, mode | reg);
+    cart.write_cpu(0x8001, bank);
+}
+
+#[test]
+fn mmc3_prg_banking() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(4, 8192, 16, 8))?;
+    mmc3_bank(&cart, 0, 6, 3);
+    mmc3_bank(&cart, 0, 7, 5);
+    let banks = |cart: &Cartridge| {
+        [0x8000, 0xA000, 0xC000, 0xE000]
+            .iter()
+            .map(|&addr| cart.read_cpu(addr).unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(banks(&cart), [3, 5, 14, 15]);
+
+    // Bit 6 swaps R6 and the second to last bank
+    cart.write_cpu(0x8000, 0x40);
+    assert_eq!(banks(&cart), [14, 5, 3, 15]);
+
+    // Out of range banks wrap around
+    mmc3_bank(&cart, 0, 6, 0x13);
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+
+    // A single bank can't fill both fixed ones
+    assert!(matches!(
+        mappers::from_rom(banked_rom(4, 8192, 1, 8)),
+        Err(Error::BadPrgRomSize {
+            mapper: 4,
+            size: 8192
+        })
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn mmc3_chr_banking() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(4, 8192, 4, 64))?;
+    // The 2kb banks ignore their bottom bit
+    mmc3_bank(&cart, 0, 0, 4);
+    mmc3_bank(&cart, 0, 1, 7);
+    for (reg, bank) in (2..6).zip(10..14) {
+        mmc3_bank(&cart, 0, reg, bank);
+    }
+    let banks = |cart: &Cartridge| {
+        (0..8)
+            .map(|i| cart.read_ppu(&vram, i * 0x400))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(banks(&cart), [4, 5, 6, 7, 10, 11, 12, 13]);
+
+    // Bit 7 swaps the halves of the pattern table
+    cart.write_cpu(0x8000, 0x80);
+    assert_eq!(banks(&cart), [10, 11, 12, 13, 4, 5, 6, 7]);
+
+    Ok(())
+}
+
+#[test]
+fn mmc3_mirroring_and_prg_ram() -> Result<()> {
+    let vram = vec![Cell::new(0); 2048];
+    let cart = mappers::from_rom(banked_rom(4, 8192, 4, 8))?;
+    cart.write_ppu(&vram, 0x2000, 0x11);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0x11);
+    cart.write_cpu(0xA000, 0x01);
+    assert_eq!(cart.read_ppu(&vram, 0x2400), 0x11);
+    assert_eq!(cart.read_ppu(&vram, 0x2800), 0x00);
+
+    // RAM is there from power on, without the header asking for it
+    cart.write_cpu(0x6000, 0x12);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x12));
+    // Write protected
+    cart.write_cpu(0xA001, 0xC0);
+    cart.write_cpu(0x6000, 0x34);
+    assert_eq!(cart.read_cpu(0x7FFF), Some(0));
+    assert_eq!(cart.read_cpu(0x6000), Some(0x12));
+    // Disabled, leaving open bus
+    cart.write_cpu(0xA001, 0x00);
+    assert_eq!(cart.read_cpu(0x6000), None);
+    cart.write_cpu(0x6000, 0x34);
+    cart.write_cpu(0xA001, 0x80);
+    assert_eq!(cart.read_cpu(0x6000), Some(0x12));
+
+    Ok(())
+}
+
+// A rising edge on PPU A12 after it's been low long enough to count
+fn mmc3_scanline(cart: &Cartridge) {
+    cart.notify_ppu_addr(0x0FF0);
+    tick(cart, 3);
+    cart.notify_ppu_addr(0x1FF0);
+}
+
+// Which of `clocks` scanline counter clocks had an IRQ after them, acknowledging each one
+fn mmc3_irqs(cart: &Cartridge, clocks: usize) -> Vec<usize> {
+    let mut irqs = vec![];
+    for i in 1..=clocks {
+        mmc3_scanline(cart);
+        if cart.irq() {
+            irqs.push(i);
+            cart.write_cpu(0xE000, 0);
+            cart.write_cpu(0xE001, 0);
+        }
+    }
+    irqs
+}
+
+#[test]
+fn mmc3_scanline_irq() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(4, 8192, 4, 8))?;
+    cart.write_cpu(0xC000, 2);
+    cart.write_cpu(0xC001, 0);
+    cart.write_cpu(0xE001, 0);
+
+    // Reloaded to 2 on the first clock, then counts down to 0 and reloads from there
+    assert_eq!(mmc3_irqs(&cart, 9), [3, 6, 9]);
+
+    // Edges with A12 only low for a moment, like between sprite fetches, don't count
+    for _ in 0..10 {
+        cart.notify_ppu_addr(0x0000);
+        tick(&cart, 1);
+        cart.notify_ppu_addr(0x1000);
+    }
+    assert_eq!(mmc3_irqs(&cart, 3), [3]);
+
+    // Disabling it stops the IRQ and acknowledges any that's pending
+    mmc3_scanline(&cart);
+    cart.write_cpu(0xE000, 0);
+    assert_eq!(mmc3_irqs(&cart, 6), []);
+
+    Ok(())
+}
+
+#[test]
+fn mmc3_irq_revisions() -> Result<()> {
+    // With a latch of 0, MMC3B raises an IRQ on every clock but MMC3A only does after a reload
+    for &(submapper, irqs) in [(0, &[1, 2, 3, 4][..]), (4, &[1])].iter() {
+        let mut rom = banked_rom(4, 8192, 4, 8);
+        rom.submapper = submapper;
+        let cart = mappers::from_rom(rom)?;
+        cart.write_cpu(0xC000, 0);
+        cart.write_cpu(0xC001, 0);
+        cart.write_cpu(0xE001, 0);
+        assert_eq!(mmc3_irqs(&cart, 4), irqs);
+
+        // They both fire on the same clock for anything else
+        cart.write_cpu(0xC000, 3);
+        cart.write_cpu(0xC001, 0);
+        assert_eq!(mmc3_irqs(&cart, 8), [4, 8]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn mmc3_state_round_trip() -> Result<()> {
+    let cart = mappers::from_rom(banked_rom(4, 8192, 16, 8))?;
+    mmc3_bank(&cart, 0, 6, 3);
+    cart.write_cpu(0x6000, 0x12);
+    cart.write_cpu(0xC000, 5);
+    cart.write_cpu(0xE001, 0);
+    mmc3_scanline(&cart);
+    let state = snapshot(&cart);
+
+    mmc3_bank(&cart, 0x40, 6, 7);
+    cart.write_cpu(0x6000, 0x34);
+    mmc3_scanline(&cart);
+    restore(&cart, &state)?;
+    assert_eq!(cart.read_cpu(0x8000), Some(3));
+    assert_eq!(cart.read_cpu(0x6000), Some(0x12));
+    assert_eq!(mmc3_irqs(&cart, 5), [5]);
+
+    Ok(())
+}
+
+#[test]
+fn registered_mappers() -> Result<()> {
+    let mut registry = MapperRegistry::default();
+    registry.register_mapper(200, |_| Cartridge::boxed(LastWrite(Cell::new(0))));
+
+    let cart = registry.from_rom(banked_rom(200, 8192, 2, 8))?;
+    cart.write_cpu(0x8000, 0x42);
+    assert_eq!(cart.read_cpu(0xC000), Some(0x42));
+
+    // Built in mappers are still there
+    let cart = registry.from_rom(banked_rom(2, 16384, 4, 8))?;
+    assert_eq!(cart.read_cpu(0xC000), Some(3));
+
+    // Submappers win over the mapper as a whole, which is still there for the others
+    registry.register_submapper(2, 5, |_| Cartridge::boxed(LastWrite(Cell::new(0))));
+    let mut rom = banked_rom(2, 16384, 4, 8);
+    rom.submapper = 5;
+    let cart = registry.from_rom(rom)?;
+    cart.write_cpu(0x8000, 0x42);
+    assert_eq!(cart.read_cpu(0xC000), Some(0x42));
+    let mut rom = banked_rom(2, 16384, 4, 8);
+    rom.submapper = 1;
+    let cart = registry.from_rom(rom)?;
+    assert_eq!(cart.read_cpu(0xC000), Some(3));
+
+    assert!(matches!(
+        mappers::from_rom(banked_rom(200, 8192, 2, 8)),
+        Err(Error::UnsupportedMapper {
+            mapper: 200,
+            submapper: 0
+        })
+    ));
+    assert!(matches!(
+        MapperRegistry::empty().from_rom(banked_rom(0, 16384, 2, 8)),
+        Err(Error::UnsupportedMapper {
+            mapper: 0,
+            submapper: 0
+        })
+    ));
+
+    // The submapper is in the message too
+    let mut rom = banked_rom(200, 8192, 2, 8);
+    rom.submapper = 3;
+    assert_eq!(
+        mappers::from_rom(rom).err().map(|e| e.to_string()),
+        Some("Unsupported mapper: 200 (submapper 3)".to_string())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn partly_done_mappers_say_what_is_missing() -> Result<()> {
+    let nrom = mappers::from_rom(banked_rom(0, 16384, 2, 8))?;
+    assert!(nrom.is_fully_supported());
+    assert!(Cartridge::NotConnected.is_fully_supported());
+
+    let mmc5 = mappers::from_rom(banked_rom(5, 8192, 4, 8))?;
+    assert!(!mmc5.is_fully_supported());
+    assert!(mmc5.missing_features().contains(&"expansion audio"));
+
+    Ok(())
+}
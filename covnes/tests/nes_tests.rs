@@ -0,0 +1,1073 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use covnes::{
+    error::Error,
+    nes::{
+        apu,
+        io::{ControllerPortDataLines, DummyIO, IO},
+        mappers::{Cartridge, CartridgeImpl},
+        palette::{self, Palette},
+        ppu::PPUSTATUS,
+        state::{SaveState, StateReader, StateWriter},
+        test_pattern, BusAccess, Nes, Overscan, Region, WatchEvent, WatchKind, FRAME_BUFFER_SIZE,
+    },
+    romfiles::RomFile,
+    test_support,
+};
+
+struct CapturingIO {
+    pixels: Box<Cell<[u8; FRAME_BUFFER_SIZE]>>,
+}
+
+impl IO for CapturingIO {
+    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
+        let pixels: &Cell<[u8]> = &*self.pixels;
+        let pixels = pixels.as_slice_of_cells();
+        let i = (row as usize * 256 + col as usize) * 3;
+        pixels[i].set(r);
+        pixels[i + 1].set(g);
+        pixels[i + 2].set(b);
+    }
+
+    fn controller_latch_change(&self, _value: bool) {}
+
+    fn controller_port_1_read(&self) -> ControllerPortDataLines {
+        ControllerPortDataLines::empty()
+    }
+
+    fn controller_port_2_read(&self) -> ControllerPortDataLines {
+        ControllerPortDataLines::empty()
+    }
+}
+
+fn load_rom<I: IO>(io: I, name: &str) -> Result<Nes<I>> {
+    let path = format!("../roms/test/{}.nes", name);
+    let mut f = File::open(path)?;
+    let rom = RomFile::from_read(&mut f)?;
+
+    let mut nes = Nes::new(io);
+    nes.load_rom(rom)?;
+
+    Ok(nes)
+}
+
+#[test]
+fn tick_without_cartridge() {
+    let nes = Nes::new(DummyIO);
+    nes.reset();
+
+    for _ in 0..5 {
+        nes.step_frame();
+    }
+}
+
+#[test]
+fn test_pattern_for_when_there_is_no_cartridge() -> Result<()> {
+    let mut nes = Nes::new(DummyIO);
+    assert!(!nes.has_cartridge());
+
+    let mut frame = vec![0; FRAME_BUFFER_SIZE];
+    test_pattern::draw_into(&mut frame);
+    let pixel = |row: usize, col: usize| {
+        let i = (row * 256 + col) * 3;
+        (frame[i], frame[i + 1], frame[i + 2])
+    };
+    assert_eq!(pixel(0, 0), palette::get_rgb(0x21));
+    assert_eq!(pixel(0, 255), palette::get_rgb(0x2C));
+    assert_eq!(pixel(180, 0), palette::get_rgb(0x11));
+    assert_eq!(pixel(239, 0), palette::get_rgb(0x0F));
+    assert_eq!(pixel(239, 255), palette::get_rgb(0x20));
+
+    nes.load_rom(RomFile::from_filename("../roms/test/nestest.nes")?)?;
+    assert!(nes.has_cartridge());
+    nes.remove_cartridge();
+    assert!(!nes.has_cartridge());
+
+    Ok(())
+}
+
+#[test]
+fn step_frame_into_matches_io() -> Result<()> {
+    let io = CapturingIO {
+        pixels: Box::new(Cell::new([0; FRAME_BUFFER_SIZE])),
+    };
+    let nes = load_rom(io, "nestest")?;
+
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..10 {
+        nes.step_frame_into(&mut buf);
+    }
+
+    assert!(buf.iter().any(|&b| b != buf[0]));
+    assert!(buf[..] == nes.io.pixels.get()[..]);
+
+    Ok(())
+}
+
+#[test]
+fn set_pixel_index_gets_the_colour_before_rgb() -> Result<()> {
+    struct IndexIO {
+        pixels: Box<Cell<[(u8, u8); 256 * 240]>>,
+    }
+
+    impl IO for IndexIO {
+        fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {
+            panic!("set_pixel_index is implemented, so this shouldn't be called");
+        }
+
+        fn set_pixel_index(
+            &self,
+            row: u16,
+            col: u16,
+            palette_index: u8,
+            emphasis: u8,
+            _palette: &Palette,
+        ) {
+            let pixels: &Cell<[(u8, u8)]> = &*self.pixels;
+            pixels.as_slice_of_cells()[row as usize * 256 + col as usize]
+                .set((palette_index, emphasis));
+        }
+
+        fn controller_latch_change(&self, _value: bool) {}
+
+        fn controller_port_1_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+
+        fn controller_port_2_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+    }
+
+    let io = IndexIO {
+        pixels: Box::new(Cell::new([(0, 0); 256 * 240])),
+    };
+    let nes = load_rom(io, "nestest")?;
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..10 {
+        nes.step_frame_into(&mut buf);
+    }
+
+    let pixels = nes.io.pixels.get();
+    assert!(pixels.iter().any(|&p| p != pixels[0]));
+    for (i, &(palette_index, emphasis)) in pixels.iter().enumerate() {
+        assert!(palette_index < 64 && emphasis < 8);
+        let (r, g, b) = palette::get_rgb_with_emphasis(palette_index, emphasis);
+        assert_eq!(buf[i * 3..i * 3 + 3], [r, g, b]);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn pal_files_with_and_without_emphasis() {
+    // The built in palette without its emphasis variants
+    let plain: Vec<u8> = (0..64)
+        .flat_map(|idx| {
+            let (r, g, b) = palette::get_rgb(idx);
+            vec![r, g, b]
+        })
+        .collect();
+    let palette = Palette::from_pal(&plain).unwrap();
+    assert_eq!(palette, Palette::BUILT_IN);
+    // $20 is (236, 238, 236), and red emphasis dims green and blue
+    assert_eq!(palette.rgb(0x20, 0b001), (236, 194, 192));
+    // Columns $E and $F stay black
+    assert_eq!(palette.rgb(0x0F, 0b111), (0, 0, 0));
+
+    // Each entry is (emphasis, index, 7)
+    let full: Vec<u8> = (0..512)
+        .flat_map(|i| vec![(i / 64) as u8, (i % 64) as u8, 7])
+        .collect();
+    let palette = Palette::from_pal(&full).unwrap();
+    assert_eq!(palette.rgb(0x12, 0b101), (5, 0x12, 7));
+    assert_eq!(palette.rgb(0x3F, 0b111), (7, 0x3F, 7));
+    assert_eq!(palette.rgb(0x00, 0), (0, 0, 7));
+
+    for &size in [0, 191, 193, 1535, 1537].iter() {
+        assert!(matches!(
+            Palette::from_pal(&vec![0; size]),
+            Err(Error::BadPaletteSize(s)) if s == size
+        ));
+    }
+}
+
+#[test]
+fn set_palette_changes_the_colours_drawn() -> Result<()> {
+    let io = CapturingIO {
+        pixels: Box::new(Cell::new([0; FRAME_BUFFER_SIZE])),
+    };
+    let mut nes = load_rom(io, "nestest")?;
+    let full: Vec<u8> = (0..512)
+        .flat_map(|i| vec![(i / 64) as u8, (i % 64) as u8, 7])
+        .collect();
+    nes.set_palette(Palette::from_pal(&full)?);
+
+    let mut buf = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..10 {
+        nes.step_frame_into(&mut buf);
+    }
+
+    assert!(buf[..] == nes.io.pixels.get()[..]);
+    assert!(buf
+        .chunks_exact(3)
+        .all(|p| p[0] < 8 && p[1] < 64 && p[2] == 7));
+
+    Ok(())
+}
+
+#[test]
+fn expansion_audio_is_mixed_into_the_output() {
+    // A cartridge full of NOPs with a constant expansion audio output
+    struct Hum;
+
+    impl CartridgeImpl for Hum {
+        fn read_cpu(&self, _addr: u16) -> Option<u8> {
+            Some(0xEA)
+        }
+
+        fn write_cpu(&self, _addr: u16, _value: u8) {}
+
+        fn read_ppu(&self, _vram: &[Cell<u8>], _addr: u16) -> u8 {
+            0
+        }
+
+        fn write_ppu(&self, _vram: &[Cell<u8>], _addr: u16, _value: u8) {}
+
+        fn audio_sample(&self) -> f32 {
+            0.25
+        }
+    }
+
+    impl SaveState for Hum {
+        fn save_state(&self, _w: &mut StateWriter) {}
+
+        fn load_state(&self, _r: &mut StateReader) -> covnes::error::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct AudioIO {
+        samples: Cell<Vec<f32>>,
+    }
+
+    impl IO for AudioIO {
+        fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+        fn controller_latch_change(&self, _value: bool) {}
+
+        fn controller_port_1_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+
+        fn controller_port_2_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+
+        fn push_audio_sample(&self, sample: f32) {
+            let mut samples = self.samples.take();
+            samples.push(sample);
+            self.samples.set(samples);
+        }
+    }
+
+    let mut nes = Nes::new(AudioIO {
+        samples: Cell::new(Vec::new()),
+    });
+    nes.insert_cartridge(Cartridge::boxed(Hum).unwrap());
+    for _ in 0..10 {
+        nes.tick_cpu();
+    }
+
+    // One sample per CPU cycle, and the APU is silent
+    assert_eq!(
+        nes.io.samples.take(),
+        vec![-1.0 + 0.25 * apu::LEVEL_SCALE; 10]
+    );
+}
+
+#[test]
+fn bus_access_helpers() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+
+    assert_eq!(nes.read_u16_le(0xFFFC), 0xC004);
+
+    // Internal RAM is mirrored every 2KB
+    nes.write_u8(0x0010, 0x34);
+    nes.write_u8(0x0811, 0x12);
+    assert_eq!(nes.read_u8(0x1810), 0x34);
+    assert_eq!(nes.read_u16_le(0x0010), 0x1234);
+
+    Ok(())
+}
+
+#[test]
+fn step_frame_into_cropped_trims_edges() -> Result<()> {
+    let full = load_rom(DummyIO, "nestest")?;
+    let cropped = load_rom(DummyIO, "nestest")?;
+    let overscan = Overscan {
+        top: 8,
+        bottom: 8,
+        left: 4,
+        right: 12,
+    };
+    assert_eq!(overscan.width(), 240);
+    assert_eq!(overscan.height(), 224);
+
+    let mut full_buf = vec![0; FRAME_BUFFER_SIZE];
+    let mut cropped_buf = vec![0; overscan.frame_size()];
+    for _ in 0..10 {
+        full.step_frame_into(&mut full_buf);
+        cropped.step_frame_into_cropped(&mut cropped_buf, overscan);
+    }
+
+    for row in 0..224 {
+        let src = ((row + 8) * 256 + 4) * 3;
+        let dst = row * 240 * 3;
+        assert_eq!(
+            full_buf[src..src + 240 * 3],
+            cropped_buf[dst..dst + 240 * 3],
+            "row {}",
+            row
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn step_scanline_advances_one_line() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    for _ in 0..5 {
+        nes.step_frame();
+    }
+    assert!(nes.ppu.is_rendering());
+
+    // Get to the start of a line
+    nes.step_scanline();
+
+    let mut skipped = 0;
+    for _ in 0..262 * 4 {
+        let scanline = nes.ppu.scanline.get();
+        let ticks = nes.step_scanline();
+        assert_eq!(nes.ppu.scanline.get(), (scanline + 1) % 262);
+        assert_eq!(nes.ppu.dot.get(), 0);
+        if ticks == 340 {
+            assert_eq!(scanline, 261);
+            skipped += 1;
+        } else {
+            assert_eq!(ticks, 341);
+        }
+    }
+    assert_eq!(skipped, 2);
+
+    Ok(())
+}
+
+#[test]
+fn reset_keeps_memory_and_restarts_the_frame() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    for _ in 0..3 {
+        nes.step_frame();
+    }
+    nes.write_u8(0x0300, 0x42);
+    nes.ppu.cgram()[5].set(0x16);
+    // Part way through a line
+    for _ in 0..100 {
+        nes.tick();
+    }
+
+    nes.reset();
+    assert_eq!((nes.ppu.scanline.get(), nes.ppu.dot.get()), (0, 0));
+    assert_eq!(nes.read_u8(0x0300), 0x42);
+    assert_eq!(nes.ppu.cgram()[5].get(), 0x16);
+
+    // The reset sequence takes 7 CPU cycles
+    let ticks = nes.step_cpu_instruction();
+    assert_eq!(ticks, 7);
+    assert_eq!((nes.ppu.scanline.get(), nes.ppu.dot.get()), (0, 21));
+    assert_eq!(nes.cpu.pc.get(), nes.read_u16_le(0xFFFC));
+
+    Ok(())
+}
+
+#[test]
+fn load_rom_keeps_the_old_cartridge_if_the_mapper_is_unsupported() -> Result<()> {
+    let mut nes = load_rom(DummyIO, "nestest")?;
+    let reset_vector = nes.read_u16_le(0xFFFC);
+
+    let mut f = File::open("../roms/test/nestest.nes")?;
+    let mut rom = RomFile::from_read(&mut f)?;
+    rom.mapper = 255;
+    assert!(matches!(
+        nes.load_rom(rom),
+        Err(covnes::Error::UnsupportedMapper {
+            mapper: 255,
+            submapper: 0
+        })
+    ));
+    assert_eq!(nes.read_u16_le(0xFFFC), reset_vector);
+
+    Ok(())
+}
+
+#[test]
+fn frame_count_survives_reset_but_not_power_cycle() -> Result<()> {
+    let mut nes = load_rom(DummyIO, "nestest")?;
+    assert_eq!(nes.frame_count(), 0);
+
+    for _ in 0..3 {
+        nes.step_frame();
+    }
+    assert_eq!(nes.frame_count(), 3);
+
+    nes.reset();
+    nes.step_frame();
+    assert_eq!(nes.frame_count(), 4);
+
+    nes.power_cycle();
+    assert_eq!(nes.frame_count(), 0);
+    nes.step_frame();
+    assert_eq!(nes.frame_count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn seeded_ram_is_the_same_for_the_same_seed() {
+    let ram = |seed| Nes::new_seeded(DummyIO, seed).cpu_ram.get();
+    assert_eq!(ram(1), ram(1));
+    assert_ne!(ram(1), ram(2));
+    assert_ne!(ram(0), [0; 2048]);
+    assert_eq!(Nes::new(DummyIO).cpu_ram.get(), [0; 2048]);
+
+    // Power cycling goes back to the same pattern
+    let mut nes = Nes::new_seeded(DummyIO, 1);
+    nes.cpu_ram.set([0xAA; 2048]);
+    nes.power_cycle();
+    assert_eq!(nes.cpu_ram.get(), ram(1));
+}
+
+#[test]
+fn frame_lengths_by_region() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    for _ in 0..5 {
+        nes.step_frame();
+    }
+    assert!(nes.ppu.is_rendering());
+    // Alternates as odd frames skip a dot
+    let mut lengths = [nes.step_frame(), nes.step_frame()];
+    lengths.sort();
+    assert_eq!(lengths, [262 * 341 - 1, 262 * 341]);
+
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.set_region(Region::Dendy);
+    for _ in 0..5 {
+        nes.step_frame();
+    }
+    assert!(nes.ppu.is_rendering());
+    assert_eq!(nes.ppu.scanline.get(), 291);
+    for _ in 0..4 {
+        assert_eq!(nes.step_frame(), 312 * 341);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn cpu_runs_a_cycle_every_three_dots() -> Result<()> {
+    for region in [Region::Ntsc, Region::Dendy] {
+        let nes = load_rom(DummyIO, "nestest")?;
+        nes.set_region(region);
+        nes.step_frame();
+        let cycles = nes.cpu_cycles();
+        for _ in 0..3000 {
+            nes.tick();
+        }
+        assert_eq!(nes.cpu_cycles() - cycles, 1000);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn step_to_vblank_stops_at_vblank_start() -> Result<()> {
+    struct VblankIO {
+        vblanks: Cell<usize>,
+    }
+
+    impl IO for VblankIO {
+        fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+        fn controller_latch_change(&self, _value: bool) {}
+
+        fn controller_port_1_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+
+        fn controller_port_2_read(&self) -> ControllerPortDataLines {
+            ControllerPortDataLines::empty()
+        }
+
+        fn vblank_start(&self) {
+            self.vblanks.set(self.vblanks.get() + 1);
+        }
+    }
+
+    let nes = load_rom(
+        VblankIO {
+            vblanks: Cell::new(0),
+        },
+        "nestest",
+    )?;
+
+    for i in 1..=3 {
+        nes.step_to_vblank();
+        assert_eq!(nes.io.vblanks.get(), i);
+        assert_eq!((nes.ppu.scanline.get(), nes.ppu.dot.get()), (241, 2));
+        assert!(nes.ppu.ppustatus.get().contains(PPUSTATUS::VBLANK));
+    }
+
+    // step_frame stops just short of vblank
+    nes.step_frame();
+    assert_eq!(nes.io.vblanks.get(), 3);
+    assert!(!nes.ppu.ppustatus.get().contains(PPUSTATUS::VBLANK));
+    assert_eq!(nes.step_to_vblank(), 1);
+    assert_eq!(nes.io.vblanks.get(), 4);
+
+    Ok(())
+}
+
+#[test]
+fn load_state_replays_the_same_frames() -> Result<()> {
+    let nes = load_rom(DummyIO, "instr_test-v5")?;
+    for _ in 0..30 {
+        nes.step_frame();
+    }
+
+    let state = nes.save_state();
+    let mut first = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..30 {
+        nes.step_frame_into(&mut first);
+    }
+    let frame_count = nes.frame_count();
+
+    nes.load_state(&state)?;
+    let mut second = vec![0; FRAME_BUFFER_SIZE];
+    for _ in 0..30 {
+        nes.step_frame_into(&mut second);
+    }
+
+    assert_eq!(nes.frame_count(), frame_count);
+    assert!(first == second);
+
+    Ok(())
+}
+
+#[test]
+fn load_state_rejects_bad_states() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.step_frame();
+    let mut state = nes.save_state();
+
+    assert!(nes.load_state(&state[1..]).is_err());
+    state[0] = b'X';
+    assert!(nes.load_state(&state).is_err());
+
+    // A state for a different cartridge
+    let other = load_rom(DummyIO, "instr_test-v5")?;
+    assert!(nes.load_state(&other.save_state()).is_err());
+    assert_eq!(nes.frame_count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn unmapped_reads_see_open_bus() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+
+    // The APU registers aren't readable (yet)
+    nes.write_u8(0x0000, 0x5A);
+    assert_eq!(nes.read_u8(0x4000), 0x5A);
+    assert_eq!(nes.open_bus(), 0x5A);
+
+    // Neither is the CPU's test mode
+    nes.write_u8(0x401A, 0x33);
+    assert_eq!(nes.read_u8(0x4018), 0x33);
+    nes.write_u8(0x0000, 0x5A);
+    assert_eq!(nes.read_u8(0x401F), 0x5A);
+
+    // Nothing on NROM responds at $5000
+    assert_eq!(nes.read_u8(0x10), 0);
+    assert_eq!(nes.read_u8(0x5000), 0);
+    nes.read_u8(0xFFFC);
+    assert_eq!(nes.read_u8(0x5000), 0x04);
+
+    // The controller ports only drive the low bits
+    nes.write_u8(0x0000, 0xFF);
+    assert_eq!(nes.read_u8(0x4016), 0xE0);
+
+    // With no cartridge, all of $4020-$FFFF is open bus
+    let empty = Nes::new(DummyIO);
+    empty.write_u8(0x0000, 0x80);
+    assert_eq!(empty.read_u8(0x8000), 0x80);
+    assert_eq!(empty.read_u8(0xFFFF), 0x80);
+
+    Ok(())
+}
+
+#[test]
+fn bus_hook_sees_every_access() -> Result<()> {
+    let mut nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+    assert_eq!(nes.cpu.pc.get(), 0xC004);
+
+    let log: Arc<Mutex<Vec<BusAccess>>> = Default::default();
+    let sink = log.clone();
+    nes.bus_hook = Some(Box::new(move |access| sink.lock().unwrap().push(access)));
+
+    // SEI, which only reads its opcode as the 6502's dummy read after it isn't emulated
+    nes.step_cpu_instruction();
+    nes.write_u8(0x0300, 0x42);
+
+    assert_eq!(
+        *log.lock().unwrap(),
+        [
+            BusAccess {
+                addr: 0xC004,
+                value: 0x78,
+                write: false
+            },
+            BusAccess {
+                addr: 0x0300,
+                value: 0x42,
+                write: true
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn run_to_pc_stops_at_the_target_or_gives_up() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+    nes.cpu.jump_to_pc(0xC000);
+
+    // The first instruction is JMP $C5F5
+    test_support::run_to_pc(&nes, 0xC000, 0)?;
+    test_support::run_to_pc(&nes, 0xC5F5, 1)?;
+    assert_eq!(nes.cpu.pc.get(), 0xC5F5);
+
+    // It never goes back to the start
+    let timeout = test_support::run_to_pc(&nes, 0xC000, 100).unwrap_err();
+    assert_eq!(timeout.target, 0xC000);
+    assert_ne!(timeout.pc, 0xC000);
+
+    Ok(())
+}
+
+// Runs STA $4014 from RAM, after `lead_in` (which is there to change which CPU cycle the DMA
+// starts on), and returns how many cycles the CPU was stalled for before the NOP after it ran
+fn oamdma_stall(lead_in: &[u8]) -> u64 {
+    oamdma_stall_with(lead_in, |_| ())
+}
+
+// The same, calling `during` on the 100th cycle of the DMA
+fn oamdma_stall_with(lead_in: &[u8], during: impl Fn(&Nes<DummyIO>)) -> u64 {
+    let nes = Nes::new(DummyIO);
+    nes.step_cpu_instruction();
+    for i in 0..256 {
+        nes.write_u8(0x0200 + i, i as u8 ^ 0x5A);
+    }
+    let mut program = lead_in.to_vec();
+    program.extend([0x8D, 0x14, 0x40, 0xEA, 0xEA]); // STA $4014, NOP, NOP
+    for (i, &b) in program.iter().enumerate() {
+        nes.write_u8(0x0300 + i as u16, b);
+    }
+    nes.cpu.a.set(0x02);
+    nes.cpu.jump_to_pc(0x0300);
+
+    let nop = 0x0300 + lead_in.len() as u16 + 3;
+    test_support::run_to_pc(&nes, nop, 10).unwrap();
+    // The CPU halts trying to fetch the NOP, so the PC stays on it for the whole DMA
+    let start = nes.cpu_cycles();
+    while nes.cpu.pc.get() == nop {
+        nes.tick_cpu();
+        if nes.cpu_cycles() - start == 100 {
+            during(&nes);
+        }
+    }
+    let stall = nes.cpu_cycles() - start - 1;
+    for (i, byte) in nes.ppu.oam().iter().enumerate() {
+        assert_eq!(byte.get(), i as u8 ^ 0x5A);
+    }
+    stall
+}
+
+#[test]
+fn oamdma_stalls_for_513_or_514_cycles() {
+    // From the timing diagram on the NESdev wiki's DMA page: a halt cycle, an alignment cycle
+    // if the next one would be a write (put) cycle, then 256 get/put pairs. 2 and 3 cycle
+    // instructions before it give both alignments.
+    let nop = oamdma_stall(&[0xEA]);
+    let ldx_zp = oamdma_stall(&[0xA6, 0x00]);
+    let mut stalls = [nop, ldx_zp];
+    stalls.sort();
+    assert_eq!(stalls, [513, 514]);
+
+    // And the same alignment again after an even number of cycles
+    assert_eq!(oamdma_stall(&[0xEA, 0xEA]), nop);
+}
+
+// Runs `lead_in` then turns on a one byte DMC sample, and returns how many cycles the fetch of the
+// sample held up the NOPs after it
+fn dmc_stall(lead_in: &[u8]) -> u64 {
+    let nes = Nes::new(DummyIO);
+    nes.step_cpu_instruction();
+    nes.write_u8(0x4013, 0x00);
+    let mut program = lead_in.to_vec();
+    program.extend([0x8E, 0x15, 0x40]); // STX $4015
+    program.extend([0xEA; 10]);
+    for (i, &b) in program.iter().enumerate() {
+        nes.write_u8(0x0300 + i as u16, b);
+    }
+    nes.cpu.x.set(0x10);
+    nes.cpu.jump_to_pc(0x0300);
+
+    let nops = 0x0300 + lead_in.len() as u16 + 3;
+    test_support::run_to_pc(&nes, nops, 10).unwrap();
+    let start = nes.cpu_cycles();
+    test_support::run_to_pc(&nes, nops + 10, 20).unwrap();
+    assert!(nes.apu.dmc.buffer.get().is_some());
+    assert_eq!(nes.apu.dmc.dma_address(), None);
+    nes.cpu_cycles() - start - 20
+}
+
+#[test]
+fn dmc_dma_stalls_for_3_or_4_cycles() {
+    // A halt cycle, a dummy cycle, an alignment cycle if the next one would be a put, then the read
+    let mut stalls = [dmc_stall(&[0xEA]), dmc_stall(&[0xA5, 0x00])];
+    stalls.sort();
+    assert_eq!(stalls, [3, 4]);
+
+    // During an OAM DMA the DMC takes a get cycle, and the OAM DMA needs another to realign
+    for lead_in in [&[0xEA][..], &[0xA6, 0x00]].iter() {
+        let stall = oamdma_stall_with(lead_in, |nes| {
+            nes.apu.dmc.bytes_remaining.set(1);
+        });
+        assert_eq!(stall, oamdma_stall(lead_in) + 2);
+    }
+}
+
+#[test]
+fn mmc3_counts_scanlines_while_rendering() -> Result<()> {
+    // NOPs with a JMP back to the start at the end
+    let mut prg_rom = vec![0xEA; 0x8000];
+    prg_rom[0x7FF0..0x7FF3].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+    for vector in (0x7FFA..0x8000).step_by(2) {
+        prg_rom[vector..vector + 2].copy_from_slice(&[0x00, 0xE0]);
+    }
+    // Dendy's idle lines after the picture mustn't clock it either, and its pre-render line is
+    // where NTSC's would be 261
+    for region in [Region::Ntsc, Region::Dendy] {
+        let rom = RomFile {
+            prg_rom: prg_rom.clone(),
+            chr_rom: None,
+            provide_prg_ram: false,
+            prg_ram_size: 0x2000,
+            mirroring: covnes::romfiles::Mirroring::Horizontal,
+            mapper: 4,
+            submapper: 0,
+            timing: covnes::romfiles::Timing::Ntsc,
+        };
+        let mut nes = Nes::new(DummyIO);
+        nes.set_region(region);
+        nes.load_rom(rom)?;
+
+        // Sprites from $1000 and the background from $0000, so A12 rises once a line
+        nes.write_u8(0x2000, 0x08);
+        nes.write_u8(0x2001, 0x18);
+        nes.step_frame();
+        nes.step_frame();
+        assert!((240..region.pre_render_scanline()).contains(&nes.ppu.scanline.get()));
+
+        // Set up in vblank, so the pre-render line's clock reloads the counter. The IRQ then
+        // comes at the end of line 19, in time for a game to change something before line 20.
+        nes.write_u8(0xC000, 20);
+        for _ in 0..3 {
+            nes.write_u8(0xC001, 0);
+            nes.write_u8(0xE001, 0);
+            while !nes.cartridge.irq() {
+                nes.tick_cpu();
+            }
+            assert_eq!(nes.ppu.scanline.get(), 19);
+            assert!((257..=270).contains(&nes.ppu.dot.get()));
+
+            // Then every 21 lines after that
+            nes.write_u8(0xE000, 0);
+            nes.write_u8(0xE001, 0);
+            while !nes.cartridge.irq() {
+                nes.tick_cpu();
+            }
+            assert_eq!(nes.ppu.scanline.get(), 40);
+            nes.write_u8(0xE000, 0);
+            nes.step_frame();
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn dumping_memory_leaves_mapper_irqs_pending() -> Result<()> {
+    // The same NOP loop as above, on MMC5, whose status register acknowledges its IRQ when read
+    let mut prg_rom = vec![0xEA; 0x8000];
+    prg_rom[0x7FF0..0x7FF3].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+    for vector in (0x7FFA..0x8000).step_by(2) {
+        prg_rom[vector..vector + 2].copy_from_slice(&[0x00, 0xE0]);
+    }
+    let rom = RomFile {
+        prg_rom,
+        chr_rom: None,
+        provide_prg_ram: false,
+        prg_ram_size: 0x2000,
+        mirroring: covnes::romfiles::Mirroring::Horizontal,
+        mapper: 5,
+        submapper: 0,
+        timing: covnes::romfiles::Timing::Ntsc,
+    };
+    let mut nes = Nes::new(DummyIO);
+    nes.load_rom(rom)?;
+
+    // The I flag's still set from reset, so the IRQ stays pending for the CPU
+    nes.write_u8(0x2001, 0x18);
+    nes.write_u8(0x5203, 20);
+    nes.write_u8(0x5204, 0x80);
+    nes.step_frame();
+    nes.step_frame();
+    assert!(nes.cartridge.irq());
+
+    let dump = nes.dump_memory(0x4020..0x6000);
+    assert_eq!(dump[0x5204 - 0x4020] & 0x80, 0x80);
+    assert!(nes.cartridge.irq());
+    assert_eq!(nes.dump_memory(0x5204..0x5205)[0] & 0x80, 0x80);
+
+    Ok(())
+}
+
+#[test]
+fn frame_irq_interrupts_the_cpu() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+    let irq_vector = nes.read_u16_le(0xFFFE);
+    // CLI, then JMP back to the JMP forever
+    for (i, &b) in [0x58, 0x4C, 0x01, 0x03].iter().enumerate() {
+        nes.write_u8(0x0300 + i as u16, b);
+    }
+    let run_loop = |frame_counter: u8| {
+        nes.cpu.jump_to_pc(0x0300);
+        // Restarts the sequence, so the IRQ is a whole 4 step sequence (~10,000 JMPs) away
+        nes.write_u8(0x4017, frame_counter);
+        test_support::run_to_pc(&nes, irq_vector, 20_000)
+    };
+
+    run_loop(0x00)?;
+    // The handler sees the frame IRQ in $4015, and reading it there acknowledges it
+    assert_eq!(nes.read_u8(0x4015) & 0x40, 0x40);
+    assert_eq!(nes.read_u8(0x4015) & 0x40, 0);
+
+    // Nothing happens with the IRQ inhibited
+    assert!(run_loop(0x40).is_err());
+
+    Ok(())
+}
+
+// Runs `program` at $0310 with the APU's frame IRQ already waiting, and returns the address
+// and flags the IRQ pushed. The CPU polls for interrupts before the last cycle of each
+// instruction, so changes to the I flag are always an instruction late.
+fn irq_return_after(program: &[u8]) -> Result<(u16, u8)> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+    let irq_vector = nes.read_u16_le(0xFFFE);
+    // SEI, then JMP back to the JMP until the IRQ comes
+    for (i, &b) in [0x78, 0x4C, 0x01, 0x03].iter().enumerate() {
+        nes.write_u8(0x0300 + i as u16, b);
+    }
+    for (i, &b) in program.iter().chain(&[0xEA; 4]).enumerate() {
+        nes.write_u8(0x0310 + i as u16, b);
+    }
+    nes.cpu.jump_to_pc(0x0300);
+    nes.write_u8(0x4017, 0x00);
+    while !nes.apu.irq() {
+        nes.step_cpu_instruction();
+    }
+
+    nes.cpu.jump_to_pc(0x0310);
+    test_support::run_to_pc(&nes, irq_vector, 10)?;
+    let s = nes.cpu.s.get() as u16;
+    let p = nes.read_u8(0x0101 + s);
+    let pc = nes.read_u8(0x0102 + s) as u16 | (nes.read_u8(0x0103 + s) as u16) << 8;
+    Ok((pc, p))
+}
+
+#[test]
+fn cli_takes_effect_an_instruction_late() -> Result<()> {
+    // The NOP after CLI runs before the IRQ
+    assert_eq!(irq_return_after(&[0x58])?.0, 0x0312);
+
+    // And an SEI straight after can't stop it, but it's still set in the pushed flags
+    let (pc, p) = irq_return_after(&[0x58, 0x78])?;
+    assert_eq!(pc, 0x0312);
+    assert_eq!(p & 0x04, 0x04);
+
+    Ok(())
+}
+
+#[test]
+fn watch_logs_accesses_to_watched_addresses() -> Result<()> {
+    let mut nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+    nes.cpu.jump_to_pc(0xC000);
+    nes.watch(0x0001, WatchKind::ReadWrite);
+    nes.watch(0x0010, WatchKind::Read);
+
+    // nestest's BIT tests store to $01 and read it back. $10 is only ever written to here.
+    test_support::run_to_pc(&nes, 0xC79B, 1000)?;
+
+    let log = nes.take_watch_log();
+    let start = log[0].cycle;
+    let event = |write, pc, old, new, cycle| WatchEvent {
+        addr: 0x0001,
+        write,
+        pc,
+        old: Some(old),
+        new,
+        cycle: start + cycle,
+    };
+    assert_eq!(
+        log,
+        [
+            event(true, 0xC780, 0x00, 0xFF, 0),
+            event(false, 0xC782, 0xFF, 0xFF, 3),
+            event(false, 0xC78B, 0xFF, 0xFF, 11),
+            event(true, 0xC799, 0xFF, 0x00, 23),
+        ]
+    );
+    assert!(nes.take_watch_log().is_empty());
+
+    nes.unwatch(0x0001);
+    nes.step_cpu_instruction();
+    assert!(nes.take_watch_log().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn memory_dumps_have_no_side_effects() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    nes.step_cpu_instruction();
+
+    nes.write_u8(0x07FF, 0x12);
+    nes.write_u8(0x0000, 0x34);
+    // RAM mirrors, then the PPU registers which can't be peeked at
+    assert_eq!(nes.dump_memory(0x1FFF..0x2002), [0x12, 0x00, 0x00]);
+    assert_eq!(nes.dump_memory(0x0800..0x0801), [0x34]);
+    assert_eq!(
+        nes.dump_memory(0xFFFC..0xFFFE),
+        [nes.read_u8(0xFFFC), nes.read_u8(0xFFFD)]
+    );
+
+    // OAMADDR isn't moved on by dumping, so the next $2004 write still goes to sprite 1
+    nes.write_u8(0x2003, 0x04);
+    nes.write_u8(0x2004, 0xAB);
+    let oam = nes.dump_oam();
+    assert_eq!(oam.len(), 256);
+    assert_eq!(oam[4], 0xAB);
+    nes.write_u8(0x2004, 0xCD);
+    assert_eq!(nes.dump_oam()[5], 0xCD);
+    assert_eq!(nes.dump_oam(), nes.ppu.oam.get());
+
+    nes.write_u8(0x2006, 0x20);
+    nes.write_u8(0x2006, 0x00);
+    nes.write_u8(0x2007, 0x56);
+    assert_eq!(nes.dump_vram().len(), 2048);
+    assert_eq!(nes.dump_vram()[0], 0x56);
+
+    nes.write_u8(0x2006, 0x3F);
+    nes.write_u8(0x2006, 0x10);
+    nes.write_u8(0x2007, 0x0F);
+    assert_eq!(nes.dump_cgram().len(), 32);
+    assert_eq!(nes.dump_cgram()[0x00], 0x0F);
+
+    Ok(())
+}
+
+#[test]
+fn palette_reads_fill_the_buffer_from_the_nametable_underneath() -> Result<()> {
+    let nes = load_rom(DummyIO, "nestest")?;
+    let set_addr = |addr: u16| {
+        nes.read_u8(0x2002);
+        nes.write_u8(0x2006, (addr >> 8) as u8);
+        nes.write_u8(0x2006, addr as u8);
+    };
+
+    set_addr(0x2F05);
+    nes.write_u8(0x2007, 0x5A);
+    set_addr(0x3F05);
+    nes.write_u8(0x2007, 0x21);
+
+    // The palette comes back straight away...
+    set_addr(0x3F05);
+    assert_eq!(nes.read_u8(0x2007), 0x21);
+    // ...but the buffer has been loaded from $2F05, which the next non-palette read returns
+    set_addr(0x2000);
+    assert_eq!(nes.read_u8(0x2007), 0x5A);
+
+    Ok(())
+}
+
+// Only the prelude is in scope here, as it would be for a new frontend
+mod prelude_only {
+    use std::cell::Cell;
+
+    use covnes::prelude::*;
+
+    struct Screen {
+        pixels_drawn: Cell<usize>,
+    }
+
+    impl SingleStandardControllerIO for Screen {
+        fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {
+            self.pixels_drawn.set(self.pixels_drawn.get() + 1);
+        }
+
+        fn poll_buttons(&self) -> StandardControllerButtons {
+            StandardControllerButtons::START
+        }
+    }
+
+    #[test]
+    fn is_enough_to_run_a_game() -> Result<(), Error> {
+        let rom = RomFile::from_filename("../roms/test/nestest.nes")?;
+        let io = SingleStandardController::new(Screen {
+            pixels_drawn: Cell::new(0),
+        });
+        let mut nes = Nes::new(io);
+        nes.insert_cartridge(from_rom(rom)?);
+        nes.set_region(Region::Ntsc);
+
+        nes.step_frame();
+        nes.io.io.pixels_drawn.set(0);
+        nes.step_frame();
+        assert_eq!(nes.io.io.pixels_drawn.get(), 256 * 240);
+
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@
 use std::{
     fs::File,
     io::{BufRead, BufReader},
+    sync::{Arc, Mutex},
 };
 
 use anyhow::Result;
@@ -11,6 +12,14 @@ use covnes::{
 };
 use regex::Regex;
 
+// The log's PPU position starts from 0 after the reset sequence, which takes 21 dots
+const RESET_DOTS: u16 = 21;
+
+// Dots since the start of the frame. Rendering is never turned on so there are no skipped dots.
+fn ppu_position(scanline: u16, dot: u16) -> u32 {
+    (scanline as u32 * 341 + dot as u32) % (262 * 341)
+}
+
 #[test]
 fn nestest() -> Result<()> {
     // Load up the rom
@@ -29,9 +38,6 @@ fn nestest() -> Result<()> {
     // Setup initial stat
     nes.cpu.jump_to_pc(0xC000);
 
-    // Annoyingly, nestest doesn't do the right thing with the PPU after reset
-    nes.ppu.dot.set(0);
-
     let mut cycles = 7;
     let mut last_cycles = 7;
 
@@ -65,8 +71,8 @@ fn nestest() -> Result<()> {
             || expected_y != nes.cpu.y.get()
             || expected_sp != nes.cpu.s.get()
             || expected_p != actual_p
-            || expected_dot != nes.ppu.dot.get()
-            || expected_scanline != nes.ppu.scanline.get()
+            || ppu_position(expected_scanline, expected_dot + RESET_DOTS)
+                != ppu_position(nes.ppu.scanline.get(), nes.ppu.dot.get())
             || expected_cycles != cycles
         {
             println!("----");
@@ -100,3 +106,61 @@ fn nestest() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn disassembly_matches_the_log() -> Result<()> {
+    let rom = RomFile::from_filename("../roms/test/nestest.nes")?;
+    let log = BufReader::new(File::open("../roms/test/nestest.log")?);
+
+    let mut nes = Nes::new(DummyIO);
+    nes.insert_cartridge(mappers::from_rom(rom)?);
+    nes.step_cpu_instruction();
+    nes.cpu.jump_to_pc(0xC000);
+
+    let lines = log.lines().collect::<Result<Vec<_>, _>>()?;
+    let disassembly = nes.step_instructions(lines.len());
+    for (line, text) in lines.iter().zip(&disassembly) {
+        // The log puts a * before undocumented opcodes, and follows the operand with the
+        // addresses and values it refers to. It also calls ISC ISB.
+        let line = line.replacen('*', " ", 1).replacen("ISB", "ISC", 1);
+        assert!(line.starts_with(text.as_str()), "{}\n{}", line, text);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn trace_lines_match_the_log() -> Result<()> {
+    let rom = RomFile::from_filename("../roms/test/nestest.nes")?;
+    let log = BufReader::new(File::open("../roms/test/nestest.log")?);
+
+    let mut nes = Nes::new(DummyIO);
+    nes.insert_cartridge(mappers::from_rom(rom)?);
+    nes.step_cpu_instruction();
+    nes.cpu.jump_to_pc(0xC000);
+    assert!(nes.trace_line().starts_with("C000  4C F5 C5  JMP $C5F5"));
+
+    let trace = Arc::new(Mutex::new(Vec::new()));
+    let hook_trace = trace.clone();
+    nes.instruction_hook = Some(Box::new(move |line| {
+        hook_trace.lock().unwrap().push(line.to_string())
+    }));
+
+    let lines = log.lines().collect::<Result<Vec<_>, _>>()?;
+    for _ in 0..lines.len() {
+        nes.step_cpu_instruction();
+    }
+
+    let trace = trace.lock().unwrap();
+    assert_eq!(trace.len(), lines.len());
+    for (line, traced) in lines.iter().zip(trace.iter()) {
+        // The registers and cycle count line up exactly. The PPU position doesn't, as the log
+        // starts counting after the reset sequence (see RESET_DOTS).
+        let registers = |l: &str| l[48..73].to_string();
+        let cycles = |l: &str| l.rsplit("CYC:").next().unwrap().to_string();
+        assert_eq!(registers(line), registers(traced), "\n{}\n{}", line, traced);
+        assert_eq!(cycles(line), cycles(traced), "\n{}\n{}", line, traced);
+    }
+
+    Ok(())
+}
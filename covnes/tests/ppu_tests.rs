@@ -0,0 +1,662 @@
+use std::{
+    cell::Cell,
+    sync::{Arc, Mutex},
+};
+
+use covnes::nes::{
+    palette,
+    ppu::{PPUHostAccess, PpuAccuracy, PpuDebug, PPU, PPUCTRL, PPUMASK, PPUSTATUS},
+    Region,
+};
+
+// Just enough of a host to drive the PPU on its own: CHR reads come from an 8kb pattern table and
+// there are two vertically mirrored nametables (which start off all 0). The last pixel output is
+// kept, and the palette index of every pixel in the frame.
+struct TestHost {
+    chr: Vec<Cell<u8>>,
+    nametables: Vec<Cell<u8>>,
+    pixel: Cell<(u8, u8, u8)>,
+    frame: Vec<Cell<u8>>,
+}
+
+impl TestHost {
+    fn new() -> TestHost {
+        TestHost {
+            chr: vec![Cell::new(0); 0x2000],
+            nametables: vec![Cell::new(0); 0x800],
+            pixel: Cell::new((0, 0, 0)),
+            frame: vec![Cell::new(0); 256 * 240],
+        }
+    }
+
+    fn row(&self, row: usize) -> Vec<u8> {
+        self.frame[row * 256..(row + 1) * 256]
+            .iter()
+            .map(Cell::get)
+            .collect()
+    }
+}
+
+impl PPUHostAccess for TestHost {
+    fn ppu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr[addr as usize].get(),
+            _ => self.nametables[addr as usize % 0x800].get(),
+        }
+    }
+
+    fn ppu_write(&self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.chr[addr as usize].set(value),
+            _ => self.nametables[addr as usize % 0x800].set(value),
+        }
+    }
+
+    fn ppu_trigger_nmi(&self) {}
+
+    fn ppu_suppress_nmi(&self) {}
+
+    fn ppu_set_pixel(&self, row: u16, col: u16, palette_index: u8, emphasis: u8) {
+        self.pixel
+            .set(palette::get_rgb_with_emphasis(palette_index, emphasis));
+        self.frame[row as usize * 256 + col as usize].set(palette_index);
+    }
+}
+
+fn set_sprite(ppu: &PPU, n: usize, y: u8, tile: u8, attributes: u8, x: u8) {
+    let oam = ppu.oam();
+    oam[n * 4].set(y);
+    oam[n * 4 + 1].set(tile);
+    oam[n * 4 + 2].set(attributes);
+    oam[n * 4 + 3].set(x);
+}
+
+// Runs sprite evaluation for `scanline` (leaving the PPU just after the sprite fetches)
+fn run_scanline(ppu: &PPU, host: &TestHost, scanline: u16) {
+    ppu.scanline.set(scanline);
+    ppu.dot.set(0);
+    while ppu.dot.get() != 321 {
+        ppu.tick(host);
+    }
+}
+
+fn new_ppu() -> PPU {
+    let ppu = PPU::new();
+    ppu.ppumask.set(PPUMASK::SHOW_SPRITES);
+    // Move everything off screen
+    for n in 0..64 {
+        set_sprite(&ppu, n, 0xF0, 0xF0, 0xF0, 0);
+    }
+    ppu
+}
+
+fn overflow(ppu: &PPU) -> bool {
+    ppu.ppustatus.get().contains(PPUSTATUS::SPRITE_OVERFLOW)
+}
+
+#[test]
+fn sprite_overflow_with_nine_sprites() {
+    for &strict in &[true, false] {
+        let host = TestHost::new();
+        let ppu = new_ppu();
+        ppu.strict_sprite_overflow.set(strict);
+
+        for n in 0..8 {
+            set_sprite(&ppu, n, 30, 0, 0, 0);
+        }
+        run_scanline(&ppu, &host, 32);
+        assert!(!overflow(&ppu));
+
+        set_sprite(&ppu, 20, 28, 0, 0, 0);
+        run_scanline(&ppu, &host, 32);
+        assert!(overflow(&ppu));
+    }
+}
+
+#[test]
+fn sprite_overflow_hardware_bug() {
+    let host = TestHost::new();
+    let ppu = new_ppu();
+
+    for n in 0..8 {
+        set_sprite(&ppu, n, 30, 0, 0, 0);
+    }
+
+    // Once secondary OAM is full, an out of range sprite increments m as well as n - so the tile
+    // number of the sprite after is treated as a y coordinate. That makes this a false positive.
+    set_sprite(&ppu, 9, 0xF0, 31, 0xF0, 0xF0);
+    run_scanline(&ppu, &host, 32);
+    assert!(overflow(&ppu));
+
+    // And a false negative - the real 9th sprite's y coordinate is skipped over
+    let ppu = new_ppu();
+    for n in 0..8 {
+        set_sprite(&ppu, n, 30, 0, 0, 0);
+    }
+    set_sprite(&ppu, 9, 30, 0xF0, 0xF0, 0xF0);
+    run_scanline(&ppu, &host, 32);
+    assert!(!overflow(&ppu));
+}
+
+#[test]
+fn sprite_overflow_extra_reads() {
+    // After an overflow the 3 bytes following the y coordinate are read before moving on, when
+    // emulated strictly. This shows up in where OAMADDR has got to by the end of evaluation.
+    let mut oamaddrs = vec![];
+    for &strict in &[true, false] {
+        let host = TestHost::new();
+        let ppu = new_ppu();
+        ppu.strict_sprite_overflow.set(strict);
+        for n in 0..9 {
+            set_sprite(&ppu, n, 30, 0, 0, 0);
+        }
+
+        ppu.scanline.set(32);
+        ppu.dot.set(0);
+        while ppu.dot.get() != 256 {
+            ppu.tick(&host);
+        }
+        assert!(overflow(&ppu));
+        oamaddrs.push(ppu.oamaddr.get());
+    }
+
+    // Sprites 0-7 are copied by dot 129, and sprite 8 overflows. Strictly its 3 extra reads take
+    // until dot 136, leaving n = 9 and m = 0, then n goes up every other dot from 138 to 254,
+    // which is 59 times and wraps round to sprite 4. Otherwise n stops at 8 on dot 130 and goes up
+    // 62 times from 132, to sprite 6.
+    assert_eq!(oamaddrs, [4 << 2, 6 << 2]);
+}
+
+#[test]
+fn large_sprite_pattern_fetches() {
+    let host = TestHost::new();
+    // Every byte of CHR is the low byte of its address so we can see exactly what was fetched
+    for (i, c) in host.chr.iter().enumerate() {
+        c.set(i as u8);
+    }
+
+    for &(tile, table) in &[(0x03, 0x1000), (0x02, 0x0000), (0x45, 0x1000)] {
+        for &flip in &[false, true] {
+            let ppu = new_ppu();
+            ppu.ppuctrl.set(PPUCTRL::LARGE_SPRITES);
+            let attributes = if flip { 0x80 } else { 0x00 };
+            set_sprite(&ppu, 0, 100, tile, attributes, 0);
+
+            for row in 0..16 {
+                run_scanline(&ppu, &host, 100 + row);
+
+                let pattern_row = if flip { 15 - row } else { row };
+                let top_tile = (tile & !1) as u16;
+                let tile_addr = table + (top_tile + pattern_row / 8) * 16;
+                let addr = tile_addr + pattern_row % 8;
+
+                let sprite = &ppu.sprites[0];
+                assert_eq!(
+                    sprite.low_pattern.get(),
+                    addr as u8,
+                    "tile {:02X}, row {}, flip {}",
+                    tile,
+                    row,
+                    flip
+                );
+                assert_eq!(sprite.high_pattern.get(), (addr + 8) as u8);
+            }
+        }
+    }
+}
+
+// How many of ten solid sprites in a row on scanline 50 get drawn
+fn sprites_drawn(sprite_limit: bool) -> usize {
+    let host = TestHost::new();
+    for b in &host.chr[0..8] {
+        b.set(0xFF);
+    }
+    let ppu = new_ppu();
+    ppu.ppumask
+        .set(PPUMASK::SHOW_SPRITES | PPUMASK::SPRITE_LEFTMOST);
+    ppu.sprite_limit.set(sprite_limit);
+    ppu.cgram()[0x11].set(0x30);
+    for n in 0..10 {
+        set_sprite(&ppu, n, 49, 0, 0, n as u8 * 16);
+    }
+
+    run_scanline(&ppu, &host, 49);
+    while ppu.scanline.get() != 51 {
+        ppu.tick(&host);
+    }
+    let row = host.row(50);
+    (0..10)
+        .filter(|&n| row[n * 16..n * 16 + 8] == [0x30; 8])
+        .count()
+}
+
+#[test]
+fn sprite_limit_can_be_turned_off() {
+    assert_eq!(sprites_drawn(true), 8);
+    assert_eq!(sprites_drawn(false), 10);
+}
+
+#[test]
+fn debug_state_tracks_scroll_registers() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+
+    ppu.reg_write(&host, 0, 0x02);
+    // $2005: X = 0x7D, Y = 0x5E
+    ppu.reg_write(&host, 5, 0x7D);
+    let state = ppu.debug_state();
+    assert!(state.w);
+    assert_eq!(state.fine_x, 0x05);
+
+    ppu.reg_write(&host, 5, 0x5E);
+    // $2006 high byte only
+    ppu.reg_write(&host, 6, 0x3D);
+    let state = ppu.debug_state();
+    assert_eq!(state.ctrl, PPUCTRL::BASE_1);
+    assert!(state.w);
+    assert_eq!(state.t, 0x3D6F);
+
+    ppu.reg_write(&host, 6, 0xF0);
+    let state = ppu.debug_state();
+    assert!(!state.w);
+    assert_eq!(state.t, 0x3DF0);
+    assert_eq!(state.v, 0x3DF0);
+}
+
+#[test]
+fn trace_hook_sees_every_dot() {
+    let host = TestHost::new();
+    let mut ppu = PPU::new();
+    let trace: Arc<Mutex<Vec<PpuDebug>>> = Default::default();
+    let sink = trace.clone();
+    ppu.trace_hook = Some(Box::new(move |state| sink.lock().unwrap().push(*state)));
+
+    ppu.scanline.set(100);
+    for _ in 0..200 {
+        ppu.tick(&host);
+    }
+    ppu.reg_write(&host, 6, 0x21);
+    ppu.reg_write(&host, 6, 0x08);
+    for _ in 0..200 {
+        ppu.tick(&host);
+    }
+
+    let trace = trace.lock().unwrap();
+    assert_eq!(trace.len(), 400);
+    for (i, state) in trace.iter().enumerate() {
+        assert_eq!(
+            (state.scanline, state.dot),
+            (100 + i as u16 / 341, i as u16 % 341)
+        );
+        let v = if i < 200 { 0 } else { 0x2108 };
+        assert_eq!(state.v, v, "dot {}", i);
+    }
+}
+
+#[test]
+fn dendy_vblank_timing() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+    ppu.region.set(Region::Dendy);
+
+    // Find which scanlines see the vblank flag over two frames
+    let mut vblank_lines = vec![];
+    for _ in 0..312 * 341 * 2 {
+        ppu.tick(&host);
+        if ppu.dot.get() == 100 && ppu.ppustatus.get().contains(PPUSTATUS::VBLANK) {
+            vblank_lines.push(ppu.scanline.get());
+        }
+    }
+
+    let expected: Vec<u16> = (291..311).chain(291..311).collect();
+    assert_eq!(vblank_lines, expected);
+}
+
+// The length in dots of each of the first `n` frames
+fn frame_lengths(ppu: &PPU, host: &TestHost, n: usize) -> Vec<u32> {
+    let mut lengths = vec![];
+    let mut dots = 0;
+    while lengths.len() < n {
+        ppu.tick(host);
+        dots += 1;
+        if ppu.scanline.get() == 0 && ppu.dot.get() == 0 {
+            lengths.push(dots);
+            dots = 0;
+        }
+    }
+    lengths
+}
+
+#[test]
+fn odd_frame_skip_can_be_turned_off() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+    ppu.ppumask.set(PPUMASK::SHOW_BG);
+    assert_eq!(frame_lengths(&ppu, &host, 4), [89342, 89341, 89342, 89341]);
+
+    ppu.accuracy.set(PpuAccuracy {
+        odd_frame_skip: false,
+        ..PpuAccuracy::default()
+    });
+    assert_eq!(frame_lengths(&ppu, &host, 2), [89342, 89342]);
+}
+
+// Whether reading $2002 just as vblank starts sees the flag, and whether it's set afterwards
+fn read_status_at_vblank(accuracy: PpuAccuracy) -> (bool, bool) {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+    ppu.accuracy.set(accuracy);
+    while !(ppu.scanline.get() == 241 && ppu.dot.get() == 1) {
+        ppu.tick(&host);
+    }
+
+    let read = ppu.reg_read(&host, 2) & PPUSTATUS::VBLANK.bits() != 0;
+    ppu.tick(&host);
+    (read, ppu.ppustatus.get().contains(PPUSTATUS::VBLANK))
+}
+
+#[test]
+fn vbl_race_can_be_turned_off() {
+    assert_eq!(
+        read_status_at_vblank(PpuAccuracy::default()),
+        (false, false)
+    );
+
+    let no_race = PpuAccuracy {
+        vbl_race: false,
+        ..PpuAccuracy::default()
+    };
+    assert_eq!(read_status_at_vblank(no_race), (false, true));
+}
+
+#[test]
+fn greyscale_with_emphasis() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+    // Red backdrop, which is (152, 34, 32) without any PPUMASK bits
+    ppu.cgram()[0].set(0x16);
+
+    let backdrop = |mask| {
+        ppu.ppumask.set(mask);
+        ppu.scanline.set(0);
+        ppu.dot.set(0);
+        while ppu.dot.get() != 10 {
+            ppu.tick(&host);
+        }
+        host.pixel.get()
+    };
+
+    assert_eq!(backdrop(PPUMASK::empty()), (152, 34, 32));
+    // Greyscale picks $10 from the same row
+    assert_eq!(backdrop(PPUMASK::GREYSCALE), (152, 150, 152));
+    // Then blue emphasis darkens red and green
+    assert_eq!(
+        backdrop(PPUMASK::GREYSCALE | PPUMASK::EMPH_BLUE),
+        (124, 122, 152)
+    );
+    // Black isn't affected by emphasis
+    ppu.cgram()[0].set(0x0F);
+    assert_eq!(
+        backdrop(PPUMASK::EMPH_RED | PPUMASK::EMPH_GREEN | PPUMASK::EMPH_BLUE),
+        (0, 0, 0)
+    );
+
+    // Reading the palette through $2007 is only affected by greyscale
+    ppu.cgram()[0].set(0x16);
+    ppu.ppumask.set(PPUMASK::GREYSCALE | PPUMASK::EMPH_BLUE);
+    assert_eq!(ppu.read(&host, 0x3F00), 0x10);
+}
+
+#[test]
+fn reset_clears_write_latch() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+
+    ppu.reg_write(&host, 6, 0x21);
+    ppu.reg_write(&host, 6, 0x08);
+    ppu.reg_write(&host, 0, 0x80);
+    // Only the high byte of the next address
+    ppu.reg_write(&host, 6, 0x3F);
+    assert!(ppu.debug_state().w);
+
+    ppu.reset();
+    let state = ppu.debug_state();
+    assert!(!state.w);
+    assert_eq!(state.ctrl, PPUCTRL::empty());
+    assert_eq!(state.v, 0x2108);
+    assert_eq!(state.t, 0x3F08);
+
+    // So the next write is taken as a high byte again
+    ppu.reg_write(&host, 6, 0x23);
+    ppu.reg_write(&host, 6, 0xC0);
+    assert_eq!(ppu.debug_state().v, 0x23C0);
+}
+
+fn set_ppuaddr(ppu: &PPU, host: &TestHost, addr: u16) {
+    ppu.reg_write(host, 6, (addr >> 8) as u8);
+    ppu.reg_write(host, 6, addr as u8);
+}
+
+#[test]
+fn ppudata_writes_increment_by_1_or_32() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+
+    set_ppuaddr(&ppu, &host, 0x2041);
+    ppu.reg_write(&host, 7, 0x11);
+    ppu.reg_write(&host, 7, 0x12);
+    assert_eq!(host.nametables[0x41].get(), 0x11);
+    assert_eq!(host.nametables[0x42].get(), 0x12);
+
+    ppu.reg_write(&host, 0, PPUCTRL::VRAM_INC.bits());
+    ppu.reg_write(&host, 7, 0x13);
+    ppu.reg_write(&host, 7, 0x14);
+    assert_eq!(host.nametables[0x43].get(), 0x13);
+    assert_eq!(host.nametables[0x63].get(), 0x14);
+    assert_eq!(ppu.debug_state().v, 0x2083);
+
+    // Reading goes up by the same amount
+    ppu.reg_read(&host, 7);
+    assert_eq!(ppu.debug_state().v, 0x20A3);
+}
+
+#[test]
+fn ppudata_reads_come_through_a_buffer() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+    host.chr[0x0100].set(0xAA);
+    host.chr[0x0101].set(0xBB);
+
+    // The first read gets whatever was in the buffer, and each one after gets the byte from the
+    // read before
+    set_ppuaddr(&ppu, &host, 0x0100);
+    assert_eq!(ppu.reg_read(&host, 7), 0x00);
+    assert_eq!(ppu.reg_read(&host, 7), 0xAA);
+    assert_eq!(ppu.reg_read(&host, 7), 0xBB);
+
+    // Moving the address doesn't refill it
+    set_ppuaddr(&ppu, &host, 0x0100);
+    assert_eq!(ppu.reg_read(&host, 7), 0x00);
+    assert_eq!(ppu.reg_read(&host, 7), 0xAA);
+}
+
+#[test]
+fn sprite_backdrop_palette_entries_mirror_the_background_ones() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+
+    for (i, &addr) in [0x3F10, 0x3F14, 0x3F18, 0x3F1C].iter().enumerate() {
+        set_ppuaddr(&ppu, &host, addr);
+        ppu.reg_write(&host, 7, 0x20 + i as u8);
+    }
+    // And the whole palette repeats every 32 bytes up to $3FFF
+    set_ppuaddr(&ppu, &host, 0x3FE5);
+    ppu.reg_write(&host, 7, 0x2A);
+
+    for (i, &addr) in [0x3F00, 0x3F04, 0x3F08, 0x3F0C].iter().enumerate() {
+        set_ppuaddr(&ppu, &host, addr);
+        assert_eq!(ppu.reg_read(&host, 7), 0x20 + i as u8, "{:04X}", addr);
+    }
+    set_ppuaddr(&ppu, &host, 0x3F05);
+    assert_eq!(ppu.reg_read(&host, 7), 0x2A);
+    // The other sprite colours have their own entries
+    set_ppuaddr(&ppu, &host, 0x3F15);
+    assert_eq!(ppu.reg_read(&host, 7), 0x00);
+}
+
+#[test]
+fn palette_ram_shows_the_mirrored_backdrop_entries() {
+    let host = TestHost::new();
+    let ppu = PPU::new();
+
+    // The writes to $3F10/$3F14/$3F18/$3F1C overwrite $3F00/$3F04/$3F08/$3F0C
+    set_ppuaddr(&ppu, &host, 0x3F00);
+    for i in 0..32 {
+        ppu.reg_write(&host, 7, i);
+    }
+    // Left over from before, but hidden behind $3F00 whenever it's read
+    ppu.cgram()[0x10].set(0x3F);
+    // Which only affects what the game sees
+    ppu.ppumask.set(PPUMASK::GREYSCALE);
+
+    let palette = ppu.palette_ram();
+    for &addr in [0x00, 0x04, 0x08, 0x0C].iter() {
+        assert_eq!(palette[addr], 0x10 + addr as u8);
+        assert_eq!(palette[0x10 + addr], 0x10 + addr as u8);
+    }
+    assert_eq!(palette[0x05], 0x05);
+    assert_eq!(palette[0x15], 0x15);
+    assert_eq!(ppu.palette_ram(), palette);
+}
+
+// Renders a frame of the background from the pre-render line on, doing each of `writes` as
+// (scanline, dot, register, value) just before that dot. Tile 1 is colour $11 and tile 2 is $22.
+fn render_with_writes(host: &TestHost, writes: &[(u16, u16, u8, u8)]) {
+    for b in &host.chr[0x10..0x18] {
+        b.set(0xFF);
+    }
+    for b in &host.chr[0x28..0x30] {
+        b.set(0xFF);
+    }
+    let ppu = PPU::new();
+    ppu.cgram()[1].set(0x11);
+    ppu.cgram()[2].set(0x22);
+    ppu.ppumask.set(PPUMASK::SHOW_BG | PPUMASK::BG_LEFTMOST);
+
+    ppu.scanline.set(261);
+    ppu.dot.set(0);
+    while ppu.scanline.get() != 240 {
+        for &(scanline, dot, reg, value) in writes {
+            if (ppu.scanline.get(), ppu.dot.get()) == (scanline, dot) {
+                ppu.reg_write(host, reg, value);
+            }
+        }
+        ppu.tick(host);
+    }
+}
+
+fn fill_nametable(host: &TestHost, base: usize, tile: impl Fn(usize, usize) -> u8) {
+    for y in 0..30 {
+        for x in 0..32 {
+            host.nametables[base + y * 32 + x].set(tile(x, y));
+        }
+    }
+}
+
+#[test]
+fn scroll_split_with_2006_starts_on_the_next_line() {
+    let host = TestHost::new();
+    fill_nametable(&host, 0x000, |_, _| 1);
+    fill_nametable(&host, 0x400, |_, _| 2);
+
+    // Point v at the top of the second nametable during hblank, like a status bar split
+    render_with_writes(&host, &[(100, 260, 6, 0x04), (100, 262, 6, 0x00)]);
+
+    for row in 0..240 {
+        let colour = if row <= 100 { 0x11 } else { 0x22 };
+        assert_eq!(host.row(row), vec![colour; 256], "row {}", row);
+    }
+}
+
+#[test]
+fn scroll_split_with_2005_waits_for_dot_257() {
+    let host = TestHost::new();
+    // Left half of the first nametable is tile 1, then it's tile 2 all the way across the second
+    fill_nametable(&host, 0x000, |x, _| if x < 16 { 1 } else { 2 });
+    fill_nametable(&host, 0x400, |_, _| 2);
+
+    // Scroll 128 pixels right on line 50 before the horizontal bits are copied in to v at dot 257,
+    // then scroll back on line 150 just after, which isn't seen until line 152
+    render_with_writes(
+        &host,
+        &[
+            (50, 200, 5, 128),
+            (50, 202, 5, 0),
+            (150, 258, 5, 0),
+            (150, 260, 5, 0),
+        ],
+    );
+
+    let mut unscrolled = vec![0x11; 128];
+    unscrolled.extend([0x22; 128]);
+    for row in 0..240 {
+        let expected = if (51..152).contains(&row) {
+            vec![0x22; 256]
+        } else {
+            unscrolled.clone()
+        };
+        assert_eq!(host.row(row), expected, "row {}", row);
+    }
+}
+
+// Where sprite 0 hit gets set, as (scanline, dot), with an opaque background everywhere and a
+// solid sprite 0 at `x` on scanline 50
+fn sprite_zero_hit(x: u8, mask: PPUMASK) -> Option<(u16, u16)> {
+    let host = TestHost::new();
+    for b in &host.chr[0..8] {
+        b.set(0xFF);
+    }
+    let ppu = new_ppu();
+    ppu.ppumask.set(mask);
+    set_sprite(&ppu, 0, 49, 0, 0, x);
+
+    ppu.scanline.set(49);
+    ppu.dot.set(0);
+    while ppu.scanline.get() < 52 {
+        let at = (ppu.scanline.get(), ppu.dot.get());
+        ppu.tick(&host);
+        if ppu.ppustatus.get().contains(PPUSTATUS::SPRITE_0_HIT) {
+            return Some(at);
+        }
+    }
+    None
+}
+
+#[test]
+fn sprite_zero_hit_timing() {
+    let all =
+        PPUMASK::SHOW_BG | PPUMASK::SHOW_SPRITES | PPUMASK::BG_LEFTMOST | PPUMASK::SPRITE_LEFTMOST;
+
+    // Pixel x is worked out on dot x + 1
+    assert_eq!(sprite_zero_hit(0, all), Some((50, 1)));
+    assert_eq!(sprite_zero_hit(100, all), Some((50, 101)));
+    assert_eq!(sprite_zero_hit(247, all), Some((50, 248)));
+
+    // Never at x = 255
+    assert_eq!(sprite_zero_hit(255, all), None);
+    assert_eq!(sprite_zero_hit(254, all), Some((50, 255)));
+
+    // Or where either layer is clipped, so a sprite at 0 first hits at 8
+    let clipped = [
+        PPUMASK::SHOW_BG | PPUMASK::SHOW_SPRITES | PPUMASK::BG_LEFTMOST,
+        PPUMASK::SHOW_BG | PPUMASK::SHOW_SPRITES | PPUMASK::SPRITE_LEFTMOST,
+    ];
+    for &mask in &clipped {
+        assert_eq!(sprite_zero_hit(0, mask), None, "{:?}", mask);
+        assert_eq!(sprite_zero_hit(4, mask), Some((50, 9)), "{:?}", mask);
+    }
+
+    // Or with either layer hidden
+    assert_eq!(sprite_zero_hit(100, all - PPUMASK::SHOW_BG), None);
+    assert_eq!(sprite_zero_hit(100, all - PPUMASK::SHOW_SPRITES), None);
+}
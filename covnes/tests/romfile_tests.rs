@@ -0,0 +1,150 @@
+use anyhow::Result;
+use covnes::romfiles::{Mirroring, RomFile, Timing};
+
+fn rom(prg_rom: &[u8], chr_rom: Option<&[u8]>) -> RomFile {
+    RomFile {
+        prg_rom: prg_rom.to_vec(),
+        chr_rom: chr_rom.map(|c| c.to_vec()),
+        provide_prg_ram: false,
+        prg_ram_size: 0x2000,
+        mirroring: Mirroring::Horizontal,
+        mapper: 0,
+        submapper: 0,
+        timing: Timing::Ntsc,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn checksums_match_the_published_test_vectors() {
+    // The check value from the CRC catalogue, split across PRG and CHR
+    assert_eq!(rom(b"1234", Some(b"56789")).prg_chr_crc32(), 0xCBF4_3926);
+    assert_eq!(rom(b"", None).prg_chr_crc32(), 0);
+
+    // From RFC 1321, with the last one long enough to need a second padding block
+    assert_eq!(
+        hex(&rom(b"", None).rom_md5()),
+        "d41d8cd98f00b204e9800998ecf8427e"
+    );
+    assert_eq!(
+        hex(&rom(b"message ", Some(b"digest")).rom_md5()),
+        "f96b697d7cb7938d525a2f31aaf161d0"
+    );
+    assert_eq!(
+        hex(&rom(b"1234567890".repeat(8).as_slice(), None).rom_md5()),
+        "57edf4a22be3c955ac49da2e2107b67a"
+    );
+}
+
+#[test]
+fn checksums_leave_out_the_header() -> Result<()> {
+    let rom = RomFile::from_filename("../roms/test/nestest.nes")?;
+    assert_eq!(rom.prg_chr_crc32(), 0x158B_0388);
+    assert_eq!(hex(&rom.rom_md5()), "f68432958cd80e78f364f8727679a170");
+
+    Ok(())
+}
+
+#[test]
+fn prg_ram_size_comes_from_the_header() -> Result<()> {
+    let header = |byte_8: u8, junk: &[u8]| {
+        let mut data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x12, 0, byte_8, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data[16 - junk.len()..].copy_from_slice(junk);
+        data.extend([0; 16384]);
+        RomFile::from_bytes(&data)
+    };
+
+    assert_eq!(header(0, &[])?.prg_ram_size, 0x2000);
+    assert_eq!(header(1, &[])?.prg_ram_size, 0x2000);
+    assert_eq!(header(4, &[])?.prg_ram_size, 0x8000);
+    assert_eq!(header(4, b"Dude")?.prg_ram_size, 0x2000);
+
+    Ok(())
+}
+
+#[test]
+fn archaic_headers_only_use_the_low_mapper_nibble() -> Result<()> {
+    let header = |byte_6: u8, rest: &[u8]| {
+        let mut data = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, byte_6];
+        data.extend(rest);
+        data.extend([0; 16384]);
+        RomFile::from_bytes(&data)
+    };
+
+    // Byte 7 is 'D' (0x44), which would make this mapper 66
+    assert_eq!(header(0x21, b"DiskDude!")?.mapper, 2);
+    assert_eq!(header(0x21, &[0x40, 0, 0, 0, 0, 0, 0, 0, 0])?.mapper, 0x42);
+    // NES 2.0 headers use bytes 12-15, so they don't count as junk there
+    assert_eq!(header(0x21, &[0x48, 0, 0, 0, 0, 0, 0, 0, 1])?.mapper, 0x42);
+
+    Ok(())
+}
+
+#[test]
+fn nes_2_headers_have_extra_mapper_bits_and_submappers() -> Result<()> {
+    let header = |byte_7: u8, byte_8: u8| {
+        let mut data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x40, byte_7, byte_8, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        data.extend([0; 16384]);
+        RomFile::from_bytes(&data)
+    };
+
+    let rom = header(0x18, 0x42)?;
+    assert_eq!(rom.mapper, 0x214);
+    assert_eq!(rom.submapper, 4);
+
+    // iNES 1.0 headers don't have either, and byte 8 is the PRG RAM size
+    let rom = header(0x10, 0x42)?;
+    assert_eq!(rom.mapper, 0x14);
+    assert_eq!(rom.submapper, 0);
+
+    Ok(())
+}
+
+#[test]
+fn nes_2_prg_ram_sizes_are_shift_counts() -> Result<()> {
+    let header = |byte_10: u8| {
+        let mut data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x12, 0x08, 0x40, 0, byte_10, 0, 0, 0, 0, 0,
+        ];
+        data.extend([0; 16384]);
+        RomFile::from_bytes(&data)
+    };
+
+    // Byte 8 is the submapper here, not a count of 8kb banks
+    assert_eq!(header(0x07)?.prg_ram_size, 0x2000);
+    assert_eq!(header(0x90)?.prg_ram_size, 0x8000);
+    assert_eq!(header(0x79)?.prg_ram_size, 0x8000);
+    assert_eq!(header(0x00)?.prg_ram_size, 0x2000);
+
+    Ok(())
+}
+
+#[test]
+fn timing_comes_from_the_header() -> Result<()> {
+    let header = |byte_7: u8, byte_9: u8, byte_12: u8| {
+        let mut data = vec![
+            0x4E, 0x45, 0x53, 0x1A, 1, 0, 0, byte_7, 0, byte_9, 0, 0, byte_12, 0, 0, 0,
+        ];
+        data.extend([0; 16384]);
+        RomFile::from_bytes(&data)
+    };
+
+    assert_eq!(header(0, 0, 0)?.timing, Timing::Ntsc);
+    assert_eq!(header(0, 1, 0)?.timing, Timing::Pal);
+    // Junk at the end means byte 9 can't be trusted
+    assert_eq!(header(0, 1, 1)?.timing, Timing::Ntsc);
+
+    assert_eq!(header(0x08, 0, 0)?.timing, Timing::Ntsc);
+    assert_eq!(header(0x08, 0, 1)?.timing, Timing::Pal);
+    assert_eq!(header(0x08, 0, 2)?.timing, Timing::MultiRegion);
+    assert_eq!(header(0x08, 1, 3)?.timing, Timing::Dendy);
+
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use std::cell::Cell;
+
+use covnes::{
+    nes::{
+        io::{SingleStandardController, SingleStandardControllerIO, StandardControllerButtons},
+        Nes, FRAME_BUFFER_SIZE,
+    },
+    romfiles::RomFile,
+};
+use numpy::{ndarray::Array3, IntoPyArray, PyArray3};
+use pyo3::{exceptions::PyValueError, prelude::*, types::PyBytes};
+
+// Python bindings, for driving the emulator from scripts or RL environments. Build with
+// `maturin develop` from this directory, then:
+//
+//     import covnes_py
+//     nes = covnes_py.Emulator()
+//     nes.load_rom(open("smb.nes", "rb").read())
+//     nes.set_buttons(0x08)  # start
+//     frame = nes.step_frame()  # numpy array of shape (240, 256, 3)
+
+fn value_error(e: covnes::error::Error) -> PyErr {
+    PyValueError::new_err(e.to_string())
+}
+
+/// A NES with a single standard controller in port 1.
+///
+/// Frames come back from `step_frame` as uint8 numpy arrays of shape (240, 256, 3) in RGB order.
+#[pyclass(unsendable)]
+pub struct Emulator {
+    nes: Nes<SingleStandardController<PyIO>>,
+}
+
+#[pymethods]
+impl Emulator {
+    #[new]
+    fn new() -> Emulator {
+        let io = SingleStandardController::new(PyIO::new());
+        Emulator { nes: Nes::new(io) }
+    }
+
+    /// Inserts a ROM from the bytes of an iNES file and resets the console.
+    ///
+    /// Raises ValueError if the ROM can't be parsed or uses an unsupported mapper.
+    fn load_rom(&mut self, rom: &[u8]) -> PyResult<()> {
+        let rom = RomFile::from_bytes(rom).map_err(value_error)?;
+        self.nes.load_rom(rom).map_err(value_error)
+    }
+
+    /// Runs until the end of the next frame and returns it as a (240, 256, 3) uint8 array.
+    fn step_frame<'py>(&self, py: Python<'py>) -> Bound<'py, PyArray3<u8>> {
+        let mut frame = vec![0; FRAME_BUFFER_SIZE];
+        self.nes.step_frame_into(&mut frame);
+        Array3::from_shape_vec((240, 256, 3), frame)
+            .unwrap()
+            .into_pyarray_bound(py)
+    }
+
+    /// Sets the buttons held on the controller, as a bitmask:
+    ///
+    ///     0x01 A
+    ///     0x02 B
+    ///     0x04 Select
+    ///     0x08 Start
+    ///     0x10 Up
+    ///     0x20 Down
+    ///     0x40 Left
+    ///     0x80 Right
+    ///
+    /// They stay held until the next call.
+    fn set_buttons(&self, buttons: u8) {
+        self.nes
+            .io
+            .io
+            .buttons
+            .set(StandardControllerButtons::from_bits_truncate(buttons));
+    }
+
+    /// Presses the console's reset button.
+    fn reset(&self) {
+        self.nes.reset();
+    }
+
+    /// The number of frames run since the console was turned on.
+    fn frame_count(&self) -> u64 {
+        self.nes.frame_count()
+    }
+
+    /// Returns a save state as bytes, which can be passed back to `load_state`.
+    ///
+    /// States only work with the ROM they were taken from.
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.nes.save_state())
+    }
+
+    /// Restores a state from `save_state`.
+    ///
+    /// Raises ValueError if the state isn't for the current ROM, leaving the console as it was.
+    fn load_state(&self, state: &[u8]) -> PyResult<()> {
+        self.nes.load_state(state).map_err(value_error)
+    }
+}
+
+// Frames are copied out with `Nes::step_frame_into`, so this only has to supply the buttons
+pub struct PyIO {
+    buttons: Cell<StandardControllerButtons>,
+}
+
+impl PyIO {
+    fn new() -> PyIO {
+        PyIO {
+            buttons: Cell::new(StandardControllerButtons::empty()),
+        }
+    }
+}
+
+impl SingleStandardControllerIO for PyIO {
+    fn set_pixel(&self, _row: u16, _col: u16, _r: u8, _g: u8, _b: u8) {}
+
+    fn poll_buttons(&self) -> StandardControllerButtons {
+        self.buttons.get()
+    }
+}
+
+#[pymodule]
+fn covnes_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Emulator>()?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+use std::cell::{Cell, RefCell};
+
+use anyhow::{anyhow, Result};
+use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
+    AudioSubsystem,
+};
+
+// The APU puts out a sample every CPU cycle, ~1.79MHz, which is filtered and decimated down to
+// the sound card's rate on the emulator thread. The UI thread then queues the result with SDL.
+//
+// The emulator's frame rate and the sound card's clock never quite agree, so the queue would
+// slowly fill up or run dry. Instead the UI thread watches how full it is, and gets the emulator
+// thread to make very slightly more or fewer samples to keep it at the latency asked for.
+
+// Where the low-pass filter starts to cut frequencies off. Anything above half the output rate
+// would alias when decimating, so this should stay well under that.
+const LOW_PASS_HZ: f64 = 14_000.0;
+// The NES's own output has a high-pass filter at about this, which takes away the DC offset from
+// the mixer's silence being -1.0
+const HIGH_PASS_HZ: f64 = 90.0;
+// The most the output rate gets nudged by to keep the queue where it should be. A pitch change of
+// half a percent can't be heard.
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+// How much of each new queue size goes into the running average. SDL takes a whole buffer at a
+// time, so the size on its own jumps about too much to steer by.
+const QUEUE_SMOOTHING: f64 = 0.05;
+
+// `alpha` for a one pole filter with its cutoff at `cutoff_hz`, run at `rate_hz`
+fn one_pole_alpha(cutoff_hz: f64, rate_hz: f64) -> f32 {
+    let rc = 1.0 / (2.0 * std::f64::consts::PI * cutoff_hz);
+    let dt = 1.0 / rate_hz;
+    (dt / (rc + dt)) as f32
+}
+
+// Turns the emulator's cycle rate samples into ones at the output rate: a one pole low-pass
+// (twice over, for a steeper slope), then each output sample is the average of the input samples
+// in its period, and finally a one pole high-pass. It's used through `&self`, like the `IO` it
+// lives in.
+pub struct Downsampler {
+    // Input samples per output sample, which isn't a whole number. `step` is `base_step` with the
+    // rate adjustment from `Audio::rate_adjustment` applied.
+    base_step: f64,
+    step: Cell<f64>,
+    low_pass_alpha: f32,
+    high_pass_alpha: f32,
+    low_passed: Cell<[f32; 2]>,
+    sum: Cell<f32>,
+    count: Cell<u32>,
+    // How far through the current output sample's period the input is, in input samples
+    position: Cell<f64>,
+    // The last output sample before and after the high-pass
+    last_average: Cell<f32>,
+    last_output: Cell<f32>,
+    output: RefCell<Vec<f32>>,
+}
+
+impl Downsampler {
+    pub fn new(input_rate_hz: f64, output_rate_hz: u32) -> Downsampler {
+        let output_rate_hz = output_rate_hz as f64;
+        let step = input_rate_hz / output_rate_hz;
+        Downsampler {
+            base_step: step,
+            step: Cell::new(step),
+            low_pass_alpha: one_pole_alpha(LOW_PASS_HZ.min(output_rate_hz * 0.4), input_rate_hz),
+            high_pass_alpha: 1.0 - one_pole_alpha(HIGH_PASS_HZ, output_rate_hz),
+            // Starting where the mixer's silence is, so there's no pop at power on
+            low_passed: Cell::new([-1.0; 2]),
+            sum: Cell::new(0.0),
+            count: Cell::new(0),
+            position: Cell::new(0.0),
+            last_average: Cell::new(-1.0),
+            last_output: Cell::new(0.0),
+            output: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn push(&self, sample: f32) {
+        let [mut first, mut second] = self.low_passed.get();
+        first += self.low_pass_alpha * (sample - first);
+        second += self.low_pass_alpha * (first - second);
+        self.low_passed.set([first, second]);
+
+        self.sum.set(self.sum.get() + second);
+        self.count.set(self.count.get() + 1);
+        self.position.set(self.position.get() + 1.0);
+        if self.position.get() < self.step.get() {
+            return;
+        }
+        self.position.set(self.position.get() - self.step.get());
+
+        let average = self.sum.get() / self.count.get() as f32;
+        self.sum.set(0.0);
+        self.count.set(0);
+        let output =
+            self.high_pass_alpha * (self.last_output.get() + average - self.last_average.get());
+        self.last_average.set(average);
+        self.last_output.set(output);
+        self.output.borrow_mut().push(output);
+    }
+
+    // Makes `ratio` times as many output samples as normal from here on
+    pub fn set_rate_adjustment(&self, ratio: f64) {
+        self.step.set(self.base_step / ratio);
+    }
+
+    // Moves the output samples so far on to the end of `samples`
+    pub fn take_output(&self, samples: &mut Vec<f32>) {
+        samples.append(&mut self.output.borrow_mut());
+    }
+}
+
+// The sound card, fed with whatever the emulator thread sends over
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    // How many samples the rate adjustment tries to keep queued up
+    target_queued: u32,
+    average_queued: Cell<f64>,
+    // Whether the queue running dry would be an underrun, see `allow_gap`
+    playing: Cell<bool>,
+    underruns: Cell<u32>,
+}
+
+impl Audio {
+    // `buffer_size` is how many samples SDL asks for at a time, and `latency_ms` is how much sound
+    // to keep queued up. The latency can't be less than two buffers, or the queue would run dry
+    // every time SDL took one.
+    pub fn open(
+        subsystem: &AudioSubsystem,
+        sample_rate: u32,
+        buffer_size: u16,
+        latency_ms: u32,
+    ) -> Result<Audio> {
+        let spec = AudioSpecDesired {
+            freq: Some(sample_rate as i32),
+            channels: Some(1),
+            samples: Some(buffer_size),
+        };
+        let queue: AudioQueue<f32> = subsystem
+            .open_queue(None, &spec)
+            .map_err(|e| anyhow!("Could not open the audio device: {}", e))?;
+        queue.resume();
+        let spec = queue.spec();
+        let latency_samples = spec.freq as u64 * latency_ms as u64 / 1000;
+        let target_queued = latency_samples.max(spec.samples as u64 * 2) as u32;
+        Ok(Audio {
+            queue,
+            target_queued,
+            average_queued: Cell::new(target_queued as f64),
+            playing: Cell::new(false),
+            underruns: Cell::new(0),
+        })
+    }
+
+    // The rate the sound card actually ended up with, which might not be the one asked for
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    fn queued(&self) -> u32 {
+        self.queue.size() / std::mem::size_of::<f32>() as u32
+    }
+
+    // Queues up `samples`. If the queue had run dry it's filled back up to the target with silence
+    // first, as the rate adjustment would take far too long to do it. Having twice the target
+    // queued means the emulator's running well ahead, and the samples are dropped.
+    pub fn queue(&self, samples: &[f32]) {
+        // There's nothing to do about a failure here apart from go without sound for a bit
+        let queued = self.queued();
+        if queued == 0 {
+            if self.playing.get() {
+                self.underruns.set(self.underruns.get() + 1);
+            }
+            let _ = self
+                .queue
+                .queue_audio(&vec![0.0; self.target_queued as usize]);
+            self.average_queued.set(self.target_queued as f64);
+            self.playing.set(true);
+        } else if queued > self.target_queued * 2 {
+            return;
+        }
+        let _ = self.queue.queue_audio(samples);
+
+        let average = self.average_queued.get();
+        let queued = self.queued() as f64;
+        self.average_queued
+            .set(average + QUEUE_SMOOTHING * (queued - average));
+    }
+
+    // How many times as many samples as normal the emulator should make, to bring the queue back
+    // towards the target. For `Downsampler::set_rate_adjustment`.
+    pub fn rate_adjustment(&self) -> f64 {
+        let target = self.target_queued as f64;
+        let error = (target - self.average_queued.get()) / target;
+        1.0 + MAX_RATE_ADJUSTMENT * error.clamp(-1.0, 1.0)
+    }
+
+    // Stops the queue running dry from counting as an underrun until the next `queue`, for when
+    // the game's paused or fast forwarding
+    pub fn allow_gap(&self) {
+        self.playing.set(false);
+    }
+
+    // How many times the queue ran dry while the game was running
+    pub fn underruns(&self) -> u32 {
+        self.underruns.get()
+    }
+}
@@ -1,35 +1,100 @@
 use std::{
-    cell::Cell,
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{BufWriter, Write},
     mem::swap,
     sync::mpsc::{channel, Receiver, Sender},
-    thread::spawn,
+    thread::{spawn, JoinHandle},
 };
 
-use covnes::nes::{
-    io::{SingleStandardController, SingleStandardControllerIO, StandardControllerButtons},
-    mappers::Cartridge,
-    Nes,
+use covnes::{
+    emulator::BufferedIO,
+    fds::FdsImage,
+    nes::{
+        mappers::common::MirrorMode, palette::Palette, InstructionHook, Overscan, FRAME_BUFFER_SIZE,
+    },
+    prelude::*,
+    Result,
 };
 
+use crate::audio::Downsampler;
+
+// `covnes::Emulator` does the same buffer swapping on one thread, but this runs the console on
+// a thread of its own and has both controller ports plugged in
 #[derive(Debug)]
 struct PixelData {
-    pixels: Box<Cell<[(u8, u8, u8); 256 * 240]>>,
+    pixels: Box<[u8; FRAME_BUFFER_SIZE]>,
+    // How many frames had been run when this one was finished
+    frame_count: u64,
+    // Where the CPU and PPU were at that point, for the debug overlay
+    pc: u16,
+    scanline: u16,
+    // The frame's sound at the output sample rate
+    audio: Vec<f32>,
 }
 
 impl PixelData {
     fn new() -> Self {
         Self {
-            pixels: Box::new(Cell::new([(0, 0, 0); 256 * 240])),
+            pixels: Box::new([0; FRAME_BUFFER_SIZE]),
+            frame_count: 0,
+            pc: 0,
+            scanline: 0,
+            audio: Vec::new(),
         }
     }
+}
 
-    fn pixels(&self) -> &[Cell<(u8, u8, u8)>] {
-        let f: &Cell<[(u8, u8, u8)]> = self.pixels.as_ref();
-        f.as_slice_of_cells()
-    }
+// A copy of each of the console's memories, see `Nes::dump_memory` and friends
+pub struct MemoryDump {
+    // All 2kb of CPU RAM
+    pub ram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub oam: Vec<u8>,
+    pub cgram: Vec<u8>,
+}
+
+// Where to write a trace log to, one `Nes::trace_line` for every instruction
+pub struct Trace {
+    pub file: File,
+    // Stops after this many frames, or never without a limit
+    pub frames: Option<u64>,
+}
+
+// What to load: a cartridge, or a disk for the Famicom Disk System along with its BIOS
+pub enum Game {
+    Rom(RomFile),
+    Fds { bios: Vec<u8>, image: FdsImage },
+}
+
+// The disk drive after inserting, ejecting or turning over a disk, see `covnes::nes::mappers::fds::Fds`
+#[derive(Debug, Clone, Copy)]
+pub struct DiskStatus {
+    pub sides: usize,
+    pub inserted: Option<usize>,
+    pub selected: usize,
+}
 
+// `BufferedIO` for the picture and controllers, with the sound going through a `Downsampler` if
+// there's anywhere for it to go
+struct EmulatorIo {
+    buffered: BufferedIO,
+    audio: Option<Downsampler>,
+}
+
+impl TwoStandardControllersIO for EmulatorIo {
     fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
-        self.pixels()[row as usize * 256 + col as usize].set((r, g, b));
+        TwoStandardControllersIO::set_pixel(&self.buffered, row, col, r, g, b);
+    }
+
+    fn poll_buttons(&self, port: usize) -> StandardControllerButtons {
+        TwoStandardControllersIO::poll_buttons(&self.buffered, port)
+    }
+
+    fn push_audio_sample(&self, sample: f32) {
+        if let Some(audio) = &self.audio {
+            audio.push(sample);
+        }
     }
 }
 
@@ -42,24 +107,51 @@ pub struct Emulator {
     // this is because we only swap two buffers between the threads -- at some
     // point in time, one of the buffers will be in transit in the channel
     buffer: Option<PixelData>,
+    // Joined on drop so that the emulator thread gets to flush the trace file
+    thread: Option<JoinHandle<()>>,
 }
 
 impl Emulator {
-    pub fn new(cartridge: Cartridge) -> Self {
+    pub fn new(
+        game: Game,
+        region: Region,
+        sprite_limit: bool,
+        palette: Palette,
+        trace: Option<Trace>,
+        sample_rate: Option<u32>,
+    ) -> Result<Self> {
+        let mut nes = Nes::new(TwoStandardControllers::new(EmulatorIo {
+            buffered: BufferedIO::new(),
+            audio: sample_rate.map(|rate| Downsampler::new(region.cpu_clock_hz(), rate)),
+        }));
+        nes.set_region(region);
+        nes.set_palette(palette);
+        nes.ppu.sprite_limit.set(sprite_limit);
+        match game {
+            Game::Rom(rom) => nes.load_rom(rom)?,
+            Game::Fds { bios, image } => nes.load_fds(bios, &image)?,
+        }
+
         let (msg_tx, msg_rx) = channel();
         let (buffer_tx, buffer_rx) = channel();
-        spawn(move || run_emulator(msg_rx, buffer_tx, cartridge));
-        Self {
+        let thread = spawn(move || run_emulator(msg_rx, buffer_tx, nes, trace));
+        Ok(Self {
             tx: msg_tx,
             rx: buffer_rx,
             buffer: Some(PixelData::new()),
-        }
+            thread: Some(thread),
+        })
     }
 
-    pub fn step_frame(&mut self) {
+    // Runs a frame with `buttons` held down for all of it. Input only ever goes along with a
+    // frame, so each frame gets exactly the input it was stepped with however far behind the
+    // emulator thread is.
+    pub fn step_frame(&mut self, buttons: [StandardControllerButtons; 2]) {
         let mut buffer = None;
         swap(&mut buffer, &mut self.buffer);
-        self.tx.send(Message::NewFrame(buffer.unwrap())).unwrap();
+        self.tx
+            .send(Message::NewFrame(buffer.unwrap(), buttons))
+            .unwrap();
         self.buffer = Some(self.rx.recv().unwrap());
     }
 
@@ -67,71 +159,216 @@ impl Emulator {
         self.tx.send(Message::Reset).unwrap();
     }
 
-    pub fn set_buttons(&mut self, buttons: StandardControllerButtons) {
-        self.tx.send(Message::SetInput(buttons)).unwrap()
+    pub fn save_state(&mut self) -> Vec<u8> {
+        let (tx, rx) = channel();
+        self.tx.send(Message::SaveState(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    // The frame on screen stays as it is until the next step_frame
+    pub fn load_state(&mut self, state: Vec<u8>) -> Result<()> {
+        let (tx, rx) = channel();
+        self.tx.send(Message::LoadState(state, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    pub fn dump_memory(&mut self) -> MemoryDump {
+        let (tx, rx) = channel();
+        self.tx.send(Message::DumpMemory(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    // These do nothing and return None if the game isn't on a disk
+    pub fn insert_or_eject_disk(&mut self) -> Option<DiskStatus> {
+        let (tx, rx) = channel();
+        self.tx.send(Message::InsertOrEjectDisk(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    pub fn select_next_disk_side(&mut self) -> Option<DiskStatus> {
+        let (tx, rx) = channel();
+        self.tx.send(Message::SelectNextDiskSide(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    /// The sound from the last frame run, at the sample rate given to `new`
+    pub fn audio_samples(&self) -> &[f32] {
+        &self.buffer.as_ref().unwrap().audio
+    }
+
+    /// The frame count of the frame that's currently being displayed
+    pub fn frame_count(&self) -> u64 {
+        self.buffer.as_ref().unwrap().frame_count
+    }
+
+    /// The CPU's PC at the end of the frame that's currently being displayed
+    pub fn pc(&self) -> u16 {
+        self.buffer.as_ref().unwrap().pc
+    }
+
+    /// The PPU's scanline at the end of the frame that's currently being displayed
+    pub fn scanline(&self) -> u16 {
+        self.buffer.as_ref().unwrap().scanline
     }
 
-    pub fn iter_pixels<F>(&mut self, mut f: F)
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.tx
+            .send(Message::SetChannelEnabled(channel, enabled))
+            .unwrap()
+    }
+
+    pub fn set_mirroring(&mut self, mode: Option<MirrorMode>) {
+        self.tx.send(Message::SetMirroring(mode)).unwrap()
+    }
+
+    /// See `Downsampler::set_rate_adjustment`, this does nothing without sound
+    pub fn set_audio_rate_adjustment(&mut self, ratio: f64) {
+        self.tx
+            .send(Message::SetAudioRateAdjustment(ratio))
+            .unwrap()
+    }
+
+    /// Calls `f` with every visible pixel, with `row` and `col` relative to the cropped frame
+    pub fn iter_pixels<F>(&mut self, overscan: Overscan, mut f: F)
     where
         F: FnMut(u8, u8, (u8, u8, u8)),
     {
-        let mut iter = self.buffer.as_ref().unwrap().pixels().iter().cloned();
+        let pixels = &self.buffer.as_ref().unwrap().pixels;
 
-        for row in 0..240 {
-            for col in 0..=255 {
-                f(row, col, iter.next().unwrap().get())
+        for row in 0..overscan.height() {
+            let start = ((row + overscan.top) * 256 + overscan.left) * 3;
+            let row_pixels = pixels[start..start + overscan.width() * 3].chunks_exact(3);
+            for (col, p) in row_pixels.enumerate() {
+                f(row as u8, col as u8, (p[0], p[1], p[2]))
             }
         }
     }
 }
 
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        // The thread might have gone already if it panicked
+        let _ = self.tx.send(Message::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 #[derive(Debug)]
 enum Message {
-    SetInput(StandardControllerButtons),
-    NewFrame(PixelData),
+    NewFrame(PixelData, [StandardControllerButtons; 2]),
     Reset,
+    SaveState(Sender<Vec<u8>>),
+    LoadState(Vec<u8>, Sender<Result<()>>),
+    DumpMemory(Sender<MemoryDump>),
+    SetChannelEnabled(usize, bool),
+    SetMirroring(Option<MirrorMode>),
+    SetAudioRateAdjustment(f64),
+    InsertOrEjectDisk(Sender<Option<DiskStatus>>),
+    SelectNextDiskSide(Sender<Option<DiskStatus>>),
+    Quit,
 }
 
-fn run_emulator(rx: Receiver<Message>, tx: Sender<PixelData>, cartridge: Cartridge) {
-    let io = SingleStandardController::new(EmulatorIo::new());
-    let mut nes = Nes::new(io);
-    nes.insert_cartridge(cartridge);
-
-    for message in rx.iter() {
-        match message {
-            Message::SetInput(input) => {
-                nes.io.io.current_key_state.set(input);
-            }
-            Message::NewFrame(mut buffer) => {
-                swap(&mut buffer, &mut nes.io.io.pixels);
-                tx.send(buffer).unwrap();
-                nes.step_frame();
-            }
-            Message::Reset => nes.reset(),
+// Writes every instruction's trace line to `file`, buffered so that it isn't a system call each.
+// If writing fails it just stops, as there's no way to report it from in the hook.
+fn trace_hook(file: File) -> InstructionHook {
+    let writer = RefCell::new(BufWriter::with_capacity(1 << 20, file));
+    let failed = Cell::new(false);
+    Box::new(move |line| {
+        if failed.get() {
+            return;
         }
-    }
+        if let Err(e) = writeln!(writer.borrow_mut(), "{}", line) {
+            println!(
+                "Couldn't write to the trace file, so tracing has stopped: {}",
+                e
+            );
+            failed.set(true);
+        }
+    })
 }
 
-struct EmulatorIo {
-    pixels: PixelData,
-    current_key_state: Cell<StandardControllerButtons>,
+fn disk_status<I: IO>(nes: &Nes<I>) -> Option<DiskStatus> {
+    nes.fds().map(|drive| DiskStatus {
+        sides: drive.side_count(),
+        inserted: drive.inserted_side(),
+        selected: drive.selected_side(),
+    })
 }
 
-impl EmulatorIo {
-    fn new() -> Self {
-        Self {
-            pixels: PixelData::new(),
-            current_key_state: Cell::new(StandardControllerButtons::empty()),
-        }
+fn run_emulator(
+    rx: Receiver<Message>,
+    tx: Sender<PixelData>,
+    mut nes: Nes<TwoStandardControllers<EmulatorIo>>,
+    trace: Option<Trace>,
+) {
+    let mut trace_frames = None;
+    if let Some(trace) = trace {
+        nes.instruction_hook = Some(trace_hook(trace.file));
+        trace_frames = trace.frames;
     }
-}
 
-impl SingleStandardControllerIO for EmulatorIo {
-    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
-        self.pixels.set_pixel(row, col, r, g, b);
-    }
+    for message in rx.iter() {
+        match message {
+            Message::NewFrame(mut buffer, input) => {
+                for (port, buttons) in input.iter().enumerate() {
+                    nes.io.io.buffered.set_buttons(port, *buttons);
+                }
+                nes.io.io.buffered.swap_frame(&mut buffer.pixels);
+                buffer.audio.clear();
+                if let Some(audio) = &nes.io.io.audio {
+                    audio.take_output(&mut buffer.audio);
+                }
+                buffer.frame_count = nes.frame_count();
+                buffer.pc = nes.cpu.pc.get();
+                buffer.scanline = nes.ppu.scanline.get();
+                tx.send(buffer).unwrap();
+                nes.step_frame();
 
-    fn poll_buttons(&self) -> StandardControllerButtons {
-        self.current_key_state.get()
+                if let Some(frames) = &mut trace_frames {
+                    *frames = frames.saturating_sub(1);
+                    if *frames == 0 {
+                        // Dropping the hook flushes the file
+                        nes.instruction_hook = None;
+                        trace_frames = None;
+                        println!("Finished tracing");
+                    }
+                }
+            }
+            Message::Reset => nes.reset(),
+            Message::SaveState(reply) => reply.send(nes.save_state()).unwrap(),
+            Message::LoadState(state, reply) => reply.send(nes.load_state(&state)).unwrap(),
+            Message::DumpMemory(reply) => reply
+                .send(MemoryDump {
+                    ram: nes.dump_memory(0x0000..0x0800),
+                    vram: nes.dump_vram(),
+                    oam: nes.dump_oam(),
+                    cgram: nes.dump_cgram(),
+                })
+                .unwrap(),
+            Message::SetChannelEnabled(channel, enabled) => {
+                nes.apu.set_channel_enabled(channel, enabled)
+            }
+            Message::SetMirroring(mode) => nes.set_mirroring(mode),
+            Message::SetAudioRateAdjustment(ratio) => {
+                if let Some(audio) = &nes.io.io.audio {
+                    audio.set_rate_adjustment(ratio);
+                }
+            }
+            Message::InsertOrEjectDisk(reply) => {
+                if let Some(drive) = nes.fds() {
+                    drive.insert_or_eject();
+                }
+                reply.send(disk_status(&nes)).unwrap()
+            }
+            Message::SelectNextDiskSide(reply) => {
+                if let Some(drive) = nes.fds() {
+                    drive.select_next_side();
+                }
+                reply.send(disk_status(&nes)).unwrap()
+            }
+            Message::Quit => break,
+        }
     }
 }
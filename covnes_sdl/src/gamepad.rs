@@ -0,0 +1,100 @@
+use covnes::nes::io::StandardControllerButtons;
+use sdl2::{
+    controller::{Axis, Button, GameController},
+    event::Event,
+    GameControllerSubsystem,
+};
+
+// Up to two game controllers, one for each player in the order they were plugged in. SDL sends
+// an added event for each controller that's already connected at startup, so that's handled the
+// same way as hotplugging.
+
+const BUTTONS: &[(Button, StandardControllerButtons)] = &[
+    // Going by position rather than label - on most pads the button to the right is B
+    (Button::B, StandardControllerButtons::A),
+    (Button::A, StandardControllerButtons::B),
+    (Button::Back, StandardControllerButtons::SELECT),
+    (Button::Start, StandardControllerButtons::START),
+    (Button::DPadUp, StandardControllerButtons::UP),
+    (Button::DPadDown, StandardControllerButtons::DOWN),
+    (Button::DPadLeft, StandardControllerButtons::LEFT),
+    (Button::DPadRight, StandardControllerButtons::RIGHT),
+];
+
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    controllers: [Option<GameController>; 2],
+    // How far the left stick has to move before it counts as a d-pad press
+    deadzone: i16,
+}
+
+impl Gamepads {
+    // `deadzone` is a fraction of the stick's full range
+    pub fn new(subsystem: GameControllerSubsystem, deadzone: f32) -> Self {
+        Self {
+            subsystem,
+            controllers: [None, None],
+            deadzone: (deadzone.clamp(0.0, 1.0) * i16::MAX as f32) as i16,
+        }
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                if let Some(slot) = self.controllers.iter_mut().find(|c| c.is_none()) {
+                    match self.subsystem.open(which) {
+                        Ok(c) => {
+                            println!("Controller connected: {}", c.name());
+                            *slot = Some(c);
+                        }
+                        Err(e) => println!("Couldn't open controller {}: {}", which, e),
+                    }
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                for slot in &mut self.controllers {
+                    if slot.as_ref().map(|c| c.instance_id()) == Some(which) {
+                        *slot = None;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn buttons(&self) -> [StandardControllerButtons; 2] {
+        let mut buttons = [StandardControllerButtons::empty(); 2];
+
+        for (c, b) in self.controllers.iter().zip(&mut buttons) {
+            if let Some(c) = c {
+                *b = self.controller_buttons(c);
+            }
+        }
+
+        buttons
+    }
+
+    fn controller_buttons(&self, c: &GameController) -> StandardControllerButtons {
+        let mut buttons = StandardControllerButtons::empty();
+        for &(button, b) in BUTTONS {
+            if c.button(button) {
+                buttons |= b;
+            }
+        }
+
+        let x = c.axis(Axis::LeftX);
+        let y = c.axis(Axis::LeftY);
+        if x < -self.deadzone {
+            buttons |= StandardControllerButtons::LEFT;
+        } else if x > self.deadzone {
+            buttons |= StandardControllerButtons::RIGHT;
+        }
+        if y < -self.deadzone {
+            buttons |= StandardControllerButtons::UP;
+        } else if y > self.deadzone {
+            buttons |= StandardControllerButtons::DOWN;
+        }
+
+        buttons
+    }
+}
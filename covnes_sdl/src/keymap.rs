@@ -0,0 +1,154 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use covnes::nes::io::StandardControllerButtons;
+use sdl2::keyboard::{KeyboardState, Scancode};
+
+// Key bindings, loaded from a file with one binding per line:
+//
+//     # player.button = key
+//     1.a = J
+//     1.turbo_b = M
+//     2.up = Up
+//
+// Players are 1 or 2. Buttons are a, b, select, start, up, down, left, right, or turbo_a and
+// turbo_b. Keys use SDL's scancode names, e.g. "Left Shift" or "Keypad 8".
+
+// Turbo buttons are held for this many frames, then released for the same number
+const TURBO_FRAMES: u32 = 2;
+
+const BUTTON_NAMES: &[(&str, StandardControllerButtons)] = &[
+    ("a", StandardControllerButtons::A),
+    ("b", StandardControllerButtons::B),
+    ("select", StandardControllerButtons::SELECT),
+    ("start", StandardControllerButtons::START),
+    ("up", StandardControllerButtons::UP),
+    ("down", StandardControllerButtons::DOWN),
+    ("left", StandardControllerButtons::LEFT),
+    ("right", StandardControllerButtons::RIGHT),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Binding {
+    scancode: Scancode,
+    player: usize,
+    button: StandardControllerButtons,
+    turbo: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: Vec<Binding>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let keys = [
+            (Scancode::W, StandardControllerButtons::UP),
+            (Scancode::A, StandardControllerButtons::LEFT),
+            (Scancode::S, StandardControllerButtons::DOWN),
+            (Scancode::D, StandardControllerButtons::RIGHT),
+            (Scancode::J, StandardControllerButtons::A),
+            (Scancode::K, StandardControllerButtons::B),
+            (Scancode::U, StandardControllerButtons::SELECT),
+            (Scancode::I, StandardControllerButtons::START),
+        ];
+
+        Keymap {
+            bindings: keys
+                .iter()
+                .map(|&(scancode, button)| Binding {
+                    scancode,
+                    player: 0,
+                    button,
+                    turbo: false,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    pub fn from_file(path: &Path) -> Result<Keymap> {
+        let config = fs::read_to_string(path)
+            .with_context(|| format!("Could not read key bindings from {}", path.display()))?;
+        Keymap::parse(&config).with_context(|| format!("Bad key bindings in {}", path.display()))
+    }
+
+    fn parse(config: &str) -> Result<Keymap> {
+        let mut bindings = vec![];
+
+        for (line_no, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let binding =
+                parse_binding(line).with_context(|| format!("On line {}", line_no + 1))?;
+            bindings.push(binding);
+        }
+
+        Ok(Keymap { bindings })
+    }
+
+    // The buttons held on each controller, given which frame we are on for turbo
+    pub fn buttons(&self, keys: &KeyboardState, frame: u32) -> [StandardControllerButtons; 2] {
+        let turbo_pressed = frame % (TURBO_FRAMES * 2) < TURBO_FRAMES;
+        let mut buttons = [StandardControllerButtons::empty(); 2];
+
+        for b in &self.bindings {
+            if keys.is_scancode_pressed(b.scancode) && (!b.turbo || turbo_pressed) {
+                buttons[b.player] |= b.button;
+            }
+        }
+
+        buttons
+    }
+}
+
+fn parse_binding(line: &str) -> Result<Binding> {
+    let (target, key) = line
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected `player.button = key`, got `{}`", line))?;
+    let (player, button) = target
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| anyhow!("Expected `player.button`, got `{}`", target.trim()))?;
+
+    let player = match player.trim() {
+        "1" => 0,
+        "2" => 1,
+        p => bail!("Unknown player `{}`, expected 1 or 2", p),
+    };
+
+    let button = button.trim();
+    let (button, turbo) = match button.strip_prefix("turbo_") {
+        Some(b) => (b, true),
+        None => (button, false),
+    };
+    let button = BUTTON_NAMES
+        .iter()
+        .find(|&&(name, _)| name == button)
+        .map(|&(_, b)| b)
+        .ok_or_else(|| anyhow!("Unknown button `{}`", button))?;
+    if turbo && !(button == StandardControllerButtons::A || button == StandardControllerButtons::B)
+    {
+        bail!("Only A and B can have turbo");
+    }
+
+    let key = key.trim();
+    let scancode = Scancode::from_name(key).ok_or_else(|| {
+        anyhow!(
+            "Unknown key `{}` - key names are SDL scancode names, like `J`, `Up` or `Left Shift`",
+            key
+        )
+    })?;
+
+    Ok(Binding {
+        scancode,
+        player,
+        button,
+        turbo,
+    })
+}
@@ -1,61 +1,158 @@
+mod audio;
 mod emulator;
+mod gamepad;
+mod keymap;
+mod overlay;
+mod savestate;
 mod timer;
 use std::{
-    fs::File,
+    fs::{self, File},
     path::{Path, PathBuf},
-    time::Instant,
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use covnes::{
+    fds::FdsImage,
     fm2_movie_file::{Command, ControllerConfiguration, FM2File, GamepadInput, InputDevice},
-    nes::{io::StandardControllerButtons, mappers},
-    romfiles::RomFile,
+    nes::{apu::CHANNEL_NAMES, mappers::common::MirrorMode, palette::Palette, Overscan},
+    prelude::*,
 };
 use sdl2::{
-    event::Event,
-    keyboard::{Keycode, Scancode},
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Mod, Scancode},
     pixels::Color,
     rect::Rect,
     render::Canvas,
-    video::Window,
+    video::{FullscreenType, Window},
     EventPump,
 };
 use structopt::StructOpt;
 use timer::{TickResult, Timer};
 
-use crate::emulator::Emulator;
+use crate::{
+    audio::Audio,
+    emulator::{DiskStatus, Emulator, Game, Trace},
+    gamepad::Gamepads,
+    keymap::Keymap,
+    overlay::OverlayInfo,
+    savestate::SaveSlots,
+};
 
-const KEYMAP: &[(Scancode, StandardControllerButtons)] = &[
-    (Scancode::W, StandardControllerButtons::UP),
-    (Scancode::A, StandardControllerButtons::LEFT),
-    (Scancode::S, StandardControllerButtons::DOWN),
-    (Scancode::D, StandardControllerButtons::RIGHT),
-    (Scancode::J, StandardControllerButtons::A),
-    (Scancode::K, StandardControllerButtons::B),
-    (Scancode::U, StandardControllerButtons::SELECT),
-    (Scancode::I, StandardControllerButtons::START),
-];
+// How long messages like "Saved slot 1" stay in the title bar
+const MESSAGE_TIME: Duration = Duration::from_secs(2);
 
-pub const TARGET_FRAMERATE: f32 = 1789772.7272727 / 29780.5;
-pub const SCALE: u32 = 3;
+// Holding this runs the emulator as fast as it'll go
+const FAST_FORWARD_KEY: Scancode = Scancode::Tab;
+// While fast forwarding, how long to spend emulating frames between each one that's drawn. Only
+// the last frame is drawn, as vsync would otherwise limit us to one frame per refresh.
+const FAST_FORWARD_TIME: Duration = Duration::from_millis(12);
 
 #[derive(Debug, StructOpt)]
 struct Opt {
-    /// ROM file to load in iNES format
+    /// ROM file to load in iNES format, or a Famicom Disk System disk image ending in .fds
     #[structopt(parse(from_os_str))]
     romfile: PathBuf,
 
+    /// The Famicom Disk System's BIOS (usually called disksys.rom), which .fds disks need
+    #[structopt(long = "fds-bios", parse(from_os_str))]
+    fds_bios: Option<PathBuf>,
+
     #[structopt(short = "m", long = "movie_file", parse(from_os_str))]
     movie_file: Option<PathBuf>,
+
+    /// Key bindings to use instead of the defaults (WASD, J/K for A/B, U/I for select/start)
+    #[structopt(short = "k", long = "keymap", parse(from_os_str))]
+    keymap: Option<PathBuf>,
+
+    /// How many times the NES's resolution to make the window
+    #[structopt(long = "scale", default_value = "3")]
+    scale: u32,
+
+    /// How far a game controller's stick has to be pushed to count as a d-pad press, from 0 to 1
+    #[structopt(long = "deadzone", default_value = "0.3")]
+    deadzone: f32,
+
+    /// Hide the top and bottom 8 scanlines, like most TVs did
+    #[structopt(long = "overscan")]
+    overscan: bool,
+
+    /// Use the timing of Dendy famiclones (50Hz, with extra post-render scanlines)
+    #[structopt(long = "dendy")]
+    dendy: bool,
+
+    /// Don't wait for vsync when drawing, and sleep between frames instead. The game runs at the
+    /// same speed either way, but this can look smoother on monitors that aren't 60Hz.
+    #[structopt(long = "no-vsync")]
+    no_vsync: bool,
+
+    /// Draw every sprite on a scanline rather than only the first 8, which gets rid of most
+    /// flicker. A few games rely on the limit to hide sprites, so those will look wrong.
+    #[structopt(long = "no-sprite-limit")]
+    no_sprite_limit: bool,
+
+    /// A .pal file to draw with instead of the built in palette, either 192 bytes or 1536 with
+    /// the emphasis colours too
+    #[structopt(long = "palette", parse(from_os_str))]
+    palette: Option<PathBuf>,
+
+    /// Write a line to this file for every instruction run, in the same format as nestest.log, for
+    /// comparing against other emulators. This slows the emulator down a lot, so expect the game
+    /// to run well below full speed.
+    #[structopt(long = "trace", parse(from_os_str))]
+    trace: Option<PathBuf>,
+
+    /// Stop tracing after this many frames, to keep the file from getting too big
+    #[structopt(long = "trace-frames", requires = "trace")]
+    trace_frames: Option<u64>,
+
+    /// The sample rate to play sound at
+    #[structopt(long = "sample-rate", default_value = "44100")]
+    sample_rate: u32,
+
+    /// How many samples the sound card is given at a time. Smaller buffers have less delay, but
+    /// crackle if they can't be kept filled.
+    #[structopt(long = "audio-buffer", default_value = "1024")]
+    audio_buffer: u16,
+
+    /// How far behind the game the sound is kept, in milliseconds. It's held there by running the
+    /// sound very slightly faster or slower, and can't be less than two of --audio-buffer.
+    #[structopt(long = "audio-latency-ms", default_value = "80")]
+    audio_latency_ms: u32,
 }
 
 struct Ui {
     emulator: Emulator,
     movie: Option<(Vec<Command>, Vec<StandardControllerButtons>)>,
+    keymap: Keymap,
+    gamepads: Gamepads,
+    // Counts calls to process_input, for turbo buttons
+    input_frame: u32,
     canvas: Canvas<Window>,
+    // Where the picture goes in the window, from fit_to_window
+    screen: Rect,
+    overscan: Overscan,
     event_pump: EventPump,
     timer: Timer,
+    // None if there's no sound card to play through
+    audio: Option<Audio>,
+    // Without vsync to block on, the loop sleeps until the timer has a frame for it
+    sleep_between_frames: bool,
+    paused: bool,
+    fast_forward: bool,
+    // Frames to run while paused, from pressing the frame advance key
+    frames_to_advance: u32,
+    frame_rate: String,
+    save_slots: SaveSlots,
+    // Shown in the title instead of the frame rate until it's MESSAGE_TIME old
+    message: Option<(String, Instant)>,
+    // APU channels toggled off with F1-F5, indexed as in `apu::CHANNEL_NAMES`
+    muted_channels: [bool; CHANNEL_NAMES.len()],
+    // Set by cycling through the modes with M, to check for mirroring bugs
+    forced_mirroring: Option<MirrorMode>,
+    // Toggled with F12
+    show_overlay: bool,
     time_rendering: f32,
     time_waiting_for_next_frame: f32,
 }
@@ -72,27 +169,109 @@ enum BreakOrContinue {
 
 fn main() -> Result<()> {
     let opt: Opt = Opt::from_args();
+    if opt.scale == 0 {
+        bail!("--scale has to be at least 1");
+    }
+    if opt.sample_rate == 0 || opt.audio_buffer == 0 || opt.audio_latency_ms == 0 {
+        bail!("--sample-rate, --audio-buffer and --audio-latency-ms have to be at least 1");
+    }
+    let game = load_game(&opt.romfile, opt.fds_bios.as_deref())?;
+    let save_slots = SaveSlots::new(
+        &opt.romfile,
+        match &game {
+            Game::Rom(rom) => rom.prg_chr_crc32(),
+            Game::Fds { image, .. } => image.crc32(),
+        },
+    );
     let movie = if let Some(m) = opt.movie_file {
-        Some(parse_movie_file(&m)?)
+        Some(parse_movie_file(&m, &game)?)
     } else {
         None
     };
 
-    let scale = 3;
-    let rom = RomFile::from_filename(opt.romfile)?;
-    let cart = mappers::from_rom(rom)?;
+    let overscan = if opt.overscan {
+        Overscan::NTSC
+    } else {
+        Overscan::NONE
+    };
+    let keymap = match &opt.keymap {
+        Some(path) => Keymap::from_file(path)?,
+        None => Keymap::default(),
+    };
+    let region = if opt.dendy {
+        Region::Dendy
+    } else {
+        Region::Ntsc
+    };
+
+    let palette = match &opt.palette {
+        Some(path) => {
+            let data =
+                fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+            Palette::from_pal(&data)?
+        }
+        None => Palette::default(),
+    };
 
-    let emulator = Emulator::new(cart);
+    let trace = match &opt.trace {
+        Some(path) => {
+            let file = File::create(path)
+                .with_context(|| format!("Could not create {}", path.display()))?;
+            println!("Tracing to {}, which slows everything down", path.display());
+            Some(Trace {
+                file,
+                frames: opt.trace_frames,
+            })
+        }
+        None => None,
+    };
 
     let sdl_context = sdl2::init().map_err(sdl_error)?;
     let video_subsystem = sdl_context.video().map_err(sdl_error)?;
+    // The game's still playable without sound, so this isn't an error
+    let (sample_rate, audio_buffer, audio_latency_ms) =
+        (opt.sample_rate, opt.audio_buffer, opt.audio_latency_ms);
+    let audio = match sdl_context
+        .audio()
+        .map_err(sdl_error)
+        .and_then(|audio| Audio::open(&audio, sample_rate, audio_buffer, audio_latency_ms))
+    {
+        Ok(audio) => Some(audio),
+        Err(e) => {
+            println!("{}, so there won't be any sound", e);
+            None
+        }
+    };
+
+    let emulator = Emulator::new(
+        game,
+        region,
+        !opt.no_sprite_limit,
+        palette,
+        trace,
+        audio.as_ref().map(Audio::sample_rate),
+    )?;
+
+    let gamepads = Gamepads::new(
+        sdl_context.game_controller().map_err(sdl_error)?,
+        opt.deadzone,
+    );
 
     let window = video_subsystem
-        .window("covnes", 256 * scale, 240 * scale)
+        .window(
+            "covnes",
+            overscan.width() as u32 * opt.scale,
+            overscan.height() as u32 * opt.scale,
+        )
         .position_centered()
+        .resizable()
         .build()?;
 
-    let mut canvas = window.into_canvas().present_vsync().build()?;
+    let mut canvas = if opt.no_vsync {
+        window.into_canvas().build()?
+    } else {
+        window.into_canvas().present_vsync().build()?
+    };
 
     canvas.set_draw_color(Color::RGB(0, 0, 0));
     canvas.clear();
@@ -103,40 +282,101 @@ fn main() -> Result<()> {
     let mut ui = Ui {
         emulator,
         movie,
+        keymap,
+        gamepads,
+        input_frame: 0,
         canvas,
+        screen: Rect::new(0, 0, 1, 1),
+        overscan,
         event_pump,
-        timer: Timer::new(TARGET_FRAMERATE),
+        timer: Timer::new(region.frame_rate() as f32),
+        audio,
+        sleep_between_frames: opt.no_vsync,
+        paused: false,
+        fast_forward: false,
+        frames_to_advance: 0,
+        frame_rate: String::new(),
+        save_slots,
+        message: None,
+        muted_channels: [false; CHANNEL_NAMES.len()],
+        forced_mirroring: None,
+        show_overlay: false,
         time_rendering: 0.0,
         time_waiting_for_next_frame: 0.0,
     };
+    ui.fit_to_window()?;
 
     ui.run()
 }
 
 impl Ui {
     fn run(&mut self) -> Result<()> {
-        'outer: loop {
+        loop {
+            let was_paused = self.paused;
+            let was_fast_forward = self.fast_forward;
+            if self.process_events()? == BreakOrContinue::Break {
+                break;
+            }
+            self.fast_forward = !self.paused
+                && self
+                    .event_pump
+                    .keyboard_state()
+                    .is_scancode_pressed(FAST_FORWARD_KEY);
+
+            if self.sleep_between_frames && !self.fast_forward {
+                let ps = Instant::now();
+                thread::sleep(self.timer.time_until_next_frame());
+                self.time_waiting_for_next_frame += ps.elapsed().as_secs_f32();
+            }
+
+            // The timer doesn't bank up time while fast forwarding, so releasing the key goes
+            // straight back to normal speed rather than rushing to catch up
             let TickResult {
                 frames_to_step,
                 frame_rate_display_update,
-            } = self.timer.tick();
-
-            for _ in 0..frames_to_step {
-                match self.process_input() {
-                    BreakOrContinue::Break => break 'outer,
-                    BreakOrContinue::Continue => (),
+            } = self.timer.tick(self.paused || self.fast_forward);
+
+            let frames_to_step = if self.paused {
+                std::mem::take(&mut self.frames_to_advance)
+            } else {
+                frames_to_step
+            };
+            if let Some(audio) = &self.audio {
+                if self.paused || self.fast_forward {
+                    audio.allow_gap();
+                }
+            }
+            if self.fast_forward {
+                self.step_fast_forward();
+            } else {
+                for _ in 0..frames_to_step {
+                    let buttons = self.process_input();
+                    self.emulator.step_frame(buttons);
+                    if let Some(audio) = &self.audio {
+                        audio.queue(self.emulator.audio_samples());
+                        self.emulator
+                            .set_audio_rate_adjustment(audio.rate_adjustment());
+                    }
                 }
-                self.emulator.step_frame();
             }
 
             let ps = Instant::now();
             self.draw_frame();
             self.time_waiting_for_next_frame += ps.elapsed().as_secs_f32();
 
+            let mut title_changed = was_paused != self.paused
+                || was_fast_forward != self.fast_forward
+                || self.paused && frames_to_step > 0;
             if let Some(update) = frame_rate_display_update {
-                self.canvas
-                    .window_mut()
-                    .set_title(&format!("covnes: {}", update))?;
+                self.frame_rate = update;
+                title_changed = true;
+            }
+            if matches!(&self.message, Some((_, shown)) if shown.elapsed() > MESSAGE_TIME) {
+                self.message = None;
+                title_changed = true;
+            }
+            if title_changed {
+                self.update_title()?;
             }
         }
 
@@ -144,73 +384,322 @@ impl Ui {
         Ok(())
     }
 
+    // Runs frames until FAST_FORWARD_TIME is up. The sound from these frames is dropped rather
+    // than sped up.
+    fn step_fast_forward(&mut self) {
+        let start = Instant::now();
+        let mut frames = 0;
+        while start.elapsed() < FAST_FORWARD_TIME {
+            let buttons = self.process_input();
+            self.emulator.step_frame(buttons);
+            frames += 1;
+        }
+        self.timer.add_emulated_frames(frames);
+    }
+
     fn draw_frame(&mut self) {
         let ps = Instant::now();
         self.canvas.set_draw_color(Color::RGB(0, 0, 0));
         self.canvas.clear();
         let canvas = &mut self.canvas;
-        self.emulator.iter_pixels(|row, col, (r, g, b)| {
-            canvas.set_draw_color(Color::RGB(r, g, b));
-            canvas
-                .fill_rect(Rect::new(
-                    col as i32 * SCALE as i32,
-                    row as i32 * SCALE as i32,
-                    SCALE,
-                    SCALE,
-                ))
-                .unwrap()
-        });
+        let screen = self.screen;
+        let scale = screen.width() / self.overscan.width() as u32;
+        self.emulator
+            .iter_pixels(self.overscan, |row, col, (r, g, b)| {
+                canvas.set_draw_color(Color::RGB(r, g, b));
+                canvas
+                    .fill_rect(Rect::new(
+                        screen.x() + col as i32 * scale as i32,
+                        screen.y() + row as i32 * scale as i32,
+                        scale,
+                        scale,
+                    ))
+                    .unwrap()
+            });
+
+        if self.show_overlay {
+            let info = OverlayInfo {
+                fps: self.timer.fps(),
+                frame_count: self.emulator.frame_count(),
+                pc: self.emulator.pc(),
+                scanline: self.emulator.scanline(),
+            };
+            overlay::draw(&mut self.canvas, screen, scale, &info).unwrap();
+        }
 
         self.time_rendering += ps.elapsed().as_secs_f32();
         self.canvas.present();
     }
 
-    fn process_input(&mut self) -> BreakOrContinue {
+    // Scales the picture up by the biggest whole number that fits in the window, so every pixel
+    // is the same size, and centres it with black bars around whatever's left over
+    fn fit_to_window(&mut self) -> Result<()> {
+        let (window_width, window_height) = self.canvas.output_size().map_err(sdl_error)?;
+        let width = self.overscan.width() as u32;
+        let height = self.overscan.height() as u32;
+        let scale = (window_width / width).min(window_height / height).max(1);
+        self.screen = Rect::new(
+            (window_width as i32 - (width * scale) as i32) / 2,
+            (window_height as i32 - (height * scale) as i32) / 2,
+            width * scale,
+            height * scale,
+        );
+        Ok(())
+    }
+
+    fn toggle_fullscreen(&mut self) -> Result<()> {
+        let window = self.canvas.window_mut();
+        let fullscreen = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(fullscreen).map_err(sdl_error)?;
+        self.fit_to_window()
+    }
+
+    fn update_title(&mut self) -> Result<()> {
+        let status = match &self.message {
+            Some((message, _)) => message,
+            None if self.paused => "PAUSED",
+            None if self.fast_forward => "FAST FORWARD",
+            None => &self.frame_rate,
+        };
+        let muted: Vec<&str> = CHANNEL_NAMES
+            .iter()
+            .zip(&self.muted_channels)
+            .filter(|(_, &muted)| muted)
+            .map(|(&name, _)| name)
+            .collect();
+        let muted = if muted.is_empty() {
+            String::new()
+        } else {
+            format!(" [muted: {}]", muted.join(", "))
+        };
+        let title = format!(
+            "covnes: {}{} (frame {})",
+            status,
+            muted,
+            self.emulator.frame_count()
+        );
+        self.canvas.window_mut().set_title(&title)?;
+        Ok(())
+    }
+
+    fn process_events(&mut self) -> Result<BreakOrContinue> {
+        let mut slot_keys = vec![];
+        let mut channel_keys = vec![];
+        let mut toggle_fullscreen = false;
+        let mut cycle_mirroring = false;
+        let mut dump_memory = false;
+        let mut insert_or_eject_disk = false;
+        let mut select_next_disk_side = false;
+        let mut resized = false;
+
         for event in self.event_pump.poll_iter() {
+            self.gamepads.handle_event(&event);
             match event {
-                Event::Quit { .. } => return BreakOrContinue::Break,
+                Event::Quit { .. } => return Ok(BreakOrContinue::Break),
                 Event::KeyDown {
-                    keycode: Some(k), ..
+                    keycode: Some(k),
+                    keymod,
+                    repeat,
+                    ..
                 } => match k {
-                    Keycode::Escape => return BreakOrContinue::Break,
+                    Keycode::Escape => return Ok(BreakOrContinue::Break),
+                    Keycode::P if !repeat => self.paused = !self.paused,
+                    Keycode::F11 if !repeat => toggle_fullscreen = !toggle_fullscreen,
+                    Keycode::F12 if !repeat => self.show_overlay = !self.show_overlay,
+                    Keycode::M if !repeat => cycle_mirroring = true,
+                    Keycode::F9 if !repeat => dump_memory = true,
+                    Keycode::F7 if !repeat => insert_or_eject_disk = true,
+                    Keycode::F8 if !repeat => select_next_disk_side = true,
+                    // Holding the key down steps at the key repeat rate
+                    Keycode::N if self.paused => self.frames_to_advance += 1,
+                    _ if !repeat => {
+                        if let Some(slot) = save_slot(k) {
+                            let save = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                            slot_keys.push((slot, save));
+                        } else if let Some(channel) = channel_key(k) {
+                            channel_keys.push(channel);
+                        }
+                    }
                     _ => (),
                 },
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => resized = true,
                 _ => (),
             }
         }
-        // The rest of the game loop goes here...
 
-        match &mut self.movie {
+        // This borrows all of self, so can't be done while polling
+        for (slot, save) in slot_keys {
+            if save {
+                self.save_to_slot(slot);
+            } else {
+                self.load_from_slot(slot);
+            }
+            self.update_title()?;
+        }
+        for channel in channel_keys {
+            self.muted_channels[channel] = !self.muted_channels[channel];
+            self.emulator
+                .set_channel_enabled(channel, !self.muted_channels[channel]);
+            self.update_title()?;
+        }
+        if cycle_mirroring {
+            self.cycle_mirroring();
+            self.update_title()?;
+        }
+        if dump_memory {
+            self.dump_memory();
+            self.update_title()?;
+        }
+        if insert_or_eject_disk {
+            let status = self.emulator.insert_or_eject_disk();
+            self.show_disk_status(status);
+            self.update_title()?;
+        }
+        if select_next_disk_side {
+            let status = self.emulator.select_next_disk_side();
+            self.show_disk_status(status);
+            self.update_title()?;
+        }
+        if toggle_fullscreen {
+            self.toggle_fullscreen()?;
+        } else if resized {
+            self.fit_to_window()?;
+        }
+
+        Ok(BreakOrContinue::Continue)
+    }
+
+    // Goes through each mirroring mode in turn and then back to the cartridge's own
+    fn cycle_mirroring(&mut self) {
+        self.forced_mirroring = match self.forced_mirroring {
+            None => Some(MirrorMode::Horizontal),
+            Some(MirrorMode::Horizontal) => Some(MirrorMode::Vertical),
+            Some(MirrorMode::Vertical) => Some(MirrorMode::OneScreenLower),
+            Some(MirrorMode::OneScreenLower) => Some(MirrorMode::OneScreenHigher),
+            Some(MirrorMode::OneScreenHigher) => None,
+        };
+        self.emulator.set_mirroring(self.forced_mirroring);
+        let message = match self.forced_mirroring {
+            Some(mode) => format!("Mirroring forced to {:?}", mode),
+            None => "Mirroring back to the cartridge's".to_string(),
+        };
+        self.message = Some((message, Instant::now()));
+    }
+
+    // Writes out each of the console's memories to the current directory, to attach to bug reports
+    fn dump_memory(&mut self) {
+        let dump = self.emulator.dump_memory();
+        let files = [
+            ("ram.bin", &dump.ram),
+            ("vram.bin", &dump.vram),
+            ("oam.bin", &dump.oam),
+            ("cgram.bin", &dump.cgram),
+        ];
+        let written = files
+            .iter()
+            .try_for_each(|(name, data)| fs::write(name, data).map_err(|e| (name, e)));
+        let message = match written {
+            Ok(()) => "Dumped memory to ram.bin, vram.bin, oam.bin and cgram.bin".to_string(),
+            Err((name, e)) => {
+                println!("Couldn't write {}: {}", name, e);
+                format!("Couldn't write {}", name)
+            }
+        };
+        self.message = Some((message, Instant::now()));
+    }
+
+    // After F7 (insert or eject) or F8 (turn the disk over), counting sides from 1
+    fn show_disk_status(&mut self, status: Option<DiskStatus>) {
+        let message = match status {
+            None => "There's no disk drive".to_string(),
+            Some(DiskStatus {
+                inserted: Some(side),
+                sides,
+                ..
+            }) => format!("Side {} of {} inserted", side + 1, sides),
+            Some(DiskStatus {
+                inserted: None,
+                selected,
+                sides,
+            }) => format!("Disk ejected, side {} of {} next", selected + 1, sides),
+        };
+        self.message = Some((message, Instant::now()));
+    }
+
+    // Shift + 1-9 saves to a slot, and 1-9 on its own loads it back
+    fn save_to_slot(&mut self, slot: u8) {
+        let state = self.emulator.save_state();
+        let message = match self.save_slots.write(slot, &state) {
+            Ok(()) => format!("Saved slot {}", slot),
+            Err(e) => {
+                println!("Couldn't save slot {}: {:#}", slot, e);
+                format!("Couldn't save slot {}", slot)
+            }
+        };
+        self.message = Some((message, Instant::now()));
+    }
+
+    fn load_from_slot(&mut self, slot: u8) {
+        let loaded = self
+            .save_slots
+            .read(slot)
+            .and_then(|state| self.emulator.load_state(state).map_err(anyhow::Error::from));
+        let message = match loaded {
+            Ok(()) => format!("Loaded slot {}", slot),
+            Err(e) => {
+                println!("Couldn't load slot {}: {:#}", slot, e);
+                e.to_string()
+            }
+        };
+        self.message = Some((message, Instant::now()));
+    }
+
+    // The buttons for the next frame
+    fn process_input(&mut self) -> [StandardControllerButtons; 2] {
+        let buttons = match &mut self.movie {
             Some((commands, buttons)) => {
                 if let Some(c) = commands.pop() {
                     if c.contains(Command::SOFT_RESET) {
                         self.emulator.reset();
                     }
+                    // The side can only be changed with the disk out, so that goes first
+                    if c.contains(Command::FDS_DISK_SELECT) {
+                        self.emulator.select_next_disk_side();
+                    }
+                    if c.contains(Command::FDS_DISK_INSERT) {
+                        self.emulator.insert_or_eject_disk();
+                    }
                 }
-                if let Some(b) = buttons.pop() {
-                    self.emulator.set_buttons(b);
-                } else {
-                    self.emulator
-                        .set_buttons(StandardControllerButtons::empty());
-                }
+                let b = buttons
+                    .pop()
+                    .unwrap_or_else(StandardControllerButtons::empty);
+                [b, StandardControllerButtons::empty()]
             }
             None => {
-                let mut buttons = StandardControllerButtons::empty();
+                // The keyboard always works, even with a controller plugged in
                 let keys = self.event_pump.keyboard_state();
-                for &(sc, k) in KEYMAP {
-                    if keys.is_scancode_pressed(sc) {
-                        buttons |= k;
-                    }
+                let mut buttons = self.keymap.buttons(&keys, self.input_frame);
+                for (b, pad) in buttons.iter_mut().zip(self.gamepads.buttons()) {
+                    *b |= pad;
                 }
-                self.emulator.set_buttons(buttons);
+                buttons
             }
-        }
-
-        BreakOrContinue::Continue
+        };
+        self.input_frame = self.input_frame.wrapping_add(1);
+        buttons
     }
 
     fn show_counts(&self) {
         println!("{}", self.timer.summary_counts());
+        if let Some(audio) = &self.audio {
+            println!("The sound ran out {} times", audio.underruns());
+        }
 
         let render_per_frame = self.time_rendering / self.timer.render_frame_count() as f32;
         println!(
@@ -228,11 +717,75 @@ impl Ui {
     }
 }
 
-fn parse_movie_file(filename: &Path) -> Result<(Vec<Command>, Vec<GamepadInput>)> {
+fn save_slot(key: Keycode) -> Option<u8> {
+    let slot = match key {
+        Keycode::Num1 => 1,
+        Keycode::Num2 => 2,
+        Keycode::Num3 => 3,
+        Keycode::Num4 => 4,
+        Keycode::Num5 => 5,
+        Keycode::Num6 => 6,
+        Keycode::Num7 => 7,
+        Keycode::Num8 => 8,
+        Keycode::Num9 => 9,
+        _ => return None,
+    };
+    Some(slot)
+}
+
+// F1-F5 mute and unmute the APU's channels, as 1-5 are taken by the save slots
+fn channel_key(key: Keycode) -> Option<usize> {
+    let channel = match key {
+        Keycode::F1 => 0,
+        Keycode::F2 => 1,
+        Keycode::F3 => 2,
+        Keycode::F4 => 3,
+        Keycode::F5 => 4,
+        _ => return None,
+    };
+    Some(channel)
+}
+
+// .fds files are disks, and anything else is taken to be an iNES ROM
+fn load_game(path: &Path, fds_bios: Option<&Path>) -> Result<Game> {
+    let is_fds = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("fds"));
+    if !is_fds {
+        return Ok(Game::Rom(RomFile::from_filename(path)?));
+    }
+
+    let bios = match fds_bios {
+        Some(bios) => {
+            fs::read(bios).with_context(|| format!("Could not read {}", bios.display()))?
+        }
+        None => bail!("Disk images need the disk system's BIOS, given with --fds-bios"),
+    };
+    let image = FdsImage::from_filename(path)?;
+    Ok(Game::Fds { bios, image })
+}
+
+fn parse_movie_file(filename: &Path, game: &Game) -> Result<(Vec<Command>, Vec<GamepadInput>)> {
     let mut f = File::open(filename)?;
     let fm2 = FM2File::parse(&mut f)?;
-    if fm2.pal_flag || fm2.fds {
-        bail!("Unsupported movie (pal or fds)");
+    if fm2.pal_flag {
+        bail!("Unsupported movie (pal)");
+    }
+    if fm2.fds != matches!(game, Game::Fds { .. }) {
+        bail!("Movies recorded on the disk system only work with disks, and the other way round");
+    }
+    // FCEUX's checksum for disks is of what it's written to them, which we can't match
+    let matches = match game {
+        Game::Rom(rom) => fm2.matches_rom(rom),
+        Game::Fds { .. } => true,
+    };
+    if !matches {
+        // Could just be a different dump of the same game, so carry on anyway
+        eprintln!(
+            "Warning: {} was recorded with a different ROM ({})",
+            filename.display(),
+            fm2.rom_filename
+        );
     }
     let mut commands = fm2.commands;
     let mut buttons = match fm2.controllers {
@@ -246,9 +799,59 @@ fn parse_movie_file(filename: &Path) -> Result<(Vec<Command>, Vec<GamepadInput>)
     commands.reverse();
     buttons.reverse();
 
-    // We tend to be one frame ahead of FCEUX
-    //    commands.pop();
-    //    buttons.pop();
+    // FCEUX's frames start at vblank, but ours end there, so our first frame is the partial one
+    // between power on and the first vblank. Without an extra empty frame up front every NMI
+    // would see the input meant for the frame after it and we'd end up one frame ahead.
+    commands.push(Command::empty());
+    buttons.push(GamepadInput::empty());
 
     Ok((commands, buttons))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+
+    #[test]
+    fn movie_input_lands_on_the_frame_after_it_was_recorded() -> Result<()> {
+        let path = env::temp_dir().join(format!("covnes-movie-test-{}.fm2", std::process::id()));
+        fs::write(
+            &path,
+            "version 3\n\
+             emuVersion 22020\n\
+             port0 1\n\
+             port1 0\n\
+             port2 0\n\
+             romFilename nestest\n\
+             guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+             romChecksum base64:AAAAAAAAAAAAAAAAAAAAAA==\n\
+             |0|.......A|||\n\
+             |1|......B.|||\n\
+             |0|....T...|||\n",
+        )?;
+        let game = Game::Rom(RomFile::from_filename("../roms/test/nestest.nes")?);
+        let movie = parse_movie_file(&path, &game);
+        fs::remove_file(&path)?;
+        let (mut commands, mut buttons) = movie?;
+
+        // Popped a frame at a time, like `process_input` does. Our first frame ends at the first
+        // vblank, before FCEUX's first frame starts, so it gets nothing.
+        let mut frames = Vec::new();
+        while let Some(b) = buttons.pop() {
+            frames.push((commands.pop(), b));
+        }
+        assert_eq!(
+            frames,
+            [
+                (Some(Command::empty()), GamepadInput::empty()),
+                (Some(Command::empty()), GamepadInput::A),
+                (Some(Command::SOFT_RESET), GamepadInput::B),
+                (Some(Command::empty()), GamepadInput::START),
+            ]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use sdl2::{pixels::Color, rect::Rect, render::Canvas, video::Window};
+
+// Debugging info drawn over the top left of the game with F12. The text is in a tiny 3x5 font
+// built in here, so there's no font file to find, and it only has the characters the overlay
+// uses. Anything else comes out as a blank.
+
+const GLYPH_WIDTH: i32 = 3;
+const GLYPH_HEIGHT: i32 = 5;
+
+// Each row is 3 bits, with the leftmost pixel in bit 2
+const GLYPHS: &[(char, [u8; 5])] = &[
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b011, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+];
+
+pub struct OverlayInfo {
+    pub fps: f32,
+    pub frame_count: u64,
+    pub pc: u16,
+    pub scanline: u16,
+}
+
+// Draws `info` in the top left corner of `screen`, with each pixel of the font `pixel_size`
+// pixels across so it's the same size as the game's own
+pub fn draw(
+    canvas: &mut Canvas<Window>,
+    screen: Rect,
+    pixel_size: u32,
+    info: &OverlayInfo,
+) -> Result<(), String> {
+    let lines = [
+        format!("FPS {:.1}", info.fps),
+        format!("FRAME {}", info.frame_count),
+        format!("PC {:04X}", info.pc),
+        format!("SL {}", info.scanline),
+    ];
+
+    // A box behind the text so it shows up on any background, with a pixel of space all around
+    let size = pixel_size as i32;
+    let columns = lines.iter().map(|l| l.len()).max().unwrap_or(0) as i32;
+    let width = columns * (GLYPH_WIDTH + 1) + 1;
+    let height = lines.len() as i32 * (GLYPH_HEIGHT + 1) + 1;
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    canvas.fill_rect(Rect::new(
+        screen.x(),
+        screen.y(),
+        (width * size) as u32,
+        (height * size) as u32,
+    ))?;
+
+    canvas.set_draw_color(Color::RGB(255, 255, 255));
+    for (row, line) in lines.iter().enumerate() {
+        let y = screen.y() + (1 + row as i32 * (GLYPH_HEIGHT + 1)) * size;
+        for (col, c) in line.chars().enumerate() {
+            let x = screen.x() + (1 + col as i32 * (GLYPH_WIDTH + 1)) * size;
+            draw_glyph(canvas, x, y, pixel_size, c)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn draw_glyph(
+    canvas: &mut Canvas<Window>,
+    x: i32,
+    y: i32,
+    pixel_size: u32,
+    c: char,
+) -> Result<(), String> {
+    let rows = match GLYPHS.iter().find(|(g, _)| *g == c) {
+        Some((_, rows)) => rows,
+        None => return Ok(()),
+    };
+
+    let size = pixel_size as i32;
+    for (dy, bits) in rows.iter().enumerate() {
+        for dx in 0..GLYPH_WIDTH {
+            if bits >> (GLYPH_WIDTH - 1 - dx) & 1 == 1 {
+                canvas.fill_rect(Rect::new(
+                    x + dx * size,
+                    y + dy as i32 * size,
+                    pixel_size,
+                    pixel_size,
+                ))?;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+
+// Save state slots, each stored next to the ROM as `<rom>.stateN`. The file is a small header
+// followed by the state from `Nes::save_state`. The header has the ROM's (or disk's) CRC-32, so states from
+// a different game (or a different dump of the same one) can be refused up front rather than
+// relying on the core noticing.
+
+// The first version of the header had a hash of the whole file instead
+const MAGIC: &[u8; 4] = b"CVS2";
+const HEADER_LEN: usize = MAGIC.len() + 4;
+
+pub struct SaveSlots {
+    rom_path: PathBuf,
+    rom_crc32: u32,
+}
+
+impl SaveSlots {
+    pub fn new(rom_path: &Path, rom_crc32: u32) -> Self {
+        Self {
+            rom_path: rom_path.to_owned(),
+            rom_crc32,
+        }
+    }
+
+    pub fn path(&self, slot: u8) -> PathBuf {
+        let mut name = self.rom_path.clone().into_os_string();
+        name.push(format!(".state{}", slot));
+        PathBuf::from(name)
+    }
+
+    pub fn write(&self, slot: u8, state: &[u8]) -> Result<()> {
+        let mut data = Vec::with_capacity(HEADER_LEN + state.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.rom_crc32.to_le_bytes());
+        data.extend_from_slice(state);
+
+        let path = self.path(slot);
+        fs::write(&path, data).with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    pub fn read(&self, slot: u8) -> Result<Vec<u8>> {
+        let path = self.path(slot);
+        let data = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == ErrorKind::NotFound => bail!("Slot {} is empty", slot),
+            Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+        };
+
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            bail!("Slot {} isn't a save state", slot);
+        }
+        let mut crc32 = [0; 4];
+        crc32.copy_from_slice(&data[MAGIC.len()..HEADER_LEN]);
+        if u32::from_le_bytes(crc32) != self.rom_crc32 {
+            bail!("Slot {} is from a different ROM", slot);
+        }
+
+        Ok(data[HEADER_LEN..].to_vec())
+    }
+}
@@ -9,6 +9,8 @@ pub struct Timer {
     emulated_frame_count: u32,
     last_update: Instant,
     render_frames_at_last_update: u32,
+    // Frames drawn per second over the last second or so, for the debug overlay
+    fps: f32,
 }
 
 pub struct TickResult {
@@ -28,12 +30,19 @@ impl Timer {
             emulated_frame_count: 0,
             last_update: now,
             render_frames_at_last_update: 0,
+            fps: 0.0,
         }
     }
 
-    pub fn tick(&mut self) -> TickResult {
+    // While paused (or fast forwarding) no time is banked up, so there isn't a rush of frames to
+    // catch up on after
+    pub fn tick(&mut self, paused: bool) -> TickResult {
         self.render_frame_count += 1;
-        self.time_to_spend += self.last_frame.elapsed().as_secs_f32();
+        if paused {
+            self.time_to_spend = 0.0;
+        } else {
+            self.time_to_spend += self.last_frame.elapsed().as_secs_f32();
+        }
         let now = Instant::now();
         self.last_frame = now;
 
@@ -46,9 +55,11 @@ impl Timer {
         self.emulated_frame_count += frames_to_step;
         let time_since_last_update = self.last_update.elapsed().as_secs_f32();
         let frame_rate_display_update = if time_since_last_update > 1.0 {
-            let ms_per_frame = 1000.0 * time_since_last_update
-                / (self.render_frame_count - self.render_frames_at_last_update) as f32;
+            let frames = self.render_frame_count - self.render_frames_at_last_update;
+            let ms_per_frame = 1000.0 * time_since_last_update / frames as f32;
+            self.fps = frames as f32 / time_since_last_update;
             self.last_update = now;
+            self.render_frames_at_last_update = self.render_frame_count;
             Some(format!("{:.1}ms/frame", ms_per_frame))
         } else {
             None
@@ -60,6 +71,23 @@ impl Timer {
         }
     }
 
+    // How long until `tick` will next have a frame to step, for sleeping on instead of vsync. Any
+    // oversleeping is made up for on the next tick, so the emulated frame rate comes out exact
+    // whatever the display is doing.
+    pub fn time_until_next_frame(&self) -> Duration {
+        let banked = self.time_to_spend + self.last_frame.elapsed().as_secs_f32();
+        Duration::from_secs_f32((self.secs_per_emulated_frame - banked).max(0.0))
+    }
+
+    // For frames that were run without the timer asking for them
+    pub fn add_emulated_frames(&mut self, frames: u32) {
+        self.emulated_frame_count += frames;
+    }
+
+    pub fn fps(&self) -> f32 {
+        self.fps
+    }
+
     pub fn render_frame_count(&self) -> u32 {
         self.render_frame_count
     }
@@ -1,14 +1,6 @@
 mod utils;
 
-use std::cell::Cell;
-
-use covnes::{
-    nes::{
-        io::{SingleStandardController, SingleStandardControllerIO, StandardControllerButtons},
-        mappers, Nes,
-    },
-    romfiles::RomFile,
-};
+use covnes::prelude::*;
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -29,65 +21,111 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
 #[wasm_bindgen]
 pub struct EmulatorState {
-    nes: Nes<SingleStandardController<WasmIO>>,
+    emulator: Emulator,
 }
 
 #[wasm_bindgen]
 impl EmulatorState {
     pub fn new() -> EmulatorState {
-        let io = SingleStandardController::new(WasmIO::new());
-        EmulatorState { nes: Nes::new(io) }
+        EmulatorState {
+            emulator: Emulator::new(),
+        }
+    }
+
+    // Does nothing until a ROM is loaded, so the test pattern stays up
+    pub fn tick_cycle(&mut self, buttons: u8) -> usize {
+        self.emulator
+            .set_buttons(StandardControllerButtons::from_bits_truncate(buttons));
+        self.emulator.step_frame()
     }
 
-    pub fn tick_cycle(&self, buttons: u8) -> usize {
-        self.nes
-            .io
-            .io
-            .buttons
-            .set(StandardControllerButtons::from_bits_truncate(buttons));
-        self.nes.step_frame()
+    pub fn frame_count(&self) -> u64 {
+        self.emulator.nes().frame_count()
     }
 
-    pub fn get_video(&self) -> *mut [u8; 256 * 240 * 3] {
-        self.nes.io.io.video_mem.as_ptr()
+    pub fn get_video(&self) -> *const u8 {
+        self.emulator.frame_buffer().as_ptr()
     }
 
-    pub fn load_rom(&mut self, mut rom: &[u8]) -> Result<(), JsValue> {
-        let rom = RomFile::from_read(&mut rom).map_err(|e| JsValue::from_str(&e.to_string()))?;
-        let cart = mappers::from_rom(rom).map_err(|e| JsValue::from_str(&e.to_string()))?;
-        self.nes.insert_cartridge(cart);
-        self.nes.reset();
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), LoadError> {
+        let rom = RomFile::from_bytes(rom)?;
+        self.emulator.load_rom(rom)?;
 
         Ok(())
     }
+
+    // What the loaded game's mapper doesn't do yet, so the page can warn that it might not work.
+    // Empty if there's nothing missing.
+    pub fn missing_features(&self) -> String {
+        self.emulator.nes().cartridge.missing_features().join(", ")
+    }
+
+    pub fn reset(&mut self) {
+        self.emulator.reset();
+    }
 }
 
+// What `load_rom` throws, so the page can tell a ROM we can't run yet from one that's broken
 #[wasm_bindgen]
-pub struct WasmIO {
-    video_mem: Cell<[u8; 240 * 256 * 3]>,
-    buttons: Cell<StandardControllerButtons>,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LoadErrorKind {
+    UnsupportedMapper,
+    // A ROM that's fine, but uses something else covnes doesn't do (trainers, odd mirroring)
+    Unsupported,
+    Corrupt,
 }
 
-impl WasmIO {
-    fn new() -> WasmIO {
-        WasmIO {
-            video_mem: Cell::new([0; 240 * 256 * 3]),
-            buttons: Cell::new(StandardControllerButtons::empty()),
-        }
-    }
+#[wasm_bindgen]
+pub struct LoadError {
+    kind: LoadErrorKind,
+    mapper: Option<u32>,
+    submapper: Option<u8>,
+    message: String,
 }
 
-impl SingleStandardControllerIO for WasmIO {
-    fn set_pixel(&self, row: u16, col: u16, r: u8, g: u8, b: u8) {
-        let f: &Cell<[u8]> = &self.video_mem;
-        let idx = (row as usize * 256 + col as usize) * 3;
-        let s = f.as_slice_of_cells();
-        s[idx].set(r);
-        s[idx + 1].set(g);
-        s[idx + 2].set(b);
+#[wasm_bindgen]
+impl LoadError {
+    #[wasm_bindgen(getter)]
+    pub fn kind(&self) -> LoadErrorKind {
+        self.kind
+    }
+
+    // Only set for `UnsupportedMapper`
+    #[wasm_bindgen(getter)]
+    pub fn mapper(&self) -> Option<u32> {
+        self.mapper
+    }
+
+    // Also only set for `UnsupportedMapper`, and 0 unless the ROM has an NES 2.0 header
+    #[wasm_bindgen(getter)]
+    pub fn submapper(&self) -> Option<u8> {
+        self.submapper
     }
 
-    fn poll_buttons(&self) -> StandardControllerButtons {
-        self.buttons.get()
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+impl From<Error> for LoadError {
+    fn from(e: Error) -> LoadError {
+        let (kind, mapper, submapper) = match e {
+            Error::UnsupportedMapper { mapper, submapper } => (
+                LoadErrorKind::UnsupportedMapper,
+                Some(mapper as u32),
+                Some(submapper),
+            ),
+            Error::TrainerUnsupported | Error::UnsupportedMirroring { .. } => {
+                (LoadErrorKind::Unsupported, None, None)
+            }
+            _ => (LoadErrorKind::Corrupt, None, None),
+        };
+        LoadError {
+            kind,
+            mapper,
+            submapper,
+            message: e.to_string(),
+        }
     }
 }
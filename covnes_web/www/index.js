@@ -14,11 +14,22 @@ function romFileChange() {
     reader.onload = (evt) => {
         let ab = evt.target.result;
         const view = new Int8Array(ab);
-        // try {
+        const loadError = document.getElementById('romfile_load_error');
+        try {
             emu.load_rom(view);
-        // } catch(err) {
-        //     document.getElementById('romfile_load_error').innerHTML = err.message;
-        // }
+            const missing = emu.missing_features();
+            loadError.textContent = missing ? `This game's mapper is missing ${missing}, so it might not work properly` : "";
+            draw();
+        } catch(err) {
+            if(err.kind === covnes.LoadErrorKind.UnsupportedMapper) {
+                const submapper = err.submapper ? ` (submapper ${err.submapper})` : "";
+                loadError.textContent = `Mapper ${err.mapper}${submapper} isn't supported yet`;
+            } else if(err.kind === covnes.LoadErrorKind.Corrupt) {
+                loadError.textContent = `That doesn't look like a NES ROM: ${err.message}`;
+            } else {
+                loadError.textContent = err.message;
+            }
+        }
     };
     reader.readAsArrayBuffer(file);
 }
@@ -77,35 +88,46 @@ playPauseButton.onclick = function() {
     }
 };
 
-function step() {
-    if(!isPaused) {
-        emu.tick_cycle(buttons);
-        const pointer = emu.get_video();
-        const cells = new Uint8Array(memory.buffer, pointer, 256 * 240 * 3);
-        const imageData = ctx.createImageData(256 * scale, 240 * scale);
-        for(let row = 0; row < 240; row++) {
-            for(let col = 0; col < 256; col++) {
-                let index = (row * 256 + col) * 3;
-                const r = cells[index];
-                const g = cells[index + 1];
-                const b = cells[index + 2];
-
-                for(let i = 0; i < scale; i++) {
-                    for(let j = 0; j < scale; j++) {
-                        let id_index = ((row * scale + i) * 256 * scale + (col * scale + j)) * 4;
-                        imageData.data[id_index] = r;
-                        imageData.data[id_index + 1] = g;
-                        imageData.data[id_index + 2] = b;
-                        imageData.data[id_index + 3] = 255;
-                    }
+document.getElementById('reset').onclick = function() {
+    emu.reset();
+    draw();
+};
+
+function draw() {
+    const pointer = emu.get_video();
+    const cells = new Uint8Array(memory.buffer, pointer, 256 * 240 * 3);
+    const imageData = ctx.createImageData(256 * scale, 240 * scale);
+    for(let row = 0; row < 240; row++) {
+        for(let col = 0; col < 256; col++) {
+            let index = (row * 256 + col) * 3;
+            const r = cells[index];
+            const g = cells[index + 1];
+            const b = cells[index + 2];
+
+            for(let i = 0; i < scale; i++) {
+                for(let j = 0; j < scale; j++) {
+                    let id_index = ((row * scale + i) * 256 * scale + (col * scale + j)) * 4;
+                    imageData.data[id_index] = r;
+                    imageData.data[id_index + 1] = g;
+                    imageData.data[id_index + 2] = b;
+                    imageData.data[id_index + 3] = 255;
                 }
             }
         }
+    }
+
+    ctx.putImageData(imageData, 0, 0);
+}
 
-        ctx.putImageData(imageData, 0, 0);
+function step() {
+    if(!isPaused) {
+        emu.tick_cycle(buttons);
+        draw();
     }
 
     window.requestAnimationFrame(step);
 }
 
+// The test pattern, until there's a ROM
+draw();
 step();